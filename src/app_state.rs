@@ -1,4 +1,10 @@
+use crate::diagnostics::InputDiagnostics;
+use crate::enhancer::Enhancer;
+use crate::passes::Pass;
+use crate::quality::QualityReport;
+
 use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
 
 pub struct AppState {
     pub debug: bool,
@@ -7,6 +13,21 @@ pub struct AppState {
     pub sync: AtomicBool,
     pub use_model: AtomicBool,
     pub use_sgbnr: AtomicBool,
+    pub split_passes: AtomicBool,
+    // Paths of every image written by the last run, first one is image.png
+    pub outputs: Mutex<Vec<String>>,
+    // Cloud fraction of the last cloud mask
+    pub cloud_fraction: Mutex<Option<f32>>,
+    // Input level and subcarrier diagnostics of the last run
+    pub input: Mutex<Option<InputDiagnostics>>,
+    // Signal quality of the last run
+    pub quality: Mutex<Option<QualityReport>>,
+    // Lines kept by the last run (first, last exclusive) and the number of decoded lines
+    pub line_span: Mutex<Option<(usize, usize, usize)>>,
+    // Passes decoded by the last split run
+    pub passes: Mutex<Vec<Pass>>,
+    // Enhancement model kept loaded between runs
+    pub enhancer: Mutex<Option<Enhancer>>,
     // You can add more shared state as needed: e.g., ProgressBar, etc.
 }
 
@@ -19,6 +40,14 @@ impl AppState {
             sync: AtomicBool::new(false),
             use_model: AtomicBool::new(false),
             use_sgbnr: AtomicBool::new(false),
+            split_passes: AtomicBool::new(false),
+            outputs: Mutex::new(Vec::new()),
+            cloud_fraction: Mutex::new(None),
+            input: Mutex::new(None),
+            quality: Mutex::new(None),
+            line_span: Mutex::new(None),
+            passes: Mutex::new(Vec::new()),
+            enhancer: Mutex::new(None),
         }
     }
 }
@@ -0,0 +1,44 @@
+use image::{GrayImage, ImageBuffer};
+
+// Width reduction applied by generate_image (10400 samples -> 2080 pixels per line)
+pub const REDUCTION_FACTOR: u32 = 5;
+
+// APT line layout in pixels after reduction
+// [Sync A | Space A | Image A | Telemetry A | Sync B | Space B | Image B | Telemetry B]
+pub const LINE_WIDTH: u32 = 2080;
+pub const CHANNEL_LINE_WIDTH: u32 = LINE_WIDTH / 2;
+pub const SYNC_WIDTH: u32 = 39;
+pub const SPACE_WIDTH: u32 = 47;
+pub const CHANNEL_WIDTH: u32 = 909;
+
+/// Column of the sync A bar in a synced image.
+/// sync_apt starts every row `additional_offset` samples before sync A.
+pub fn sync_column(additional_offset: usize) -> u32 {
+    (additional_offset as u32 / REDUCTION_FACTOR) % LINE_WIDTH
+}
+
+/// Column of the first image pixel of channel A (0) or channel B (1).
+pub fn channel_start(sync_column: u32, channel: u32) -> u32 {
+    sync_column + channel * CHANNEL_LINE_WIDTH + SYNC_WIDTH + SPACE_WIDTH
+}
+
+/// Extract `width` columns starting at `start`, wrapping around the line.
+pub fn extract_columns(image: &GrayImage, start: u32, width: u32) -> GrayImage {
+    let (w, h) = image.dimensions();
+    ImageBuffer::from_fn(width, h, |x, y| *image.get_pixel((start + x) % w, y))
+}
+
+/// Split a synced APT image into its channel A (visible) and channel B (IR) images.
+/// An image upscaled by an integer factor, `scale` times LINE_WIDTH wide, gives
+/// channels `scale` times CHANNEL_WIDTH wide.
+pub fn split_channels(image: &GrayImage, sync_column: u32) -> (GrayImage, GrayImage) {
+    let scale = (image.width() / LINE_WIDTH).max(1);
+    let channel = |index| {
+        extract_columns(
+            image,
+            channel_start(sync_column, index) * scale,
+            CHANNEL_WIDTH * scale,
+        )
+    };
+    (channel(0), channel(1))
+}
@@ -0,0 +1,431 @@
+use crate::trim;
+
+use std::f32::consts::PI;
+
+// Impulses are samples above this many times the local mean level, blanked with a guard
+const IMPULSE_FACTOR: f32 = 6.0;
+const IMPULSE_WINDOW: usize = 1024;
+const IMPULSE_GUARD: usize = 4;
+
+// Tones are searched every second in a Welch spectrum and notched when they stand
+// this far above the median of the bins around them
+const NOTCH_BLOCK: usize = 20800;
+const NOTCH_FFT: usize = 2048;
+const MIN_TONE_DB: f32 = 20.0;
+const TONE_NEIGHBORHOOD: usize = 50;
+const MAX_NOTCHES: usize = 4;
+const NOTCH_Q: f32 = 30.0;
+// The carrier and its sync sidebands (sync A 1040 Hz, sync B 832 Hz) look like tones too
+const PROTECTED_FREQUENCIES: [f32; 9] = [
+    2400.0, 1360.0, 3440.0, 1568.0, 3232.0, 320.0, 4480.0, 736.0, 4064.0,
+];
+const PROTECTED_WIDTH: f32 = 30.0;
+
+// Spectral subtraction
+const STFT_SIZE: usize = 512;
+const STFT_HOP: usize = STFT_SIZE / 2;
+const OVER_SUBTRACTION: f32 = 1.0;
+const GAIN_FLOOR: f32 = 0.1;
+// Lines carrying less subcarrier than trim::MIN_SUBCARRIER_FRACTION are noise only
+// Without noise-only lines the noise is taken as flat at its level above the APT band (Hz)
+const OUT_OF_BAND_FREQUENCY: f32 = 5000.0;
+
+pub struct CleanupSettings {
+    pub impulse_blanker: bool,
+    pub notch_filter: bool,
+    pub noise_reduction: bool,
+}
+
+/// In-place iterative radix-2 FFT, the length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        for i in 0..n {
+            re[i] /= n as f32;
+            im[i] /= n as f32;
+        }
+    }
+}
+
+/// Periodic Hann window, or its square root for analysis/synthesis pairs.
+fn hann(size: usize, sqrt: bool) -> Vec<f32> {
+    (0..size)
+        .map(|i| {
+            let w = 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos();
+            if sqrt {
+                w.sqrt()
+            } else {
+                w
+            }
+        })
+        .collect()
+}
+
+/// Zero samples far above the local mean level, plus a few samples around them.
+pub fn blank_impulses(samples: &mut [f32]) -> usize {
+    let mut prefix = vec![0.0f64; samples.len() + 1];
+    for (i, sample) in samples.iter().enumerate() {
+        prefix[i + 1] = prefix[i] + sample.abs() as f64;
+    }
+
+    let half = IMPULSE_WINDOW / 2;
+    let impulses: Vec<usize> = (0..samples.len())
+        .filter(|&i| {
+            let (start, end) = (i.saturating_sub(half), (i + half).min(samples.len()));
+            let level = ((prefix[end] - prefix[start]) / (end - start) as f64) as f32;
+            samples[i].abs() > IMPULSE_FACTOR * level
+        })
+        .collect();
+
+    for &i in &impulses {
+        let end = (i + IMPULSE_GUARD + 1).min(samples.len());
+        for sample in &mut samples[i.saturating_sub(IMPULSE_GUARD)..end] {
+            *sample = 0.0;
+        }
+    }
+    impulses.len()
+}
+
+/// Averaged power spectrum of `samples` (Hann windowed, 50% overlap).
+fn welch(samples: &[f32], size: usize) -> Vec<f32> {
+    let window = hann(size, false);
+    let mut power = vec![0.0; size / 2];
+    let mut frames = 0;
+    for start in (0..samples.len().saturating_sub(size - 1)).step_by(size / 2) {
+        let mut re: Vec<f32> = (0..size).map(|i| samples[start + i] * window[i]).collect();
+        let mut im = vec![0.0; size];
+        fft(&mut re, &mut im, false);
+        for (k, p) in power.iter_mut().enumerate() {
+            *p += re[k] * re[k] + im[k] * im[k];
+        }
+        frames += 1;
+    }
+    for p in &mut power {
+        *p /= frames.max(1) as f32;
+    }
+    power
+}
+
+/// Frequencies of the strongest tones standing out of the spectrum, away from the APT lines.
+fn find_tones(samples: &[f32], sample_rate: f32) -> Vec<f32> {
+    let power = welch(samples, NOTCH_FFT);
+    let bin_width = sample_rate / NOTCH_FFT as f32;
+
+    let mut tones: Vec<(f32, f32)> = Vec::new();
+    for k in 1..power.len() - 1 {
+        if power[k] <= power[k - 1] || power[k] < power[k + 1] {
+            continue;
+        }
+        let frequency = k as f32 * bin_width;
+        if PROTECTED_FREQUENCIES
+            .iter()
+            .any(|f| (frequency - f).abs() < PROTECTED_WIDTH)
+        {
+            continue;
+        }
+        let mut neighborhood: Vec<f32> = power
+            [k.saturating_sub(TONE_NEIGHBORHOOD)..(k + TONE_NEIGHBORHOOD).min(power.len())]
+            .to_vec();
+        neighborhood.sort_by(|a, b| a.total_cmp(b));
+        let median = neighborhood[neighborhood.len() / 2].max(f32::MIN_POSITIVE);
+        let prominence_db = 10.0 * (power[k] / median).log10();
+        if prominence_db >= MIN_TONE_DB {
+            tones.push((frequency, prominence_db));
+        }
+    }
+
+    tones.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tones.truncate(MAX_NOTCHES);
+    tones.into_iter().map(|(frequency, _)| frequency).collect()
+}
+
+/// RBJ biquad notch with its state.
+struct Notch {
+    frequency: f32,
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Notch {
+    fn new(frequency: f32, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * NOTCH_Q);
+        let a0 = 1.0 + alpha;
+        Self {
+            frequency,
+            b: [1.0 / a0, -2.0 * w0.cos() / a0, 1.0 / a0],
+            a: [-2.0 * w0.cos() / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let output = self.b[0] * sample + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [sample, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// Notch the interfering tones found in every one second block. A notch whose tone
+/// stays within a bin keeps its state across blocks.
+pub fn notch_tones(samples: &mut [f32], sample_rate: f32) -> Vec<f32> {
+    let bin_width = sample_rate / NOTCH_FFT as f32;
+    let mut notches: Vec<Notch> = Vec::new();
+    let mut found = Vec::new();
+
+    for block in samples.chunks_mut(NOTCH_BLOCK) {
+        let tones = find_tones(block, sample_rate);
+        let mut previous = std::mem::take(&mut notches);
+        for &frequency in &tones {
+            let notch = match previous
+                .iter()
+                .position(|n| (n.frequency - frequency).abs() <= bin_width)
+            {
+                Some(i) => previous.swap_remove(i),
+                None => Notch::new(frequency, sample_rate),
+            };
+            notches.push(notch);
+        }
+        for notch in &mut notches {
+            for sample in block.iter_mut() {
+                *sample = notch.process(*sample);
+            }
+        }
+        for frequency in tones {
+            if !found
+                .iter()
+                .any(|f: &f32| (f - frequency).abs() <= bin_width)
+            {
+                found.push(frequency);
+            }
+        }
+    }
+    found
+}
+
+/// Spectral subtraction with the noise spectrum of the noise-only lines
+/// (or the flat noise level above the APT band when the whole file carries signal).
+pub fn reduce_noise(samples: &[f32], sample_rate: f32) -> Vec<f32> {
+    let window = hann(STFT_SIZE, true);
+    let frames = samples.len().saturating_sub(STFT_SIZE) / STFT_HOP + 1;
+    if samples.len() < STFT_SIZE {
+        return samples.to_vec();
+    }
+
+    let spectra: Vec<(Vec<f32>, Vec<f32>)> = (0..frames)
+        .map(|f| {
+            let start = f * STFT_HOP;
+            let mut re: Vec<f32> = (0..STFT_SIZE)
+                .map(|i| samples[start + i] * window[i])
+                .collect();
+            let mut im = vec![0.0; STFT_SIZE];
+            fft(&mut re, &mut im, false);
+            (re, im)
+        })
+        .collect();
+    let power =
+        |f: usize, k: usize| spectra[f].0[k] * spectra[f].0[k] + spectra[f].1[k] * spectra[f].1[k];
+
+    // Frames inside noise-only lines
+    let frame_width = (sample_rate * 0.5) as usize;
+    let energy = trim::subcarrier_energy(samples, frame_width, sample_rate);
+    let noise_frames: Vec<usize> = (0..frames)
+        .filter(|&f| {
+            energy
+                .get((f * STFT_HOP + STFT_SIZE / 2) / frame_width)
+                .is_some_and(|&e| e < trim::MIN_SUBCARRIER_FRACTION)
+        })
+        .collect();
+    println!(
+        "Noise reduction: {} noise-only frames of {}",
+        noise_frames.len(),
+        frames
+    );
+
+    let noise: Vec<f32> = if noise_frames.is_empty() {
+        let first_bin = (OUT_OF_BAND_FREQUENCY / sample_rate * STFT_SIZE as f32) as usize;
+        let mut out_of_band: Vec<f32> = (0..frames)
+            .flat_map(|f| (first_bin..STFT_SIZE / 2).map(move |k| (f, k)))
+            .map(|(f, k)| power(f, k))
+            .collect();
+        out_of_band.sort_by(|a, b| a.total_cmp(b));
+        let level = out_of_band
+            .get(out_of_band.len() / 2)
+            .copied()
+            .unwrap_or(0.0);
+        vec![level; STFT_SIZE]
+    } else {
+        (0..STFT_SIZE)
+            .map(|k| {
+                noise_frames.iter().map(|&f| power(f, k)).sum::<f32>() / noise_frames.len() as f32
+            })
+            .collect()
+    };
+
+    // Gain, inverse transform and overlap-add
+    let mut output = vec![0.0; samples.len()];
+    for (f, (re, im)) in spectra.into_iter().enumerate() {
+        let (mut re, mut im) = (re, im);
+        for k in 0..STFT_SIZE {
+            let p = re[k] * re[k] + im[k] * im[k];
+            let gain = if p > 0.0 {
+                ((p - OVER_SUBTRACTION * noise[k]) / p).max(GAIN_FLOOR)
+            } else {
+                GAIN_FLOOR
+            };
+            re[k] *= gain;
+            im[k] *= gain;
+        }
+        fft(&mut re, &mut im, true);
+        let start = f * STFT_HOP;
+        for i in 0..STFT_SIZE {
+            output[start + i] += re[i] * window[i];
+        }
+    }
+    // The first and last half frames are only covered once
+    output[..STFT_HOP].copy_from_slice(&samples[..STFT_HOP]);
+    let covered = (frames - 1) * STFT_HOP + STFT_SIZE;
+    output[covered - STFT_HOP..].copy_from_slice(&samples[covered - STFT_HOP..]);
+    output
+}
+
+/// Run the enabled cleanup stages on the resampled audio, before demodulation.
+pub fn clean(mut samples: Vec<f32>, sample_rate: f32, settings: &CleanupSettings) -> Vec<f32> {
+    if settings.impulse_blanker {
+        let impulses = blank_impulses(&mut samples);
+        println!("Impulse blanker: {} impulses", impulses);
+    }
+    if settings.notch_filter {
+        let tones = notch_tones(&mut samples, sample_rate);
+        println!("Notch filter: tones at {:?} Hz", tones);
+    }
+    if settings.noise_reduction {
+        samples = reduce_noise(&samples, sample_rate);
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 20800.0;
+    // On a bin of the notch search, so that the notch lands on it
+    const HUM: f32 = 100.0 * SAMPLE_RATE / NOTCH_FFT as f32;
+
+    fn tone(frequency: f32, amplitude: f32, i: usize) -> f32 {
+        amplitude * (2.0 * PI * frequency * i as f32 / SAMPLE_RATE).sin()
+    }
+
+    /// Carrier, hum and a little noise, three seconds long.
+    fn recording() -> Vec<f32> {
+        let mut seed = 7u32;
+        (0..3 * NOTCH_BLOCK)
+            .map(|i| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                tone(2400.0, 0.5, i) + tone(HUM, 0.3, i) + 0.02 * noise
+            })
+            .collect()
+    }
+
+    #[test]
+    fn notch_removes_hum_and_keeps_the_carrier() {
+        let mut samples = recording();
+        let last_second = 2 * NOTCH_BLOCK..3 * NOTCH_BLOCK;
+        let hum_before = trim::goertzel_fraction(&samples[last_second.clone()], HUM, SAMPLE_RATE);
+        let carrier_before =
+            trim::goertzel_fraction(&samples[last_second.clone()], 2400.0, SAMPLE_RATE);
+
+        let found = notch_tones(&mut samples, SAMPLE_RATE);
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!((found[0] - HUM).abs() < 1.0, "{:?}", found);
+
+        let hum_after = trim::goertzel_fraction(&samples[last_second.clone()], HUM, SAMPLE_RATE);
+        let carrier_after = trim::goertzel_fraction(&samples[last_second], 2400.0, SAMPLE_RATE);
+        assert!(
+            hum_after < hum_before / 100.0,
+            "{} {}",
+            hum_before,
+            hum_after
+        );
+        assert!(
+            carrier_after > carrier_before,
+            "{} {}",
+            carrier_before,
+            carrier_after
+        );
+    }
+
+    #[test]
+    fn impulses_are_blanked_with_their_guard() {
+        let original: Vec<f32> = (0..20000).map(|i| tone(2400.0, 0.1, i)).collect();
+        let mut samples = original.clone();
+        samples[5000] = 5.0;
+        samples[12000] = -5.0;
+
+        assert_eq!(blank_impulses(&mut samples), 2);
+        for (i, (&sample, &expected)) in samples.iter().zip(&original).enumerate() {
+            if i.abs_diff(5000) <= IMPULSE_GUARD || i.abs_diff(12000) <= IMPULSE_GUARD {
+                assert_eq!(sample, 0.0, "sample {}", i);
+            } else {
+                assert_eq!(sample, expected, "sample {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn fft_round_trips() {
+        // Five whole periods over the block, so that only bins 0, 5 and 59 are set
+        let frequency = 5.0 * SAMPLE_RATE / 64.0;
+        let signal: Vec<f32> = (0..64).map(|i| tone(frequency, 1.0, i) + 0.25).collect();
+        let (mut re, mut im) = (signal.clone(), vec![0.0; 64]);
+        fft(&mut re, &mut im, false);
+        assert!((re[0] - 0.25 * 64.0).abs() < 1e-3);
+        assert!((im[5] + 32.0).abs() < 1e-3);
+        assert!(re[1].abs() < 1e-3 && im[1].abs() < 1e-3);
+        fft(&mut re, &mut im, true);
+        for (value, expected) in re.iter().zip(&signal) {
+            assert!((value - expected).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::error::Error;
+
+pub const AUGMENTATION_NAMES: [&str; 3] = ["Off", "4x", "8x"];
+pub const COMBINE_NAMES: [&str; 2] = ["Mean", "Median"];
+
+/// Test-time augmentation: every patch also goes through the model rotated (4x) or
+/// rotated and mirrored (8x), and the outputs, transformed back, are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Augmentation {
+    #[default]
+    Off,
+    Four,
+    Eight,
+}
+
+impl Augmentation {
+    pub const ALL: [Augmentation; 3] = [Augmentation::Off, Augmentation::Four, Augmentation::Eight];
+
+    /// Map a dropdown index (see AUGMENTATION_NAMES) to an augmentation.
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+
+    pub fn index(&self) -> u32 {
+        Self::ALL.iter().position(|a| a == self).unwrap_or(0) as u32
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        AUGMENTATION_NAMES
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        AUGMENTATION_NAMES[self.index() as usize]
+    }
+
+    /// Model runs per patch.
+    pub fn count(&self) -> usize {
+        match self {
+            Augmentation::Off => 1,
+            Augmentation::Four => 4,
+            Augmentation::Eight => 8,
+        }
+    }
+}
+
+/// How the outputs of the augmented runs are merged per pixel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Combine {
+    #[default]
+    Mean,
+    // Drops the odd transform that brings out an artifact
+    Median,
+}
+
+impl Combine {
+    pub const ALL: [Combine; 2] = [Combine::Mean, Combine::Median];
+
+    /// Map a dropdown index (see COMBINE_NAMES) to a combination.
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+
+    pub fn index(&self) -> u32 {
+        Self::ALL.iter().position(|c| c == self).unwrap_or(0) as u32
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        COMBINE_NAMES
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        COMBINE_NAMES[self.index() as usize]
+    }
+
+    fn apply(&self, values: &mut [f32]) -> f32 {
+        match self {
+            Combine::Mean => values.iter().sum::<f32>() / values.len() as f32,
+            Combine::Median => {
+                values.sort_by(f32::total_cmp);
+                let middle = values.len() / 2;
+                if values.len().is_multiple_of(2) {
+                    (values[middle - 1] + values[middle]) / 2.0
+                } else {
+                    values[middle]
+                }
+            }
+        }
+    }
+}
+
+/// Where pixel (x, y) of a square patch lands under transform `k`: a rotation by
+/// k * 90 degrees clockwise, after mirroring left to right for k >= 4.
+fn map(x: usize, y: usize, size: usize, k: usize) -> (usize, usize) {
+    let last = size - 1;
+    let x = if k >= 4 { last - x } else { x };
+    match k % 4 {
+        0 => (x, y),
+        1 => (last - y, x),
+        2 => (last - x, last - y),
+        _ => (y, last - x),
+    }
+}
+
+fn transform(patch: &[f32], size: usize, k: usize) -> Vec<f32> {
+    let mut transformed = vec![0.0; patch.len()];
+    for y in 0..size {
+        for x in 0..size {
+            let (tx, ty) = map(x, y, size, k);
+            transformed[ty * size + tx] = patch[y * size + x];
+        }
+    }
+    transformed
+}
+
+fn invert(patch: &[f32], size: usize, k: usize) -> Vec<f32> {
+    let mut restored = vec![0.0; patch.len()];
+    for y in 0..size {
+        for x in 0..size {
+            let (tx, ty) = map(x, y, size, k);
+            restored[y * size + x] = patch[ty * size + tx];
+        }
+    }
+    restored
+}
+
+/// Run `infer` on `count` square patches laid out one after the other, once per
+/// transform of `augmentation`, and combine the outputs, `scale` times the patch side,
+/// transformed back. Each run gets the same number of patches as `infer` would
+/// without augmentation.
+pub fn run_augmented<F>(
+    patches: &[f32],
+    count: usize,
+    patch_size: usize,
+    scale: usize,
+    augmentation: Augmentation,
+    combine: Combine,
+    infer: F,
+) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>>
+where
+    F: Fn(&[f32], usize) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>>,
+{
+    if augmentation == Augmentation::Off {
+        return infer(patches, count);
+    }
+
+    let pixels = patch_size * patch_size;
+    let output_size = patch_size * scale;
+    let outputs = (0..augmentation.count())
+        .map(|k| {
+            let transformed: Vec<f32> = patches
+                .chunks_exact(pixels)
+                .flat_map(|patch| transform(patch, patch_size, k))
+                .collect();
+            let output = infer(&transformed, count)?;
+            if output.len() != transformed.len() * scale * scale {
+                return Err(format!(
+                    "Expected {} output values per batch, got {}",
+                    transformed.len() * scale * scale,
+                    output.len()
+                )
+                .into());
+            }
+            Ok(output
+                .chunks_exact(output_size * output_size)
+                .flat_map(|patch| invert(patch, output_size, k))
+                .collect())
+        })
+        .collect::<Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>>>()?;
+
+    let mut values = vec![0.0; outputs.len()];
+    Ok((0..outputs[0].len())
+        .map(|i| {
+            for (value, output) in values.iter_mut().zip(&outputs) {
+                *value = output[i];
+            }
+            combine.apply(&mut values)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 8;
+
+    fn patch() -> Vec<f32> {
+        (0..SIZE * SIZE)
+            .map(|i| (i * 37 % 64) as f32 / 64.0)
+            .collect()
+    }
+
+    #[test]
+    fn transforms_are_distinct_and_invertible() {
+        let patch = patch();
+        let transformed: Vec<Vec<f32>> = (0..8).map(|k| transform(&patch, SIZE, k)).collect();
+        for (k, t) in transformed.iter().enumerate() {
+            assert_eq!(invert(t, SIZE, k), patch, "transform {}", k);
+            assert!(transformed[..k].iter().all(|other| other != t));
+        }
+        // A quarter turn clockwise moves the top left pixel to the top right
+        assert_eq!(transformed[1][SIZE - 1], patch[0]);
+    }
+
+    #[test]
+    fn augmentation_averages_out_a_one_sided_artifact() {
+        // A model brightening the left column of every patch it sees
+        let left_edge = |patches: &[f32], _| {
+            Ok(patches
+                .iter()
+                .enumerate()
+                .map(|(i, &p)| if i % SIZE == 0 { p + 0.4 } else { p })
+                .collect())
+        };
+        let patches: Vec<f32> = patch().into_iter().chain(patch()).collect();
+        let error = |output: Vec<f32>| {
+            output
+                .iter()
+                .zip(&patches)
+                .map(|(o, p)| (o - p).abs())
+                .fold(0.0, f32::max)
+        };
+
+        let plain = run_augmented(
+            &patches,
+            2,
+            SIZE,
+            1,
+            Augmentation::Off,
+            Combine::Mean,
+            left_edge,
+        );
+        let mean = run_augmented(
+            &patches,
+            2,
+            SIZE,
+            1,
+            Augmentation::Eight,
+            Combine::Mean,
+            left_edge,
+        );
+        let median = run_augmented(
+            &patches,
+            2,
+            SIZE,
+            1,
+            Augmentation::Four,
+            Combine::Median,
+            left_edge,
+        );
+        assert!((error(plain.unwrap()) - 0.4).abs() < 1e-6);
+        // Every edge is the left one in two of the eight views, corners in four
+        assert!((error(mean.unwrap()) - 0.2).abs() < 1e-6);
+        // The median drops the one view of four brightening an edge, not the two
+        // brightening a corner
+        let median = median.unwrap();
+        let changed: Vec<usize> = (0..patches.len())
+            .filter(|&i| (median[i] - patches[i]).abs() > 1e-6)
+            .map(|i| i % (SIZE * SIZE))
+            .collect();
+        let corners = [0, SIZE - 1, SIZE * (SIZE - 1), SIZE * SIZE - 1];
+        assert_eq!(changed.len(), 8);
+        assert!(changed.iter().all(|i| corners.contains(i)));
+    }
+
+    #[test]
+    fn upscaled_outputs_are_transformed_back() {
+        // Nearest neighbor 2x upscaling commutes with every transform
+        let upscale = |patches: &[f32], count| {
+            Ok((0..count * SIZE * SIZE * 4)
+                .map(|i| {
+                    let (n, i) = (i / (SIZE * SIZE * 4), i % (SIZE * SIZE * 4));
+                    let (x, y) = (i % (SIZE * 2) / 2, i / (SIZE * 2) / 2);
+                    patches[n * SIZE * SIZE + y * SIZE + x]
+                })
+                .collect())
+        };
+        let patches: Vec<f32> = patch().into_iter().chain(patch()).collect();
+        let plain = run_augmented(
+            &patches,
+            2,
+            SIZE,
+            2,
+            Augmentation::Off,
+            Combine::Mean,
+            upscale,
+        );
+        let augmented = run_augmented(
+            &patches,
+            2,
+            SIZE,
+            2,
+            Augmentation::Eight,
+            Combine::Median,
+            upscale,
+        );
+        assert_eq!(plain.unwrap(), augmented.unwrap());
+    }
+}
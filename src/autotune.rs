@@ -0,0 +1,305 @@
+use crate::apt;
+use crate::quality::QualityReport;
+use crate::settings::FunctionsSettings;
+use crate::trim;
+use crate::wav;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Length of the excerpt the parameters are tuned on (20 seconds)
+const EXCERPT_LINES: usize = 40;
+// Coordinate descent rounds over the parameter grids
+const ROUNDS: usize = 2;
+// SNR contribution to the score is snr_db / SNR_SCALE
+const SNR_SCALE: f32 = 40.0;
+
+const CUTOFF_FREQUENCIES: [f32; 8] = [
+    3000.0, 3500.0, 4000.0, 4500.0, 5000.0, 6000.0, 7000.0, 8000.0,
+];
+const WINDOW_SIZES: [usize; 7] = [3, 5, 8, 10, 15, 20, 30];
+const SCALING_FACTORS: [f32; 10] = [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 5.0, 7.0, 10.0];
+const ADDITIONAL_OFFSETS: [usize; 7] = [0, 40, 80, 120, 160, 240, 320];
+// A synced line is aligned when sync A lands this close to the offset (one pixel)
+const ALIGNMENT_TOLERANCE: usize = apt::REDUCTION_FACTOR as usize;
+// Smaller gains are rounding, they do not replace the current parameters
+const MIN_IMPROVEMENT: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Parameters {
+    pub cutoff_freq: f32,
+    pub window_size: usize,
+    pub scaling_factor: f32,
+    pub additional_offset: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Score {
+    pub sync_score: f32,
+    pub snr_db: f32,
+    // Mean correlation of consecutive image lines
+    pub coherence: f32,
+    // Share of the lines whose sync A lands at apt::sync_column. sync_apt cannot move
+    // a line back by more than its sync position, those lines start at their sample 0.
+    pub alignment: f32,
+}
+
+impl Score {
+    fn total(&self) -> f32 {
+        self.sync_score + self.snr_db / SNR_SCALE + self.coherence + self.alignment
+    }
+}
+
+pub struct TuneResult {
+    pub initial_score: Score,
+    pub best: Parameters,
+    pub best_score: Score,
+}
+
+impl TuneResult {
+    pub fn summary(&self) -> String {
+        format!(
+            "Cutoff {} Hz, window {}, scaling {}, offset {} (sync {:.2}, SNR {:.1} dB, coherence {:.2}, aligned {:.0}%; was sync {:.2}, SNR {:.1} dB, coherence {:.2}, aligned {:.0}%)",
+            self.best.cutoff_freq,
+            self.best.window_size,
+            self.best.scaling_factor,
+            self.best.additional_offset,
+            self.best_score.sync_score,
+            self.best_score.snr_db,
+            self.best_score.coherence,
+            self.best_score.alignment * 100.0,
+            self.initial_score.sync_score,
+            self.initial_score.snr_db,
+            self.initial_score.coherence,
+            self.initial_score.alignment * 100.0
+        )
+    }
+}
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut covariance, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return 0.0;
+    }
+    covariance / (var_a.sqrt() * var_b.sqrt())
+}
+
+/// Share of the synced lines whose sync A starts `additional_offset` samples in,
+/// where apt::sync_column expects it.
+fn alignment(synced: &[f32], frame_width: usize, additional_offset: usize) -> f32 {
+    let rows = synced.len() / frame_width;
+    let aligned = synced
+        .chunks_exact(frame_width)
+        .filter(|row| {
+            let (position, _) = wav::find_sync_position(row, &wav::SYNC_PATTERN);
+            position.abs_diff(additional_offset % frame_width) <= ALIGNMENT_TOLERANCE
+        })
+        .count();
+    aligned as f32 / rows.max(1) as f32
+}
+
+struct Tuner {
+    excerpt: Vec<f32>,
+    sample_rate: f32,
+    // Unscaled envelope for every (cutoff, window) pair tried
+    envelopes: HashMap<(u32, usize), Vec<f32>>,
+}
+
+impl Tuner {
+    fn score(&mut self, parameters: &Parameters) -> Score {
+        let key = (parameters.cutoff_freq as u32, parameters.window_size);
+        let (excerpt, sample_rate) = (&self.excerpt, self.sample_rate);
+        let envelope = self.envelopes.entry(key).or_insert_with(|| {
+            let filtered = wav::low_pass_filter(excerpt, parameters.cutoff_freq, sample_rate);
+            wav::envelope_detection(&filtered, parameters.window_size, 1.0)
+        });
+        let am_signal: Vec<f32> = envelope
+            .iter()
+            .map(|s| s * parameters.scaling_factor)
+            .collect();
+
+        let frame_width = (sample_rate * 0.5) as usize;
+        let (synced, lines) = wav::sync_apt(
+            &am_signal,
+            frame_width,
+            &wav::SYNC_PATTERN,
+            parameters.additional_offset,
+        );
+        let report = QualityReport::new(lines);
+
+        // Coherence of the quantized lines, clipping and crushed levels both lower it
+        let rows: Vec<Vec<f32>> = synced
+            .chunks_exact(frame_width)
+            .map(|row| row.iter().map(|&s| wav::luminance(s) as f32).collect())
+            .collect();
+        let coherence = rows
+            .windows(2)
+            .map(|pair| correlation(&pair[0], &pair[1]))
+            .sum::<f32>()
+            / rows.len().saturating_sub(1).max(1) as f32;
+
+        Score {
+            sync_score: report.mean_sync_score,
+            snr_db: report.mean_snr_db,
+            coherence,
+            alignment: alignment(&synced, frame_width, parameters.additional_offset),
+        }
+    }
+
+    /// Keep the best of the current parameters and the candidates.
+    fn descend(
+        &mut self,
+        best: &mut (Parameters, Score),
+        candidates: impl Iterator<Item = Parameters>,
+    ) {
+        for candidate in candidates {
+            let score = self.score(&candidate);
+            if score.total() > best.1.total() + MIN_IMPROVEMENT {
+                *best = (candidate, score);
+            }
+        }
+    }
+}
+
+/// Tune the demodulation parameters on the strongest excerpt of a recording by
+/// coordinate descent, maximizing mean sync score, SNR, line coherence and the share
+/// of lines with sync A where the rest of the decoder expects it.
+pub fn tune(
+    filepath: &str,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<TuneResult, String> {
+    let (samples, spec) = wav::load_samples(filepath, false)?;
+    let sample_rate = 20800.0;
+    let resampled = wav::resample_signal(&samples, sample_rate as f64 / spec.sample_rate as f64);
+
+    // Excerpt around the lines with the most subcarrier
+    let frame_width = (sample_rate * 0.5) as usize;
+    let energy = trim::subcarrier_energy(&resampled, frame_width, sample_rate);
+    if energy.len() < EXCERPT_LINES {
+        return Err(format!(
+            "The recording is shorter than the {} lines needed to tune",
+            EXCERPT_LINES
+        ));
+    }
+    let first = (0..=energy.len() - EXCERPT_LINES)
+        .max_by(|&a, &b| {
+            let sum = |start: usize| energy[start..start + EXCERPT_LINES].iter().sum::<f32>();
+            sum(a).total_cmp(&sum(b))
+        })
+        .unwrap_or(0);
+    println!("Tuning on lines {}-{}", first, first + EXCERPT_LINES);
+    let excerpt = resampled[first * frame_width..(first + EXCERPT_LINES) * frame_width].to_vec();
+
+    let initial = {
+        let s = settings.lock().unwrap();
+        Parameters {
+            cutoff_freq: s.cutoff_freq,
+            window_size: s.window_size,
+            scaling_factor: s.scaling_factor,
+            additional_offset: s.additional_offset,
+        }
+    };
+
+    let mut tuner = Tuner {
+        excerpt,
+        sample_rate,
+        envelopes: HashMap::new(),
+    };
+    let initial_score = tuner.score(&initial);
+    let mut best = (initial, initial_score);
+
+    for round in 0..ROUNDS {
+        let base = best.0;
+        tuner.descend(
+            &mut best,
+            CUTOFF_FREQUENCIES.iter().map(|&cutoff_freq| Parameters {
+                cutoff_freq,
+                ..base
+            }),
+        );
+        let base = best.0;
+        tuner.descend(
+            &mut best,
+            WINDOW_SIZES.iter().map(|&window_size| Parameters {
+                window_size,
+                ..base
+            }),
+        );
+        let base = best.0;
+        tuner.descend(
+            &mut best,
+            SCALING_FACTORS.iter().map(|&scaling_factor| Parameters {
+                scaling_factor,
+                ..base
+            }),
+        );
+        let base = best.0;
+        tuner.descend(
+            &mut best,
+            ADDITIONAL_OFFSETS
+                .iter()
+                .map(|&additional_offset| Parameters {
+                    additional_offset,
+                    ..base
+                }),
+        );
+        println!("Tuning round {}: {:?} {:?}", round + 1, best.0, best.1);
+    }
+
+    Ok(TuneResult {
+        initial_score,
+        best: best.0,
+        best_score: best.1,
+    })
+}
+
+/// Store tuned parameters in the settings.
+pub fn apply(parameters: &Parameters, settings: &Arc<Mutex<FunctionsSettings>>) {
+    let mut s = settings.lock().unwrap();
+    s.cutoff_freq = parameters.cutoff_freq;
+    s.window_size = parameters.window_size;
+    s.scaling_factor = parameters.scaling_factor;
+    s.additional_offset = parameters.additional_offset;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_WIDTH: usize = 10400;
+
+    /// Lines of faint texture with sync A `sync_start` samples in.
+    fn signal(lines: usize, sync_start: usize) -> Vec<f32> {
+        (0..lines * FRAME_WIDTH)
+            .map(|i| {
+                let x = i % FRAME_WIDTH;
+                match x.checked_sub(sync_start) {
+                    Some(k) if k < wav::SYNC_PATTERN.len() => wav::SYNC_PATTERN[k],
+                    _ => 0.05 * (x as f32 * 0.013).sin(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn offsets_past_the_sync_position_are_misaligned() {
+        let signal = signal(4, 200);
+        for (offset, expected) in [(0, 1.0), (120, 1.0), (200, 1.0), (240, 0.0), (320, 0.0)] {
+            let (synced, _) = wav::sync_apt(&signal, FRAME_WIDTH, &wav::SYNC_PATTERN, offset);
+            assert_eq!(
+                alignment(&synced, FRAME_WIDTH, offset),
+                expected,
+                "offset {}",
+                offset
+            );
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::apt;
+use crate::telemetry::{self, Telemetry};
+
+use image::GrayImage;
+
+// Planck constants in mW/(m2 sr cm-4) and cm K
+const C1: f32 = 1.191_042_7e-5;
+const C2: f32 = 1.438_775_2;
+// Columns ignored on each side of the space view (blurred by resampling)
+const SPACE_MARGIN: u32 = 8;
+
+pub const SATELLITE_NAMES: [&str; 3] = ["NOAA-15", "NOAA-18", "NOAA-19"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Satellite {
+    Noaa15,
+    Noaa18,
+    Noaa19,
+}
+
+// Nominal AVHRR channel 4 coefficients from the NOAA KLM user's guide
+struct Coefficients {
+    // Central wavenumber (cm-1) and band correction T* = a + b * T
+    wavenumber: f32,
+    a: f32,
+    b: f32,
+    // Radiance of space
+    space_radiance: f32,
+    // Average PRT count to temperature polynomial
+    prt: [f32; 3],
+}
+
+impl Satellite {
+    pub fn from_index(index: u32) -> Self {
+        match index {
+            0 => Satellite::Noaa15,
+            1 => Satellite::Noaa18,
+            _ => Satellite::Noaa19,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace(['-', ' '], "").as_str() {
+            "noaa15" | "15" => Some(Satellite::Noaa15),
+            "noaa18" | "18" => Some(Satellite::Noaa18),
+            "noaa19" | "19" => Some(Satellite::Noaa19),
+            _ => None,
+        }
+    }
+
+    fn coefficients(&self) -> Coefficients {
+        match self {
+            Satellite::Noaa15 => Coefficients {
+                wavenumber: 925.4075,
+                a: 0.337810,
+                b: 0.998719,
+                space_radiance: -4.50,
+                prt: [276.6016, 0.051045, 1.36328e-6],
+            },
+            Satellite::Noaa18 => Coefficients {
+                wavenumber: 928.146,
+                a: 0.436645,
+                b: 0.998607,
+                space_radiance: -5.53,
+                prt: [276.601, 0.0509, 1.657e-6],
+            },
+            Satellite::Noaa19 => Coefficients {
+                wavenumber: 928.9,
+                a: 0.53959,
+                b: 0.998534,
+                space_radiance: -5.49,
+                prt: [276.615, 0.051073, 1.437e-6],
+            },
+        }
+    }
+}
+
+fn planck(wavenumber: f32, temperature: f32) -> f32 {
+    C1 * wavenumber.powi(3) / ((C2 * wavenumber / temperature).exp() - 1.0)
+}
+
+fn inverse_planck(wavenumber: f32, radiance: f32) -> f32 {
+    C2 * wavenumber / (1.0 + C1 * wavenumber.powi(3) / radiance.max(1e-3)).ln()
+}
+
+/// Channel B (IR) calibration from the telemetry wedges and the space view.
+pub struct Calibration {
+    satellite: Satellite,
+    // Image value -> nominal 0-255 value
+    gain: f32,
+    offset: f32,
+    // 10-bit counts of the onboard blackbody and of space
+    blackbody_count: f32,
+    space_count: f32,
+    blackbody_temperature: f32,
+}
+
+impl Calibration {
+    pub fn new(image: &GrayImage, sync_column: u32, satellite: Satellite) -> Option<Self> {
+        let telemetry = telemetry::read_telemetry(image, sync_column, 1)?;
+        let (gain, offset) = telemetry.grey_scale_fit();
+        let space_value = space_view_mean(image, sync_column);
+        Self::from_telemetry(&telemetry, gain, offset, space_value, satellite)
+    }
+
+    fn from_telemetry(
+        telemetry: &Telemetry,
+        gain: f32,
+        offset: f32,
+        space_value: f32,
+        satellite: Satellite,
+    ) -> Option<Self> {
+        let to_count = |value: f32| (gain * value + offset) * 1023.0 / 255.0;
+        let coefficients = satellite.coefficients();
+
+        let blackbody_temperature = telemetry::PRT_WEDGES
+            .iter()
+            .map(|&wedge| {
+                let count = to_count(telemetry.wedges[wedge]);
+                coefficients.prt[0]
+                    + coefficients.prt[1] * count
+                    + coefficients.prt[2] * count * count
+            })
+            .sum::<f32>()
+            / telemetry::PRT_WEDGES.len() as f32;
+        let blackbody_count = to_count(telemetry.wedges[telemetry::BACK_SCAN_WEDGE]);
+        let space_count = to_count(space_value);
+
+        println!(
+            "IR calibration ({:?}): blackbody {:.1} K at count {:.0}, space count {:.0}",
+            satellite, blackbody_temperature, blackbody_count, space_count
+        );
+
+        // Space must read colder (higher count) than the blackbody
+        if space_count - blackbody_count < 50.0 || !(250.0..330.0).contains(&blackbody_temperature)
+        {
+            return None;
+        }
+
+        Some(Self {
+            satellite,
+            gain,
+            offset,
+            blackbody_count,
+            space_count,
+            blackbody_temperature,
+        })
+    }
+
+    /// Fallback when the telemetry cannot be read: a fixed linear range.
+    pub fn nominal(satellite: Satellite) -> Self {
+        Self {
+            satellite,
+            gain: 1.0,
+            offset: 0.0,
+            blackbody_count: 400.0,
+            space_count: 1000.0,
+            blackbody_temperature: 290.0,
+        }
+    }
+
+    /// Brightness temperature in kelvin of an image value of channel B.
+    pub fn temperature(&self, value: u8) -> f32 {
+        let coefficients = self.satellite.coefficients();
+        let count = (self.gain * value as f32 + self.offset) * 1023.0 / 255.0;
+
+        let blackbody_radiance = planck(
+            coefficients.wavenumber,
+            coefficients.a + coefficients.b * self.blackbody_temperature,
+        );
+        let radiance = coefficients.space_radiance
+            + (blackbody_radiance - coefficients.space_radiance) * (self.space_count - count)
+                / (self.space_count - self.blackbody_count);
+
+        (inverse_planck(coefficients.wavenumber, radiance) - coefficients.a) / coefficients.b
+    }
+
+    /// Temperature of every possible image value.
+    pub fn temperature_table(&self) -> [f32; 256] {
+        let mut table = [0.0; 256];
+        for (value, temperature) in table.iter_mut().enumerate() {
+            *temperature = self.temperature(value as u8);
+        }
+        table
+    }
+}
+
+/// Mean value of the channel B space view.
+fn space_view_mean(image: &GrayImage, sync_column: u32) -> f32 {
+    let start = sync_column + apt::CHANNEL_LINE_WIDTH + apt::SYNC_WIDTH + SPACE_MARGIN;
+    let width = apt::SPACE_WIDTH - 2 * SPACE_MARGIN;
+    let space = apt::extract_columns(image, start, width);
+    space.iter().map(|&p| p as f32).sum::<f32>() / space.len().max(1) as f32
+}
+
+/// Calibrate channel B, falling back to the nominal range if the telemetry is unusable.
+pub fn calibrate(image: &GrayImage, sync_column: u32, satellite: Satellite) -> Calibration {
+    Calibration::new(image, sync_column, satellite).unwrap_or_else(|| {
+        eprintln!("Could not calibrate from telemetry, using nominal IR range");
+        Calibration::nominal(satellite)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SATELLITES: [Satellite; 3] = [Satellite::Noaa15, Satellite::Noaa18, Satellite::Noaa19];
+
+    #[test]
+    fn brighter_values_are_colder() {
+        for satellite in SATELLITES {
+            let table = Calibration::nominal(satellite).temperature_table();
+            // Strictly decreasing until the radiance bottoms out near space
+            assert!(
+                table
+                    .windows(2)
+                    .all(|pair| pair[1] < pair[0] || (pair[1] == pair[0] && pair[0] < 150.0)),
+                "{:?} is not monotonic",
+                satellite
+            );
+            assert!(table.iter().all(|t| t.is_finite()));
+        }
+    }
+
+    #[test]
+    fn blackbody_count_reads_blackbody_temperature() {
+        for satellite in SATELLITES {
+            let calibration = Calibration::nominal(satellite);
+            // Nominal blackbody count 400 of 1023 is image value 99.7
+            let below = calibration.temperature(99);
+            let above = calibration.temperature(100);
+            assert!(below > 290.0 && above < 290.0, "{} {}", below, above);
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::apt;
+use crate::calibration::{self, Satellite};
+use crate::inference::{self, Backend, ExecutionProvider};
+use crate::telemetry;
+use crate::tiling;
+
+use image::{GrayImage, ImageBuffer, Luma};
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+
+// Mask values
+pub const CLEAR: u8 = 0;
+pub const PROBABLY_CLOUDY: u8 = 128;
+pub const CLOUDY: u8 = 255;
+
+pub const FRACTIONS_PATH: &str = "cloud_fraction.csv";
+
+// Below this mean albedo (%) the pass is treated as night and the visible test is skipped
+const NIGHT_ALBEDO: f32 = 2.0;
+// IR tops colder than this are always cloud (kelvin)
+const COLD_CLOUD: f32 = 240.0;
+// Regions used for the cloud fraction statistics
+const REGION_COLUMNS: u32 = 3;
+const REGION_HEIGHT: u32 = 128;
+const PATCH_SIZE: usize = 256;
+// Pixels shared by neighboring patches, blended so that no seams show
+const TILE_OVERLAP: usize = 32;
+
+pub struct CloudMaskSettings {
+    pub visible_threshold: f32,
+    pub infrared_threshold: f32,
+    pub uniformity_threshold: f32,
+    pub model_path: String,
+    pub cpu_threads: usize,
+    pub execution_provider: ExecutionProvider,
+    pub backend: Backend,
+    pub satellite: Satellite,
+}
+
+pub struct RegionFraction {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub fraction: f32,
+}
+
+/// Standard deviation of the 3x3 neighborhood of every pixel.
+fn local_std_dev(values: &[f32], width: u32, height: u32) -> Vec<f32> {
+    let (w, h) = (width as i64, height as i64);
+    let mut std_dev = vec![0.0; values.len()];
+    for y in 0..h {
+        for x in 0..w {
+            let (mut sum, mut sum_sq, mut count) = (0.0, 0.0, 0.0);
+            for j in (y - 1).max(0)..=(y + 1).min(h - 1) {
+                for i in (x - 1).max(0)..=(x + 1).min(w - 1) {
+                    let value = values[(j * w + i) as usize];
+                    sum += value;
+                    sum_sq += value * value;
+                    count += 1.0;
+                }
+            }
+            let mean = sum / count;
+            std_dev[(y * w + x) as usize] = (sum_sq / count - mean * mean).max(0.0).sqrt();
+        }
+    }
+    std_dev
+}
+
+/// Threshold classification: visible albedo, IR temperature and IR uniformity tests.
+/// A pixel failing one test is probably cloudy, two or more (or a very cold top) is cloudy.
+pub fn classify(
+    visible: &GrayImage,
+    infrared: &GrayImage,
+    albedo: &[f32; 256],
+    temperatures: &[f32; 256],
+    settings: &CloudMaskSettings,
+) -> GrayImage {
+    let (width, height) = infrared.dimensions();
+    let temperature: Vec<f32> = infrared.iter().map(|&p| temperatures[p as usize]).collect();
+    let uniformity = local_std_dev(&temperature, width, height);
+
+    let mean_albedo =
+        visible.iter().map(|&p| albedo[p as usize]).sum::<f32>() / visible.len().max(1) as f32;
+    let daytime = mean_albedo > NIGHT_ALBEDO;
+    println!(
+        "Cloud mask: mean albedo {:.1}%, {}",
+        mean_albedo,
+        if daytime { "day" } else { "night" }
+    );
+
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let i = (y * width + x) as usize;
+        let mut tests = 0;
+        if daytime && albedo[visible.get_pixel(x, y)[0] as usize] > settings.visible_threshold {
+            tests += 1;
+        }
+        if temperature[i] < settings.infrared_threshold {
+            tests += 1;
+        }
+        if uniformity[i] > settings.uniformity_threshold {
+            tests += 1;
+        }
+
+        let value = if tests >= 2 || temperature[i] < COLD_CLOUD {
+            CLOUDY
+        } else if tests == 1 {
+            PROBABLY_CLOUDY
+        } else {
+            CLEAR
+        };
+        Luma([value])
+    })
+}
+
+/// Classify with an ONNX model taking (visible, IR) patches in [0, 1]
+/// and returning a cloud probability per pixel.
+pub fn classify_with_model(
+    visible: &GrayImage,
+    infrared: &GrayImage,
+    model_path: &str,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+) -> Result<GrayImage, Box<dyn Error>> {
+    // Load the ONNX model
+    let model = inference::load_model(model_path, cpu_threads, provider, backend)?;
+    let input_name = model
+        .inputs()
+        .first()
+        .ok_or("The model has no input")?
+        .name
+        .clone();
+    let output_name = model
+        .outputs()
+        .first()
+        .ok_or("The model has no output")?
+        .name
+        .clone();
+
+    let probability = tiling::run_tiled_channels(
+        &[visible, infrared],
+        PATCH_SIZE,
+        1,
+        TILE_OVERLAP,
+        1,
+        |patches, count| {
+            let input = ndarray::Array4::from_shape_vec(
+                (count, 2, PATCH_SIZE, PATCH_SIZE),
+                patches.to_vec(),
+            )?;
+            let output = model.run(&input_name, input.into_dyn(), &output_name)?;
+            Ok(output.iter().copied().collect())
+        },
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mask = ImageBuffer::from_fn(probability.width(), probability.height(), |x, y| {
+        let p = probability.get_pixel(x, y)[0] as f32 / 255.0;
+        let value = if p > 0.6 {
+            CLOUDY
+        } else if p > 0.4 {
+            PROBABLY_CLOUDY
+        } else {
+            CLEAR
+        };
+        Luma([value])
+    });
+
+    Ok(mask)
+}
+
+/// Cloud fraction of every region of the mask. Probably cloudy pixels count for half.
+pub fn region_fractions(mask: &GrayImage) -> Vec<RegionFraction> {
+    let (width, height) = mask.dimensions();
+    let region_width = width.div_ceil(REGION_COLUMNS);
+    let mut regions = Vec::new();
+
+    for y in (0..height).step_by(REGION_HEIGHT as usize) {
+        for x in (0..width).step_by(region_width as usize) {
+            let w = region_width.min(width - x);
+            let h = REGION_HEIGHT.min(height - y);
+            let mut cloud = 0.0;
+            for j in y..y + h {
+                for i in x..x + w {
+                    cloud += mask.get_pixel(i, j)[0] as f32 / CLOUDY as f32;
+                }
+            }
+            regions.push(RegionFraction {
+                x,
+                y,
+                width: w,
+                height: h,
+                fraction: cloud / (w * h) as f32,
+            });
+        }
+    }
+
+    regions
+}
+
+pub fn cloud_fraction(mask: &GrayImage) -> f32 {
+    mask.iter().map(|&p| p as f32 / CLOUDY as f32).sum::<f32>() / mask.len().max(1) as f32
+}
+
+fn write_fractions(path: &str, regions: &[RegionFraction], total: f32) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(file, "x,y,width,height,cloud_fraction")?;
+    for region in regions {
+        writeln!(
+            file,
+            "{},{},{},{},{:.4}",
+            region.x, region.y, region.width, region.height, region.fraction
+        )?;
+    }
+    writeln!(file, "total,,,,{:.4}", total)?;
+    Ok(())
+}
+
+/// Build the cloud mask of a synced, unenhanced APT image, save it with the
+/// per-region cloud fractions and return the mask path and the total cloud fraction.
+pub fn generate_cloud_mask(
+    image_path: &str,
+    sync_column: u32,
+    settings: &CloudMaskSettings,
+) -> Result<(String, f32), String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+    if image.width() != apt::LINE_WIDTH {
+        return Err(format!(
+            "Expected an APT image {} pixels wide, got {}",
+            apt::LINE_WIDTH,
+            image.width()
+        ));
+    }
+
+    let (visible, infrared) = apt::split_channels(&image, sync_column);
+
+    let mask = if settings.model_path.is_empty() {
+        let temperatures =
+            calibration::calibrate(&image, sync_column, settings.satellite).temperature_table();
+        let (gain, offset) = telemetry::read_telemetry(&image, sync_column, 0)
+            .map(|telemetry| telemetry.grey_scale_fit())
+            .unwrap_or((1.0, 0.0));
+        let mut albedo = [0.0; 256];
+        for (value, a) in albedo.iter_mut().enumerate() {
+            *a = ((gain * value as f32 + offset) / 255.0 * 100.0).clamp(0.0, 100.0);
+        }
+        classify(&visible, &infrared, &albedo, &temperatures, settings)
+    } else {
+        println!("Classifying clouds with model {}", settings.model_path);
+        classify_with_model(
+            &visible,
+            &infrared,
+            &settings.model_path,
+            settings.cpu_threads,
+            settings.execution_provider,
+            settings.backend,
+        )
+        .map_err(|e| e.to_string())?
+    };
+
+    let regions = region_fractions(&mask);
+    let total = cloud_fraction(&mask);
+    println!("Cloud fraction: {:.1}%", total * 100.0);
+
+    let fractions_path = FRACTIONS_PATH;
+    write_fractions(fractions_path, &regions, total).map_err(|e| e.to_string())?;
+    println!("Saving cloud fractions to: {}", fractions_path);
+
+    let output_path = String::from("cloud_mask.png");
+    println!("Saving cloud mask to: {}", output_path);
+    mask.save(&output_path).map_err(|e| e.to_string())?;
+    Ok((output_path, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> CloudMaskSettings {
+        CloudMaskSettings {
+            visible_threshold: 30.0,
+            infrared_threshold: 265.0,
+            uniformity_threshold: 1.5,
+            model_path: String::new(),
+            cpu_threads: 1,
+            execution_provider: ExecutionProvider::Cpu,
+            backend: Backend::default(),
+            satellite: Satellite::Noaa19,
+        }
+    }
+
+    #[test]
+    fn tests_add_up_to_the_class() {
+        // Dark and warm, bright and warm, bright and cold blocks of 10 columns
+        let visible = GrayImage::from_fn(30, 10, |x, _| Luma([if x < 10 { 20 } else { 200 }]));
+        let infrared = GrayImage::from_fn(30, 10, |x, _| Luma([if x < 20 { 0 } else { 120 }]));
+        let albedo: [f32; 256] = std::array::from_fn(|v| v as f32 * 100.0 / 255.0);
+        let temperatures: [f32; 256] = std::array::from_fn(|v| 320.0 - v as f32 * 0.5);
+
+        let mask = classify(&visible, &infrared, &albedo, &temperatures, &settings());
+        // Block centers, away from the non-uniform edges between blocks
+        assert_eq!(mask.get_pixel(4, 5)[0], CLEAR);
+        assert_eq!(mask.get_pixel(14, 5)[0], PROBABLY_CLOUDY);
+        assert_eq!(mask.get_pixel(24, 5)[0], CLOUDY);
+    }
+
+    #[test]
+    fn fractions_cover_the_mask() {
+        let mask = GrayImage::from_fn(90, 200, |x, _| Luma([if x < 30 { CLOUDY } else { CLEAR }]));
+        let regions = region_fractions(&mask);
+        assert_eq!(regions.len(), 6);
+        assert_eq!(
+            regions.iter().map(|r| r.width * r.height).sum::<u32>(),
+            90 * 200
+        );
+        assert!(regions
+            .iter()
+            .all(|r| r.fraction == if r.x == 0 { 1.0 } else { 0.0 }));
+        assert!((cloud_fraction(&mask) - 1.0 / 3.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,339 @@
+use crate::apt;
+use crate::legend;
+
+use image::{imageops, GrayImage, ImageBuffer, Rgb, RgbImage};
+
+// Palettes are 256x256 lookup tables: x = channel A (visible), y = channel B (IR)
+pub const LUT_SIZE: u32 = 256;
+// Height of the lookup table thumbnail strip, the IR scale follows below it
+const LEGEND_HEIGHT: u32 = 140;
+const LEGEND_MARGIN: u32 = 6;
+
+pub const PALETTE_NAMES: [&str; 6] = [
+    "None",
+    "MCIR",
+    "HVC",
+    "Sea surface",
+    "Thermal rainbow",
+    "Custom LUT",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Palette {
+    Mcir,
+    Hvc,
+    SeaSurface,
+    ThermalRainbow,
+    Custom(String),
+}
+
+impl Palette {
+    /// Map a dropdown index (see PALETTE_NAMES) to a palette. Index 0 disables coloring.
+    pub fn from_index(index: u32, custom_lut: &str) -> Option<Self> {
+        match index {
+            1 => Some(Palette::Mcir),
+            2 => Some(Palette::Hvc),
+            3 => Some(Palette::SeaSurface),
+            4 => Some(Palette::ThermalRainbow),
+            5 if !custom_lut.is_empty() => Some(Palette::Custom(custom_lut.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Parse a palette name as given on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "mcir" => Some(Palette::Mcir),
+            "hvc" => Some(Palette::Hvc),
+            "sea" | "sea-surface" => Some(Palette::SeaSurface),
+            "thermal" | "thermal-rainbow" => Some(Palette::ThermalRainbow),
+            path if path.ends_with(".png") => Some(Palette::Custom(name.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Build (or load) the 256x256 lookup table for this palette.
+    pub fn lut(&self) -> Result<RgbImage, String> {
+        match self {
+            Palette::Mcir => Ok(build_lut(mcir)),
+            Palette::Hvc => Ok(build_lut(hvc)),
+            Palette::SeaSurface => Ok(build_lut(sea_surface)),
+            Palette::ThermalRainbow => Ok(build_lut(thermal_rainbow)),
+            Palette::Custom(path) => load_lut(path),
+        }
+    }
+}
+
+fn build_lut(color: fn(u8, u8) -> [u8; 3]) -> RgbImage {
+    ImageBuffer::from_fn(LUT_SIZE, LUT_SIZE, |vis, ir| {
+        Rgb(color(vis as u8, ir as u8))
+    })
+}
+
+/// Load a user palette. The PNG must be 256x256 with the visible value on
+/// the x axis and the IR value on the y axis.
+pub fn load_lut(path: &str) -> Result<RgbImage, String> {
+    let lut = image::open(path).map_err(|e| e.to_string())?.to_rgb8();
+    if lut.dimensions() != (LUT_SIZE, LUT_SIZE) {
+        return Err(format!(
+            "Palette {} must be {}x{}, got {}x{}",
+            path,
+            LUT_SIZE,
+            LUT_SIZE,
+            lut.width(),
+            lut.height()
+        ));
+    }
+    Ok(lut)
+}
+
+/// Apply a palette to the visible and IR channels of a synced APT image, possibly
+/// upscaled. The result is one channel wide with the legend appended below.
+pub fn apply_palette(
+    image: &GrayImage,
+    sync_column: u32,
+    palette: &Palette,
+) -> Result<RgbImage, String> {
+    let lut = palette.lut()?;
+    let (visible, infrared) = apt::split_channels(image, sync_column);
+    let (width, height) = visible.dimensions();
+
+    let mut output: RgbImage =
+        ImageBuffer::new(width, height + LEGEND_HEIGHT + legend::LEGEND_HEIGHT);
+    for y in 0..height {
+        for x in 0..width {
+            let vis = visible.get_pixel(x, y)[0] as u32;
+            let ir = infrared.get_pixel(x, y)[0] as u32;
+            output.put_pixel(x, y, *lut.get_pixel(vis, ir));
+        }
+    }
+
+    draw_legend(&mut output, height, &lut);
+
+    Ok(output)
+}
+
+/// Draw the legend below the image: a thumbnail of the lookup table with its VIS
+/// (rightwards) and IR (downwards) axes labelled, then the IR scale at mid visible.
+fn draw_legend(output: &mut RgbImage, top: u32, lut: &RgbImage) {
+    let white = Rgb([255, 255, 255]);
+    let scale = legend::TEXT_SCALE;
+    let text_height = legend::text_height(scale);
+    let axis_width = legend::text_width("255", scale) + LEGEND_MARGIN;
+    let size = LEGEND_HEIGHT - 3 * LEGEND_MARGIN - text_height;
+    let (left, thumbnail_top) = (LEGEND_MARGIN + axis_width, top + LEGEND_MARGIN);
+    let thumbnail = imageops::resize(lut, size, size, imageops::FilterType::Nearest);
+    imageops::replace(output, &thumbnail, left as i64, thumbnail_top as i64);
+
+    // IR axis on the left, top to bottom
+    legend::draw_text(output, LEGEND_MARGIN, thumbnail_top, "0", scale, white);
+    legend::draw_text(
+        output,
+        LEGEND_MARGIN,
+        thumbnail_top + (size - text_height) / 2,
+        "IR",
+        scale,
+        white,
+    );
+    legend::draw_text(
+        output,
+        LEGEND_MARGIN,
+        thumbnail_top + size - text_height,
+        "255",
+        scale,
+        white,
+    );
+
+    // VIS axis below, left to right
+    let axis_top = thumbnail_top + size + LEGEND_MARGIN;
+    legend::draw_text(output, left, axis_top, "0", scale, white);
+    legend::draw_text(
+        output,
+        left + (size - legend::text_width("VIS", scale)) / 2,
+        axis_top,
+        "VIS",
+        scale,
+        white,
+    );
+    legend::draw_text(
+        output,
+        left + size - legend::text_width("255", scale),
+        axis_top,
+        "255",
+        scale,
+        white,
+    );
+
+    legend::draw_color_scale(output, top + LEGEND_HEIGHT, 0.0, 255.0, 85.0, "", |ir| {
+        *lut.get_pixel(LUT_SIZE / 2, ir as u32)
+    });
+}
+
+/// Colorize the image at `image_path` and save it next to the other outputs.
+pub fn generate_color_image(
+    image_path: &str,
+    sync_column: u32,
+    palette: &Palette,
+) -> Result<String, String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+    // Super-resolution models give images a multiple of the line wide
+    if image.width() == 0 || !image.width().is_multiple_of(apt::LINE_WIDTH) {
+        return Err(format!(
+            "Expected an APT image a multiple of {} pixels wide, got {}",
+            apt::LINE_WIDTH,
+            image.width()
+        ));
+    }
+
+    let output = apply_palette(&image, sync_column, palette)?;
+
+    let output_path = String::from("color_image.png");
+    println!("Saving color image to: {}", output_path);
+    output.save(&output_path).map_err(|e| e.to_string())?;
+    Ok(output_path)
+}
+
+/// Blue -> cyan -> green -> yellow -> red for t in [0, 1].
+pub fn rainbow(t: f32) -> [u8; 3] {
+    let t = t.clamp(0.0, 1.0) * 4.0;
+    let (r, g, b) = match t as u32 {
+        0 => (0.0, t, 1.0),
+        1 => (0.0, 1.0, 2.0 - t),
+        2 => (t - 2.0, 1.0, 0.0),
+        _ => (1.0, (4.0 - t).max(0.0), 0.0),
+    };
+    [(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [u8; 3] {
+    [
+        (a[0] + (b[0] - a[0]) * t) as u8,
+        (a[1] + (b[1] - a[1]) * t) as u8,
+        (a[2] + (b[2] - a[2]) * t) as u8,
+    ]
+}
+
+// In APT IR imagery bright pixels are cold, so high IR values are cloud tops.
+
+/// Map color IR: surface tinted from IR temperature, clouds whitened by how cold they are.
+fn mcir(vis: u8, ir: u8) -> [u8; 3] {
+    let ir = ir as f32;
+    let cloud = smoothstep(110.0, 200.0, ir);
+    let shade = 0.5 + ir / 510.0;
+    let surface = if vis < 40 {
+        [20.0 * shade, 60.0 * shade, 160.0 * shade]
+    } else {
+        [110.0 * shade, 130.0 * shade, 60.0 * shade]
+    };
+    mix(surface, [ir, ir, ir], cloud)
+}
+
+/// High visible contrast: visible brightness drives clouds, IR separates land from sea.
+fn hvc(vis: u8, ir: u8) -> [u8; 3] {
+    let (v, i) = (vis as f32, ir as f32);
+    let cloud = smoothstep(90.0, 180.0, v) * smoothstep(60.0, 140.0, i);
+    let surface = if v < 50.0 {
+        [10.0 + v * 0.4, 40.0 + v * 0.8, 90.0 + v * 1.5]
+    } else {
+        [50.0 + v * 0.5, 80.0 + v * 0.6, 30.0 + v * 0.2]
+    };
+    mix(surface, [v, v, v], cloud)
+}
+
+/// Sea surface: rainbow temperature for clear sky, clouds masked in grey.
+fn sea_surface(vis: u8, ir: u8) -> [u8; 3] {
+    if ir > 120 || vis > 100 {
+        let grey = 64 + vis / 4;
+        [grey, grey, grey]
+    } else {
+        rainbow(1.0 - ir as f32 / 120.0)
+    }
+}
+
+/// Thermal rainbow: IR only, warm in red and cold in blue.
+fn thermal_rainbow(_vis: u8, ir: u8) -> [u8; 3] {
+    rainbow(1.0 - ir as f32 / 255.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VISIBLE: u8 = 10;
+    const INFRARED: u8 = 200;
+
+    /// A synced APT image with flat channels, sync A at column 0.
+    fn apt_image(height: u32) -> GrayImage {
+        let a = apt::channel_start(0, 0);
+        let b = apt::channel_start(0, 1);
+        GrayImage::from_fn(apt::LINE_WIDTH, height, |x, _| {
+            if (a..a + apt::CHANNEL_WIDTH).contains(&x) {
+                image::Luma([VISIBLE])
+            } else if (b..b + apt::CHANNEL_WIDTH).contains(&x) {
+                image::Luma([INFRARED])
+            } else {
+                image::Luma([0])
+            }
+        })
+    }
+
+    /// A palette that writes the visible value to red and the IR value to green.
+    fn write_axes_lut(name: &str) -> String {
+        let lut: RgbImage =
+            ImageBuffer::from_fn(LUT_SIZE, LUT_SIZE, |x, y| Rgb([x as u8, y as u8, 0]));
+        let path = std::env::temp_dir().join(format!("trans-misja-{}.png", name));
+        lut.save(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn custom_lut_is_indexed_by_visible_then_infrared() {
+        let path = write_axes_lut("axes-lut");
+        let lut = load_lut(&path).unwrap();
+        assert_eq!(*lut.get_pixel(12, 34), Rgb([12, 34, 0]));
+
+        let output = apply_palette(&apt_image(8), 0, &Palette::Custom(path)).unwrap();
+        assert_eq!(output.width(), apt::CHANNEL_WIDTH);
+        assert!((0..8)
+            .flat_map(|y| (0..output.width()).map(move |x| (x, y)))
+            .all(|(x, y)| *output.get_pixel(x, y) == Rgb([VISIBLE, INFRARED, 0])));
+    }
+
+    #[test]
+    fn built_in_luts_put_infrared_on_the_y_axis() {
+        // Thermal rainbow ignores the visible channel: every row is a single color
+        let lut = Palette::ThermalRainbow.lut().unwrap();
+        assert_eq!(lut.dimensions(), (LUT_SIZE, LUT_SIZE));
+        assert_eq!(lut.get_pixel(0, 40), lut.get_pixel(255, 40));
+        assert_ne!(lut.get_pixel(40, 0), lut.get_pixel(40, 255));
+    }
+
+    #[test]
+    fn legend_labels_the_lut_axes() {
+        let path = write_axes_lut("legend-lut");
+        let output = apply_palette(&apt_image(8), 0, &Palette::Custom(path)).unwrap();
+        assert_eq!(output.height(), 8 + LEGEND_HEIGHT + legend::LEGEND_HEIGHT);
+        // White label pixels left of the thumbnail, where the IR axis is written
+        let axis = LEGEND_MARGIN..LEGEND_MARGIN + legend::text_width("255", legend::TEXT_SCALE);
+        assert!((8..8 + LEGEND_HEIGHT).any(|y| axis
+            .clone()
+            .any(|x| *output.get_pixel(x, y) == Rgb([255, 255, 255]))));
+        // The IR scale shows the green (IR) ramp of the palette
+        let bar = output.get_pixel(output.width() / 2, 8 + LEGEND_HEIGHT + 8);
+        assert!(bar[1].abs_diff(128) <= 2, "{:?}", bar);
+    }
+
+    #[test]
+    fn lut_of_the_wrong_size_is_rejected() {
+        let path = std::env::temp_dir().join("trans-misja-small-lut.png");
+        RgbImage::new(LUT_SIZE, LUT_SIZE / 2).save(&path).unwrap();
+        assert!(load_lut(&path.to_string_lossy()).is_err());
+    }
+}
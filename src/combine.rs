@@ -0,0 +1,426 @@
+use crate::app_state::AppState;
+use crate::apt;
+use crate::quality::{LineQuality, QualityReport, MIN_SYNC_SCORE};
+use crate::settings::FunctionsSettings;
+use crate::wav;
+
+use async_channel::Sender;
+use image::{GrayImage, ImageBuffer, Luma};
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+// APT sends two lines per second
+const LINES_PER_SECOND: f64 = 2.0;
+// Lines below quality::MIN_SYNC_SCORE are dropouts and get no weight
+// Shift searched around the timestamp estimate, station clocks may disagree (30 seconds)
+const MAX_SHIFT_LINES: i64 = 60;
+// Alignment needs this many good lines in common
+const MIN_OVERLAP_LINES: usize = 20;
+// Lines are compared by the means of blocks of this many pixels
+const BLOCK_WIDTH: usize = 32;
+
+/// One recording of the pass, demodulated and synced.
+pub struct Station {
+    pub image: GrayImage,
+    pub lines: Vec<LineQuality>,
+    // Time of the first image line, from the file's modification time
+    pub start: Option<SystemTime>,
+}
+
+/// Demodulate and sync a recording and keep its image, line quality and start time.
+/// None of the image stages run, nothing is written.
+pub fn decode(
+    filepath: &str,
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<Station, String> {
+    // Progress of every recording would jump back and forth, keep it away from the caller's
+    let (station_sender, _station_receiver) = async_channel::unbounded();
+    let demodulated = wav::demodulate(filepath, app_state, settings, &station_sender, &mut || {})?
+        .ok_or(format!("Nothing to decode in {}", filepath))?;
+    let image = wav::build_image(
+        &demodulated.signal,
+        demodulated.sample_rate,
+        apt::REDUCTION_FACTOR,
+    )
+    .map_err(|e| format!("No image decoded from {}: {}", filepath, e))?;
+    let lines = demodulated.lines;
+
+    let (first_line, _, total_lines) = app_state.line_span.lock().unwrap().unwrap_or((0, 0, 0));
+    let start = fs::metadata(filepath)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| {
+            modified.checked_sub(Duration::from_secs_f64(
+                total_lines as f64 / LINES_PER_SECOND,
+            ))
+        })
+        .map(|start| start + Duration::from_secs_f64(first_line as f64 / LINES_PER_SECOND));
+
+    Ok(Station {
+        image,
+        lines,
+        start,
+    })
+}
+
+/// Lines reduced to block means with the mean of every block over the image removed,
+/// so that only the scene is left and not the sync and telemetry repeated on every line.
+fn signatures(image: &GrayImage) -> Vec<Vec<f32>> {
+    let (width, height) = image.dimensions();
+    let blocks = width as usize / BLOCK_WIDTH;
+    let mut rows: Vec<Vec<f32>> = image
+        .rows()
+        .map(|row| {
+            let pixels: Vec<f32> = row.map(|p| p[0] as f32).collect();
+            pixels
+                .chunks_exact(BLOCK_WIDTH)
+                .map(|block| block.iter().sum::<f32>() / BLOCK_WIDTH as f32)
+                .collect()
+        })
+        .collect();
+    for block in 0..blocks {
+        let mean = rows.iter().map(|row| row[block]).sum::<f32>() / height.max(1) as f32;
+        for row in rows.iter_mut() {
+            row[block] -= mean;
+        }
+    }
+    rows
+}
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let (mut covariance, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += x * y;
+        var_a += x * x;
+        var_b += y * y;
+    }
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return 0.0;
+    }
+    covariance / (var_a.sqrt() * var_b.sqrt())
+}
+
+fn good(line: &LineQuality) -> bool {
+    line.sync_score >= MIN_SYNC_SCORE
+}
+
+/// Shift that puts line y of `station` on line y + shift of `reference`: the
+/// timestamp estimate refined by matching the scene line by line.
+fn align(reference: &Station, station: &Station) -> Result<i64, String> {
+    let reference_rows = signatures(&reference.image);
+    let station_rows = signatures(&station.image);
+    let (reference_height, station_height) =
+        (reference_rows.len() as i64, station_rows.len() as i64);
+
+    let range = match (reference.start, station.start) {
+        (Some(reference_start), Some(station_start)) => {
+            let seconds = match station_start.duration_since(reference_start) {
+                Ok(later) => later.as_secs_f64(),
+                Err(earlier) => -earlier.duration().as_secs_f64(),
+            };
+            let estimate = (seconds * LINES_PER_SECOND).round() as i64;
+            println!("Timestamp offset: {} lines", estimate);
+            estimate - MAX_SHIFT_LINES..=estimate + MAX_SHIFT_LINES
+        }
+        _ => -station_height..=reference_height,
+    };
+
+    let mut best: Option<(i64, f32)> = None;
+    for shift in range {
+        let (mut sum, mut count) = (0.0, 0);
+        for y in 0..station_height {
+            let reference_y = y + shift;
+            if reference_y < 0 || reference_y >= reference_height {
+                continue;
+            }
+            let (y, reference_y) = (y as usize, reference_y as usize);
+            if good(&station.lines[y]) && good(&reference.lines[reference_y]) {
+                sum += correlation(&station_rows[y], &reference_rows[reference_y]);
+                count += 1;
+            }
+        }
+        if count < MIN_OVERLAP_LINES {
+            continue;
+        }
+        let score = sum / count as f32;
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((shift, score));
+        }
+    }
+
+    let (shift, score) = best.ok_or("The recordings do not overlap")?;
+    println!(
+        "Aligned with shift {} lines (correlation {:.2})",
+        shift, score
+    );
+    Ok(shift)
+}
+
+/// Gain and offset bringing the levels of `station` to those of `reference` over
+/// the good lines they share.
+fn match_levels(reference: &Station, station: &Station, shift: i64) -> (f32, f32) {
+    let mut pairs = Vec::new();
+    for (y, row) in station.image.rows().enumerate() {
+        let reference_y = y as i64 + shift;
+        if reference_y < 0 || reference_y >= reference.lines.len() as i64 {
+            continue;
+        }
+        let reference_y = reference_y as usize;
+        if good(&station.lines[y]) && good(&reference.lines[reference_y]) {
+            for (x, p) in row.enumerate() {
+                pairs.push((
+                    p[0] as f32,
+                    reference.image.get_pixel(x as u32, reference_y as u32)[0] as f32,
+                ));
+            }
+        }
+    }
+    let n = pairs.len().max(1) as f32;
+    let mean_station = pairs.iter().map(|p| p.0).sum::<f32>() / n;
+    let mean_reference = pairs.iter().map(|p| p.1).sum::<f32>() / n;
+    let std = |mean: f32, value: fn(&(f32, f32)) -> f32| {
+        (pairs.iter().map(|p| (value(p) - mean).powi(2)).sum::<f32>() / n).sqrt()
+    };
+    let std_station = std(mean_station, |p| p.0);
+    let std_reference = std(mean_reference, |p| p.1);
+    let gain = if std_station > 0.0 {
+        std_reference / std_station
+    } else {
+        1.0
+    };
+    (gain, mean_reference - gain * mean_station)
+}
+
+/// Weight of a line when averaging: its SNR as a power ratio, zero for dropouts.
+fn weight(line: &LineQuality) -> f32 {
+    if good(line) {
+        10f32.powf(line.snr_db / 10.0)
+    } else {
+        0.0
+    }
+}
+
+/// Decode several recordings of the same pass, align them in time and combine them
+/// line by line weighted by line quality, so that the dropouts of one recording are
+/// covered by the others. Saves the combined image and its expected line quality and
+/// returns the image path.
+pub fn combine_recordings(
+    filepaths: &[String],
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+    sender: &Sender<(f64, String)>,
+) -> Result<String, String> {
+    if filepaths.len() < 2 {
+        return Err(String::from("Combining needs at least two recordings"));
+    }
+    // Unsynced lines start anywhere in the APT frame, aligning them would average garbage
+    if !app_state.sync.load(Ordering::Relaxed) {
+        return Err(String::from("Combining needs synced lines, enable sync"));
+    }
+
+    let mut stations = Vec::new();
+    let mut outputs = Vec::new();
+    for (n, filepath) in filepaths.iter().enumerate() {
+        println!("Decoding recording {}: {}", n + 1, filepath);
+        let _ = sender.try_send((
+            0.9 * n as f64 / filepaths.len() as f64,
+            format!("Decoding recording {} of {}...", n + 1, filepaths.len()),
+        ));
+        let station = decode(filepath, app_state, settings)?;
+        let station_path = format!("station_{}.png", n + 1);
+        station
+            .image
+            .save(&station_path)
+            .map_err(|e| e.to_string())?;
+        outputs.push(station_path);
+        stations.push(station);
+    }
+
+    let width = stations[0].image.width();
+    if stations
+        .iter()
+        .any(|station| station.image.width() != width)
+    {
+        return Err(String::from("The decoded images differ in width"));
+    }
+
+    let _ = sender.try_send((0.9, String::from("Combining recordings...")));
+    // Placement of every station relative to the first one, then gain and offset
+    let mut placements = vec![(0i64, 1.0f32, 0.0f32)];
+    for (n, station) in stations.iter().enumerate().skip(1) {
+        println!("Aligning recording {}", n + 1);
+        let shift = align(&stations[0], station)?;
+        let (gain, offset) = match_levels(&stations[0], station, shift);
+        placements.push((shift, gain, offset));
+    }
+
+    let top = placements.iter().map(|p| p.0).min().unwrap_or(0).min(0);
+    let bottom = stations
+        .iter()
+        .zip(&placements)
+        .map(|(station, p)| p.0 + station.lines.len() as i64)
+        .max()
+        .unwrap_or(0);
+    let height = (bottom - top) as u32;
+
+    let mut combined: GrayImage = ImageBuffer::new(width, height);
+    let mut combined_lines = Vec::with_capacity(height as usize);
+    let mut covered = vec![0usize; stations.len()];
+    for y in 0..height {
+        // Every station's line at this output line, with its weight
+        let sources: Vec<(usize, usize, f32)> = stations
+            .iter()
+            .zip(&placements)
+            .enumerate()
+            .filter_map(|(n, (station, p))| {
+                let line = y as i64 + top - p.0;
+                (line >= 0 && (line as usize) < station.lines.len()).then(|| {
+                    let line = line as usize;
+                    (n, line, weight(&station.lines[line]))
+                })
+            })
+            .collect();
+        let total: f32 = sources.iter().map(|s| s.2).sum();
+        // When every line is a dropout the average is the best there is
+        let weights: Vec<f32> = sources
+            .iter()
+            .map(|s| {
+                if total > 0.0 {
+                    s.2 / total
+                } else {
+                    1.0 / sources.len() as f32
+                }
+            })
+            .collect();
+
+        for x in 0..width {
+            let value: f32 = sources
+                .iter()
+                .zip(&weights)
+                .map(|(&(n, line, _), w)| {
+                    let (_, gain, offset) = placements[n];
+                    let pixel = stations[n].image.get_pixel(x, line as u32)[0] as f32;
+                    w * (gain * pixel + offset)
+                })
+                .sum();
+            combined.put_pixel(x, y, Luma([value.round().clamp(0.0, 255.0) as u8]));
+        }
+
+        if let Some(&(n, _, _)) = sources.iter().max_by(|a, b| a.2.total_cmp(&b.2)) {
+            covered[n] += 1;
+        }
+
+        // Independent noise: the SNR power ratios of the averaged lines add up
+        let lines: Vec<&LineQuality> = sources
+            .iter()
+            .map(|&(n, line, _)| &stations[n].lines[line])
+            .collect();
+        combined_lines.push(LineQuality {
+            sync_score: lines.iter().map(|l| l.sync_score).fold(0.0, f32::max),
+            snr_db: if total > 0.0 {
+                10.0 * total.log10()
+            } else {
+                lines.iter().map(|l| l.snr_db).fold(f32::MIN, f32::max)
+            },
+            noise: lines.iter().map(|l| l.noise).fold(f32::MAX, f32::min),
+        });
+    }
+
+    for (n, count) in covered.iter().enumerate() {
+        println!("Recording {} is the best source of {} lines", n + 1, count);
+    }
+
+    let path = String::from("combined_image.png");
+    println!("Saving combined image to: {}", path);
+    combined.save(&path).map_err(|e| e.to_string())?;
+
+    let report = QualityReport::new(combined_lines);
+    println!("Combined quality: {}", report.summary());
+    let mut all_outputs = vec![path.clone()];
+    match report.save() {
+        Ok(plot_path) => all_outputs.push(plot_path),
+        Err(e) => eprintln!("Error saving quality report: {}", e),
+    }
+    all_outputs.extend(outputs);
+
+    *app_state.quality.lock().unwrap() = Some(report);
+    *app_state.line_span.lock().unwrap() = None;
+    *app_state.cloud_fraction.lock().unwrap() = None;
+    *app_state.outputs.lock().unwrap() = all_outputs;
+    while sender.try_send((1.0, path.clone())).is_err() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(sync_score: f32, snr_db: f32) -> LineQuality {
+        LineQuality {
+            sync_score,
+            snr_db,
+            noise: 1.0,
+        }
+    }
+
+    fn scene(x: u32, y: u32) -> u8 {
+        let mut seed = (x / BLOCK_WIDTH as u32 * 7919 + y * 104729).wrapping_mul(2654435761);
+        seed ^= seed >> 15;
+        (seed % 200) as u8 + 20
+    }
+
+    /// Lines `first..first + height` of the scene, started at `start` seconds.
+    fn station(first: u32, height: u32, start: Option<u64>) -> Station {
+        Station {
+            image: GrayImage::from_fn(256, height, |x, y| Luma([scene(x, first + y)])),
+            lines: vec![line(0.9, 20.0); height as usize],
+            start: start.map(|seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)),
+        }
+    }
+
+    #[test]
+    fn stations_are_aligned_on_the_scene() {
+        let reference = station(0, 120, None);
+        assert_eq!(align(&reference, &station(7, 100, None)), Ok(7));
+        assert_eq!(align(&station(7, 100, None), &reference), Ok(-7));
+    }
+
+    #[test]
+    fn timestamps_narrow_the_search() {
+        // The clock says 3 seconds (6 lines) later, the scene says 7 lines
+        let reference = station(0, 120, Some(1000));
+        assert_eq!(align(&reference, &station(7, 100, Some(1003))), Ok(7));
+    }
+
+    #[test]
+    fn recordings_without_common_lines_do_not_align() {
+        let reference = station(0, 120, None);
+        let mut lost = station(7, 100, None);
+        lost.lines = vec![line(0.1, 0.0); 100];
+        assert!(align(&reference, &lost).is_err());
+    }
+
+    #[test]
+    fn levels_are_matched_to_the_reference() {
+        let reference = station(0, 60, None);
+        let mut dim = station(0, 60, None);
+        for pixel in dim.image.pixels_mut() {
+            pixel[0] = pixel[0] / 2 + 20;
+        }
+        let (gain, offset) = match_levels(&reference, &dim, 0);
+        assert!((gain - 2.0).abs() < 0.02, "gain {}", gain);
+        assert!((offset + 40.0).abs() < 1.5, "offset {}", offset);
+    }
+
+    #[test]
+    fn weights_follow_snr_and_skip_dropouts() {
+        assert!((weight(&line(0.9, 10.0)) - 10.0).abs() < 1e-4);
+        assert!((weight(&line(0.9, 20.0)) - 100.0).abs() < 1e-2);
+        assert_eq!(weight(&line(MIN_SYNC_SCORE - 0.01, 30.0)), 0.0);
+    }
+}
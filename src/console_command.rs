@@ -1,20 +1,609 @@
-use crate::gaussian_blur::selective_gaussian_blur;
+use crate::app_state::AppState;
+use crate::augment::{Augmentation, Combine};
+use crate::autotune;
+use crate::calibration::Satellite;
+use crate::color::Palette;
+use crate::combine::combine_recordings;
+use crate::dataset::{self, DatasetFormat, DatasetOptions, Degradation};
+use crate::enhancer::{self, EnhanceOptions, Enhancer};
+use crate::evaluation::{self, Method};
+use crate::gaussian_blur::{self, selective_gaussian_blur, SgbnrOptions};
+use crate::inference::{self, Backend, ExecutionProvider};
+use crate::model_spec::ModelSpec;
+use crate::models::{self, Registry, Task};
+use crate::passes::split_passes;
+use crate::products::Product;
 use crate::settings::FunctionsSettings;
-use crate::wav::enhance_image_with_model;
+use crate::wav::{compute_signal, enhance_image_with_model};
 
+use std::env;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 
+pub const USAGE: &str = "Usage:
+  trans-misja <image.png>
+  trans-misja --bench-enhancer <image.png> [model.onnx] [runs]
+  trans-misja --bench-sgbnr <image.png> [runs]
+  trans-misja --models
+  trans-misja --import-model <model.onnx> [name] [version] [task]
+  trans-misja --download-model [--mirror <url>] [--proxy <url>] [--sha256 <hex>]
+  trans-misja --export-dataset <output-dir> <recording.wav>... [dataset options]
+  trans-misja --evaluate <image-dir> [--reference <dir>] [--output <dir>] [--methods <list>]
+  trans-misja <recording.wav> [options]
+
+Options for WAV files:
+  --auto-tune             Tune cutoff, window, scaling and offset on the recording first
+  --force                 Decode even when no APT subcarrier is found
+  --blanker               Blank impulses in the audio before demodulation
+  --notch                 Notch interfering tones in the audio
+  --denoise               Spectral subtraction with the noise of noise-only lines
+  --sync                  Sync lines on the APT sync A pattern
+  --repair                Detect dropout lines and segments and fill them in
+  --inpaint-model <path>  ONNX inpainting model used by --repair
+  --model                 Enhance the image with the U-Net model
+  --enhance-model <name>  Installed model used by --model instead of the active one
+  --sgbnr                 Enhance the image with SGBNR
+  --sgbnr-radius <px>     Radius of the SGBNR statistics window (default 1)
+  --sgbnr-transition <levels>
+                          Width of the soft blur switch around the SGBNR thresholds,
+                          0 for the hard switch (default 4)
+  --sgbnr-mask            Also save the SGBNR blend mask
+  --sgbnr-auto            Estimate the SGBNR thresholds from the image
+  --split-passes          Decode every pass of a long recording into its own directory
+  --combine <other.wav>   Combine with another recording of the same pass (repeatable)
+  --lines <start:end>     Keep only these lines (end exclusive), overrides trimming
+  --no-trim               Keep the noise-only lines before and after the pass
+  --trim-margin <lines>   Lines kept around the detected pass (default 20)
+  --palette <name>        False-color palette: mcir, hvc, sea, thermal or a LUT .png
+  --products <list>       Comma separated: cloud-top, precipitation, sst or all
+  --satellite <name>      NOAA-15, NOAA-18 or NOAA-19 (IR calibration)
+  --cloud-mask            Classify clouds and report the cloud fraction
+  --cloud-model <path>    ONNX cloud classifier used by --cloud-mask
+  --provider <name>       ONNX execution provider: cpu, cuda, tensorrt, openvino or xnnpack
+  --backend <name>        Inference backend: ort or tract
+  --tile-overlap <px>     Pixels shared by neighboring model patches (default 32)
+  --batch-size <n>        Patches per model call (default 4)
+  --tta <mode>            Test-time augmentation for --model: off, 4x or 8x
+  --tta-combine <name>    How augmented outputs are combined: mean or median
+
+Options for --export-dataset:
+  --combine               Combine the recordings into one clean pass first
+  --patch-size <px>       Patch side (default: the enhancement model's)
+  --stride <px>           Distance between patches (default: the patch size)
+  --format <name>         png or npy (float32 in [0, 1])
+  --min-snr <dB>          SNR of every line of a clean patch (default 20)
+  --noise <sigma>         Largest added noise in pixel levels (default: measured)
+  --streaks <chance>      Chance of a line getting a streak (default 0.05)
+  --seed <n>              Seed of the synthetic degradation (default 0)
+
+Options for --evaluate:
+  --reference <dir>       Clean images named like the inputs, for PSNR, SSIM and MS-SSIM
+  --output <dir>          Enhanced images and the tables (default evaluation)
+  --methods <list>        Comma separated: none, sgbnr, model, model-tta (default all)";
+
 pub fn generate_images(img_path: &str, function_settings: Arc<Mutex<FunctionsSettings>>) {
     // Call the function to enhance the image with the model
-    match enhance_image_with_model(img_path, "model.onnx", 4) {
+    let (provider, backend, options) = {
+        let s = function_settings.lock().unwrap();
+        (
+            s.execution_provider,
+            s.backend,
+            EnhanceOptions::from_settings(&s),
+        )
+    };
+    let enhanced = models::model_path(None)
+        .map_err(|e| e.into())
+        .and_then(|model_path| Enhancer::new(&model_path, 4, provider, backend))
+        .and_then(|enhancer| enhance_image_with_model(img_path, &enhancer, options));
+    match enhanced {
         Ok(output_path) => println!("Image saved at: {}", output_path),
         Err(e) => eprintln!("Error processing image: {}", e),
     }
+    if let Some(provider) = inference::active_provider() {
+        println!("Execution provider: {}", provider.name());
+    }
 
     // Call the function to apply selective Gaussian blur
     match selective_gaussian_blur(img_path, &function_settings) {
-        Ok(output_path) => println!("Image saved at: {}", output_path),
+        Ok((output_path, mask_path)) => {
+            println!("Image saved at: {}", output_path);
+            if let Some(mask_path) = mask_path {
+                println!("Blend mask saved at: {}", mask_path);
+            }
+        }
         Err(e) => eprintln!("Error processing image: {}", e),
     }
 }
+
+pub fn list_models() -> Result<(), String> {
+    let registry = Registry::load()?;
+    println!("Models in {}", models::models_dir().display());
+    if registry.models.is_empty() {
+        println!("No model installed, model.onnx in the working directory is used");
+    }
+    for model in &registry.models {
+        let marker = if registry.active.as_ref() == Some(&model.name) {
+            "*"
+        } else {
+            " "
+        };
+        println!("{} {}", marker, model.summary());
+    }
+    Ok(())
+}
+
+pub fn import_model(args: &[String]) -> Result<(), String> {
+    let source = args
+        .first()
+        .ok_or(format!("Missing model for --import-model\n{}", USAGE))?;
+    let name = args.get(1).map(|name| name.as_str());
+    let version = args.get(2).map_or("1", |version| version.as_str());
+    let task = args
+        .get(3)
+        .map(|task| Task::from_name(task).ok_or(format!("Unknown model task: {}", task)))
+        .transpose()?;
+    let mut registry = Registry::load()?;
+    let entry = registry.import(std::path::Path::new(source), name, version, task)?;
+    println!("Imported {} into {}", entry.name, entry.path().display());
+    Ok(())
+}
+
+/// Patch side of the enhancement model in use.
+fn model_patch_size(settings: &FunctionsSettings) -> Result<usize, String> {
+    let model_path = models::model_path(settings.enhance_model.as_deref())?;
+    let model = inference::load_model(
+        &model_path,
+        settings.cpu_threads,
+        settings.execution_provider,
+        settings.backend,
+    )
+    .map_err(|e| format!("Cannot load {} ({}), set --patch-size", model_path, e))?;
+    Ok(ModelSpec::from_model(model.as_ref(), &model_path)?.patch_size)
+}
+
+/// Cut clean/noisy training pairs out of decoded recordings.
+pub fn export_dataset(
+    args: &[String],
+    function_settings: Arc<Mutex<FunctionsSettings>>,
+) -> Result<(), String> {
+    let output_dir = args.first().ok_or(format!(
+        "Missing output directory for --export-dataset\n{}",
+        USAGE
+    ))?;
+    let mut recordings = Vec::new();
+    let mut patch_size = None;
+    let mut stride = None;
+    let mut options = DatasetOptions {
+        patch_size: 0,
+        stride: 0,
+        format: DatasetFormat::Png,
+        min_snr_db: 20.0,
+        combine: false,
+        degradation: Degradation::default(),
+        seed: 0,
+    };
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or(format!("Missing value for {}\n{}", arg, USAGE))
+        };
+        let invalid = |value: &str| format!("Invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--combine" => options.combine = true,
+            "--patch-size" => {
+                let size = value()?;
+                patch_size = Some(size.parse().map_err(|_| invalid(size))?);
+            }
+            "--stride" => {
+                let size = value()?;
+                stride = Some(size.parse().map_err(|_| invalid(size))?);
+            }
+            "--format" => {
+                let name = value()?;
+                options.format = DatasetFormat::from_name(name)
+                    .ok_or(format!("Unknown dataset format: {}", name))?;
+            }
+            "--min-snr" => {
+                let snr = value()?;
+                options.min_snr_db = snr.parse().map_err(|_| invalid(snr))?;
+            }
+            "--noise" => {
+                let sigma = value()?;
+                options.degradation.noise_sigma = Some(sigma.parse().map_err(|_| invalid(sigma))?);
+            }
+            "--streaks" => {
+                let chance = value()?;
+                options.degradation.streak_probability = chance
+                    .parse()
+                    .ok()
+                    .filter(|chance| (0.0..=1.0).contains(chance))
+                    .ok_or(invalid(chance))?;
+            }
+            "--seed" => {
+                let seed = value()?;
+                options.seed = seed.parse().map_err(|_| invalid(seed))?;
+            }
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown option: {}\n{}", arg, USAGE))
+            }
+            _ => recordings.push(arg.clone()),
+        }
+    }
+    if recordings.is_empty() {
+        return Err(format!(
+            "Missing recordings for --export-dataset\n{}",
+            USAGE
+        ));
+    }
+    options.patch_size = match patch_size {
+        Some(size) => size,
+        None => model_patch_size(&function_settings.lock().unwrap())?,
+    };
+    options.stride = stride.unwrap_or(options.patch_size);
+
+    // Patches of synced images keep the sync and telemetry in place from pass to pass
+    let app_state = AppState::new(false, false, false);
+    app_state.sync.store(true, Ordering::Relaxed);
+    let summary = dataset::export_dataset(
+        &recordings,
+        output_dir,
+        &options,
+        &app_state,
+        &function_settings,
+    )?;
+    println!(
+        "Wrote {} pairs of {} px from {} pass(es), manifest at {}",
+        summary.pairs, options.patch_size, summary.sources, summary.manifest_path
+    );
+    Ok(())
+}
+
+/// Compare the enhancement methods over a directory of images.
+pub fn evaluate(
+    args: &[String],
+    function_settings: Arc<Mutex<FunctionsSettings>>,
+) -> Result<(), String> {
+    let input_dir = args
+        .first()
+        .ok_or(format!("Missing image directory for --evaluate\n{}", USAGE))?;
+    let mut reference_dir = None;
+    let mut output_dir = String::from("evaluation");
+    let mut methods = Method::ALL.to_vec();
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or(format!("Missing value for {}\n{}", arg, USAGE))?;
+        match arg.as_str() {
+            "--reference" => reference_dir = Some(value.as_str()),
+            "--output" => output_dir = value.clone(),
+            "--methods" => {
+                methods = value
+                    .split(',')
+                    .map(|name| Method::from_name(name).ok_or(format!("Unknown method: {}", name)))
+                    .collect::<Result<Vec<Method>, String>>()?;
+            }
+            _ => return Err(format!("Unknown option: {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let evaluations = evaluation::evaluate(
+        input_dir,
+        reference_dir,
+        &output_dir,
+        &methods,
+        &function_settings,
+    )?;
+    let csv_path = format!("{}/evaluation.csv", output_dir);
+    let html_path = format!("{}/evaluation.html", output_dir);
+    evaluation::write_csv(&evaluations, &csv_path).map_err(|e| e.to_string())?;
+    evaluation::write_html(&evaluations, &html_path).map_err(|e| e.to_string())?;
+    println!("Saved {} and {}", csv_path, html_path);
+    Ok(())
+}
+
+/// Download and install the U-Net model, resuming an earlier interrupted download.
+pub fn download_model(args: &[String]) -> Result<(), String> {
+    let (mut mirror, mut proxy, mut sha256) = ("", "", "");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or(format!("Missing value for {}\n{}", arg, USAGE))?;
+        match arg.as_str() {
+            "--mirror" => mirror = value,
+            "--proxy" => proxy = value,
+            "--sha256" => sha256 = value,
+            _ => return Err(format!("Unknown option: {}\n{}", arg, USAGE)),
+        }
+    }
+    let source = models::unet_source(mirror, proxy, sha256);
+    let cancel = AtomicBool::new(false);
+    let mut last_text = String::new();
+    let entry = models::install_unet_model(&source, &cancel, |progress| {
+        // Only print when the shown text changes
+        let text = progress.text();
+        if text != last_text {
+            print!("\r{}", text);
+            let _ = std::io::stdout().flush();
+            last_text = text;
+        }
+    })?;
+    println!();
+    println!("Installed {}", entry.summary());
+    Ok(())
+}
+
+/// Compare the throughput of a new session per image against a kept session at
+/// several batch sizes.
+pub fn benchmark_enhancer(
+    args: &[String],
+    function_settings: Arc<Mutex<FunctionsSettings>>,
+) -> Result<(), String> {
+    let image_path = args
+        .first()
+        .ok_or(format!("Missing image for --bench-enhancer\n{}", USAGE))?;
+    let model_path = match args.get(1) {
+        Some(path) => path.clone(),
+        None => models::model_path(None)?,
+    };
+    let runs = match args.get(2) {
+        Some(runs) => runs
+            .parse()
+            .map_err(|_| format!("Invalid number of runs: {}", runs))?,
+        None => 3,
+    };
+    let (cpu_threads, provider, backend, options) = {
+        let s = function_settings.lock().unwrap();
+        (
+            s.cpu_threads,
+            s.execution_provider,
+            s.backend,
+            EnhanceOptions::from_settings(&s),
+        )
+    };
+    enhancer::benchmark(
+        image_path,
+        &model_path,
+        cpu_threads,
+        provider,
+        backend,
+        options,
+        runs,
+    )
+    .map_err(|e| e.to_string())?;
+    if let Some(provider) = inference::active_provider() {
+        println!("Execution provider: {}", provider.name());
+    }
+    Ok(())
+}
+
+/// Compare the former RGB SGBNR with the grayscale one.
+pub fn benchmark_sgbnr(
+    args: &[String],
+    function_settings: Arc<Mutex<FunctionsSettings>>,
+) -> Result<(), String> {
+    let image_path = args
+        .first()
+        .ok_or(format!("Missing image for --bench-sgbnr\n{}", USAGE))?;
+    let runs = match args.get(1) {
+        Some(runs) => runs
+            .parse()
+            .map_err(|_| format!("Invalid number of runs: {}", runs))?,
+        None => 3,
+    };
+    let options = SgbnrOptions::from_settings(&function_settings.lock().unwrap());
+    gaussian_blur::benchmark(image_path, &options, runs)
+}
+
+pub fn decode_wav(
+    args: &[String],
+    function_settings: Arc<Mutex<FunctionsSettings>>,
+) -> Result<(), String> {
+    let debug: bool = env::var("DEBUG").is_ok_and(|v| v == "1");
+    let benchmark_ram: bool = env::var("BENCH_RAM").is_ok_and(|v| v == "1");
+    let benchmark_cpu: bool = env::var("BENCH_CPU").is_ok_and(|v| v == "1");
+    let app_state = AppState::new(debug, benchmark_ram, benchmark_cpu);
+
+    let wav_path = &args[0];
+    let mut auto_tune = false;
+    let mut recordings = vec![wav_path.clone()];
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || {
+            options
+                .next()
+                .ok_or(format!("Missing value for {}\n{}", option, USAGE))
+        };
+        match option.as_str() {
+            "--auto-tune" => auto_tune = true,
+            "--force" => function_settings.lock().unwrap().force_decode = true,
+            "--blanker" => function_settings.lock().unwrap().impulse_blanker = true,
+            "--notch" => function_settings.lock().unwrap().notch_filter = true,
+            "--denoise" => function_settings.lock().unwrap().noise_reduction = true,
+            "--sync" => app_state.sync.store(true, Ordering::Relaxed),
+            "--repair" => function_settings.lock().unwrap().repair_dropouts = true,
+            "--inpaint-model" => {
+                function_settings.lock().unwrap().inpaint_model_path = value()?.to_string();
+            }
+            "--model" => app_state.use_model.store(true, Ordering::Relaxed),
+            "--enhance-model" => {
+                function_settings.lock().unwrap().enhance_model = Some(value()?.to_string());
+            }
+            "--sgbnr" => app_state.use_sgbnr.store(true, Ordering::Relaxed),
+            "--sgbnr-radius" => {
+                let radius = value()?;
+                function_settings.lock().unwrap().sgbnr_radius = radius
+                    .parse()
+                    .ok()
+                    .filter(|&radius| radius > 0)
+                    .ok_or(format!("Invalid SGBNR radius: {}", radius))?;
+            }
+            "--sgbnr-transition" => {
+                let transition = value()?;
+                function_settings.lock().unwrap().sgbnr_transition = transition
+                    .parse()
+                    .ok()
+                    .filter(|&transition: &f32| transition >= 0.0)
+                    .ok_or(format!("Invalid SGBNR transition width: {}", transition))?;
+            }
+            "--sgbnr-mask" => function_settings.lock().unwrap().sgbnr_export_mask = true,
+            "--sgbnr-auto" => function_settings.lock().unwrap().sgbnr_auto_thresholds = true,
+            "--split-passes" => app_state.split_passes.store(true, Ordering::Relaxed),
+            "--combine" => recordings.push(value()?.to_string()),
+            "--lines" => {
+                let range = value()?;
+                let (first, last) = range
+                    .split_once(':')
+                    .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)))
+                    .filter(|(first, last)| first < last)
+                    .ok_or(format!("Invalid line range: {}", range))?;
+                function_settings.lock().unwrap().line_range = Some((first, last));
+            }
+            "--no-trim" => function_settings.lock().unwrap().auto_trim = false,
+            "--trim-margin" => {
+                let margin = value()?;
+                function_settings.lock().unwrap().trim_margin = margin
+                    .parse()
+                    .map_err(|_| format!("Invalid trim margin: {}", margin))?;
+            }
+            "--palette" => {
+                let name = value()?;
+                let palette =
+                    Palette::from_name(name).ok_or(format!("Unknown palette: {}", name))?;
+                function_settings.lock().unwrap().palette = Some(palette);
+            }
+            "--products" => {
+                let list = value()?;
+                let products = if list == "all" {
+                    Product::ALL.to_vec()
+                } else {
+                    list.split(',')
+                        .map(|name| {
+                            Product::from_name(name).ok_or(format!("Unknown product: {}", name))
+                        })
+                        .collect::<Result<Vec<Product>, String>>()?
+                };
+                function_settings.lock().unwrap().products = products;
+            }
+            "--satellite" => {
+                let name = value()?;
+                let satellite =
+                    Satellite::from_name(name).ok_or(format!("Unknown satellite: {}", name))?;
+                function_settings.lock().unwrap().satellite = satellite;
+            }
+            "--cloud-mask" => function_settings.lock().unwrap().cloud_mask = true,
+            "--cloud-model" => {
+                function_settings.lock().unwrap().cloud_model_path = value()?.to_string();
+            }
+            "--provider" => {
+                let name = value()?;
+                let provider = ExecutionProvider::from_name(name)
+                    .ok_or(format!("Unknown execution provider: {}", name))?;
+                function_settings.lock().unwrap().execution_provider = provider;
+            }
+            "--backend" => {
+                let name = value()?;
+                let backend = Backend::from_name(name)
+                    .ok_or(format!("Unknown inference backend: {}", name))?;
+                function_settings.lock().unwrap().backend = backend;
+            }
+            "--batch-size" => {
+                let size = value()?;
+                function_settings.lock().unwrap().batch_size = size
+                    .parse()
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or(format!("Invalid batch size: {}", size))?;
+            }
+            "--tile-overlap" => {
+                let overlap = value()?;
+                function_settings.lock().unwrap().tile_overlap = overlap
+                    .parse()
+                    .map_err(|_| format!("Invalid tile overlap: {}", overlap))?;
+            }
+            "--tta" => {
+                let name = value()?;
+                let augmentation = Augmentation::from_name(name)
+                    .ok_or(format!("Unknown test-time augmentation: {}", name))?;
+                function_settings.lock().unwrap().augmentation = augmentation;
+            }
+            "--tta-combine" => {
+                let name = value()?;
+                let combine =
+                    Combine::from_name(name).ok_or(format!("Unknown combination: {}", name))?;
+                function_settings.lock().unwrap().augmentation_combine = combine;
+            }
+            _ => return Err(format!("Unknown option: {}\n{}", option, USAGE)),
+        }
+    }
+
+    if auto_tune {
+        let result = autotune::tune(wav_path, &function_settings)?;
+        autotune::apply(&result.best, &function_settings);
+        println!("Auto-tune: {}", result.summary());
+    }
+
+    // Nothing listens to progress on the command line, use an unbounded channel so sends never block
+    let (sender, _receiver) = async_channel::unbounded();
+    if app_state.split_passes.load(Ordering::Relaxed) {
+        let passes = split_passes(wav_path, &app_state, &function_settings, &sender)?;
+        for (n, pass) in passes.iter().enumerate() {
+            println!("Pass {}: {} in {}", n + 1, pass.summary(), pass.directory);
+        }
+        for output in app_state.outputs.lock().unwrap().iter() {
+            println!("Output: {}", output);
+        }
+        return Ok(());
+    }
+    if recordings.len() > 1 {
+        // Lines of the stations are only comparable once synced
+        if !app_state.sync.swap(true, Ordering::Relaxed) {
+            println!("Combining syncs the recordings");
+        }
+        let path = combine_recordings(&recordings, &app_state, &function_settings, &sender)?;
+        println!("Image saved at: {}", path);
+        for output in app_state.outputs.lock().unwrap().iter() {
+            println!("Output: {}", output);
+        }
+        if let Some(report) = app_state.quality.lock().unwrap().as_ref() {
+            println!("Quality: {}", report.summary());
+        }
+        return Ok(());
+    }
+
+    let (path, _) = compute_signal(wav_path, &app_state, &function_settings, &sender);
+    if let Some(diagnostics) = app_state.input.lock().unwrap().as_ref() {
+        println!("Input: {}", diagnostics.summary());
+        for warning in &diagnostics.warnings {
+            println!("Warning: {}", warning);
+        }
+    }
+    if path.is_empty() {
+        return Err(format!(
+            "Nothing to decode in {}, use --force to decode anyway",
+            wav_path
+        ));
+    }
+    println!("Image saved at: {}", path);
+    for output in app_state.outputs.lock().unwrap().iter() {
+        println!("Output: {}", output);
+    }
+    if let Some((first, last, total)) = *app_state.line_span.lock().unwrap() {
+        println!("Lines: {}:{} of {}", first, last, total);
+    }
+    if let Some(report) = app_state.quality.lock().unwrap().as_ref() {
+        println!("Quality: {}", report.summary());
+    }
+    if let Some(fraction) = *app_state.cloud_fraction.lock().unwrap() {
+        println!("Cloud fraction: {:.1}%", fraction * 100.0);
+    }
+    if let Some(provider) = inference::active_provider() {
+        println!("Execution provider: {}", provider.name());
+    }
+
+    Ok(())
+}
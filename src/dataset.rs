@@ -0,0 +1,470 @@
+use crate::app_state::AppState;
+use crate::combine::{self, combine_recordings};
+use crate::quality::{self, LineQuality};
+use crate::settings::FunctionsSettings;
+
+use image::{GrayImage, Luma};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const DATASET_FORMAT_NAMES: [&str; 2] = ["png", "npy"];
+
+// Pixel noise assumed when the pass has too few noisy lines to measure it (0-255)
+const DEFAULT_NOISE_SIGMA: f32 = 12.0;
+// Noisy lines needed to measure the pixel noise of the pass
+const MIN_NOISY_LINES: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DatasetFormat {
+    // 8-bit grayscale images
+    #[default]
+    Png,
+    // float32 arrays of shape (1, patch_size, patch_size) in [0, 1]
+    Npy,
+}
+
+impl DatasetFormat {
+    pub const ALL: [DatasetFormat; 2] = [DatasetFormat::Png, DatasetFormat::Npy];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        DATASET_FORMAT_NAMES
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        DATASET_FORMAT_NAMES[Self::ALL.iter().position(|f| f == self).unwrap_or(0)]
+    }
+}
+
+/// Synthetic degradation turning a clean patch into its noisy counterpart. Sigmas
+/// are in 8-bit pixel levels, an unset noise sigma is measured on the pass.
+#[derive(Clone, Copy, Debug)]
+pub struct Degradation {
+    // Upper bound of the Gaussian noise, drawn uniformly per patch
+    pub noise_sigma: Option<f32>,
+    // Chance of a line getting a constant offset, as interference leaves streaks
+    pub streak_probability: f32,
+    pub streak_sigma: f32,
+    // Chance of a pixel being replaced by black or white
+    pub impulse_probability: f32,
+}
+
+impl Default for Degradation {
+    fn default() -> Self {
+        Self {
+            noise_sigma: None,
+            streak_probability: 0.05,
+            streak_sigma: 20.0,
+            impulse_probability: 0.002,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DatasetOptions {
+    pub patch_size: usize,
+    // Distance between patch origins, patch_size for patches side by side
+    pub stride: usize,
+    pub format: DatasetFormat,
+    // Every line of a clean patch has at least this SNR
+    pub min_snr_db: f32,
+    // Combine the recordings into one clean pass instead of using each on its own
+    pub combine: bool,
+    pub degradation: Degradation,
+    pub seed: u64,
+}
+
+pub struct DatasetSummary {
+    pub pairs: usize,
+    pub sources: usize,
+    pub manifest_path: String,
+}
+
+/// SplitMix64, enough for noise and reproducible from the seed alone.
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Standard normal (Box-Muller).
+    fn normal(&mut self) -> f32 {
+        let u = self.uniform().max(f32::MIN_POSITIVE);
+        let v = self.uniform();
+        (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos()
+    }
+}
+
+/// One decoded pass the patches are cut from.
+struct Source {
+    name: String,
+    image: GrayImage,
+    lines: Vec<LineQuality>,
+}
+
+fn clean_line(line: &LineQuality, min_snr_db: f32) -> bool {
+    line.sync_score >= quality::MIN_SYNC_SCORE && line.snr_db >= min_snr_db
+}
+
+/// Pixel noise of the lines too noisy for a clean patch: the median absolute
+/// difference of horizontal neighbors, scaled to a Gaussian sigma.
+fn station_noise(image: &GrayImage, lines: &[LineQuality], min_snr_db: f32) -> Option<f32> {
+    let rows: Vec<u32> = lines
+        .iter()
+        .enumerate()
+        .filter(|(y, line)| *y < image.height() as usize && !clean_line(line, min_snr_db))
+        .map(|(y, _)| y as u32)
+        .collect();
+    // A few noisy lines say little about the station
+    if rows.len() < MIN_NOISY_LINES {
+        return None;
+    }
+    let columns: Vec<u32> = (0..image.width()).collect();
+    quality::neighbor_noise_sigma(image, &rows, &columns)
+}
+
+/// Origins of the patches whose lines are all clean.
+fn clean_patches(source: &Source, options: &DatasetOptions) -> Vec<(u32, u32)> {
+    let size = options.patch_size as u32;
+    let stride = options.stride.max(1) as u32;
+    let (width, height) = source.image.dimensions();
+    let height = height.min(source.lines.len() as u32);
+    if width < size || height < size {
+        return Vec::new();
+    }
+    (0..=height - size)
+        .step_by(stride as usize)
+        .filter(|&y| {
+            source.lines[y as usize..(y + size) as usize]
+                .iter()
+                .all(|line| clean_line(line, options.min_snr_db))
+        })
+        .flat_map(|y| {
+            (0..=width - size)
+                .step_by(stride as usize)
+                .map(move |x| (x, y))
+        })
+        .collect()
+}
+
+/// Degrade a clean patch, returning it with the noise sigma drawn for it.
+fn degrade(
+    clean: &GrayImage,
+    degradation: &Degradation,
+    noise_sigma: f32,
+    random: &mut Random,
+) -> (GrayImage, f32) {
+    let sigma = noise_sigma * random.uniform();
+    let mut noisy = clean.clone();
+    for y in 0..noisy.height() {
+        let streak = if random.uniform() < degradation.streak_probability {
+            degradation.streak_sigma * random.normal()
+        } else {
+            0.0
+        };
+        for x in 0..noisy.width() {
+            let value = if random.uniform() < degradation.impulse_probability {
+                if random.uniform() < 0.5 {
+                    0.0
+                } else {
+                    255.0
+                }
+            } else {
+                clean.get_pixel(x, y)[0] as f32 + streak + sigma * random.normal()
+            };
+            noisy.put_pixel(x, y, Luma([value.round().clamp(0.0, 255.0) as u8]));
+        }
+    }
+    (noisy, sigma)
+}
+
+/// Quoted JSON string, escaping quotes, backslashes and control characters.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Header of a version 1.0 .npy file, padded so that the data is 64-byte aligned.
+fn npy_header(shape: &[usize]) -> Vec<u8> {
+    let shape = shape
+        .iter()
+        .map(|dim| format!("{},", dim))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let mut dict = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}), }}",
+        shape
+    );
+    // Magic (6), version (2), header length (2), dict and its closing newline
+    let padding = (64 - (10 + dict.len() + 1) % 64) % 64;
+    dict.push_str(&" ".repeat(padding));
+    dict.push('\n');
+
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend((dict.len() as u16).to_le_bytes());
+    header.extend(dict.as_bytes());
+    header
+}
+
+fn write_npy(path: &Path, image: &GrayImage) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&npy_header(&[
+        1,
+        image.height() as usize,
+        image.width() as usize,
+    ]))?;
+    for pixel in image.pixels() {
+        file.write_all(&(pixel[0] as f32 / 255.0).to_le_bytes())?;
+    }
+    file.flush()
+}
+
+fn write_patch(path: &Path, image: &GrayImage, format: DatasetFormat) -> Result<(), String> {
+    match format {
+        DatasetFormat::Png => image.save(path).map_err(|e| e.to_string()),
+        DatasetFormat::Npy => write_npy(path, image).map_err(|e| e.to_string()),
+    }
+}
+
+/// Decode the recordings, each on its own or combined into one pass, and keep their
+/// unprocessed images and line quality.
+fn decode_sources(
+    recordings: &[String],
+    combine: bool,
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<Vec<Source>, String> {
+    if combine && recordings.len() > 1 {
+        // Nothing listens to progress, use an unbounded channel so sends never block
+        let (sender, _receiver) = async_channel::unbounded();
+        let path = combine_recordings(recordings, app_state, settings, &sender)?;
+        let image = image::open(&path).map_err(|e| e.to_string())?.to_luma8();
+        let lines = app_state
+            .quality
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|report| report.lines.clone())
+            .ok_or("No quality report for the combined pass")?;
+        return Ok(vec![Source {
+            name: String::from("combined"),
+            image,
+            lines,
+        }]);
+    }
+
+    recordings
+        .iter()
+        .map(|recording| {
+            let station = combine::decode(recording, app_state, settings)?;
+            Ok(Source {
+                name: recording.clone(),
+                image: station.image,
+                lines: station.lines,
+            })
+        })
+        .collect()
+}
+
+/// Cut aligned clean/noisy patch pairs for training an enhancement model out of
+/// decoded passes. Clean patches come from lines above the SNR threshold, of every
+/// recording or of their combination, and noisy ones from degrading them with the
+/// noise measured on the pass. Writes `clean/` and `noisy/` under `output_dir` and
+/// a `manifest.json` listing every pair.
+pub fn export_dataset(
+    recordings: &[String],
+    output_dir: &str,
+    options: &DatasetOptions,
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<DatasetSummary, String> {
+    if options.patch_size == 0 {
+        return Err(String::from("The patch size must be positive"));
+    }
+    let sources = decode_sources(recordings, options.combine, app_state, settings)?;
+
+    let output_dir = Path::new(output_dir);
+    for directory in ["clean", "noisy"] {
+        fs::create_dir_all(output_dir.join(directory)).map_err(|e| e.to_string())?;
+    }
+    let extension = options.format.name();
+
+    let mut random = Random(options.seed);
+    let mut entries = Vec::new();
+    for source in &sources {
+        let noise_sigma = options.degradation.noise_sigma.unwrap_or_else(|| {
+            station_noise(&source.image, &source.lines, options.min_snr_db).unwrap_or_else(|| {
+                println!(
+                    "Too few noisy lines in {}, assuming noise sigma {}",
+                    source.name, DEFAULT_NOISE_SIGMA
+                );
+                DEFAULT_NOISE_SIGMA
+            })
+        });
+        let origins = clean_patches(source, options);
+        println!(
+            "{}: {} clean patches, noise sigma up to {:.1}",
+            source.name,
+            origins.len(),
+            noise_sigma
+        );
+
+        for (x, y) in origins {
+            let size = options.patch_size as u32;
+            let clean = image::imageops::crop_imm(&source.image, x, y, size, size).to_image();
+            let (noisy, sigma) = degrade(&clean, &options.degradation, noise_sigma, &mut random);
+            let file = format!("{:06}.{}", entries.len(), extension);
+            write_patch(
+                &output_dir.join("clean").join(&file),
+                &clean,
+                options.format,
+            )?;
+            write_patch(
+                &output_dir.join("noisy").join(&file),
+                &noisy,
+                options.format,
+            )?;
+            let snr_db = source.lines[y as usize..(y + size) as usize]
+                .iter()
+                .map(|line| line.snr_db)
+                .fold(f32::MAX, f32::min);
+            entries.push(format!(
+                "    {{ \"clean\": {}, \"noisy\": {}, \"source\": {}, \"x\": {}, \"y\": {}, \"min_snr_db\": {:.3}, \"noise_sigma\": {:.3} }}",
+                json_string(&format!("clean/{}", file)),
+                json_string(&format!("noisy/{}", file)),
+                json_string(&source.name), x, y, snr_db, sigma
+            ));
+        }
+    }
+    if entries.is_empty() {
+        return Err(format!(
+            "No patch of {} lines above {} dB, lower --min-snr or the patch size",
+            options.patch_size, options.min_snr_db
+        ));
+    }
+
+    let manifest_path = output_dir.join("manifest.json");
+    let write_manifest = || -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(&manifest_path)?);
+        writeln!(file, "{{")?;
+        writeln!(file, "  \"patch_size\": {},", options.patch_size)?;
+        writeln!(file, "  \"format\": {},", json_string(extension))?;
+        writeln!(file, "  \"min_snr_db\": {:.3},", options.min_snr_db)?;
+        writeln!(file, "  \"combined\": {},", options.combine)?;
+        writeln!(file, "  \"seed\": {},", options.seed)?;
+        writeln!(
+            file,
+            "  \"degradation\": {{ \"streak_probability\": {}, \"streak_sigma\": {}, \"impulse_probability\": {} }},",
+            options.degradation.streak_probability,
+            options.degradation.streak_sigma,
+            options.degradation.impulse_probability
+        )?;
+        writeln!(file, "  \"pairs\": [\n{}\n  ]", entries.join(",\n"))?;
+        writeln!(file, "}}")?;
+        file.flush()
+    };
+    write_manifest().map_err(|e| e.to_string())?;
+
+    Ok(DatasetSummary {
+        pairs: entries.len(),
+        sources: sources.len(),
+        manifest_path: manifest_path.to_string_lossy().into_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(snr_db: f32) -> LineQuality {
+        LineQuality {
+            sync_score: 0.9,
+            snr_db,
+            noise: 1.0,
+        }
+    }
+
+    #[test]
+    fn npy_header_is_aligned_and_parsable() {
+        let header = npy_header(&[1, 256, 256]);
+        assert_eq!(header.len() % 64, 0);
+        assert_eq!(&header[..8], b"\x93NUMPY\x01\x00");
+        let text = String::from_utf8_lossy(&header[10..]);
+        assert!(text.contains("'shape': (1, 256, 256,)"));
+        assert!(text.ends_with('\n'));
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("noaa-19.wav"), "\"noaa-19.wav\"");
+        assert_eq!(
+            json_string("a \"b\"\\c\nd\u{1}"),
+            "\"a \\\"b\\\"\\\\c\\nd\\u0001\""
+        );
+        assert_eq!(json_string("zażółć"), "\"zażółć\"");
+    }
+
+    #[test]
+    fn patches_avoid_noisy_lines() {
+        // Lines 40-44 are too noisy for a clean patch
+        let lines: Vec<LineQuality> = (0..100)
+            .map(|y| line(if (40..45).contains(&y) { 2.0 } else { 20.0 }))
+            .collect();
+        let source = Source {
+            name: String::from("test"),
+            image: GrayImage::new(64, 100),
+            lines,
+        };
+        let options = DatasetOptions {
+            patch_size: 16,
+            stride: 8,
+            format: DatasetFormat::Png,
+            min_snr_db: 10.0,
+            combine: false,
+            degradation: Degradation::default(),
+            seed: 0,
+        };
+        let origins = clean_patches(&source, &options);
+        assert!(!origins.is_empty());
+        assert!(origins.iter().all(|&(_, y)| y + 16 <= 40 || y >= 45));
+        assert!(origins.iter().all(|&(x, _)| x + 16 <= 64));
+    }
+
+    #[test]
+    fn degradation_is_reproducible() {
+        let clean = GrayImage::from_fn(32, 32, |x, y| Luma([(x * 4 + y * 2) as u8]));
+        let degradation = Degradation::default();
+        let (a, sigma_a) = degrade(&clean, &degradation, 10.0, &mut Random(7));
+        let (b, sigma_b) = degrade(&clean, &degradation, 10.0, &mut Random(7));
+        assert_eq!(a, b);
+        assert_eq!(sigma_a, sigma_b);
+        assert!(sigma_a <= 10.0);
+        assert_ne!(a, clean);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::trim::{self, MIN_SUBCARRIER_FRACTION, SUBCARRIER_FREQ};
+
+use hound::{SampleFormat, WavSpec};
+
+// Samples this close to full scale count as clipped
+const CLIP_LEVEL: f32 = 0.999;
+const MAX_CLIPPED_FRACTION: f32 = 0.001;
+const MIN_RMS_DBFS: f32 = -50.0;
+// Lines carrying less subcarrier than trim::MIN_SUBCARRIER_FRACTION are noise.
+// The subcarrier is searched this far from 2400 Hz, in steps of OFFSET_STEP
+const MAX_OFFSET: f32 = 100.0;
+const OFFSET_STEP: f32 = 0.5;
+const MAX_EXPECTED_OFFSET: f32 = 20.0;
+// Lines around the strongest one used to measure the offset (2 seconds)
+const OFFSET_LINES: usize = 4;
+
+#[derive(Clone, Debug)]
+pub struct InputDiagnostics {
+    pub peak_dbfs: f32,
+    pub rms_dbfs: f32,
+    pub clipped_fraction: f32,
+    // Share of the energy carried by the subcarrier in the strongest 10% of the lines
+    pub subcarrier_fraction: f32,
+    // Measured subcarrier frequency minus 2400 Hz, None without subcarrier
+    pub subcarrier_offset: Option<f32>,
+    pub warnings: Vec<String>,
+    // Nothing decodable: silence or no subcarrier at all
+    pub hopeless: bool,
+}
+
+fn dbfs(value: f32) -> f32 {
+    20.0 * value.max(1e-10).log10()
+}
+
+/// Frequency of the strongest component near 2400 Hz in `samples`.
+fn subcarrier_frequency(samples: &[f32], sample_rate: f32) -> f32 {
+    let steps = (2.0 * MAX_OFFSET / OFFSET_STEP) as usize;
+    (0..=steps)
+        .map(|i| SUBCARRIER_FREQ - MAX_OFFSET + i as f32 * OFFSET_STEP)
+        .map(|frequency| {
+            (
+                frequency,
+                trim::goertzel_fraction(samples, frequency, sample_rate),
+            )
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(frequency, _)| frequency)
+        .unwrap_or(SUBCARRIER_FREQ)
+}
+
+impl InputDiagnostics {
+    /// Measure the raw samples of a recording, before resampling.
+    pub fn new(samples: &[f32], spec: &WavSpec) -> Self {
+        let full_scale = match spec.sample_format {
+            SampleFormat::Float => 1.0,
+            SampleFormat::Int => (1u64 << (spec.bits_per_sample - 1)) as f32,
+        };
+        let count = samples.len().max(1) as f32;
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs())) / full_scale;
+        let rms = (samples
+            .iter()
+            .map(|s| (s / full_scale).powi(2))
+            .sum::<f32>()
+            / count)
+            .sqrt();
+        let clipped_fraction = samples
+            .iter()
+            .filter(|s| s.abs() / full_scale >= CLIP_LEVEL)
+            .count() as f32
+            / count;
+
+        let sample_rate = spec.sample_rate as f32;
+        let frame_width = spec.sample_rate as usize / 2;
+        let energy = trim::subcarrier_energy(samples, frame_width, sample_rate);
+        let mut sorted = energy.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let subcarrier_fraction = sorted
+            .get(sorted.len().saturating_sub(1) * 9 / 10)
+            .copied()
+            .unwrap_or(0.0);
+
+        let subcarrier_offset = (subcarrier_fraction >= MIN_SUBCARRIER_FRACTION).then(|| {
+            let strongest = energy
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(line, _)| line)
+                .unwrap_or(0);
+            let first = strongest.saturating_sub(OFFSET_LINES / 2);
+            let last = (first + OFFSET_LINES).min(energy.len());
+            let excerpt = &samples[first * frame_width..last * frame_width];
+            subcarrier_frequency(excerpt, sample_rate) - SUBCARRIER_FREQ
+        });
+
+        let mut warnings = Vec::new();
+        if rms == 0.0 {
+            warnings.push(String::from("The recording is silent"));
+        } else if dbfs(rms) < MIN_RMS_DBFS {
+            warnings.push(format!(
+                "Very low level ({:.1} dBFS RMS), raise the recording gain",
+                dbfs(rms)
+            ));
+        }
+        if clipped_fraction > MAX_CLIPPED_FRACTION {
+            warnings.push(format!(
+                "{:.2}% of the samples are clipped, lower the recording gain",
+                clipped_fraction * 100.0
+            ));
+        }
+        match subcarrier_offset {
+            None => warnings.push(String::from(
+                "No 2400 Hz subcarrier found, this does not look like an APT recording",
+            )),
+            Some(offset) if offset.abs() > MAX_EXPECTED_OFFSET => warnings.push(format!(
+                "Subcarrier {:+.1} Hz off 2400 Hz, check the sample rate of the recording",
+                offset
+            )),
+            Some(_) => {}
+        }
+
+        Self {
+            peak_dbfs: dbfs(peak),
+            rms_dbfs: dbfs(rms),
+            clipped_fraction,
+            subcarrier_fraction,
+            subcarrier_offset,
+            warnings,
+            hopeless: rms == 0.0 || subcarrier_offset.is_none(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let subcarrier = match self.subcarrier_offset {
+            Some(offset) => format!(
+                "subcarrier {:.0}% at {:+.1} Hz",
+                self.subcarrier_fraction * 100.0,
+                offset
+            ),
+            None => String::from("no subcarrier"),
+        };
+        format!(
+            "Peak {:.1} dBFS, RMS {:.1} dBFS, clipped {:.2}%, {}",
+            self.peak_dbfs,
+            self.rms_dbfs,
+            self.clipped_fraction * 100.0,
+            subcarrier
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn spec() -> WavSpec {
+        WavSpec {
+            channels: 1,
+            sample_rate: 20800,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        }
+    }
+
+    #[test]
+    fn clipping_and_subcarrier_offset_are_measured() {
+        // Half scale subcarrier 12 Hz high, every 100th sample at full scale
+        let samples: Vec<f32> = (0..20800 * 5)
+            .map(|i| {
+                if i % 100 == 0 {
+                    32767.0
+                } else {
+                    16384.0 * (2.0 * PI * 2412.0 * i as f32 / 20800.0).sin()
+                }
+            })
+            .collect();
+        let diagnostics = InputDiagnostics::new(&samples, &spec());
+        assert!((diagnostics.clipped_fraction - 0.01).abs() < 1e-4);
+        assert!(diagnostics.peak_dbfs.abs() < 0.01);
+        let offset = diagnostics.subcarrier_offset.unwrap();
+        assert!((offset - 12.0).abs() <= OFFSET_STEP, "offset {}", offset);
+        assert!(!diagnostics.hopeless);
+        assert_eq!(diagnostics.warnings.len(), 1, "{:?}", diagnostics.warnings);
+        assert!(diagnostics.warnings[0].contains("clipped"));
+    }
+
+    #[test]
+    fn silence_and_noise_are_hopeless() {
+        let silence = InputDiagnostics::new(&[0.0; 20800], &spec());
+        assert!(silence.hopeless);
+        assert!(silence.warnings[0].contains("silent"));
+
+        let mut seed = 3u32;
+        let noise: Vec<f32> = (0..20800 * 2)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 16) as f32 - 32768.0
+            })
+            .collect();
+        let noise = InputDiagnostics::new(&noise, &spec());
+        assert!(noise.hopeless);
+        assert_eq!(noise.subcarrier_offset, None);
+    }
+}
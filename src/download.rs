@@ -0,0 +1,531 @@
+use reqwest::{
+    blocking::{Client, Response},
+    header::{CONTENT_RANGE, ETAG, RANGE},
+    StatusCode,
+};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Hugging Face sends the SHA-256 of LFS files in this header
+const LINKED_ETAG: &str = "x-linked-etag";
+
+/// Where to fetch a file from and how to check it.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadSource {
+    // Tried in order until one delivers the whole file
+    pub urls: Vec<String>,
+    // Proxy for every request, the http(s)_proxy environment variables when None
+    pub proxy: Option<String>,
+    // Expected hex SHA-256, the one the server advertises is used when None
+    pub sha256: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    pub downloaded: u64,
+    // None when the server does not send the length
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Fraction done, None when the total is unknown.
+    pub fn fraction(&self) -> Option<f64> {
+        self.total
+            .filter(|&total| total > 0)
+            .map(|total| (self.downloaded as f64 / total as f64).min(1.0))
+    }
+
+    pub fn text(&self) -> String {
+        let megabytes = |bytes: u64| bytes as f64 / 1_000_000.0;
+        match (self.fraction(), self.total) {
+            (Some(fraction), Some(total)) => format!(
+                "Downloading... {:.0}% ({:.1} of {:.1} MB)",
+                fraction * 100.0,
+                megabytes(self.downloaded),
+                megabytes(total)
+            ),
+            _ => format!("Downloading... {:.1} MB", megabytes(self.downloaded)),
+        }
+    }
+}
+
+/// Hex SHA-256 of a file.
+pub fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// File the download is written to until it is verified, kept between attempts.
+pub fn partial_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".part");
+    target.with_file_name(name)
+}
+
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// SHA-256 the server advertises for the file, if any.
+fn advertised_sha256(response: &Response) -> Option<String> {
+    [LINKED_ETAG, ETAG.as_str()]
+        .iter()
+        .filter_map(|name| response.headers().get(*name))
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.trim_start_matches("W/").trim_matches('"'))
+        .find(|value| is_sha256(value))
+        .map(|value| value.to_ascii_lowercase())
+}
+
+/// First byte and total length of a `Content-Range: bytes first-last/total` answer.
+fn content_range(response: &Response) -> Option<(u64, Option<u64>)> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let first = range.split_once('-')?.0.parse().ok()?;
+    Some((first, total.parse().ok()))
+}
+
+/// Fetch `url` into the partial file, continuing where an earlier attempt stopped
+/// when the server honors ranges. Returns the SHA-256 the server advertises.
+fn fetch(
+    client: &Client,
+    url: &str,
+    partial: &Path,
+    cancel: &AtomicBool,
+    progress: &mut impl FnMut(Progress),
+) -> Result<Option<String>, String> {
+    let offset = fs::metadata(partial).map_or(0, |metadata| metadata.len());
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    let mut response = request.send().map_err(|e| format!("{}: {}", url, e))?;
+    let advertised = advertised_sha256(&response);
+
+    let (mut file, mut downloaded, total) = match response.status() {
+        // The partial file already holds everything
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(advertised),
+        StatusCode::PARTIAL_CONTENT => match content_range(&response) {
+            Some((first, total)) if first == offset => {
+                println!("Resuming download at {} bytes", offset);
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(partial)
+                    .map_err(|e| e.to_string())?;
+                (file, offset, total)
+            }
+            _ => return Err(format!("{}: unexpected range in the answer", url)),
+        },
+        // No range support, start over
+        status if status.is_success() => {
+            let file = File::create(partial).map_err(|e| e.to_string())?;
+            (file, 0, response.content_length())
+        }
+        status => return Err(format!("{}: server answered {}", url, status)),
+    };
+
+    let mut buf = vec![0u8; 1 << 16];
+    progress(Progress { downloaded, total });
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(String::from("Download cancelled"));
+        }
+        let n = response
+            .read(&mut buf)
+            .map_err(|e| format!("{}: {}", url, e))?;
+        if n == 0 {
+            break;
+        }
+        file.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+        downloaded += n as u64;
+        progress(Progress { downloaded, total });
+    }
+    file.sync_all().map_err(|e| e.to_string())?;
+
+    match total {
+        Some(total) if downloaded < total => Err(format!(
+            "{}: connection closed after {} of {} bytes",
+            url, downloaded, total
+        )),
+        _ => Ok(advertised),
+    }
+}
+
+/// Download a file to `target`. The data goes to a partial file next to it, which
+/// an interrupted or cancelled download leaves behind for the next attempt to resume
+/// with an HTTP range request. `target` only appears, by renaming, once the whole
+/// file is there and its SHA-256 matches. Returns the SHA-256.
+pub fn download(
+    source: &DownloadSource,
+    target: &Path,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(Progress),
+) -> Result<String, String> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(30))
+        // Large models take longer than the default 30 s
+        .timeout(None);
+    if let Some(proxy) = source.proxy.as_deref().filter(|proxy| !proxy.is_empty()) {
+        let proxy =
+            reqwest::Proxy::all(proxy).map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    }
+    let client = builder.build().map_err(|e| e.to_string())?;
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let partial = partial_path(target);
+    let mut errors = Vec::new();
+    let mut advertised = None;
+    let mut complete = false;
+    for url in &source.urls {
+        match fetch(&client, url, &partial, cancel, &mut progress) {
+            Ok(sha256) => {
+                advertised = sha256;
+                complete = true;
+                break;
+            }
+            Err(e) => {
+                if cancel.load(Ordering::SeqCst) {
+                    return Err(e);
+                }
+                eprintln!("Download failed: {}", e);
+                errors.push(e);
+            }
+        }
+    }
+    if !complete {
+        if errors.is_empty() {
+            return Err(String::from("No download address given"));
+        }
+        return Err(errors.join("\n"));
+    }
+
+    let sha256 = sha256_file(&partial)?;
+    let expected = source
+        .sha256
+        .as_deref()
+        .map(|expected| expected.trim().to_ascii_lowercase())
+        .filter(|expected| !expected.is_empty())
+        .or(advertised);
+    match expected {
+        Some(expected) if expected != sha256 => {
+            // A corrupt file cannot be resumed into a good one
+            let _ = fs::remove_file(&partial);
+            return Err(format!(
+                "Checksum mismatch, expected SHA-256 {} but got {}",
+                expected, sha256
+            ));
+        }
+        Some(_) => println!("SHA-256 verified: {}", sha256),
+        None => eprintln!("No checksum to verify against, SHA-256 is {}", sha256),
+    }
+    fs::rename(&partial, target).map_err(|e| e.to_string())?;
+    Ok(sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// How the stand-in server behaves.
+    #[derive(Clone, Default)]
+    struct Server {
+        body: Vec<u8>,
+        ranges: bool,
+        content_length: bool,
+        // Bytes sent before the connection is dropped, on the first request only
+        drop_after: Option<usize>,
+        status: Option<u16>,
+        etag: Option<String>,
+    }
+
+    /// Serve `server` on a local port, recording the Range header of every request.
+    fn serve(server: Server) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/model.onnx", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        range = Some(value.trim().trim_end_matches('-').to_string());
+                    }
+                }
+                log.lock().unwrap().push(range.clone());
+
+                let start: usize = match (&range, server.ranges) {
+                    (Some(start), true) => start.parse().unwrap(),
+                    _ => 0,
+                };
+                let mut head = if let Some(status) = server.status {
+                    format!("HTTP/1.1 {} Error\r\nContent-Length: 0\r\n", status)
+                } else if start >= server.body.len() && start > 0 {
+                    String::from("HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\n")
+                } else if start > 0 {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                        start,
+                        server.body.len() - 1,
+                        server.body.len()
+                    )
+                } else {
+                    String::from("HTTP/1.1 200 OK\r\n")
+                };
+                let body = match server.status {
+                    Some(_) => &[][..],
+                    None if start >= server.body.len() => &[][..],
+                    None => &server.body[start..],
+                };
+                if server.status.is_none() && server.content_length {
+                    head += &format!("Content-Length: {}\r\n", body.len());
+                }
+                if let Some(etag) = &server.etag {
+                    head += &format!("ETag: \"{}\"\r\n", etag);
+                }
+                head += "Connection: close\r\n\r\n";
+                let sent = match server.drop_after {
+                    Some(limit) if index == 0 => limit.min(body.len()),
+                    _ => body.len(),
+                };
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&body[..sent]);
+            }
+        });
+        (url, requests)
+    }
+
+    fn body() -> Vec<u8> {
+        (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn target(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trans-misja-download-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("model.onnx")
+    }
+
+    fn source(urls: Vec<String>, sha256: Option<String>) -> DownloadSource {
+        DownloadSource {
+            urls,
+            proxy: None,
+            sha256,
+        }
+    }
+
+    #[test]
+    fn verified_download_lands_atomically() {
+        let data = body();
+        let (url, _) = serve(Server {
+            body: data.clone(),
+            content_length: true,
+            ..Default::default()
+        });
+        let target = target("verified");
+        let mut updates = Vec::new();
+        let result = download(
+            &source(vec![url], Some(sha256(&data))),
+            &target,
+            &AtomicBool::new(false),
+            |progress| updates.push(progress),
+        );
+        assert_eq!(result, Ok(sha256(&data)));
+        assert_eq!(fs::read(&target).unwrap(), data);
+        assert!(!partial_path(&target).exists());
+        assert_eq!(updates.last().unwrap().fraction(), Some(1.0));
+    }
+
+    #[test]
+    fn interrupted_download_resumes_with_a_range() {
+        let data = body();
+        let (url, requests) = serve(Server {
+            body: data.clone(),
+            ranges: true,
+            content_length: true,
+            drop_after: Some(100_000),
+            ..Default::default()
+        });
+        let target = target("resume");
+        let sources = source(vec![url], Some(sha256(&data)));
+        let cancel = AtomicBool::new(false);
+
+        assert!(download(&sources, &target, &cancel, |_| {}).is_err());
+        assert!(!target.exists());
+        assert_eq!(fs::metadata(partial_path(&target)).unwrap().len(), 100_000);
+
+        assert_eq!(
+            download(&sources, &target, &cancel, |_| {}),
+            Ok(sha256(&data))
+        );
+        assert_eq!(fs::read(&target).unwrap(), data);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![None, Some(String::from("100000"))]
+        );
+    }
+
+    #[test]
+    fn server_without_ranges_starts_over() {
+        let data = body();
+        let (url, _) = serve(Server {
+            body: data.clone(),
+            content_length: true,
+            ..Default::default()
+        });
+        let target = target("no-ranges");
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        fs::write(partial_path(&target), &data[..5000]).unwrap();
+        let result = download(
+            &source(vec![url], Some(sha256(&data))),
+            &target,
+            &AtomicBool::new(false),
+            |_| {},
+        );
+        assert_eq!(result, Ok(sha256(&data)));
+        assert_eq!(fs::read(&target).unwrap(), data);
+    }
+
+    #[test]
+    fn checksum_mismatch_leaves_nothing_behind() {
+        let (url, _) = serve(Server {
+            body: body(),
+            content_length: true,
+            ..Default::default()
+        });
+        let target = target("mismatch");
+        let result = download(
+            &source(vec![url], Some("00".repeat(32))),
+            &target,
+            &AtomicBool::new(false),
+            |_| {},
+        );
+        assert!(result.unwrap_err().contains("Checksum mismatch"));
+        assert!(!target.exists());
+        assert!(!partial_path(&target).exists());
+    }
+
+    #[test]
+    fn advertised_checksum_is_checked() {
+        let (url, _) = serve(Server {
+            body: body(),
+            content_length: true,
+            etag: Some("ab".repeat(32)),
+            ..Default::default()
+        });
+        let target = target("advertised");
+        let result = download(
+            &source(vec![url], None),
+            &target,
+            &AtomicBool::new(false),
+            |_| {},
+        );
+        assert!(result.unwrap_err().contains("Checksum mismatch"));
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn missing_length_reports_no_fraction() {
+        let data = body();
+        let (url, _) = serve(Server {
+            body: data.clone(),
+            ..Default::default()
+        });
+        let target = target("no-length");
+        let mut updates = Vec::new();
+        let result = download(
+            &source(vec![url], Some(sha256(&data))),
+            &target,
+            &AtomicBool::new(false),
+            |progress| updates.push(progress),
+        );
+        assert!(result.is_ok());
+        assert!(updates.iter().all(|progress| progress.fraction().is_none()));
+        assert!(!updates.last().unwrap().text().contains("NaN"));
+    }
+
+    #[test]
+    fn cancel_keeps_the_partial_file() {
+        let data = body();
+        let (url, _) = serve(Server {
+            body: data.clone(),
+            ranges: true,
+            content_length: true,
+            ..Default::default()
+        });
+        let target = target("cancel");
+        let cancel = AtomicBool::new(false);
+        let result = download(
+            &source(vec![url], Some(sha256(&data))),
+            &target,
+            &cancel,
+            |progress| {
+                if progress.downloaded > 0 {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            },
+        );
+        assert_eq!(result, Err(String::from("Download cancelled")));
+        assert!(!target.exists());
+        assert!(partial_path(&target).exists());
+    }
+
+    #[test]
+    fn mirror_is_tried_after_a_failure() {
+        let data = body();
+        let (broken, _) = serve(Server {
+            status: Some(503),
+            ..Default::default()
+        });
+        let (mirror, _) = serve(Server {
+            body: data.clone(),
+            content_length: true,
+            ..Default::default()
+        });
+        let target = target("mirror");
+        let result = download(
+            &source(vec![broken, mirror], Some(sha256(&data))),
+            &target,
+            &AtomicBool::new(false),
+            |_| {},
+        );
+        assert_eq!(result, Ok(sha256(&data)));
+    }
+}
@@ -0,0 +1,347 @@
+use crate::inference::{self, Backend, ExecutionProvider};
+use crate::quality::MIN_SYNC_SCORE;
+use crate::tiling;
+
+use image::{GrayImage, ImageBuffer, Luma};
+use std::error::Error;
+
+// Mask values
+pub const KEPT: u8 = 0;
+pub const REPAIRED: u8 = 255;
+
+// Lines whose sync search failed (below quality::MIN_SYNC_SCORE) are dropped entirely
+// Neighbors of a good line correlate well above this, a line below half of it is bad
+const MIN_NEIGHBOR_CORRELATION: f32 = 0.5;
+// Width of the segments tested on their own
+const SEGMENT_WIDTH: u32 = 32;
+// A segment differing from both neighbor lines by this many times the median difference is bad
+const DIFFERENCE_FACTOR: f32 = 6.0;
+const MIN_DIFFERENCE: f32 = 20.0;
+// A flat segment (stuck receiver) between textured lines is bad
+const FLAT_VARIANCE: f32 = 1.0;
+const TEXTURED_VARIANCE: f32 = 25.0;
+const PATCH_SIZE: usize = 256;
+// Pixels shared by neighboring patches, blended so that no seams show
+const TILE_OVERLAP: usize = 32;
+
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut covariance, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    if var_a <= 0.0 || var_b <= 0.0 {
+        return 0.0;
+    }
+    covariance / (var_a.sqrt() * var_b.sqrt())
+}
+
+fn variance(values: &[f32]) -> f32 {
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32
+}
+
+/// Mask of the pixels to repair, from the sync score of every line (one per image row),
+/// line-to-line correlation and the variance of short segments.
+pub fn detect_dropouts(image: &GrayImage, sync_scores: &[f32]) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let mut mask: GrayImage = ImageBuffer::new(width, height);
+    let rows: Vec<Vec<f32>> = image
+        .rows()
+        .map(|row| row.map(|p| p[0] as f32).collect())
+        .collect();
+    let mark = |mask: &mut GrayImage, y: u32, x0: u32, x1: u32| {
+        for x in x0..x1 {
+            mask.put_pixel(x, y, Luma([REPAIRED]));
+        }
+    };
+
+    // Whole lines
+    for y in 0..height as usize {
+        let lost_sync = sync_scores.get(y).is_some_and(|&s| s < MIN_SYNC_SCORE);
+        let uncorrelated = y > 0 && y + 1 < height as usize && {
+            let across = correlation(&rows[y - 1], &rows[y + 1]);
+            let up = correlation(&rows[y], &rows[y - 1]);
+            let down = correlation(&rows[y], &rows[y + 1]);
+            across >= MIN_NEIGHBOR_CORRELATION && up.max(down) < across * 0.5
+        };
+        if lost_sync || uncorrelated {
+            mark(&mut mask, y as u32, 0, width);
+        }
+    }
+
+    // Segments: difference to the closest neighbor line and flatness
+    let segments = width.div_ceil(SEGMENT_WIDTH);
+    let mut differences = Vec::new();
+    for y in 1..height.saturating_sub(1) as usize {
+        for s in 0..segments {
+            let x0 = (s * SEGMENT_WIDTH) as usize;
+            let x1 = (x0 + SEGMENT_WIDTH as usize).min(width as usize);
+            let mean_diff = |other: &[f32]| {
+                rows[y][x0..x1]
+                    .iter()
+                    .zip(&other[x0..x1])
+                    .map(|(a, b)| (a - b).abs())
+                    .sum::<f32>()
+                    / (x1 - x0) as f32
+            };
+            differences.push(mean_diff(&rows[y - 1]).min(mean_diff(&rows[y + 1])));
+        }
+    }
+    let mut sorted = differences.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted.get(sorted.len() / 2).copied().unwrap_or(0.0);
+    let max_difference = (median * DIFFERENCE_FACTOR).max(MIN_DIFFERENCE);
+
+    for y in 1..height.saturating_sub(1) as usize {
+        for s in 0..segments {
+            let x0 = (s * SEGMENT_WIDTH) as usize;
+            let x1 = (x0 + SEGMENT_WIDTH as usize).min(width as usize);
+            let noisy = differences[(y - 1) * segments as usize + s as usize] > max_difference;
+            let stuck = variance(&rows[y][x0..x1]) < FLAT_VARIANCE
+                && variance(&rows[y - 1][x0..x1]) > TEXTURED_VARIANCE
+                && variance(&rows[y + 1][x0..x1]) > TEXTURED_VARIANCE;
+            if noisy || stuck {
+                mark(&mut mask, y as u32, x0 as u32, x1 as u32);
+            }
+        }
+    }
+
+    mask
+}
+
+/// Fill masked pixels by linear interpolation between the closest kept pixels
+/// above and below in the same column.
+pub fn interpolate(image: &GrayImage, mask: &GrayImage) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let mut repaired = image.clone();
+    for x in 0..width {
+        let mut y = 0;
+        while y < height {
+            if mask.get_pixel(x, y)[0] == KEPT {
+                y += 1;
+                continue;
+            }
+            let start = y;
+            while y < height && mask.get_pixel(x, y)[0] != KEPT {
+                y += 1;
+            }
+            let above = start
+                .checked_sub(1)
+                .map(|a| image.get_pixel(x, a)[0] as f32);
+            let below = (y < height).then(|| image.get_pixel(x, y)[0] as f32);
+            let gap = (y - start + 1) as f32;
+            for (i, fill_y) in (start..y).enumerate() {
+                let t = (i + 1) as f32 / gap;
+                let value = match (above, below) {
+                    (Some(a), Some(b)) => a + (b - a) * t,
+                    (Some(a), None) => a,
+                    (None, Some(b)) => b,
+                    (None, None) => continue,
+                };
+                repaired.put_pixel(x, fill_y, Luma([value.round() as u8]));
+            }
+        }
+    }
+    repaired
+}
+
+/// Fill masked pixels with an ONNX model taking (image with holes zeroed, mask)
+/// patches in [0, 1] and returning the completed image. Kept pixels are left untouched.
+pub fn inpaint_with_model(
+    image: &GrayImage,
+    mask: &GrayImage,
+    model_path: &str,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+) -> Result<GrayImage, Box<dyn Error>> {
+    // Nothing to fill, no need for a session
+    if mask.pixels().all(|p| p[0] == KEPT) {
+        return Ok(image.clone());
+    }
+
+    // Load the ONNX model
+    let model = inference::load_model(model_path, cpu_threads, provider, backend)?;
+    let input_name = model
+        .inputs()
+        .first()
+        .ok_or("The model has no input")?
+        .name
+        .clone();
+    let output_name = model
+        .outputs()
+        .first()
+        .ok_or("The model has no output")?
+        .name
+        .clone();
+
+    // Image with its holes zeroed and the holes themselves, as the two channels
+    let holed = ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        if mask.get_pixel(x, y)[0] == KEPT {
+            *image.get_pixel(x, y)
+        } else {
+            Luma([0])
+        }
+    });
+    let holes = ImageBuffer::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([if mask.get_pixel(x, y)[0] == KEPT {
+            0
+        } else {
+            255
+        }])
+    });
+    let completed = tiling::run_tiled_channels(
+        &[&holed, &holes],
+        PATCH_SIZE,
+        1,
+        TILE_OVERLAP,
+        1,
+        |patches, count| {
+            let input = ndarray::Array4::from_shape_vec(
+                (count, 2, PATCH_SIZE, PATCH_SIZE),
+                patches.to_vec(),
+            )?;
+            let output = model.run(&input_name, input.into_dyn(), &output_name)?;
+            Ok(output.iter().copied().collect())
+        },
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut repaired = image.clone();
+    for (x, y, pixel) in repaired.enumerate_pixels_mut() {
+        if mask.get_pixel(x, y)[0] != KEPT {
+            *pixel = *completed.get_pixel(x, y);
+        }
+    }
+
+    Ok(repaired)
+}
+
+/// Detect and repair dropouts of an APT image, save the repaired image and the
+/// mask of repaired pixels and return both paths.
+pub fn repair_dropouts(
+    image_path: &str,
+    sync_scores: &[f32],
+    model_path: &str,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+) -> Result<(String, String), String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+
+    let mask = detect_dropouts(&image, sync_scores);
+    let fraction = mask.iter().filter(|&&p| p != KEPT).count() as f32 / mask.len().max(1) as f32;
+    println!("Dropouts: {:.2}% of the pixels", fraction * 100.0);
+
+    let repaired = if model_path.is_empty() {
+        interpolate(&image, &mask)
+    } else {
+        println!("Inpainting dropouts with model {}", model_path);
+        inpaint_with_model(&image, &mask, model_path, cpu_threads, provider, backend)
+            .unwrap_or_else(|e| {
+                eprintln!("Error inpainting dropouts, interpolating instead: {}", e);
+                interpolate(&image, &mask)
+            })
+    };
+
+    let mask_path = String::from("repair_mask.png");
+    println!("Saving repair mask to: {}", mask_path);
+    mask.save(&mask_path).map_err(|e| e.to_string())?;
+
+    let output_path = String::from("repaired_image.png");
+    println!("Saving repaired image to: {}", output_path);
+    repaired.save(&output_path).map_err(|e| e.to_string())?;
+    Ok((output_path, mask_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(x: u32, y: u32) -> u8 {
+        (100.0 + 50.0 * (x as f32 / 5.0 + y as f32 / 20.0).sin()) as u8
+    }
+
+    fn masked_columns(mask: &GrayImage, y: u32) -> Vec<u32> {
+        (0..mask.width())
+            .filter(|&x| mask.get_pixel(x, y)[0] != KEPT)
+            .collect()
+    }
+
+    #[test]
+    fn dropouts_are_found_by_sync_correlation_and_flatness() {
+        // Line 10 lost its sync, line 20 is static, line 30 is stuck at 32-63
+        let mut seed = 1u32;
+        let image = GrayImage::from_fn(128, 40, |x, y| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            Luma([match (x, y) {
+                (_, 20) => (seed >> 24) as u8,
+                (32..64, 30) => 0,
+                _ => texture(x, y),
+            }])
+        });
+        let sync_scores: Vec<f32> = (0..40).map(|y| if y == 10 { 0.0 } else { 0.9 }).collect();
+
+        let mask = detect_dropouts(&image, &sync_scores);
+        for y in 0..40 {
+            let expected: Vec<u32> = match y {
+                10 | 20 => (0..128).collect(),
+                30 => (32..64).collect(),
+                _ => Vec::new(),
+            };
+            assert_eq!(masked_columns(&mask, y), expected, "line {}", y);
+        }
+    }
+
+    #[test]
+    fn interpolation_fills_between_kept_lines() {
+        let image = GrayImage::from_fn(4, 10, |_, y| Luma([(y * 10) as u8]));
+        let mask = GrayImage::from_fn(4, 10, |x, y| {
+            Luma([if y == 0 || (4..7).contains(&y) || (x == 1 && y == 9) {
+                REPAIRED
+            } else {
+                KEPT
+            }])
+        });
+        // Punch the holes so that only the interpolation can restore them
+        let damaged = GrayImage::from_fn(4, 10, |x, y| {
+            if mask.get_pixel(x, y)[0] == KEPT {
+                *image.get_pixel(x, y)
+            } else {
+                Luma([255])
+            }
+        });
+
+        let repaired = interpolate(&damaged, &mask);
+        for x in 0..4 {
+            assert_eq!(repaired.get_pixel(x, 5)[0], 50);
+            assert_eq!(repaired.get_pixel(x, 6)[0], 60);
+            // Holes at the border repeat the closest kept pixel
+            assert_eq!(repaired.get_pixel(x, 0)[0], 10);
+        }
+        assert_eq!(repaired.get_pixel(1, 9)[0], 80);
+    }
+
+    #[test]
+    fn clean_mask_needs_no_model() {
+        let image = GrayImage::from_fn(8, 8, |x, y| Luma([texture(x, y)]));
+        let mask = GrayImage::new(8, 8);
+        let repaired = inpaint_with_model(
+            &image,
+            &mask,
+            "missing.onnx",
+            1,
+            ExecutionProvider::Cpu,
+            Backend::default(),
+        )
+        .unwrap();
+        assert_eq!(repaired, image);
+    }
+}
@@ -0,0 +1,449 @@
+use crate::augment::{self, Augmentation, Combine};
+use crate::inference::{self, Backend, ExecutionProvider, Model};
+use crate::model_spec::ModelSpec;
+use crate::settings::FunctionsSettings;
+use crate::tiling;
+
+use image::GrayImage;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How an image is run through the model, as opposed to which model is loaded.
+#[derive(Clone, Copy, Debug)]
+pub struct EnhanceOptions {
+    // Patches per model call, unless the model fixes its batch size
+    pub batch_size: usize,
+    pub tile_overlap: usize,
+    pub augmentation: Augmentation,
+    pub combine: Combine,
+}
+
+impl EnhanceOptions {
+    pub fn from_settings(settings: &FunctionsSettings) -> Self {
+        Self {
+            batch_size: settings.batch_size,
+            tile_overlap: settings.tile_overlap,
+            augmentation: settings.augmentation,
+            combine: settings.augmentation_combine,
+        }
+    }
+}
+
+/// An image enhancement model loaded once and kept, so that building and optimizing
+/// the session is not paid again on every image.
+pub struct Enhancer {
+    model: Box<dyn Model>,
+    pub spec: ModelSpec,
+    // What the model was loaded with, anything else needs a new enhancer
+    model_path: String,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+    // Provider the session actually runs on, after any fallback
+    active_provider: ExecutionProvider,
+}
+
+impl Enhancer {
+    pub fn new(
+        model_path: &str,
+        cpu_threads: usize,
+        provider: ExecutionProvider,
+        backend: Backend,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Load the ONNX model
+        let model = inference::load_model(model_path, cpu_threads, provider, backend)?;
+        let active_provider = inference::active_provider().unwrap_or(ExecutionProvider::Cpu);
+        Self::with_model(
+            model,
+            model_path,
+            cpu_threads,
+            provider,
+            backend,
+            active_provider,
+        )
+    }
+
+    fn with_model(
+        model: Box<dyn Model>,
+        model_path: &str,
+        cpu_threads: usize,
+        provider: ExecutionProvider,
+        backend: Backend,
+        active_provider: ExecutionProvider,
+    ) -> Result<Self, Box<dyn Error>> {
+        println!("Inputs:");
+        for (i, input) in model.inputs().iter().enumerate() {
+            println!("    {i} {}: {:?}", input.name, input.dimensions);
+        }
+        println!("Outputs:");
+        for (i, output) in model.outputs().iter().enumerate() {
+            println!("    {i} {}: {:?}", output.name, output.dimensions);
+        }
+
+        let mut spec = ModelSpec::from_model(model.as_ref(), model_path)?;
+        spec.measure_scale(model.as_ref())?;
+        println!("Model: {}", spec.summary());
+
+        Ok(Self {
+            model,
+            spec,
+            model_path: model_path.to_string(),
+            cpu_threads,
+            provider,
+            backend,
+            active_provider,
+        })
+    }
+
+    fn matches(
+        &self,
+        model_path: &str,
+        cpu_threads: usize,
+        provider: ExecutionProvider,
+        backend: Backend,
+    ) -> bool {
+        self.model_path == model_path
+            && self.cpu_threads == cpu_threads
+            && self.provider == provider
+            && self.backend == backend
+    }
+
+    /// Run `count` patches laid out one after the other in a single model call. The
+    /// output patches are `spec.scale` times the side of the input ones.
+    fn run_batch(
+        &self,
+        patches: &[f32],
+        count: usize,
+    ) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> {
+        let spec = &self.spec;
+        let pixels = spec.patch_size * spec.patch_size;
+        // A model with a fixed batch size gets blank patches after the last one
+        let batch = spec.batch.unwrap_or(count).max(count);
+
+        // The gray patch goes into every channel
+        let mut input = vec![0.0; batch * spec.channels * pixels];
+        for n in 0..count {
+            let patch = &patches[n * pixels..(n + 1) * pixels];
+            for c in 0..spec.channels {
+                let offset = (n * spec.channels + c) * pixels;
+                for (value, &p) in input[offset..offset + pixels].iter_mut().zip(patch) {
+                    *value = spec.normalize(p);
+                }
+            }
+        }
+        let input = ndarray::Array4::from_shape_vec(
+            (batch, spec.channels, spec.patch_size, spec.patch_size),
+            input,
+        )?;
+
+        // Run the model
+        let output = self
+            .model
+            .run(&spec.input_name, input.into_dyn(), &spec.output_name)?;
+
+        // Get the output patches, averaging their channels
+        let output_size = spec.patch_size * spec.scale;
+        let output_pixels = output_size * output_size;
+        let output_channels = match output.shape() {
+            &[n, channels, height, width]
+                if n == batch && height == output_size && width == output_size && channels > 0 =>
+            {
+                channels
+            }
+            other => {
+                return Err(format!(
+                    "Unexpected output shape {:?}, expected {} patches of {} px at scale {}",
+                    other, batch, output_size, spec.scale
+                )
+                .into())
+            }
+        };
+        let values: Vec<f32> = output.iter().copied().collect();
+        Ok((0..count * output_pixels)
+            .map(|index| {
+                let (n, i) = (index / output_pixels, index % output_pixels);
+                let sum: f32 = (0..output_channels)
+                    .map(|c| values[(n * output_channels + c) * output_pixels + i])
+                    .sum();
+                spec.denormalize(sum / output_channels as f32)
+            })
+            .collect())
+    }
+
+    /// Enhance an image in overlapping patches. With test-time augmentation every
+    /// batch of patches is run once per transform and the outputs combined.
+    pub fn enhance(
+        &self,
+        image: &GrayImage,
+        options: EnhanceOptions,
+    ) -> Result<GrayImage, Box<dyn Error>> {
+        let batch_size = self.spec.batch.unwrap_or(options.batch_size.max(1));
+        inference::record_active_provider(self.active_provider);
+        tiling::run_tiled(
+            image,
+            self.spec.patch_size,
+            self.spec.scale,
+            options.tile_overlap,
+            batch_size,
+            |patches, count| {
+                augment::run_augmented(
+                    patches,
+                    count,
+                    self.spec.patch_size,
+                    self.spec.scale,
+                    options.augmentation,
+                    options.combine,
+                    |patches, count| self.run_batch(patches, count),
+                )
+            },
+        )
+        .map_err(|e| e.to_string().into())
+    }
+}
+
+/// Call `f` with the enhancer kept in `cache`, loading the model again only when the
+/// model path, thread count, execution provider or backend changed.
+pub fn with_cached<T>(
+    cache: &Mutex<Option<Enhancer>>,
+    model_path: &str,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+    f: impl FnOnce(&Enhancer) -> T,
+) -> Result<T, Box<dyn Error>> {
+    let mut cache = cache.lock().unwrap();
+    let reusable = cache
+        .as_ref()
+        .is_some_and(|enhancer| enhancer.matches(model_path, cpu_threads, provider, backend));
+    if !reusable {
+        *cache = Some(Enhancer::new(model_path, cpu_threads, provider, backend)?);
+    } else {
+        println!("Reusing loaded model {}", model_path);
+    }
+    Ok(f(cache.as_ref().unwrap()))
+}
+
+/// Time enhancing an image the way it was done before sessions were kept (a new
+/// session per image, one patch per call) against a kept session at several batch
+/// sizes and with test-time augmentation, and print the throughput of each.
+pub fn benchmark(
+    image_path: &str,
+    model_path: &str,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+    options: EnhanceOptions,
+    runs: usize,
+) -> Result<(), Box<dyn Error>> {
+    let tile_overlap = options.tile_overlap;
+    let plain = EnhanceOptions {
+        augmentation: Augmentation::Off,
+        ..options
+    };
+    let image = image::open(image_path)?.to_luma8();
+    let runs = runs.max(1);
+
+    let mut rows = Vec::new();
+    let start = Instant::now();
+    let mut patches = 0;
+    for _ in 0..runs {
+        let enhancer = Enhancer::new(model_path, cpu_threads, provider, backend)?;
+        patches = tiling::tile_count(image.dimensions(), enhancer.spec.patch_size, tile_overlap);
+        let options = EnhanceOptions {
+            batch_size: 1,
+            ..plain
+        };
+        enhancer.enhance(&image, options)?;
+    }
+    rows.push((
+        String::from("new session per image, batch 1"),
+        start.elapsed().as_secs_f64(),
+    ));
+
+    let start = Instant::now();
+    let enhancer = Enhancer::new(model_path, cpu_threads, provider, backend)?;
+    let load = start.elapsed().as_secs_f64();
+    let batch_sizes: Vec<usize> = match enhancer.spec.batch {
+        Some(fixed) => vec![fixed],
+        None => vec![1, 4, 8, 16],
+    };
+    for &batch_size in &batch_sizes {
+        let options = EnhanceOptions {
+            batch_size,
+            ..plain
+        };
+        let start = Instant::now();
+        for _ in 0..runs {
+            enhancer.enhance(&image, options)?;
+        }
+        rows.push((
+            format!("kept session, batch {}", batch_size),
+            start.elapsed().as_secs_f64(),
+        ));
+    }
+
+    // Augmentation at the configured batch size, against the same run without it
+    let batch_size = enhancer.spec.batch.unwrap_or(options.batch_size.max(1));
+    let baseline = {
+        let options = EnhanceOptions {
+            batch_size,
+            ..plain
+        };
+        let start = Instant::now();
+        for _ in 0..runs {
+            enhancer.enhance(&image, options)?;
+        }
+        start.elapsed().as_secs_f64()
+    };
+    let mut augmented = Vec::new();
+    for augmentation in [Augmentation::Four, Augmentation::Eight] {
+        for combine in Combine::ALL {
+            let options = EnhanceOptions {
+                batch_size,
+                augmentation,
+                combine,
+                ..options
+            };
+            let start = Instant::now();
+            for _ in 0..runs {
+                enhancer.enhance(&image, options)?;
+            }
+            augmented.push((
+                format!(
+                    "TTA {} {}, batch {}",
+                    augmentation.name(),
+                    combine.name().to_lowercase(),
+                    batch_size
+                ),
+                start.elapsed().as_secs_f64(),
+            ));
+        }
+    }
+
+    println!(
+        "{} {}x{} images, {} patches each, overlap {}, model loaded in {:.2} s",
+        runs,
+        image.width(),
+        image.height(),
+        patches,
+        tile_overlap,
+        load
+    );
+    println!("{:<34} {:>10} {:>12}", "Mode", "s/image", "patches/s");
+    for (mode, seconds) in rows {
+        println!(
+            "{:<34} {:>10.3} {:>12.1}",
+            mode,
+            seconds / runs as f64,
+            (patches * runs) as f64 / seconds
+        );
+    }
+    println!(
+        "{:<34} {:>10} {:>12}",
+        "Test-time augmentation", "s/image", "vs. off"
+    );
+    for (mode, seconds) in augmented {
+        println!(
+            "{:<34} {:>10.3} {:>11.1}x",
+            mode,
+            seconds / runs as f64,
+            seconds / baseline
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apt;
+    use crate::color::{self, Palette};
+    use crate::inference::TensorInfo;
+    use ndarray::{ArrayD, IxDyn};
+
+    /// A super-resolution model repeating every pixel into 2x2 output pixels.
+    struct Upscale {
+        inputs: Vec<TensorInfo>,
+        outputs: Vec<TensorInfo>,
+    }
+
+    impl Upscale {
+        fn new() -> Self {
+            let info = |name: &str, side| TensorInfo {
+                name: name.to_string(),
+                dimensions: Some(vec![-1, 1, side, side]),
+            };
+            Self {
+                inputs: vec![info("input", 32)],
+                outputs: vec![info("output", 64)],
+            }
+        }
+    }
+
+    impl Model for Upscale {
+        fn inputs(&self) -> &[TensorInfo] {
+            &self.inputs
+        }
+
+        fn outputs(&self) -> &[TensorInfo] {
+            &self.outputs
+        }
+
+        fn run(
+            &self,
+            _input_name: &str,
+            input: ArrayD<f32>,
+            _output_name: &str,
+        ) -> Result<ArrayD<f32>, Box<dyn Error + Send + Sync>> {
+            let shape = input.shape().to_vec();
+            let output_shape = [shape[0], shape[1], shape[2] * 2, shape[3] * 2];
+            Ok(ArrayD::from_shape_fn(IxDyn(&output_shape), |index| {
+                input[[index[0], index[1], index[2] / 2, index[3] / 2]]
+            }))
+        }
+    }
+
+    #[test]
+    fn palette_follows_a_super_resolution_model() {
+        let enhancer = Enhancer::with_model(
+            Box::new(Upscale::new()),
+            "upscale.onnx",
+            1,
+            ExecutionProvider::Cpu,
+            Backend::default(),
+            ExecutionProvider::Cpu,
+        )
+        .unwrap();
+        assert_eq!(enhancer.spec.scale, 2);
+
+        let image = GrayImage::from_fn(apt::LINE_WIDTH, 40, |x, y| {
+            image::Luma([((x * 7 + y * 13) % 251) as u8])
+        });
+        let options = EnhanceOptions {
+            batch_size: 4,
+            tile_overlap: 8,
+            augmentation: Augmentation::Off,
+            combine: Combine::Mean,
+        };
+        let enhanced = enhancer.enhance(&image, options).unwrap();
+        assert_eq!(enhanced.dimensions(), (2 * apt::LINE_WIDTH, 80));
+
+        // Coloring the upscaled image upscales the colored image
+        let sync_column = apt::sync_column(100);
+        let colored = color::apply_palette(&image, sync_column, &Palette::Mcir).unwrap();
+        let upscaled = color::apply_palette(&enhanced, sync_column, &Palette::Mcir).unwrap();
+        assert_eq!(upscaled.width(), 2 * colored.width());
+        for y in 0..80 {
+            for x in 0..upscaled.width() {
+                assert_eq!(
+                    upscaled.get_pixel(x, y),
+                    colored.get_pixel(x / 2, y / 2),
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,691 @@
+use crate::apt;
+use crate::augment::Augmentation;
+use crate::enhancer::{EnhanceOptions, Enhancer};
+use crate::gaussian_blur;
+use crate::models;
+use crate::settings::FunctionsSettings;
+
+use image::{imageops, GrayImage};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const METHOD_NAMES: [&str; 4] = ["none", "sgbnr", "model", "model-tta"];
+
+// Gaussian window of SSIM (Wang et al. 2004)
+const SSIM_SIGMA: f32 = 1.5;
+const SSIM_RADIUS: usize = 5;
+const SSIM_C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+// Weights of the MS-SSIM scales, finest first
+const MS_SSIM_WEIGHTS: [f32; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+// Pulses of the sync A bar, each with a rising and a falling edge
+const SYNC_PULSES: usize = 7;
+
+/// An enhancement method compared by the evaluation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    // The image as decoded
+    None,
+    Sgbnr,
+    // The enhancement model with the configured test-time augmentation
+    Model,
+    // The enhancement model with 8x test-time augmentation
+    ModelTta,
+}
+
+impl Method {
+    pub const ALL: [Method; 4] = [Method::None, Method::Sgbnr, Method::Model, Method::ModelTta];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        METHOD_NAMES
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        METHOD_NAMES[Self::ALL.iter().position(|m| m == self).unwrap_or(0)]
+    }
+
+    fn uses_model(&self) -> bool {
+        matches!(self, Method::Model | Method::ModelTta)
+    }
+}
+
+/// Metrics of one enhanced image. The full-reference ones are None without a
+/// reference image, sync sharpness without a synced APT line.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    pub psnr: Option<f32>,
+    pub ssim: Option<f32>,
+    pub ms_ssim: Option<f32>,
+    // Variance of the pixel noise (Immerkaer's estimate)
+    pub noise_variance: f32,
+    // Mean squared line-to-line jump of the row means
+    pub stripe_energy: f32,
+    // Mean absolute step across the sync A pulses
+    pub sync_sharpness: Option<f32>,
+    pub seconds: f64,
+}
+
+pub struct Evaluation {
+    pub file: String,
+    pub method: Method,
+    pub metrics: Metrics,
+}
+
+fn values(image: &GrayImage) -> Vec<f32> {
+    image.pixels().map(|p| p[0] as f32).collect()
+}
+
+/// Peak signal to noise ratio in dB, infinite for identical images.
+pub fn psnr(image: &GrayImage, reference: &GrayImage) -> f32 {
+    let mse = image
+        .pixels()
+        .zip(reference.pixels())
+        .map(|(a, b)| (a[0] as f32 - b[0] as f32).powi(2))
+        .sum::<f32>()
+        / (image.width() * image.height()).max(1) as f32;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Separable Gaussian filter of the SSIM window, borders clamped.
+fn gaussian_filter(values: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let kernel: Vec<f32> = (0..=2 * SSIM_RADIUS)
+        .map(|i| {
+            let d = i as f32 - SSIM_RADIUS as f32;
+            (-d * d / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp()
+        })
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    let at = |i: usize, offset: usize, length: usize| {
+        (i + offset).saturating_sub(SSIM_RADIUS).min(length - 1)
+    };
+
+    let mut rows = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            rows[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * values[y * width + at(x, k, width)])
+                .sum::<f32>()
+                / total;
+        }
+    }
+    let mut filtered = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            filtered[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * rows[at(y, k, height) * width + x])
+                .sum::<f32>()
+                / total;
+        }
+    }
+    filtered
+}
+
+/// Mean SSIM and mean contrast-structure term over the pixels, the luminance term
+/// being weighed in pixel by pixel.
+fn ssim_terms(a: &[f32], b: &[f32], width: usize, height: usize) -> (f32, f32) {
+    let product = |x: &[f32], y: &[f32]| x.iter().zip(y).map(|(x, y)| x * y).collect::<Vec<f32>>();
+    let mean_a = gaussian_filter(a, width, height);
+    let mean_b = gaussian_filter(b, width, height);
+    let aa = gaussian_filter(&product(a, a), width, height);
+    let bb = gaussian_filter(&product(b, b), width, height);
+    let ab = gaussian_filter(&product(a, b), width, height);
+
+    let (mut similarity, mut contrast_structure) = (0.0, 0.0);
+    for i in 0..a.len() {
+        let (mu_a, mu_b) = (mean_a[i], mean_b[i]);
+        let var_a = aa[i] - mu_a * mu_a;
+        let var_b = bb[i] - mu_b * mu_b;
+        let covariance = ab[i] - mu_a * mu_b;
+        let luminance = (2.0 * mu_a * mu_b + SSIM_C1) / (mu_a * mu_a + mu_b * mu_b + SSIM_C1);
+        let cs = (2.0 * covariance + SSIM_C2) / (var_a + var_b + SSIM_C2);
+        similarity += luminance * cs;
+        contrast_structure += cs;
+    }
+    let count = a.len().max(1) as f32;
+    (similarity / count, contrast_structure / count)
+}
+
+/// Structural similarity with an 11x11 Gaussian window, 1 for identical images.
+pub fn ssim(image: &GrayImage, reference: &GrayImage) -> f32 {
+    let (width, height) = image.dimensions();
+    let (similarity, _) = ssim_terms(
+        &values(image),
+        &values(reference),
+        width as usize,
+        height as usize,
+    );
+    similarity
+}
+
+/// Halve an image by averaging 2x2 blocks.
+fn downsample(values: &[f32], width: usize, height: usize) -> (Vec<f32>, usize, usize) {
+    let (half_width, half_height) = (width / 2, height / 2);
+    let downsampled = (0..half_height)
+        .flat_map(|y| {
+            (0..half_width).map(move |x| {
+                (values[2 * y * width + 2 * x]
+                    + values[2 * y * width + 2 * x + 1]
+                    + values[(2 * y + 1) * width + 2 * x]
+                    + values[(2 * y + 1) * width + 2 * x + 1])
+                    / 4.0
+            })
+        })
+        .collect();
+    (downsampled, half_width, half_height)
+}
+
+/// Multi-scale SSIM (Wang et al. 2003) over up to five scales, fewer on images too
+/// small to halve that often, with the weights of the scales used renormalized.
+pub fn ms_ssim(image: &GrayImage, reference: &GrayImage) -> f32 {
+    let (width, height) = image.dimensions();
+    let (mut a, mut b) = (values(image), values(reference));
+    let (mut width, mut height) = (width as usize, height as usize);
+    let mut terms = Vec::new();
+    for scale in 0..MS_SSIM_WEIGHTS.len() {
+        terms.push(ssim_terms(&a, &b, width, height));
+        if scale + 1 == MS_SSIM_WEIGHTS.len() || width.min(height) / 2 <= 2 * SSIM_RADIUS {
+            break;
+        }
+        (b, _, _) = downsample(&b, width, height);
+        (a, width, height) = downsample(&a, width, height);
+    }
+    let weights = &MS_SSIM_WEIGHTS[..terms.len()];
+    let total: f32 = weights.iter().sum();
+    let last = terms.len() - 1;
+    terms
+        .iter()
+        .zip(weights)
+        .enumerate()
+        .map(|(scale, (&(similarity, contrast_structure), weight))| {
+            // Negative terms of very dissimilar images would make the power undefined
+            let term = if scale == last {
+                similarity
+            } else {
+                contrast_structure
+            };
+            term.max(0.0).powf(weight / total)
+        })
+        .product()
+}
+
+/// Variance of the noise of an image from its response to a Laplacian difference
+/// mask, which cancels smooth structure (Immerkaer 1996).
+pub fn noise_variance(image: &GrayImage) -> f32 {
+    let (width, height) = image.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let at = |x: u32, y: u32| image.get_pixel(x, y)[0] as f32;
+    let mut sum = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let response =
+                at(x - 1, y - 1) + at(x + 1, y - 1) + at(x - 1, y + 1) + at(x + 1, y + 1)
+                    - 2.0 * (at(x, y - 1) + at(x - 1, y) + at(x + 1, y) + at(x, y + 1))
+                    + 4.0 * at(x, y);
+            sum += response.abs();
+        }
+    }
+    let sigma = (std::f32::consts::PI / 2.0).sqrt() * sum
+        / (6.0 * (width - 2) as f32 * (height - 2) as f32);
+    sigma * sigma
+}
+
+/// Mean squared difference of every row mean from the average of its neighbors:
+/// high when interference leaves horizontal stripes, low for a smooth scene.
+pub fn stripe_energy(image: &GrayImage) -> f32 {
+    let (width, height) = image.dimensions();
+    if height < 3 {
+        return 0.0;
+    }
+    let means: Vec<f32> = (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| image.get_pixel(x, y)[0] as f32)
+                .sum::<f32>()
+                / width as f32
+        })
+        .collect();
+    means
+        .windows(3)
+        .map(|w| (w[1] - (w[0] + w[2]) / 2.0).powi(2))
+        .sum::<f32>()
+        / (height - 2) as f32
+}
+
+/// Sharpness of the sync A pulses of a synced APT image: the mean absolute step
+/// between neighbors at the edges of the seven pulses, less the one between
+/// neighbors elsewhere in the bar. The pulses are a known square wave, so blur shows
+/// as softer edges, while noise raises both steps alike. The edges are the largest
+/// steps of the bar averaged over every line.
+pub fn sync_sharpness(image: &GrayImage, sync_column: u32) -> Option<f32> {
+    let (width, height) = image.dimensions();
+    if width != apt::LINE_WIDTH || height == 0 {
+        return None;
+    }
+    let step = |y: u32, i: u32| {
+        let x = (sync_column + i) % width;
+        let next = (x + 1) % width;
+        image.get_pixel(next, y)[0] as f32 - image.get_pixel(x, y)[0] as f32
+    };
+
+    let mut steps: Vec<(u32, f32)> = (0..apt::SYNC_WIDTH - 1)
+        .map(|i| (i, (0..height).map(|y| step(y, i)).sum::<f32>().abs()))
+        .collect();
+    steps.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let edges: Vec<u32> = steps[..2 * SYNC_PULSES].iter().map(|&(i, _)| i).collect();
+
+    let (mut edge_sum, mut flat_sum) = (0.0, 0.0);
+    for y in 0..height {
+        for i in 0..apt::SYNC_WIDTH - 1 {
+            if edges.contains(&i) {
+                edge_sum += step(y, i).abs();
+            } else {
+                flat_sum += step(y, i).abs();
+            }
+        }
+    }
+    let flat_count = (apt::SYNC_WIDTH - 1) as usize - edges.len();
+    Some(
+        edge_sum / (height as usize * edges.len()) as f32
+            - flat_sum / (height as usize * flat_count) as f32,
+    )
+}
+
+fn metrics(
+    enhanced: &GrayImage,
+    reference: Option<&GrayImage>,
+    sync_column: u32,
+    seconds: f64,
+) -> Metrics {
+    let reference = reference.filter(|reference| reference.dimensions() == enhanced.dimensions());
+    Metrics {
+        psnr: reference.map(|reference| psnr(enhanced, reference)),
+        ssim: reference.map(|reference| ssim(enhanced, reference)),
+        ms_ssim: reference.map(|reference| ms_ssim(enhanced, reference)),
+        noise_variance: noise_variance(enhanced),
+        stripe_energy: stripe_energy(enhanced),
+        sync_sharpness: sync_sharpness(enhanced, sync_column),
+        seconds,
+    }
+}
+
+/// PNG images of a directory, sorted by name.
+fn images_in(directory: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut images: Vec<PathBuf> = fs::read_dir(directory)
+        .map_err(|e| format!("Cannot read {}: {}", directory.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+        })
+        .collect();
+    images.sort();
+    Ok(images)
+}
+
+fn enhance(
+    method: Method,
+    image: &GrayImage,
+    enhancer: Option<&Enhancer>,
+    options: EnhanceOptions,
+    settings: &FunctionsSettings,
+) -> Result<GrayImage, String> {
+    let enhanced = match method {
+        Method::None => image.clone(),
+        Method::Sgbnr => {
+            gaussian_blur::sgbnr(image, &gaussian_blur::options_for_image(image, settings).0)
+        }
+        Method::Model | Method::ModelTta => {
+            let options = if method == Method::ModelTta {
+                EnhanceOptions {
+                    augmentation: Augmentation::Eight,
+                    ..options
+                }
+            } else {
+                options
+            };
+            enhancer
+                .ok_or("No enhancement model loaded")?
+                .enhance(image, options)
+                .map_err(|e| e.to_string())?
+        }
+    };
+    // Super-resolution output is compared at the size of the input
+    if enhanced.dimensions() != image.dimensions() {
+        return Ok(imageops::resize(
+            &enhanced,
+            image.width(),
+            image.height(),
+            imageops::FilterType::Lanczos3,
+        ));
+    }
+    Ok(enhanced)
+}
+
+/// Run every method over the PNG images of `input_dir` and measure the results,
+/// against the image of the same name in `reference_dir` when there is one.
+/// Enhanced images are kept in `output_dir/<method>/`.
+pub fn evaluate(
+    input_dir: &str,
+    reference_dir: Option<&str>,
+    output_dir: &str,
+    methods: &[Method],
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<Vec<Evaluation>, String> {
+    let images = images_in(Path::new(input_dir))?;
+    if images.is_empty() {
+        return Err(format!("No PNG image in {}", input_dir));
+    }
+    let settings = settings.lock().unwrap();
+    let options = EnhanceOptions::from_settings(&settings);
+    let sync_column = apt::sync_column(settings.additional_offset);
+
+    // A model that does not load only drops the model methods
+    let enhancer = if methods.iter().any(Method::uses_model) {
+        match models::model_path(settings.enhance_model.as_deref()).and_then(|model_path| {
+            Enhancer::new(
+                &model_path,
+                settings.cpu_threads,
+                settings.execution_provider,
+                settings.backend,
+            )
+            .map_err(|e| e.to_string())
+        }) {
+            Ok(enhancer) => Some(enhancer),
+            Err(e) => {
+                eprintln!("Skipping the model methods: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let methods: Vec<Method> = methods
+        .iter()
+        .copied()
+        .filter(|method| !method.uses_model() || enhancer.is_some())
+        .collect();
+
+    let mut evaluations = Vec::new();
+    for path in &images {
+        let file = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        println!("Evaluating {}", file);
+        let image = image::open(path).map_err(|e| e.to_string())?.to_luma8();
+        let reference = match reference_dir.map(|directory| Path::new(directory).join(&file)) {
+            Some(reference_path) if reference_path.exists() => Some(
+                image::open(&reference_path)
+                    .map_err(|e| e.to_string())?
+                    .to_luma8(),
+            ),
+            Some(reference_path) => {
+                eprintln!("No reference {}", reference_path.display());
+                None
+            }
+            None => None,
+        };
+
+        for &method in &methods {
+            let start = std::time::Instant::now();
+            let enhanced = enhance(method, &image, enhancer.as_ref(), options, &settings)?;
+            let seconds = start.elapsed().as_secs_f64();
+
+            let directory = Path::new(output_dir).join(method.name());
+            fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+            enhanced
+                .save(directory.join(&file))
+                .map_err(|e| e.to_string())?;
+            evaluations.push(Evaluation {
+                file: file.clone(),
+                method,
+                metrics: metrics(&enhanced, reference.as_ref(), sync_column, seconds),
+            });
+        }
+    }
+    Ok(evaluations)
+}
+
+/// A CSV field, quoted when it holds a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Percent-encode a file name for a relative URL, keeping only unreserved characters.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn optional(value: Option<f32>) -> String {
+    value.map_or(String::new(), |value| format!("{:.4}", value))
+}
+
+pub fn write_csv(evaluations: &[Evaluation], path: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(
+        file,
+        "file,method,psnr_db,ssim,ms_ssim,noise_variance,stripe_energy,sync_sharpness,seconds"
+    )?;
+    for evaluation in evaluations {
+        let m = &evaluation.metrics;
+        writeln!(
+            file,
+            "{},{},{},{},{},{:.4},{:.4},{},{:.3}",
+            csv_field(&evaluation.file),
+            evaluation.method.name(),
+            optional(m.psnr),
+            optional(m.ssim),
+            optional(m.ms_ssim),
+            m.noise_variance,
+            m.stripe_energy,
+            optional(m.sync_sharpness),
+            m.seconds
+        )?;
+    }
+    file.flush()
+}
+
+/// Comparison table with the best method of every image and metric in bold:
+/// higher PSNR, SSIM, MS-SSIM and sync sharpness, lower noise and stripes.
+pub fn write_html(evaluations: &[Evaluation], path: &str) -> std::io::Result<()> {
+    type Metric = (&'static str, fn(&Metrics) -> Option<f32>, bool);
+    let columns: [Metric; 6] = [
+        ("PSNR (dB)", |m| m.psnr, true),
+        ("SSIM", |m| m.ssim, true),
+        ("MS-SSIM", |m| m.ms_ssim, true),
+        ("Noise variance", |m| Some(m.noise_variance), false),
+        ("Stripe energy", |m| Some(m.stripe_energy), false),
+        ("Sync sharpness", |m| m.sync_sharpness, true),
+    ];
+
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(
+        file,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">"
+    )?;
+    writeln!(file, "<title>Enhancement evaluation</title>")?;
+    writeln!(
+        file,
+        "<style>table {{ border-collapse: collapse; }} td, th {{ border: 1px solid #999; padding: 4px 8px; text-align: right; }} td:first-child, td:nth-child(2) {{ text-align: left; }}</style>"
+    )?;
+    writeln!(
+        file,
+        "</head>\n<body>\n<h1>Enhancement evaluation</h1>\n<table>"
+    )?;
+    write!(file, "<tr><th>Image</th><th>Method</th>")?;
+    for (name, _, _) in &columns {
+        write!(file, "<th>{}</th>", name)?;
+    }
+    writeln!(file, "<th>Seconds</th></tr>")?;
+
+    for (index, evaluation) in evaluations.iter().enumerate() {
+        let same_image: Vec<&Evaluation> = evaluations
+            .iter()
+            .filter(|other| other.file == evaluation.file)
+            .collect();
+        let name = html_escape(&evaluation.file);
+        write!(
+            file,
+            "<tr><td>{}</td><td><a href=\"{}/{}\">{}</a></td>",
+            name,
+            evaluation.method.name(),
+            percent_encode(&evaluation.file),
+            evaluation.method.name()
+        )?;
+        for (_, value, higher_is_better) in &columns {
+            let Some(mine) = value(&evaluation.metrics) else {
+                write!(file, "<td></td>")?;
+                continue;
+            };
+            let best = same_image
+                .iter()
+                .filter_map(|other| value(&other.metrics))
+                .fold(None, |best: Option<f32>, v| {
+                    Some(match best {
+                        Some(best) if *higher_is_better => best.max(v),
+                        Some(best) => best.min(v),
+                        None => v,
+                    })
+                });
+            if same_image.len() > 1 && best == Some(mine) {
+                write!(file, "<td><b>{:.4}</b></td>", mine)?;
+            } else {
+                write!(file, "<td>{:.4}</td>", mine)?;
+            }
+        }
+        writeln!(file, "<td>{:.3}</td></tr>", evaluation.metrics.seconds)?;
+        // A blank row between images
+        if evaluations
+            .get(index + 1)
+            .is_some_and(|next| next.file != evaluation.file)
+        {
+            writeln!(file, "<tr><td colspan=\"{}\"></td></tr>", columns.len() + 3)?;
+        }
+    }
+    writeln!(file, "</table>\n</body>\n</html>")?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn scene() -> GrayImage {
+        GrayImage::from_fn(96, 80, |x, y| {
+            Luma([(128.0 + 60.0 * ((x as f32 / 9.0).sin() * (y as f32 / 7.0).cos())) as u8])
+        })
+    }
+
+    /// Deterministic noise in [-amplitude, amplitude].
+    fn noisy(image: &GrayImage, amplitude: f32) -> GrayImage {
+        let mut state = 12345u32;
+        GrayImage::from_fn(image.width(), image.height(), |x, y| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let noise = ((state >> 16) as f32 / 32768.0 - 1.0) * amplitude;
+            Luma([(image.get_pixel(x, y)[0] as f32 + noise).clamp(0.0, 255.0) as u8])
+        })
+    }
+
+    #[test]
+    fn full_reference_metrics_rank_noise() {
+        let clean = scene();
+        let light = noisy(&clean, 10.0);
+        let heavy = noisy(&clean, 40.0);
+        assert!(psnr(&clean, &clean).is_infinite());
+        assert!((ssim(&clean, &clean) - 1.0).abs() < 1e-4);
+        assert!((ms_ssim(&clean, &clean) - 1.0).abs() < 1e-4);
+        assert!(psnr(&light, &clean) > psnr(&heavy, &clean));
+        assert!(ssim(&light, &clean) > ssim(&heavy, &clean));
+        assert!(ms_ssim(&light, &clean) > ms_ssim(&heavy, &clean));
+    }
+
+    #[test]
+    fn ssim_averages_similarity_per_pixel() {
+        // The left half matches, the right half has nothing in common with the textured
+        // image: SSIM is about a half, not the product of half-matching mean terms
+        let image = noisy(&scene(), 60.0);
+        let reference = GrayImage::from_fn(96, 80, |x, y| {
+            Luma([if x < 48 { image.get_pixel(x, y)[0] } else { 0 }])
+        });
+        let similarity = ssim(&image, &reference);
+        assert!((0.4..0.55).contains(&similarity), "{}", similarity);
+    }
+
+    #[test]
+    fn links_are_percent_encoded() {
+        assert_eq!(percent_encode("noaa-19_a.png"), "noaa-19_a.png");
+        assert_eq!(percent_encode("pass #1?.png"), "pass%20%231%3F.png");
+        assert_eq!(percent_encode("100%ł"), "100%25%C5%82");
+    }
+
+    #[test]
+    fn no_reference_metrics_see_noise_and_stripes() {
+        let clean = scene();
+        assert!(noise_variance(&noisy(&clean, 40.0)) > 4.0 * noise_variance(&clean));
+
+        let striped = GrayImage::from_fn(96, 80, |x, y| {
+            let offset = if y % 9 == 0 { 40 } else { 0 };
+            Luma([clean.get_pixel(x, y)[0].saturating_add(offset)])
+        });
+        assert!(stripe_energy(&striped) > 10.0 * stripe_energy(&clean));
+    }
+
+    #[test]
+    fn sync_sharpness_drops_with_blur_not_noise() {
+        // Sync A: four dark words, then seven pulses of two bright and two dark words
+        let sync_column = 100;
+        let image = GrayImage::from_fn(apt::LINE_WIDTH, 60, |x, y| {
+            let i = x.wrapping_sub(sync_column);
+            let value = if i < apt::SYNC_WIDTH {
+                if (4..32).contains(&i) && (i - 4) % 4 < 2 {
+                    220.0
+                } else {
+                    30.0
+                }
+            } else {
+                128.0 + 60.0 * ((x as f32 / 9.0).sin() * (y as f32 / 7.0).cos())
+            };
+            Luma([value as u8])
+        });
+        let sharp = sync_sharpness(&image, sync_column).unwrap();
+        let blurred = sync_sharpness(&imageops::blur(&image, 1.0), sync_column).unwrap();
+        let with_noise = sync_sharpness(&noisy(&image, 20.0), sync_column).unwrap();
+        assert!((sharp - 190.0).abs() < 1e-3, "{}", sharp);
+        assert!(blurred < 0.8 * sharp, "{} {}", blurred, sharp);
+        assert!(with_noise <= sharp, "{} {}", with_noise, sharp);
+        assert!(with_noise > blurred, "{} {}", with_noise, blurred);
+    }
+}
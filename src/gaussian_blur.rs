@@ -1,57 +1,349 @@
+use crate::apt;
+use crate::quality;
 use crate::settings::FunctionsSettings;
 
 use image::imageops;
-use image::{ImageBuffer, Rgb};
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Instant;
 
-pub fn selective_gaussian_blur(
-    image_path: &str,
-    settings: &Arc<Mutex<FunctionsSettings>>,
-) -> Result<String, String> {
-    // Load the image and convert to RGB8
-    let img = image::open(image_path).map_err(|e| e.to_string())?;
-    let img = img.to_rgb8();
-    let (width, height) = img.dimensions();
-    println!("Image dimensions: {}x{}", width, height);
+/// Selective Gaussian blur noise reduction (SGBNR) parameters.
+#[derive(Clone, Copy, Debug)]
+pub struct SgbnrOptions {
+    pub blur_sigma: f32,
+    // Windows darker than this on average are signal loss and get blurred
+    pub brightness_threshold: f32,
+    // Windows with a larger standard deviation are noise and get blurred
+    pub noise_threshold: f32,
+    pub sharpen_sigma: f32,
+    pub sharpen_threshold: i32,
+    // The statistics window is (2 * radius + 1) pixels wide, cut at the borders
+    pub radius: u32,
+    // Width in pixel levels over which the blur fades in around each threshold,
+    // 0 for the hard switch between the original and the blurred pixel
+    pub transition: f32,
+}
 
-    // Create the blurred version of the image
-    let settings = settings.lock().map_err(|e| e.to_string())?;
-    let blurred = imageops::blur(&img, settings.blur_sigma);
+impl SgbnrOptions {
+    pub fn from_settings(settings: &FunctionsSettings) -> Self {
+        Self {
+            blur_sigma: settings.blur_sigma,
+            brightness_threshold: settings.brightness_threshold,
+            noise_threshold: settings.noise_threshold,
+            sharpen_sigma: settings.sharpen_sigma,
+            sharpen_threshold: settings.sharpen_threshold,
+            radius: settings.sgbnr_radius,
+            transition: settings.sgbnr_transition,
+        }
+    }
 
-    // Thresholds for region detection:
-    let brightness_threshold: f32 = settings.brightness_threshold; // areas with low average intensity (signal loss)
-    let noise_threshold: f32 = settings.noise_threshold; // areas with high local variability (noise)
+    /// Share of the blurred pixel for a window of this mean and standard deviation:
+    /// 1 well below the brightness threshold or well above the noise threshold,
+    /// 0 well inside both, and a smooth ramp `transition` wide across each.
+    pub fn blur_weight(&self, mean: f32, std_dev: f32) -> f32 {
+        if self.transition <= 0.0 {
+            let blur = mean < self.brightness_threshold || std_dev > self.noise_threshold;
+            return if blur { 1.0 } else { 0.0 };
+        }
+        let dark = 1.0 - ramp(mean, self.brightness_threshold, self.transition);
+        let noisy = ramp(std_dev, self.noise_threshold, self.transition);
+        dark.max(noisy)
+    }
+}
 
-    // Create an output image buffer
-    let mut output = ImageBuffer::new(width, height);
+// Columns left out at each side of a space view, as in the calibration
+const SPACE_MARGIN: u32 = 8;
+// Rows darker than this fraction of the median row are signal loss
+const SIGNAL_LOSS_FRACTION: f32 = 0.5;
+// Thresholds sit this many noise sigmas above the signal loss level and the noise
+const THRESHOLD_SIGMAS: f32 = 3.0;
+// Quantized or very clean images give a zero MAD
+const MIN_NOISE_SIGMA: f32 = 1.0;
 
-    // Process each pixel
-    for y in 0..height {
-        for x in 0..width {
-            let current = img.get_pixel(x, y);
-            let (mean, std_dev) = neighborhood_stats(&img, x, y, width, height);
+/// SGBNR thresholds estimated from an image, with the statistics they come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThresholdEstimate {
+    pub brightness_threshold: f32,
+    pub noise_threshold: f32,
+    pub noise_sigma: f32,
+    // Median of the signal loss row means, 0 when there are none
+    pub dark_level: f32,
+}
 
-            // If the region is dark (signal loss) or noisy, use the blurred pixel.
-            if mean < brightness_threshold || std_dev > noise_threshold {
-                output.put_pixel(x, y, *blurred.get_pixel(x, y));
-            } else {
-                output.put_pixel(x, y, *current);
+impl ThresholdEstimate {
+    pub fn summary(&self) -> String {
+        format!(
+            "brightness {:.2}, noise {:.1} (noise sigma {:.1}, signal loss level {:.1})",
+            self.brightness_threshold, self.noise_threshold, self.noise_sigma, self.dark_level
+        )
+    }
+}
+
+/// Estimate the SGBNR thresholds of an image for windows of `radius`.
+///
+/// The noise sigma is the MAD of horizontal neighbors in the two space views, which
+/// only hold noise on a synced APT image, or in the whole image when it is not 2080
+/// pixels wide. Signal loss rows are the ones much darker than the median row, and
+/// the brightness threshold sits above their level by the spread of a window mean,
+/// the noise threshold above the spread of pixels, both clamped to the ranges of the
+/// settings dialog.
+pub fn estimate_thresholds(image: &GrayImage, radius: u32, sync_column: u32) -> ThresholdEstimate {
+    let (width, height) = image.dimensions();
+    let columns: Vec<u32> = if width == apt::LINE_WIDTH {
+        (0..2)
+            .flat_map(|channel| {
+                let start = sync_column
+                    + channel * apt::CHANNEL_LINE_WIDTH
+                    + apt::SYNC_WIDTH
+                    + SPACE_MARGIN;
+                (start..start + apt::SPACE_WIDTH - 2 * SPACE_MARGIN).map(|x| x % width)
+            })
+            .collect()
+    } else {
+        (0..width).collect()
+    };
+    let rows: Vec<u32> = (0..height).collect();
+    let noise_sigma = quality::neighbor_noise_sigma(image, &rows, &columns)
+        .unwrap_or(0.0)
+        .max(MIN_NOISE_SIGMA);
+
+    let mut row_means: Vec<f32> = image
+        .rows()
+        .map(|row| row.map(|pixel| pixel[0] as f32).sum::<f32>() / width.max(1) as f32)
+        .collect();
+    row_means.sort_by(f32::total_cmp);
+    let median_row = row_means.get(row_means.len() / 2).copied().unwrap_or(0.0);
+    let dark_rows: Vec<f32> = row_means
+        .into_iter()
+        .take_while(|&mean| mean < SIGNAL_LOSS_FRACTION * median_row)
+        .collect();
+    let dark_level = dark_rows.get(dark_rows.len() / 2).copied().unwrap_or(0.0);
+
+    // A window mean of pure noise spreads by sigma over the square root of its pixels
+    let window_sigma = noise_sigma / (2 * radius + 1) as f32;
+    ThresholdEstimate {
+        brightness_threshold: (dark_level + THRESHOLD_SIGMAS * window_sigma).clamp(0.01, 100.0),
+        noise_threshold: (THRESHOLD_SIGMAS * noise_sigma).clamp(0.1, 50.0),
+        noise_sigma,
+        dark_level,
+    }
+}
+
+/// SGBNR options for an image: the settings, with the thresholds estimated from the
+/// image in auto mode.
+pub fn options_for_image(
+    image: &GrayImage,
+    settings: &FunctionsSettings,
+) -> (SgbnrOptions, Option<ThresholdEstimate>) {
+    let mut options = SgbnrOptions::from_settings(settings);
+    if !settings.sgbnr_auto_thresholds {
+        return (options, None);
+    }
+    let estimate = estimate_thresholds(
+        image,
+        options.radius,
+        apt::sync_column(settings.additional_offset),
+    );
+    options.brightness_threshold = estimate.brightness_threshold;
+    options.noise_threshold = estimate.noise_threshold;
+    (options, Some(estimate))
+}
+
+/// Smoothstep from 0 to 1 over `width` centered on `threshold`.
+fn ramp(value: f32, threshold: f32, width: f32) -> f32 {
+    let t = ((value - threshold) / width + 0.5).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Summed-area tables of the pixels and their squares, giving the mean and standard
+/// deviation of any window in constant time. Sums of 8-bit pixels are exact in u64.
+struct IntegralImages {
+    width: usize,
+    height: usize,
+    sum: Vec<u64>,
+    sum_squares: Vec<u64>,
+}
+
+impl IntegralImages {
+    fn new(image: &GrayImage) -> Self {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let stride = width + 1;
+        let mut sum = vec![0u64; stride * (height + 1)];
+        let mut sum_squares = vec![0u64; stride * (height + 1)];
+        for (y, row) in image.chunks_exact(width.max(1)).enumerate() {
+            let (mut row_sum, mut row_squares) = (0u64, 0u64);
+            for (x, &pixel) in row.iter().enumerate() {
+                row_sum += pixel as u64;
+                row_squares += pixel as u64 * pixel as u64;
+                let index = (y + 1) * stride + x + 1;
+                sum[index] = sum[index - stride] + row_sum;
+                sum_squares[index] = sum_squares[index - stride] + row_squares;
             }
         }
+        Self {
+            width,
+            height,
+            sum,
+            sum_squares,
+        }
     }
 
+    /// Mean and standard deviation of the window of `radius` around (x, y).
+    fn stats(&self, x: usize, y: usize, radius: usize) -> (f32, f32) {
+        let (left, right) = (x.saturating_sub(radius), (x + radius + 1).min(self.width));
+        let (top, bottom) = (y.saturating_sub(radius), (y + radius + 1).min(self.height));
+        let stride = self.width + 1;
+        let area = |table: &[u64]| {
+            table[bottom * stride + right] + table[top * stride + left]
+                - table[top * stride + right]
+                - table[bottom * stride + left]
+        };
+        let count = ((right - left) * (bottom - top)) as f64;
+        let mean = area(&self.sum) as f64 / count;
+        let variance = (area(&self.sum_squares) as f64 / count - mean * mean).max(0.0);
+        (mean as f32, variance.sqrt() as f32)
+    }
+}
+
+/// SGBNR of a grayscale image in memory: every pixel is blended between its original
+/// and blurred value by the blur weight of its window, dark (signal loss) or noisy
+/// windows taking the blurred one, and the result is sharpened. Also returns the
+/// blend mask, white where the blurred pixel is used. Rows are processed in parallel.
+pub fn sgbnr_with_mask(image: &GrayImage, options: &SgbnrOptions) -> (GrayImage, GrayImage) {
+    let width = image.width() as usize;
+    if width == 0 || image.height() == 0 {
+        return (image.clone(), image.clone());
+    }
+    let blurred = imageops::blur(image, options.blur_sigma);
+    let integral = IntegralImages::new(image);
+    let radius = options.radius as usize;
+
+    let mut output = image.clone();
+    let mut mask = GrayImage::new(image.width(), image.height());
+    output
+        .par_chunks_mut(width)
+        .zip(mask.par_chunks_mut(width))
+        .zip(blurred.par_chunks(width))
+        .enumerate()
+        .for_each(|(y, ((row, mask_row), blurred_row))| {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let (mean, std_dev) = integral.stats(x, y, radius);
+                let weight = options.blur_weight(mean, std_dev);
+                let value = *pixel as f32 + weight * (blurred_row[x] as f32 - *pixel as f32);
+                *pixel = value.round() as u8;
+                mask_row[x] = (weight * 255.0).round() as u8;
+            }
+        });
+
     // Sharp the image to enhance edges
-    let sharpened =
-        imageops::unsharpen(&output, settings.sharpen_sigma, settings.sharpen_threshold);
-    // Save the sharpened image
-    output = sharpened;
+    let output = imageops::unsharpen(&output, options.sharpen_sigma, options.sharpen_threshold);
+    (output, mask)
+}
+
+/// SGBNR output alone, see `sgbnr_with_mask`.
+pub fn sgbnr(image: &GrayImage, options: &SgbnrOptions) -> GrayImage {
+    sgbnr_with_mask(image, options).0
+}
+
+/// Apply SGBNR to an image file and save the result next to it, prefixed with
+/// `selective_blur_`. Returns its path and, when exporting the mask is enabled, the
+/// path of the mask saved beside it.
+pub fn selective_gaussian_blur(
+    image_path: &str,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<(String, Option<String>), String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+    println!("Image dimensions: {}x{}", image.width(), image.height());
+
+    let (options, estimate, export_mask) = {
+        let settings = settings.lock().map_err(|e| e.to_string())?;
+        let (options, estimate) = options_for_image(&image, &settings);
+        (options, estimate, settings.sgbnr_export_mask)
+    };
+    if let Some(estimate) = estimate {
+        println!("Estimated SGBNR thresholds: {}", estimate.summary());
+    }
+    let (output, mask) = sgbnr_with_mask(&image, &options);
 
     // Save the resulting image
-    let output_path = format!("selective_blur_{}", image_path);
+    let path = Path::new(image_path);
+    let file_name = path
+        .file_name()
+        .ok_or(format!("Invalid image path: {}", image_path))?
+        .to_string_lossy();
+    let output_path = path
+        .with_file_name(format!("selective_blur_{}", file_name))
+        .to_string_lossy()
+        .into_owned();
     println!("Saving output image to: {}", output_path);
     output.save(&output_path).map_err(|e| e.to_string())?;
-    Ok(output_path)
+
+    if !export_mask {
+        return Ok((output_path, None));
+    }
+    let mask_path = path
+        .with_file_name(format!("sgbnr_mask_{}", file_name))
+        .to_string_lossy()
+        .into_owned();
+    println!("Saving blend mask to: {}", mask_path);
+    mask.save(&mask_path).map_err(|e| e.to_string())?;
+    Ok((output_path, Some(mask_path)))
+}
+
+/// SGBNR with the current settings on an image for the settings preview: the output
+/// and the blend mask are saved to the temporary directory and their paths returned
+/// with the thresholds estimated from the image, used in auto mode only.
+pub fn preview(
+    image_path: &Path,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<(PathBuf, PathBuf, ThresholdEstimate), String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+    let (options, estimate) = {
+        let settings = settings.lock().map_err(|e| e.to_string())?;
+        let (options, estimate) = options_for_image(&image, &settings);
+        let estimate = estimate.unwrap_or_else(|| {
+            estimate_thresholds(
+                &image,
+                options.radius,
+                apt::sync_column(settings.additional_offset),
+            )
+        });
+        (options, estimate)
+    };
+    let (output, mask) = sgbnr_with_mask(&image, &options);
+
+    let dir = std::env::temp_dir();
+    let output_path = dir.join("trans-misja-sgbnr-preview.png");
+    let mask_path = dir.join("trans-misja-sgbnr-mask.png");
+    output.save(&output_path).map_err(|e| e.to_string())?;
+    mask.save(&mask_path).map_err(|e| e.to_string())?;
+    Ok((output_path, mask_path, estimate))
+}
+
+/// The former SGBNR on RGB8 with a 3x3 window gathered pixel by pixel, kept as the
+/// baseline of the benchmark.
+fn sgbnr_rgb(img: &RgbImage, options: &SgbnrOptions) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let blurred = imageops::blur(img, options.blur_sigma);
+    let mut output = ImageBuffer::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let (mean, std_dev) = neighborhood_stats(img, x, y, width, height);
+            if mean < options.brightness_threshold || std_dev > options.noise_threshold {
+                output.put_pixel(x, y, *blurred.get_pixel(x, y));
+            } else {
+                output.put_pixel(x, y, *img.get_pixel(x, y));
+            }
+        }
+    }
+    imageops::unsharpen(&output, options.sharpen_sigma, options.sharpen_threshold)
 }
 
 /// Helper function to compute the average intensity and standard deviation
@@ -93,3 +385,205 @@ fn neighborhood_stats(
     let std_dev = (variance_sum / count as f32).sqrt();
     (mean, std_dev)
 }
+
+/// Time the former RGB SGBNR against the grayscale one on an image (3x3 window, hard
+/// switch) and print both with the number of pixels where their outputs differ.
+pub fn benchmark(image_path: &str, options: &SgbnrOptions, runs: usize) -> Result<(), String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+    let runs = runs.max(1);
+    let options = SgbnrOptions {
+        radius: 1,
+        transition: 0.0,
+        ..*options
+    };
+
+    let start = Instant::now();
+    let mut former = GrayImage::new(0, 0);
+    for _ in 0..runs {
+        // The conversions were part of the former path
+        let rgb = DynamicImage::ImageLuma8(image.clone()).to_rgb8();
+        former = DynamicImage::ImageRgb8(sgbnr_rgb(&rgb, &options)).to_luma8();
+    }
+    let former_seconds = start.elapsed().as_secs_f64() / runs as f64;
+
+    let start = Instant::now();
+    let mut current = GrayImage::new(0, 0);
+    for _ in 0..runs {
+        current = sgbnr(&image, &options);
+    }
+    let current_seconds = start.elapsed().as_secs_f64() / runs as f64;
+
+    let different = former
+        .pixels()
+        .zip(current.pixels())
+        .filter(|(a, b)| a != b)
+        .count();
+    println!(
+        "{} {}x{} images, blur sigma {}",
+        runs,
+        image.width(),
+        image.height(),
+        options.blur_sigma
+    );
+    println!("{:<34} {:>10} {:>10}", "Mode", "s/image", "speedup");
+    println!(
+        "{:<34} {:>10.3} {:>9.1}x",
+        "RGB, per-pixel window", former_seconds, 1.0
+    );
+    println!(
+        "{:<34} {:>10.3} {:>9.1}x",
+        "grayscale, integral images, rayon",
+        current_seconds,
+        former_seconds / current_seconds
+    );
+    println!("Pixels that differ: {}", different);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn test_image() -> GrayImage {
+        GrayImage::from_fn(120, 90, |x, y| {
+            // A gradient with a dark band and a noisy patch
+            let value = if (30..40).contains(&y) {
+                2
+            } else if x > 80 && y > 50 {
+                (x * 7919 + y * 104729) % 97 + 80
+            } else {
+                x + y
+            };
+            Luma([value as u8])
+        })
+    }
+
+    #[test]
+    fn integral_stats_match_direct_ones() {
+        let image = test_image();
+        let integral = IntegralImages::new(&image);
+        for radius in [0u32, 1, 3] {
+            for (x, y) in [(0u32, 0u32), (5, 33), (119, 89), (100, 60)] {
+                let window: Vec<f64> = (y.saturating_sub(radius)..=(y + radius).min(89))
+                    .flat_map(|j| {
+                        (x.saturating_sub(radius)..=(x + radius).min(119)).map(move |i| (i, j))
+                    })
+                    .map(|(i, j)| image.get_pixel(i, j)[0] as f64)
+                    .collect();
+                let mean = window.iter().sum::<f64>() / window.len() as f64;
+                let variance =
+                    window.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / window.len() as f64;
+                let (m, s) = integral.stats(x as usize, y as usize, radius as usize);
+                assert!((m as f64 - mean).abs() < 1e-4, "mean at {} {}", x, y);
+                assert!(
+                    (s as f64 - variance.sqrt()).abs() < 1e-3,
+                    "std at {} {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn grayscale_matches_former_rgb() {
+        let image = test_image();
+        let options = SgbnrOptions {
+            blur_sigma: 2.0,
+            brightness_threshold: 5.0,
+            noise_threshold: 10.0,
+            sharpen_sigma: 1.5,
+            sharpen_threshold: 5,
+            radius: 1,
+            transition: 0.0,
+        };
+        let rgb = DynamicImage::ImageLuma8(image.clone()).to_rgb8();
+        let former = DynamicImage::ImageRgb8(sgbnr_rgb(&rgb, &options)).to_luma8();
+        let current = sgbnr(&image, &options);
+        let different = former
+            .pixels()
+            .zip(current.pixels())
+            .filter(|(a, b)| a[0].abs_diff(b[0]) > 1)
+            .count();
+        assert!(different == 0, "{} pixels differ", different);
+        assert_ne!(current, image);
+    }
+
+    #[test]
+    fn soft_weight_ramps_across_the_thresholds() {
+        let options = SgbnrOptions {
+            blur_sigma: 2.0,
+            brightness_threshold: 10.0,
+            noise_threshold: 20.0,
+            sharpen_sigma: 1.5,
+            sharpen_threshold: 5,
+            radius: 1,
+            transition: 8.0,
+        };
+        // Bright and quiet is kept, dark or noisy is blurred, half way at a threshold
+        assert_eq!(options.blur_weight(100.0, 0.0), 0.0);
+        assert_eq!(options.blur_weight(0.0, 0.0), 1.0);
+        assert_eq!(options.blur_weight(100.0, 40.0), 1.0);
+        assert!((options.blur_weight(10.0, 0.0) - 0.5).abs() < 1e-6);
+        assert!((options.blur_weight(100.0, 20.0) - 0.5).abs() < 1e-6);
+        let weights: Vec<f32> = (0..=40)
+            .map(|std_dev| options.blur_weight(100.0, std_dev as f32))
+            .collect();
+        assert!(weights.windows(2).all(|w| w[0] <= w[1]));
+        assert!(weights.windows(2).all(|w| w[1] - w[0] < 0.25));
+
+        let hard = SgbnrOptions {
+            transition: 0.0,
+            ..options
+        };
+        assert_eq!(hard.blur_weight(10.0, 20.0), 0.0);
+        assert_eq!(hard.blur_weight(9.9, 20.0), 1.0);
+        assert_eq!(hard.blur_weight(10.0, 20.1), 1.0);
+    }
+
+    #[test]
+    fn thresholds_follow_the_noise_and_the_signal_loss() {
+        // A synced APT image: gray scene, noise of sigma 6 everywhere and signal
+        // loss rows at level 20
+        let mut state = 1u64;
+        let mut gaussian = || {
+            (0..12)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (state >> 40) as f32 / (1u64 << 24) as f32
+                })
+                .sum::<f32>()
+                - 6.0
+        };
+        let image = GrayImage::from_fn(apt::LINE_WIDTH, 120, |x, y| {
+            let level = if (40..60).contains(&y) {
+                20.0
+            } else if (200..1000).contains(&x) || x >= 1200 {
+                // Scene detail, away from the space views
+                120.0 + 60.0 * ((x / 3) % 2) as f32
+            } else {
+                120.0
+            };
+            Luma([(level + 6.0 * gaussian()).round().clamp(0.0, 255.0) as u8])
+        });
+
+        let estimate = estimate_thresholds(&image, 1, 0);
+        assert!((estimate.noise_sigma - 6.0).abs() < 1.0, "{:?}", estimate);
+        assert!((estimate.dark_level - 20.0).abs() < 1.0, "{:?}", estimate);
+        assert!((estimate.noise_threshold - 18.0).abs() < 3.0);
+        // Above the signal loss level, by three times the spread of a 3x3 mean
+        assert!((estimate.brightness_threshold - 26.0).abs() < 1.5);
+
+        // A quieter pass, without signal loss, gets lower thresholds
+        let quiet = GrayImage::from_fn(apt::LINE_WIDTH, 120, |_, _| {
+            Luma([(120.0 + 2.0 * gaussian()).round() as u8])
+        });
+        let estimate = estimate_thresholds(&quiet, 1, 0);
+        assert!((estimate.noise_sigma - 2.0).abs() < 0.6, "{:?}", estimate);
+        assert_eq!(estimate.dark_level, 0.0);
+        assert!(estimate.brightness_threshold < 3.0);
+    }
+}
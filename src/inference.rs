@@ -0,0 +1,491 @@
+#[cfg(feature = "ort")]
+use crate::ort_backend;
+#[cfg(feature = "tract")]
+use crate::tract_backend;
+
+use ndarray::ArrayD;
+use std::error::Error;
+use std::sync::Mutex;
+
+pub const BACKEND_NAMES: [&str; 2] = ["ONNX Runtime", "tract"];
+
+/// What runs the ONNX models: ONNX Runtime (with its execution providers) or tract,
+/// which is pure Rust and needs no shared library.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    OnnxRuntime,
+    Tract,
+}
+
+impl Default for Backend {
+    /// ONNX Runtime when built in, tract otherwise.
+    fn default() -> Self {
+        if Backend::OnnxRuntime.compiled() || !Backend::Tract.compiled() {
+            Backend::OnnxRuntime
+        } else {
+            Backend::Tract
+        }
+    }
+}
+
+impl Backend {
+    pub const ALL: [Backend; 2] = [Backend::OnnxRuntime, Backend::Tract];
+
+    /// Map a dropdown index (see BACKEND_NAMES) to a backend.
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL
+            .get(index as usize)
+            .copied()
+            .unwrap_or(Backend::OnnxRuntime)
+    }
+
+    pub fn index(&self) -> u32 {
+        Self::ALL.iter().position(|b| b == self).unwrap_or(0) as u32
+    }
+
+    /// Accepts the display names and the cargo feature names.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|backend| backend.name().eq_ignore_ascii_case(name) || backend.feature() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        BACKEND_NAMES[self.index() as usize]
+    }
+
+    /// Cargo feature building in the backend.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Backend::OnnxRuntime => "ort",
+            Backend::Tract => "tract",
+        }
+    }
+
+    pub fn compiled(&self) -> bool {
+        match self {
+            Backend::OnnxRuntime => cfg!(feature = "ort"),
+            Backend::Tract => cfg!(feature = "tract"),
+        }
+    }
+}
+
+/// Names of the backends built into this binary, for display.
+pub fn compiled_backends() -> Vec<&'static str> {
+    Backend::ALL
+        .iter()
+        .filter(|b| b.compiled())
+        .map(|b| b.name())
+        .collect()
+}
+
+/// Name and shape of a model input or output. Dynamic dimensions are -1, the
+/// dimensions are None when the value is not a tensor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TensorInfo {
+    pub name: String,
+    pub dimensions: Option<Vec<i64>>,
+}
+
+/// A loaded ONNX model, whichever backend runs it.
+pub trait Model: Send + Sync {
+    fn inputs(&self) -> &[TensorInfo];
+
+    fn outputs(&self) -> &[TensorInfo];
+
+    /// Feed `input` to the input called `input_name` and return the output called
+    /// `output_name`.
+    fn run(
+        &self,
+        input_name: &str,
+        input: ArrayD<f32>,
+        output_name: &str,
+    ) -> Result<ArrayD<f32>, Box<dyn Error + Send + Sync>>;
+}
+
+pub const PROVIDER_NAMES: [&str; 5] = ["CPU", "CUDA", "TensorRT", "OpenVINO", "XNNPACK"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecutionProvider {
+    Cpu,
+    Cuda,
+    TensorRt,
+    OpenVino,
+    Xnnpack,
+}
+
+// Provider the last session was created with, after any fallback
+static ACTIVE_PROVIDER: Mutex<Option<ExecutionProvider>> = Mutex::new(None);
+
+impl Default for ExecutionProvider {
+    /// CUDA when built in, CPU otherwise.
+    fn default() -> Self {
+        if ExecutionProvider::Cuda.compiled() {
+            ExecutionProvider::Cuda
+        } else {
+            ExecutionProvider::Cpu
+        }
+    }
+}
+
+impl ExecutionProvider {
+    pub const ALL: [ExecutionProvider; 5] = [
+        ExecutionProvider::Cpu,
+        ExecutionProvider::Cuda,
+        ExecutionProvider::TensorRt,
+        ExecutionProvider::OpenVino,
+        ExecutionProvider::Xnnpack,
+    ];
+
+    /// Map a dropdown index (see PROVIDER_NAMES) to a provider.
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL
+            .get(index as usize)
+            .copied()
+            .unwrap_or(ExecutionProvider::Cpu)
+    }
+
+    pub fn index(&self) -> u32 {
+        Self::ALL.iter().position(|p| p == self).unwrap_or(0) as u32
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PROVIDER_NAMES
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        PROVIDER_NAMES[self.index() as usize]
+    }
+
+    /// Cargo feature building in support for the provider, None for the CPU.
+    #[cfg_attr(not(feature = "ort"), allow(dead_code))]
+    pub fn feature(&self) -> Option<&'static str> {
+        match self {
+            ExecutionProvider::Cpu => None,
+            ExecutionProvider::Cuda => Some("cuda"),
+            ExecutionProvider::TensorRt => Some("tensorrt"),
+            ExecutionProvider::OpenVino => Some("openvino"),
+            ExecutionProvider::Xnnpack => Some("xnnpack"),
+        }
+    }
+
+    pub fn compiled(&self) -> bool {
+        match self {
+            ExecutionProvider::Cpu => true,
+            ExecutionProvider::Cuda => cfg!(feature = "cuda"),
+            ExecutionProvider::TensorRt => cfg!(feature = "tensorrt"),
+            ExecutionProvider::OpenVino => cfg!(feature = "openvino"),
+            ExecutionProvider::Xnnpack => cfg!(feature = "xnnpack"),
+        }
+    }
+}
+
+/// Names of the providers built into this binary, for display.
+pub fn compiled_providers() -> Vec<&'static str> {
+    ExecutionProvider::ALL
+        .iter()
+        .filter(|p| p.compiled())
+        .map(|p| p.name())
+        .collect()
+}
+
+/// Provider the last model ran on, None when no model ran since the last reset.
+pub fn active_provider() -> Option<ExecutionProvider> {
+    *ACTIVE_PROVIDER.lock().unwrap()
+}
+
+pub fn reset_active_provider() {
+    *ACTIVE_PROVIDER.lock().unwrap() = None;
+}
+
+/// Note that a model runs on `provider`, for sessions created earlier and kept.
+pub fn record_active_provider(provider: ExecutionProvider) {
+    *ACTIVE_PROVIDER.lock().unwrap() = Some(provider);
+}
+
+/// Load an ONNX model with the requested backend, on the requested execution
+/// provider for ONNX Runtime. A backend that is not built in is replaced by the one
+/// that is, and tract always runs on the CPU.
+#[cfg_attr(not(feature = "ort"), allow(unused_variables))]
+pub fn load_model(
+    model_path: &str,
+    cpu_threads: usize,
+    provider: ExecutionProvider,
+    backend: Backend,
+) -> Result<Box<dyn Model>, Box<dyn Error>> {
+    let backend = if backend.compiled() {
+        backend
+    } else {
+        let fallback = Backend::default();
+        eprintln!(
+            "{} support is not built in (cargo feature \"{}\"), using {}",
+            backend.name(),
+            backend.feature(),
+            fallback.name()
+        );
+        fallback
+    };
+
+    match backend {
+        #[cfg(feature = "ort")]
+        Backend::OnnxRuntime => Ok(Box::new(ort_backend::OrtModel::load(
+            model_path,
+            cpu_threads,
+            provider,
+        )?)),
+        #[cfg(feature = "tract")]
+        Backend::Tract => {
+            if provider != ExecutionProvider::Cpu {
+                eprintln!(
+                    "tract runs on the CPU only, {} is not used",
+                    provider.name()
+                );
+            }
+            let model = tract_backend::TractModel::load(model_path)?;
+            println!("Running {} with tract on CPU", model_path);
+            record_active_provider(ExecutionProvider::Cpu);
+            Ok(Box::new(model))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(String::from(
+            "No inference backend is built in, enable the \"ort\" or \"tract\" cargo feature",
+        )
+        .into()),
+    }
+}
+
+// The tests run a small model on each enabled backend, `cargo test --features tract`
+// builds both and checks that they agree (see .github/workflows/ci.yml)
+#[cfg(all(test, any(feature = "ort", feature = "tract")))]
+mod tests {
+    use super::*;
+
+    const KERNEL: [f32; 9] = [0.05, 0.1, 0.05, 0.1, 0.4, 0.1, 0.05, 0.1, 0.05];
+    const BIAS: f32 = -0.1;
+
+    // Just enough protobuf to write an ONNX model
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn integer(field: u64, value: i64, out: &mut Vec<u8>) {
+        varint(field << 3, out);
+        varint(value as u64, out);
+    }
+
+    fn bytes(field: u64, value: &[u8], out: &mut Vec<u8>) {
+        varint(field << 3 | 2, out);
+        varint(value.len() as u64, out);
+        out.extend_from_slice(value);
+    }
+
+    fn message(field: u64, build: impl FnOnce(&mut Vec<u8>), out: &mut Vec<u8>) {
+        let mut inner = Vec::new();
+        build(&mut inner);
+        bytes(field, &inner, out);
+    }
+
+    /// Float NCHW value info with symbolic batch, height and width.
+    fn value_info(field: u64, name: &str, out: &mut Vec<u8>) {
+        message(
+            field,
+            |info| {
+                bytes(1, name.as_bytes(), info);
+                message(
+                    2,
+                    |kind| {
+                        message(
+                            1,
+                            |tensor| {
+                                integer(1, 1, tensor);
+                                message(
+                                    2,
+                                    |shape| {
+                                        message(1, |d| bytes(2, b"N", d), shape);
+                                        message(1, |d| integer(1, 1, d), shape);
+                                        message(1, |d| bytes(2, b"H", d), shape);
+                                        message(1, |d| bytes(2, b"W", d), shape);
+                                    },
+                                    tensor,
+                                );
+                            },
+                            kind,
+                        );
+                    },
+                    info,
+                );
+            },
+            out,
+        );
+    }
+
+    fn initializer(name: &str, dimensions: &[i64], values: &[f32], out: &mut Vec<u8>) {
+        message(
+            5,
+            |tensor| {
+                for &d in dimensions {
+                    integer(1, d, tensor);
+                }
+                integer(2, 1, tensor);
+                bytes(8, name.as_bytes(), tensor);
+                let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                bytes(9, &raw, tensor);
+            },
+            out,
+        );
+    }
+
+    fn ints_attribute(name: &str, values: &[i64], out: &mut Vec<u8>) {
+        message(
+            5,
+            |attribute| {
+                bytes(1, name.as_bytes(), attribute);
+                for &v in values {
+                    integer(8, v, attribute);
+                }
+                integer(20, 7, attribute);
+            },
+            out,
+        );
+    }
+
+    /// A 3x3 convolution followed by a ReLU, like the first layer of a U-Net.
+    fn small_model() -> Vec<u8> {
+        let mut model = Vec::new();
+        integer(1, 7, &mut model);
+        message(
+            7,
+            |graph| {
+                message(
+                    1,
+                    |node| {
+                        for input in ["input", "weight", "bias"] {
+                            bytes(1, input.as_bytes(), node);
+                        }
+                        bytes(2, b"conv", node);
+                        bytes(4, b"Conv", node);
+                        ints_attribute("kernel_shape", &[3, 3], node);
+                        ints_attribute("pads", &[1, 1, 1, 1], node);
+                    },
+                    graph,
+                );
+                message(
+                    1,
+                    |node| {
+                        bytes(1, b"conv", node);
+                        bytes(2, b"output", node);
+                        bytes(4, b"Relu", node);
+                    },
+                    graph,
+                );
+                bytes(2, b"small", graph);
+                initializer("weight", &[1, 1, 3, 3], &KERNEL, graph);
+                initializer("bias", &[1], &[BIAS], graph);
+                value_info(11, "input", graph);
+                value_info(12, "output", graph);
+            },
+            &mut model,
+        );
+        message(
+            8,
+            |opset| {
+                bytes(1, b"", opset);
+                integer(2, 13, opset);
+            },
+            &mut model,
+        );
+        model
+    }
+
+    fn write_small_model(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("trans-misja-{}.onnx", name));
+        std::fs::write(&path, small_model()).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn input() -> ArrayD<f32> {
+        ndarray::Array4::from_shape_fn((2, 1, 9, 13), |(n, _, y, x)| {
+            ((n * 31 + y * 7 + x * 3) % 17) as f32 / 16.0
+        })
+        .into_dyn()
+    }
+
+    /// The small model computed directly, zero padded like the Conv.
+    fn reference(input: &ArrayD<f32>) -> ArrayD<f32> {
+        let shape = input.shape().to_vec();
+        let (height, width) = (shape[2] as i64, shape[3] as i64);
+        ArrayD::from_shape_fn(shape.clone(), |index| {
+            let mut sum = BIAS;
+            for ky in 0..3 {
+                for kx in 0..3 {
+                    let (y, x) = (index[2] as i64 + ky - 1, index[3] as i64 + kx - 1);
+                    if (0..height).contains(&y) && (0..width).contains(&x) {
+                        sum += KERNEL[(ky * 3 + kx) as usize]
+                            * input[[index[0], 0, y as usize, x as usize]];
+                    }
+                }
+            }
+            sum.max(0.0)
+        })
+    }
+
+    fn max_difference(a: &ArrayD<f32>, b: &ArrayD<f32>) -> f32 {
+        assert_eq!(a.shape(), b.shape());
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    #[cfg(feature = "ort")]
+    fn ort_runs_small_model() {
+        let path = write_small_model("ort");
+        let model = load_model(&path, 1, ExecutionProvider::Cpu, Backend::OnnxRuntime).unwrap();
+        assert_eq!(model.inputs()[0].name, "input");
+        assert_eq!(model.outputs()[0].name, "output");
+        assert_eq!(model.inputs()[0].dimensions, Some(vec![-1, 1, -1, -1]));
+
+        let input = input();
+        let output = model.run("input", input.clone(), "output").unwrap();
+        assert!(max_difference(&output, &reference(&input)) < 1e-5);
+    }
+
+    #[test]
+    #[cfg(feature = "tract")]
+    fn tract_runs_small_model() {
+        let path = write_small_model("tract");
+        let model = load_model(&path, 1, ExecutionProvider::Cpu, Backend::Tract).unwrap();
+        assert_eq!(model.inputs()[0].name, "input");
+        assert_eq!(model.outputs()[0].name, "output");
+        assert_eq!(model.inputs()[0].dimensions, Some(vec![-1, 1, -1, -1]));
+
+        let input = input();
+        let output = model.run("input", input.clone(), "output").unwrap();
+        assert!(max_difference(&output, &reference(&input)) < 1e-5);
+    }
+
+    #[test]
+    #[cfg(all(feature = "ort", feature = "tract"))]
+    fn backends_agree_on_small_model() {
+        let path = write_small_model("backends");
+        let input = input();
+        let outputs: Vec<ArrayD<f32>> = [Backend::OnnxRuntime, Backend::Tract]
+            .into_iter()
+            .map(|backend| {
+                let model = load_model(&path, 1, ExecutionProvider::Cpu, backend).unwrap();
+                assert_eq!(model.inputs()[0].dimensions, Some(vec![-1, 1, -1, -1]));
+                model.run("input", input.clone(), "output").unwrap()
+            })
+            .collect();
+        assert!(max_difference(&outputs[0], &outputs[1]) < 1e-5);
+        assert!(max_difference(&outputs[0], &reference(&input)) < 1e-5);
+    }
+}
@@ -0,0 +1,164 @@
+use image::{Rgb, RgbImage};
+
+// 3x5 bitmap font used for legend labels
+const GLYPH_WIDTH: u32 = 3;
+pub const LEGEND_HEIGHT: u32 = 48;
+const BAR_MARGIN: u32 = 8;
+const BAR_HEIGHT: u32 = 16;
+pub const TEXT_SCALE: u32 = 2;
+
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b111, 0b100, 0b100, 0b100, 0b111],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'N' => [0b111, 0b101, 0b101, 0b101, 0b101],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        _ => [0; 5],
+    }
+}
+
+/// Draw `text` with its top-left corner at (x, y), clipped to the image.
+pub fn draw_text(image: &mut RgbImage, x: u32, y: u32, text: &str, scale: u32, color: Rgb<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c.to_ascii_uppercase());
+        let origin = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = origin + column * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+                        if px < image.width() && py < image.height() {
+                            image.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Height of a line of text drawn at `scale`.
+pub fn text_height(scale: u32) -> u32 {
+    5 * scale
+}
+
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    text.chars().count() as u32 * (GLYPH_WIDTH + 1) * scale
+}
+
+/// Draw a labelled color bar from `min` to `max` in a strip starting at row `top`.
+/// The strip must be LEGEND_HEIGHT rows high.
+pub fn draw_color_scale(
+    image: &mut RgbImage,
+    top: u32,
+    min: f32,
+    max: f32,
+    label_step: f32,
+    unit: &str,
+    color: impl Fn(f32) -> Rgb<u8>,
+) {
+    let bar_width = image.width().saturating_sub(2 * BAR_MARGIN).max(1);
+    let bar_top = top + BAR_MARGIN / 2;
+
+    for x in 0..bar_width {
+        let value = min + (max - min) * x as f32 / bar_width as f32;
+        let pixel = color(value);
+        for y in 0..BAR_HEIGHT {
+            image.put_pixel(BAR_MARGIN + x, bar_top + y, pixel);
+        }
+    }
+
+    let white = Rgb([255, 255, 255]);
+    let mut value = (min / label_step).ceil() * label_step;
+    while value <= max {
+        let x = BAR_MARGIN + ((value - min) / (max - min) * bar_width as f32) as u32;
+        for y in bar_top + BAR_HEIGHT..bar_top + BAR_HEIGHT + 4 {
+            if x < image.width() {
+                image.put_pixel(x, y, white);
+            }
+        }
+        let label = format!("{}{}", value.round(), unit);
+        let label_x = x.saturating_sub(text_width(&label, TEXT_SCALE) / 2);
+        draw_text(
+            image,
+            label_x,
+            bar_top + BAR_HEIGHT + 6,
+            &label,
+            TEXT_SCALE,
+            white,
+        );
+        value += label_step;
+    }
+}
+
+/// Draw labelled color swatches for discrete classes in a strip starting at row `top`.
+pub fn draw_swatches(image: &mut RgbImage, top: u32, classes: &[(&str, Rgb<u8>)]) {
+    let white = Rgb([255, 255, 255]);
+    let mut x = BAR_MARGIN;
+    for (label, pixel) in classes {
+        for dy in 0..BAR_HEIGHT {
+            for dx in 0..BAR_HEIGHT {
+                if x + dx < image.width() {
+                    image.put_pixel(x + dx, top + BAR_MARGIN + dy, *pixel);
+                }
+            }
+        }
+        x += BAR_HEIGHT + 4;
+        draw_text(image, x, top + BAR_MARGIN + 3, label, TEXT_SCALE, white);
+        x += text_width(label, TEXT_SCALE) + 2 * BAR_MARGIN;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_characters_have_distinct_glyphs() {
+        let characters = "0123456789-.:%BCDIKNRSVY";
+        for (i, a) in characters.chars().enumerate() {
+            assert_ne!(glyph(a), [0; 5], "{} has no glyph", a);
+            for b in characters.chars().skip(i + 1) {
+                assert_ne!(glyph(a), glyph(b), "{} and {} look the same", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn text_is_clipped_to_the_image() {
+        let mut image = RgbImage::new(10, 6);
+        draw_text(
+            &mut image,
+            4,
+            2,
+            "VIS 255",
+            TEXT_SCALE,
+            Rgb([255, 255, 255]),
+        );
+        assert!(image.pixels().any(|p| p[0] == 255));
+    }
+}
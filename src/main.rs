@@ -3,10 +3,38 @@ use gtk4::prelude::*;
 use std::env;
 
 mod app_state;
+mod apt;
+mod audio_cleanup;
+mod augment;
+mod autotune;
+mod calibration;
+mod cloud_mask;
+mod color;
+mod combine;
 mod console_command;
+mod dataset;
+mod diagnostics;
+mod download;
+mod dropout;
+mod enhancer;
+mod evaluation;
 mod gaussian_blur;
+mod inference;
+mod legend;
+mod model_spec;
+mod models;
+#[cfg(feature = "ort")]
+mod ort_backend;
+mod passes;
+mod products;
+mod quality;
 mod settings;
 mod settings_logic;
+mod telemetry;
+mod tiling;
+#[cfg(feature = "tract")]
+mod tract_backend;
+mod trim;
 mod ui_elements;
 mod ui_logic;
 mod wav;
@@ -17,6 +45,77 @@ fn main() -> glib::ExitCode {
     let args: Vec<String> = env::args().collect();
     if args.len() > 1 {
         let function_settings = settings::FunctionsSettings::new_without_ui();
+        if args[1] == "--help" || args[1] == "-h" {
+            println!("{}", console_command::USAGE);
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1] == "--models" {
+            return match console_command::list_models() {
+                Ok(()) => glib::ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    glib::ExitCode::FAILURE
+                }
+            };
+        }
+
+        if args[1] == "--import-model" {
+            if let Err(e) = console_command::import_model(&args[2..]) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1] == "--download-model" {
+            if let Err(e) = console_command::download_model(&args[2..]) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1] == "--export-dataset" {
+            if let Err(e) = console_command::export_dataset(&args[2..], function_settings) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1] == "--evaluate" {
+            if let Err(e) = console_command::evaluate(&args[2..], function_settings) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1] == "--bench-sgbnr" {
+            if let Err(e) = console_command::benchmark_sgbnr(&args[2..], function_settings) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1] == "--bench-enhancer" {
+            if let Err(e) = console_command::benchmark_enhancer(&args[2..], function_settings) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
+        if args[1].to_lowercase().ends_with(".wav") {
+            if let Err(e) = console_command::decode_wav(&args[1..], function_settings) {
+                eprintln!("{}", e);
+                return glib::ExitCode::FAILURE;
+            }
+            return glib::ExitCode::SUCCESS;
+        }
+
         let img_path = &args[1];
 
         console_command::generate_images(img_path, function_settings);
@@ -0,0 +1,387 @@
+use crate::inference::Model;
+
+use ndarray::{ArrayD, IxDyn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Patch side used when the model accepts any spatial size and no descriptor sets one
+const DEFAULT_PATCH_SIZE: usize = 256;
+
+/// How an image enhancement model is fed, read from the model's inputs and outputs
+/// and from an optional sidecar descriptor next to it (`model.onnx` -> `model.toml`):
+///
+/// ```toml
+/// patch_size = 256   # side of the square input patch, for dynamic spatial dimensions
+/// channels = 1       # the gray patch is repeated into every input channel
+/// scale = 2          # output side over input side, for dynamic spatial dimensions
+/// mean = 0.0         # input = (pixel / 255 - mean) / std, the output is mapped back
+/// std = 1.0
+/// input = "input.1"  # tensor names, the first input and output by default
+/// output = "95"
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelSpec {
+    pub input_name: String,
+    pub output_name: String,
+    pub channels: usize,
+    pub patch_size: usize,
+    pub scale: usize,
+    // False when neither the model shapes nor the descriptor give the scale
+    pub scale_known: bool,
+    // Batch size the model is fixed to, None when it takes any
+    pub batch: Option<usize>,
+    pub mean: f32,
+    pub std: f32,
+}
+
+pub fn descriptor_path(model_path: &str) -> PathBuf {
+    Path::new(model_path).with_extension("toml")
+}
+
+fn read_descriptor(path: &Path) -> Result<Option<toml::Table>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    text.parse::<toml::Table>()
+        .map(Some)
+        .map_err(|e| format!("Invalid model descriptor {}: {}", path.display(), e))
+}
+
+fn positive(table: &toml::Table, key: &str) -> Result<Option<usize>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_integer()
+            .filter(|&v| v > 0)
+            .map(|v| Some(v as usize))
+            .ok_or(format!("{} must be a positive integer", key)),
+    }
+}
+
+fn number(table: &toml::Table, key: &str) -> Result<Option<f32>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(value) => value
+            .as_float()
+            .or(value.as_integer().map(|v| v as f64))
+            .map(|v| Some(v as f32))
+            .ok_or(format!("{} must be a number", key)),
+    }
+}
+
+/// Known size of a dimension, None when dynamic.
+fn known(dimensions: &[i64], index: usize) -> Option<usize> {
+    dimensions
+        .get(index)
+        .filter(|&&d| d > 0)
+        .map(|&d| d as usize)
+}
+
+impl ModelSpec {
+    /// Inspect an NCHW image model, letting its descriptor fill in what the model
+    /// leaves open. A descriptor contradicting the model is an error.
+    pub fn from_model(model: &dyn Model, model_path: &str) -> Result<Self, String> {
+        let path = descriptor_path(model_path);
+        let descriptor = read_descriptor(&path)?.unwrap_or_default();
+        if !descriptor.is_empty() {
+            println!("Using model descriptor {}", path.display());
+        }
+        let name = |key: &str| {
+            descriptor
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(String::from)
+        };
+
+        let input = match name("input") {
+            Some(input_name) => model.inputs().iter().find(|i| i.name == input_name),
+            None => model.inputs().first(),
+        }
+        .ok_or("The model has no such input")?;
+        let output = match name("output") {
+            Some(output_name) => model.outputs().iter().find(|o| o.name == output_name),
+            None => model.outputs().first(),
+        }
+        .ok_or("The model has no such output")?;
+
+        let input_dimensions = input
+            .dimensions
+            .as_deref()
+            .ok_or("The model input is not a tensor")?;
+        let output_dimensions = output
+            .dimensions
+            .as_deref()
+            .ok_or("The model output is not a tensor")?;
+        if input_dimensions.len() != 4 || output_dimensions.len() != 4 {
+            return Err(format!(
+                "Expected NCHW input and output, got {:?} and {:?}",
+                input_dimensions, output_dimensions
+            ));
+        }
+
+        let channels = match (
+            known(input_dimensions, 1),
+            positive(&descriptor, "channels")?,
+        ) {
+            (Some(model), Some(declared)) if model != declared => {
+                return Err(format!(
+                    "The descriptor declares {} channels, the model takes {}",
+                    declared, model
+                ))
+            }
+            (Some(model), _) => model,
+            (None, declared) => declared.unwrap_or(1),
+        };
+
+        let (height, width) = (known(input_dimensions, 2), known(input_dimensions, 3));
+        let patch_size = match (height, width, positive(&descriptor, "patch_size")?) {
+            (Some(h), Some(w), _) if h != w => {
+                return Err(format!("Only square inputs are supported, got {}x{}", w, h))
+            }
+            (Some(h), _, Some(declared)) if h != declared => {
+                return Err(format!(
+                    "The descriptor declares {} pixel patches, the model takes {}",
+                    declared, h
+                ))
+            }
+            (Some(h), _, _) | (None, Some(h), _) => h,
+            (None, None, declared) => declared.unwrap_or(DEFAULT_PATCH_SIZE),
+        };
+
+        let measured_scale = match (height, known(output_dimensions, 2)) {
+            (Some(input_size), Some(output_size)) if output_size.is_multiple_of(input_size) => {
+                Some(output_size / input_size)
+            }
+            (Some(input_size), Some(output_size)) => {
+                return Err(format!(
+                    "Output side {} is not a multiple of input side {}",
+                    output_size, input_size
+                ))
+            }
+            _ => None,
+        };
+        let declared_scale = positive(&descriptor, "scale")?;
+        let scale = match (measured_scale, declared_scale) {
+            (Some(model), Some(declared)) if model != declared => {
+                return Err(format!(
+                    "The descriptor declares scale {}, the model scales by {}",
+                    declared, model
+                ))
+            }
+            (Some(model), _) => model,
+            (None, declared) => declared.unwrap_or(1),
+        };
+
+        let std = number(&descriptor, "std")?.unwrap_or(1.0);
+        if std <= 0.0 {
+            return Err(String::from("std must be positive"));
+        }
+
+        Ok(Self {
+            input_name: input.name.clone(),
+            output_name: output.name.clone(),
+            channels,
+            patch_size,
+            scale,
+            scale_known: measured_scale.is_some() || declared_scale.is_some(),
+            batch: known(input_dimensions, 0),
+            mean: number(&descriptor, "mean")?.unwrap_or(0.0),
+            std,
+        })
+    }
+
+    /// Find the scale of a model with dynamic spatial dimensions and no descriptor
+    /// scale by running it once on a blank patch.
+    pub fn measure_scale(&mut self, model: &dyn Model) -> Result<(), String> {
+        if self.scale_known {
+            return Ok(());
+        }
+        let shape = [
+            self.batch.unwrap_or(1),
+            self.channels,
+            self.patch_size,
+            self.patch_size,
+        ];
+        let output = model
+            .run(
+                &self.input_name,
+                ArrayD::zeros(IxDyn(&shape)),
+                &self.output_name,
+            )
+            .map_err(|e| format!("Cannot run the model to find its scale: {}", e))?;
+        let side = match output.shape() {
+            [_, _, height, width] if height == width => *height,
+            other => return Err(format!("Unexpected output shape {:?}", other)),
+        };
+        if side == 0 || !side.is_multiple_of(self.patch_size) {
+            return Err(format!(
+                "Output side {} is not a multiple of input side {}",
+                side, self.patch_size
+            ));
+        }
+        self.scale = side / self.patch_size;
+        self.scale_known = true;
+        Ok(())
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} -> {}, {} channel(s), {} px patches, scale {}, batch {}, mean {}, std {}",
+            self.input_name,
+            self.output_name,
+            self.channels,
+            self.patch_size,
+            self.scale,
+            self.batch
+                .map_or(String::from("any"), |batch| batch.to_string()),
+            self.mean,
+            self.std
+        )
+    }
+
+    /// Model input value for a pixel in [0, 1].
+    pub fn normalize(&self, value: f32) -> f32 {
+        (value - self.mean) / self.std
+    }
+
+    /// Pixel in [0, 1] for a model output value.
+    pub fn denormalize(&self, value: f32) -> f32 {
+        value * self.std + self.mean
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::TensorInfo;
+    use std::error::Error;
+
+    // Stands in for a loaded model, the output side is the input side times `scale`
+    struct Fake {
+        inputs: Vec<TensorInfo>,
+        outputs: Vec<TensorInfo>,
+        scale: usize,
+    }
+
+    impl Fake {
+        fn new(input: [i64; 4], output: [i64; 4], scale: usize) -> Self {
+            let tensor = |name: &str, dimensions: [i64; 4]| TensorInfo {
+                name: String::from(name),
+                dimensions: Some(dimensions.to_vec()),
+            };
+            Self {
+                inputs: vec![tensor("input", input)],
+                outputs: vec![tensor("output", output)],
+                scale,
+            }
+        }
+    }
+
+    impl Model for Fake {
+        fn inputs(&self) -> &[TensorInfo] {
+            &self.inputs
+        }
+
+        fn outputs(&self) -> &[TensorInfo] {
+            &self.outputs
+        }
+
+        fn run(
+            &self,
+            _input_name: &str,
+            input: ArrayD<f32>,
+            _output_name: &str,
+        ) -> Result<ArrayD<f32>, Box<dyn Error + Send + Sync>> {
+            let shape = input.shape();
+            Ok(ArrayD::zeros(IxDyn(&[
+                shape[0],
+                shape[1],
+                shape[2] * self.scale,
+                shape[3] * self.scale,
+            ])))
+        }
+    }
+
+    /// Model path whose descriptor holds `descriptor`, or that has none when empty.
+    fn model_path(name: &str, descriptor: &str) -> String {
+        let path = std::env::temp_dir().join(format!("trans-misja-spec-{}.onnx", name));
+        let descriptor_file = descriptor_path(path.to_str().unwrap());
+        if descriptor.is_empty() {
+            let _ = fs::remove_file(&descriptor_file);
+        } else {
+            fs::write(&descriptor_file, descriptor).unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn fixed_shapes_need_no_descriptor() {
+        let model = Fake::new([1, 3, 128, 128], [1, 3, 256, 256], 2);
+        let spec = ModelSpec::from_model(&model, &model_path("fixed", "")).unwrap();
+        assert_eq!(
+            (spec.channels, spec.patch_size, spec.scale, spec.batch),
+            (3, 128, 2, Some(1))
+        );
+        assert!(spec.scale_known);
+    }
+
+    #[test]
+    fn descriptor_fills_in_dynamic_dimensions() {
+        let model = Fake::new([-1, -1, -1, -1], [-1, -1, -1, -1], 4);
+        let path = model_path(
+            "dynamic",
+            "patch_size = 64\nchannels = 2\nscale = 4\nmean = 0.5\nstd = 0.25\n",
+        );
+        let spec = ModelSpec::from_model(&model, &path).unwrap();
+        assert_eq!(
+            (spec.channels, spec.patch_size, spec.scale, spec.batch),
+            (2, 64, 4, None)
+        );
+        assert_eq!((spec.mean, spec.std), (0.5, 0.25));
+    }
+
+    #[test]
+    fn descriptor_contradicting_the_model_is_rejected() {
+        let model = Fake::new([1, 1, 128, 128], [1, 1, 256, 256], 2);
+        for (name, descriptor) in [
+            ("channels", "channels = 3"),
+            ("patch", "patch_size = 256"),
+            ("scale", "scale = 4"),
+            ("std", "std = 0"),
+            ("output", "output = \"missing\""),
+        ] {
+            let path = model_path(&format!("conflict-{}", name), descriptor);
+            assert!(
+                ModelSpec::from_model(&model, &path).is_err(),
+                "{} accepted",
+                descriptor
+            );
+        }
+        let path = model_path(
+            "conflict-agrees",
+            "channels = 1\npatch_size = 128\nscale = 2",
+        );
+        assert!(ModelSpec::from_model(&model, &path).is_ok());
+    }
+
+    #[test]
+    fn shapes_the_pipeline_cannot_feed_are_rejected() {
+        let path = model_path("shapes", "");
+        let rectangular = Fake::new([1, 1, 128, 64], [1, 1, 128, 64], 1);
+        assert!(ModelSpec::from_model(&rectangular, &path).is_err());
+        let fractional = Fake::new([1, 1, 128, 128], [1, 1, 192, 192], 1);
+        assert!(ModelSpec::from_model(&fractional, &path).is_err());
+    }
+
+    #[test]
+    fn unknown_scale_is_measured() {
+        let model = Fake::new([1, 1, -1, -1], [1, 1, -1, -1], 3);
+        let path = model_path("measured", "patch_size = 32");
+        let mut spec = ModelSpec::from_model(&model, &path).unwrap();
+        assert!(!spec.scale_known);
+        spec.measure_scale(&model).unwrap();
+        assert_eq!(spec.scale, 3);
+        assert!(spec.scale_known);
+    }
+}
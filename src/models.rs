@@ -0,0 +1,362 @@
+use crate::download::{self, sha256_file, DownloadSource, Progress};
+use crate::inference::{self, Backend, ExecutionProvider};
+use crate::model_spec::ModelSpec;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+const REGISTRY_FILE: &str = "registry.toml";
+// Model in the working directory, used when no model is installed
+const LEGACY_MODEL: &str = "model.onnx";
+
+pub const UNET_MODEL_URL: &str =
+    "https://huggingface.co/TempUser123/NOAA_U-Net/resolve/main/model.onnx?download=true";
+// Registry name of the downloaded U-Net model
+const UNET_MODEL_NAME: &str = "noaa-unet";
+
+pub const TASK_NAMES: [&str; 3] = ["denoise", "super-resolution", "inpaint"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Task {
+    Denoise,
+    SuperResolution,
+    Inpaint,
+}
+
+impl Task {
+    pub const ALL: [Task; 3] = [Task::Denoise, Task::SuperResolution, Task::Inpaint];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        TASK_NAMES
+            .iter()
+            .position(|n| n.eq_ignore_ascii_case(name))
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn name(&self) -> &'static str {
+        TASK_NAMES[Self::ALL.iter().position(|t| t == self).unwrap_or(0)]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ModelEntry {
+    pub name: String,
+    pub version: String,
+    pub task: Task,
+    // ModelSpec summary of the model's inputs and outputs
+    pub input_spec: String,
+    pub sha256: String,
+    // File name in the models directory
+    pub file: String,
+}
+
+impl ModelEntry {
+    pub fn path(&self) -> PathBuf {
+        models_dir().join(&self.file)
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} {} ({}), {}, SHA-256 {}",
+            self.name,
+            self.version,
+            self.task.name(),
+            self.input_spec,
+            self.sha256
+        )
+    }
+}
+
+/// Installed models, kept in registry.toml in the models directory.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    pub models: Vec<ModelEntry>,
+    // Name of the model used when none is asked for
+    pub active: Option<String>,
+}
+
+/// $XDG_DATA_HOME/trans-misja/models, ~/.local/share by default.
+pub fn models_dir() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("trans-misja")
+        .join("models")
+}
+
+fn string(table: &toml::Table, key: &str) -> Result<String, String> {
+    table
+        .get(key)
+        .and_then(|v| v.as_str())
+        .map(String::from)
+        .ok_or(format!("Model entry without {}", key))
+}
+
+impl Registry {
+    pub fn load() -> Result<Self, String> {
+        let path = models_dir().join(REGISTRY_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e| format!("Invalid model registry {}: {}", path.display(), e))?;
+
+        let mut models = Vec::new();
+        for entry in table
+            .get("models")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let entry = entry.as_table().ok_or("Model entry is not a table")?;
+            let task = string(entry, "task")?;
+            models.push(ModelEntry {
+                name: string(entry, "name")?,
+                version: string(entry, "version")?,
+                task: Task::from_name(&task).ok_or(format!("Unknown model task: {}", task))?,
+                input_spec: string(entry, "input")?,
+                sha256: string(entry, "sha256")?,
+                file: string(entry, "file")?,
+            });
+        }
+        Ok(Self {
+            models,
+            active: table
+                .get("active")
+                .and_then(|v| v.as_str())
+                .map(String::from),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut table = toml::Table::new();
+        if let Some(active) = &self.active {
+            table.insert(String::from("active"), toml::Value::from(active.as_str()));
+        }
+        let models = self
+            .models
+            .iter()
+            .map(|model| {
+                let mut entry = toml::Table::new();
+                for (key, value) in [
+                    ("name", &model.name),
+                    ("version", &model.version),
+                    ("task", &model.task.name().to_string()),
+                    ("input", &model.input_spec),
+                    ("sha256", &model.sha256),
+                    ("file", &model.file),
+                ] {
+                    entry.insert(String::from(key), toml::Value::from(value.as_str()));
+                }
+                toml::Value::Table(entry)
+            })
+            .collect::<Vec<toml::Value>>();
+        table.insert(String::from("models"), toml::Value::Array(models));
+
+        let dir = models_dir();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        // Written aside and renamed so that a crash never leaves half a registry
+        let temporary = dir.join(format!("{}.tmp", REGISTRY_FILE));
+        fs::write(&temporary, table.to_string()).map_err(|e| e.to_string())?;
+        fs::rename(&temporary, dir.join(REGISTRY_FILE)).map_err(|e| e.to_string())
+    }
+
+    pub fn find(&self, name: &str) -> Option<&ModelEntry> {
+        self.models.iter().find(|model| model.name == name)
+    }
+
+    pub fn active_entry(&self) -> Option<&ModelEntry> {
+        self.active.as_deref().and_then(|name| self.find(name))
+    }
+
+    pub fn set_active(&mut self, name: &str) -> Result<(), String> {
+        if self.find(name).is_none() {
+            return Err(format!("No model named {} is installed", name));
+        }
+        self.active = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Copy a model (and its descriptor, see ModelSpec) into the models directory and
+    /// register it, replacing any model of the same name. The name defaults to the file
+    /// name and the task to what the model's shapes suggest. The first model installed
+    /// becomes the active one.
+    pub fn import(
+        &mut self,
+        source: &Path,
+        name: Option<&str>,
+        version: &str,
+        task: Option<Task>,
+    ) -> Result<ModelEntry, String> {
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => source
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .ok_or(format!("Invalid model path: {}", source.display()))?,
+        };
+        if name.is_empty() || name.contains(['/', '\\']) {
+            return Err(format!("Invalid model name: {}", name));
+        }
+        // Both end up in the file name inside the models directory
+        if version.is_empty() || version.contains(['/', '\\']) {
+            return Err(format!("Invalid model version: {}", version));
+        }
+
+        // A model whose inputs cannot be read cannot be run either
+        let source_path = source.to_string_lossy();
+        let model =
+            inference::load_model(&source_path, 1, ExecutionProvider::Cpu, Backend::default())
+                .map_err(|e| format!("Cannot load {}: {}", source.display(), e))?;
+        let mut spec = ModelSpec::from_model(model.as_ref(), &source_path)?;
+        if task.is_none() {
+            spec.measure_scale(model.as_ref())?;
+        }
+        let task = task.unwrap_or(if spec.scale > 1 {
+            Task::SuperResolution
+        } else if spec.channels == 2 {
+            Task::Inpaint
+        } else {
+            Task::Denoise
+        });
+
+        let dir = models_dir();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let file = format!("{}-{}.onnx", name, version);
+        let target = dir.join(&file);
+        if fs::canonicalize(source).ok() != fs::canonicalize(&target).ok() {
+            fs::copy(source, &target).map_err(|e| e.to_string())?;
+        }
+        let descriptor = source.with_extension("toml");
+        if descriptor.exists() {
+            fs::copy(&descriptor, target.with_extension("toml")).map_err(|e| e.to_string())?;
+        }
+
+        let entry = ModelEntry {
+            name: name.clone(),
+            version: version.to_string(),
+            task,
+            input_spec: spec.summary(),
+            sha256: sha256_file(&target)?,
+            file,
+        };
+        println!("Installed model {}", entry.summary());
+        self.models.retain(|model| model.name != name);
+        self.models.push(entry.clone());
+        if self.active_entry().is_none() {
+            self.active = Some(name);
+        }
+        self.save()?;
+        Ok(entry)
+    }
+}
+
+/// Path of the enhancement model to run: the registry model called `name` when given,
+/// else the active model, else model.onnx in the working directory.
+pub fn model_path(name: Option<&str>) -> Result<String, String> {
+    let registry = Registry::load()?;
+    let entry = match name {
+        Some(name) => Some(
+            registry
+                .find(name)
+                .ok_or(format!("No model named {} is installed", name))?,
+        ),
+        None => registry.active_entry(),
+    };
+    Ok(entry.map_or(String::from(LEGACY_MODEL), |entry| {
+        entry.path().to_string_lossy().into_owned()
+    }))
+}
+
+/// Where the U-Net model is downloaded from: the mirror first when one is set, then
+/// UNET_MODEL_URL. Empty strings leave the proxy and checksum unset.
+pub fn unet_source(mirror: &str, proxy: &str, sha256: &str) -> DownloadSource {
+    let set = |value: &str| Some(value.trim().to_string()).filter(|value| !value.is_empty());
+    DownloadSource {
+        urls: set(mirror)
+            .into_iter()
+            .chain([String::from(UNET_MODEL_URL)])
+            .collect(),
+        proxy: set(proxy),
+        sha256: set(sha256),
+    }
+}
+
+/// Download the U-Net model into the models directory and install it. An interrupted
+/// download resumes on the next call.
+pub fn install_unet_model(
+    source: &DownloadSource,
+    cancel: &AtomicBool,
+    progress: impl FnMut(Progress),
+) -> Result<ModelEntry, String> {
+    let target = models_dir().join(format!("{}-1.onnx", UNET_MODEL_NAME));
+    download::download(source, &target, cancel, progress)?;
+    Registry::load()
+        .and_then(|mut registry| {
+            registry.import(&target, Some(UNET_MODEL_NAME), "1", Some(Task::Denoise))
+        })
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&target);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_names_round_trip() {
+        for task in Task::ALL {
+            assert_eq!(Task::from_name(task.name()), Some(task));
+        }
+        assert_eq!(Task::from_name("Inpaint"), Some(Task::Inpaint));
+        assert_eq!(Task::from_name("colorize"), None);
+    }
+
+    #[test]
+    fn registry_round_trip() {
+        // The only test that moves the models directory
+        let data = env::temp_dir().join("trans-misja-registry");
+        let _ = fs::remove_dir_all(&data);
+        env::set_var("XDG_DATA_HOME", &data);
+        assert!(Registry::load().unwrap().models.is_empty());
+
+        let entry = |name: &str, task| ModelEntry {
+            name: String::from(name),
+            version: String::from("1.0"),
+            task,
+            input_spec: String::from("input -> output, 1 channel(s)"),
+            sha256: String::from("00ff"),
+            file: format!("{}.onnx", name),
+        };
+        let mut registry = Registry {
+            models: vec![
+                entry("unet", Task::Denoise),
+                entry("fill \"gaps\"", Task::Inpaint),
+            ],
+            active: None,
+        };
+        assert!(registry.set_active("missing").is_err());
+        registry.set_active("fill \"gaps\"").unwrap();
+        registry.save().unwrap();
+
+        let loaded = Registry::load().unwrap();
+        assert_eq!(loaded.active, registry.active);
+        assert_eq!(loaded.models.len(), 2);
+        for (loaded, saved) in loaded.models.iter().zip(&registry.models) {
+            assert_eq!(loaded.summary(), saved.summary());
+            assert_eq!(loaded.file, saved.file);
+        }
+        assert_eq!(loaded.active_entry().unwrap().task, Task::Inpaint);
+        assert_eq!(
+            loaded.find("unet").unwrap().path(),
+            data.join("trans-misja/models/unet.onnx")
+        );
+    }
+}
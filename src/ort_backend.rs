@@ -0,0 +1,134 @@
+use crate::inference::{record_active_provider, ExecutionProvider, Model, TensorInfo};
+
+use ndarray::ArrayD;
+use ort::{
+    execution_providers::{
+        CUDAExecutionProvider, ExecutionProviderDispatch, OpenVINOExecutionProvider,
+        TensorRTExecutionProvider, XNNPACKExecutionProvider,
+    },
+    session::{
+        builder::{GraphOptimizationLevel, SessionBuilder},
+        Session,
+    },
+    value::Tensor,
+};
+use std::error::Error;
+
+fn dispatch(provider: ExecutionProvider) -> Option<ExecutionProviderDispatch> {
+    match provider {
+        ExecutionProvider::Cpu => None,
+        ExecutionProvider::Cuda => Some(CUDAExecutionProvider::default().build()),
+        ExecutionProvider::TensorRt => Some(TensorRTExecutionProvider::default().build()),
+        ExecutionProvider::OpenVino => Some(OpenVINOExecutionProvider::default().build()),
+        ExecutionProvider::Xnnpack => Some(XNNPACKExecutionProvider::default().build()),
+    }
+}
+
+/// A model run by ONNX Runtime.
+pub struct OrtModel {
+    session: Session,
+    inputs: Vec<TensorInfo>,
+    outputs: Vec<TensorInfo>,
+}
+
+impl OrtModel {
+    /// Load an ONNX model on the requested execution provider. A provider that is not
+    /// built in or fails to register falls back to the CPU, and the fallback is logged.
+    pub fn load(
+        model_path: &str,
+        cpu_threads: usize,
+        provider: ExecutionProvider,
+    ) -> Result<Self, Box<dyn Error>> {
+        let builder = || -> ort::Result<SessionBuilder> {
+            Session::builder()?
+                .with_optimization_level(GraphOptimizationLevel::Level3)?
+                .with_intra_threads(cpu_threads)
+        };
+
+        let accelerated = match (dispatch(provider), provider.feature()) {
+            (Some(_), Some(feature)) if !provider.compiled() => {
+                eprintln!(
+                    "{} support is not built in (cargo feature \"{}\"), falling back to CPU",
+                    provider.name(),
+                    feature
+                );
+                None
+            }
+            (Some(dispatch), _) => {
+                // Registration errors are reported instead of silently running on the CPU
+                match builder()?
+                    .with_execution_providers([dispatch.error_on_failure()])
+                    .and_then(|builder| builder.commit_from_file(model_path))
+                {
+                    Ok(session) => Some(session),
+                    Err(e) => {
+                        eprintln!(
+                            "{} execution provider failed ({}), falling back to CPU",
+                            provider.name(),
+                            e
+                        );
+                        None
+                    }
+                }
+            }
+            (None, _) => None,
+        };
+
+        let (session, active) = match accelerated {
+            Some(session) => (session, provider),
+            None => (
+                builder()?.commit_from_file(model_path)?,
+                ExecutionProvider::Cpu,
+            ),
+        };
+        println!("Running {} on {}", model_path, active.name());
+        record_active_provider(active);
+
+        let inputs = session
+            .inputs
+            .iter()
+            .map(|input| TensorInfo {
+                name: input.name.clone(),
+                dimensions: input.input_type.tensor_dimensions().cloned(),
+            })
+            .collect();
+        let outputs = session
+            .outputs
+            .iter()
+            .map(|output| TensorInfo {
+                name: output.name.clone(),
+                dimensions: output.output_type.tensor_dimensions().cloned(),
+            })
+            .collect();
+        Ok(Self {
+            session,
+            inputs,
+            outputs,
+        })
+    }
+}
+
+impl Model for OrtModel {
+    fn inputs(&self) -> &[TensorInfo] {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> &[TensorInfo] {
+        &self.outputs
+    }
+
+    fn run(
+        &self,
+        input_name: &str,
+        input: ArrayD<f32>,
+        output_name: &str,
+    ) -> Result<ArrayD<f32>, Box<dyn Error + Send + Sync>> {
+        let tensor: Tensor<f32> = Tensor::from_array(input)?;
+        let result = self.session.run(vec![(input_name, tensor)])?;
+        let output = result
+            .get(output_name)
+            .ok_or("Missing model output")?
+            .try_extract_tensor::<f32>()?;
+        Ok(output.into_owned())
+    }
+}
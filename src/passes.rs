@@ -0,0 +1,314 @@
+use crate::app_state::AppState;
+use crate::quality::{LineQuality, MIN_SYNC_SCORE};
+use crate::settings::FunctionsSettings;
+use crate::trim::MIN_SUBCARRIER_FRACTION;
+use crate::wav;
+
+use async_channel::Sender;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// APT sends two lines per second
+const LINES_PER_SECOND: usize = 2;
+// A line is active when its subcarrier energy passes trim::MIN_SUBCARRIER_FRACTION
+// and at least half of the sync searches around it succeed
+const MIN_SYNC_DENSITY: f32 = 0.5;
+const DENSITY_LINES: usize = 31;
+// Dropouts shorter than this stay inside the pass (1 minute)
+const MAX_GAP_LINES: usize = 120;
+// Shorter stretches of signal are not a pass (2 minutes)
+const MIN_PASS_LINES: usize = 240;
+// Lines of noise kept around each pass, the decoder trims them (30 seconds)
+const PASS_MARGIN: usize = 60;
+
+#[derive(Clone, Debug)]
+pub struct Pass {
+    // Lines of the whole recording, end exclusive
+    pub first_line: usize,
+    pub last_line: usize,
+    // UTC start of the pass, "recording +HH:MM:SS" when the recording time is unknown
+    pub timestamp: String,
+    pub directory: String,
+}
+
+impl Pass {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} ({} min, lines {}-{})",
+            self.timestamp,
+            (self.last_line - self.first_line) / LINES_PER_SECOND / 60,
+            self.first_line,
+            self.last_line
+        )
+    }
+}
+
+/// Line ranges (end exclusive) holding a pass, from the subcarrier energy
+/// and the density of successful sync detections.
+pub fn find_passes(energy: &[f32], quality: &[LineQuality]) -> Vec<(usize, usize)> {
+    let lines = energy.len().min(quality.len());
+    let synced: Vec<bool> = quality[..lines]
+        .iter()
+        .map(|line| line.sync_score >= MIN_SYNC_SCORE)
+        .collect();
+
+    let half = DENSITY_LINES / 2;
+    let active: Vec<bool> = (0..lines)
+        .map(|i| {
+            let window = &synced[i.saturating_sub(half)..(i + half + 1).min(lines)];
+            let density = window.iter().filter(|&&s| s).count() as f32 / window.len() as f32;
+            energy[i] >= MIN_SUBCARRIER_FRACTION && density >= MIN_SYNC_DENSITY
+        })
+        .collect();
+
+    // Group active lines, bridging short dropouts
+    let mut passes: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in active.iter().enumerate().filter(|(_, &a)| a) {
+        match passes.last_mut() {
+            Some((_, end)) if i - *end <= MAX_GAP_LINES => *end = i + 1,
+            _ => passes.push((i, i + 1)),
+        }
+    }
+    passes.retain(|(start, end)| end - start >= MIN_PASS_LINES);
+    passes
+}
+
+/// Civil date of a day count since 1970-01-01 (Howard Hinnant's algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// (display, directory name) of a UTC time.
+fn format_time(time: SystemTime) -> (String, String) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let second_of_day = seconds.rem_euclid(86400);
+    let (hour, minute, second) = (
+        second_of_day / 3600,
+        second_of_day % 3600 / 60,
+        second_of_day % 60,
+    );
+    (
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year, month, day, hour, minute, second
+        ),
+        format!(
+            "{:04}{:02}{:02}_{:02}{:02}{:02}",
+            year, month, day, hour, minute, second
+        ),
+    )
+}
+
+fn write_segment(path: &str, samples: &[f32], sample_rate: u32) -> Result<(), String> {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(path, spec).map_err(|e| e.to_string())?;
+    for &sample in samples {
+        writer.write_sample(sample).map_err(|e| e.to_string())?;
+    }
+    writer.finalize().map_err(|e| e.to_string())
+}
+
+/// Move a file written in the working directory into `directory`, returning its new path.
+fn move_into(path: &str, directory: &str) -> Result<String, String> {
+    let name = Path::new(path)
+        .file_name()
+        .ok_or(format!("Invalid output path: {}", path))?;
+    let target = Path::new(directory).join(name);
+    fs::rename(path, &target).map_err(|e| e.to_string())?;
+    Ok(target.to_string_lossy().into_owned())
+}
+
+/// Decode the samples of one pass into `directory`, moving every file written there.
+/// Returns the moved image to display and the moved files.
+fn decode_pass(
+    samples: &[f32],
+    sample_rate: u32,
+    directory: &str,
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> Result<(String, Vec<String>), String> {
+    fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+    let segment_path = format!("{}/pass.wav", directory);
+    write_segment(&segment_path, samples, sample_rate)?;
+
+    // compute_signal reports completion on its own channel, keep it away from the caller's
+    let (pass_sender, _pass_receiver) = async_channel::unbounded();
+    let (pass_path, files) = wav::compute_signal(&segment_path, app_state, settings, &pass_sender);
+    if files.is_empty() {
+        return Err(String::from("nothing decoded"));
+    }
+
+    let mut path = String::new();
+    let mut moved_files = Vec::new();
+    for file in files {
+        let moved = move_into(&file, directory)?;
+        if file == pass_path {
+            path = moved.clone();
+        }
+        moved_files.push(moved);
+    }
+    Ok((path, moved_files))
+}
+
+/// Find every pass of a long recording and decode each one into its own directory,
+/// named after the pass start time. The recording is assumed to end at the file's
+/// modification time. Returns the passes and leaves every output in app_state.outputs.
+pub fn split_passes(
+    filepath: &str,
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+    sender: &Sender<(f64, String)>,
+) -> Result<Vec<Pass>, String> {
+    let _ = sender.try_send((0.05, String::from("Scanning recording for passes...")));
+    let (samples, spec) = wav::load_samples(filepath, app_state.debug)?;
+    let sample_rate = spec.sample_rate;
+    let duration = Duration::from_secs_f64(samples.len() as f64 / sample_rate as f64);
+    let recording_start = fs::metadata(filepath)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.checked_sub(duration));
+
+    let (energy, quality) = wav::scan_lines(&samples, sample_rate, settings);
+    let ranges = find_passes(&energy, &quality);
+    println!("Found {} passes in {:?}", ranges.len(), duration);
+    if ranges.is_empty() {
+        return Err(String::from("No pass found in the recording"));
+    }
+
+    let line_samples = sample_rate as usize / LINES_PER_SECOND;
+    let mut passes = Vec::new();
+    let mut outputs = Vec::new();
+    let mut path = String::new();
+    for (n, &(first, last)) in ranges.iter().enumerate() {
+        let offset = Duration::from_secs((first / LINES_PER_SECOND) as u64);
+        let (timestamp, directory) = match recording_start {
+            Some(start) => {
+                let (display, name) = format_time(start + offset);
+                (display, format!("pass_{}_{}", n + 1, name))
+            }
+            None => {
+                let seconds = offset.as_secs();
+                (
+                    format!(
+                        "recording +{:02}:{:02}:{:02}",
+                        seconds / 3600,
+                        seconds % 3600 / 60,
+                        seconds % 60
+                    ),
+                    format!("pass_{}", n + 1),
+                )
+            }
+        };
+        println!("Pass {}: {}", n + 1, timestamp);
+        let _ = sender.try_send((
+            0.1 + 0.9 * n as f64 / ranges.len() as f64,
+            format!("Decoding pass {} of {}...", n + 1, ranges.len()),
+        ));
+
+        let start = first.saturating_sub(PASS_MARGIN) * line_samples;
+        let end = ((last + PASS_MARGIN) * line_samples).min(samples.len());
+        let (pass_path, moved) = match decode_pass(
+            &samples[start..end],
+            sample_rate,
+            &directory,
+            app_state,
+            settings,
+        ) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                eprintln!("Skipping pass {}: {}", n + 1, e);
+                continue;
+            }
+        };
+        path = pass_path;
+        outputs.extend(moved.into_iter().filter(|moved| moved.ends_with(".png")));
+
+        passes.push(Pass {
+            first_line: first,
+            last_line: last,
+            timestamp,
+            directory,
+        });
+    }
+
+    *app_state.outputs.lock().unwrap() = outputs;
+    *app_state.passes.lock().unwrap() = passes.clone();
+    while sender.try_send((1.0, path.clone())).is_err() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(passes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_bridge_dropouts_and_drop_short_bursts() {
+        // Signal at lines 100-699 with a dropout at 400-449, a burst at 1000-1099
+        // and a second pass at 1300-1799
+        let signal = |i: usize| {
+            ((100..700).contains(&i) && !(400..450).contains(&i))
+                || (1000..1100).contains(&i)
+                || (1300..1800).contains(&i)
+        };
+        let energy: Vec<f32> = (0..2000)
+            .map(|i| if signal(i) { 0.3 } else { 0.001 })
+            .collect();
+        let quality: Vec<LineQuality> = (0..2000)
+            .map(|i| LineQuality {
+                sync_score: if signal(i) { 0.8 } else { 0.1 },
+                snr_db: 20.0,
+                noise: 1.0,
+            })
+            .collect();
+        assert_eq!(
+            find_passes(&energy, &quality),
+            vec![(100, 700), (1300, 1800)]
+        );
+    }
+
+    #[test]
+    fn energy_without_sync_is_not_a_pass() {
+        // A carrier with no APT on it, like an interfering transmitter
+        let quality = vec![
+            LineQuality {
+                sync_score: 0.1,
+                snr_db: 0.0,
+                noise: 1.0,
+            };
+            1000
+        ];
+        assert!(find_passes(&[0.5; 1000], &quality).is_empty());
+    }
+
+    #[test]
+    fn times_are_formatted_in_utc() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        let (display, directory) = format_time(UNIX_EPOCH + Duration::from_secs(1709210096));
+        assert_eq!(display, "2024-02-29 12:34:56 UTC");
+        assert_eq!(directory, "20240229_123456");
+    }
+}
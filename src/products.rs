@@ -0,0 +1,207 @@
+use crate::apt;
+use crate::calibration::{self, Satellite};
+use crate::color::rainbow;
+use crate::legend;
+
+use image::{GrayImage, ImageBuffer, Rgb, RgbImage};
+
+const KELVIN: f32 = 273.15;
+
+// Cloud-top temperature scale (kelvin)
+const CLOUD_TOP_MIN: f32 = 183.0;
+const CLOUD_TOP_MAX: f32 = 313.0;
+
+// Cloud tops colder than these are likely to precipitate (kelvin)
+const LIGHT_PRECIPITATION: f32 = 233.0;
+const MODERATE_PRECIPITATION: f32 = 218.0;
+const HEAVY_PRECIPITATION: f32 = 203.0;
+
+// Sea surface temperature scale (kelvin) and cloud tests
+const SST_MIN: f32 = 271.0;
+const SST_MAX: f32 = 305.0;
+const SST_MAX_VISIBLE: u8 = 100;
+
+pub const PRODUCT_NAMES: [&str; 3] = [
+    "Cloud-top temperature",
+    "Precipitation",
+    "Sea surface temperature",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Product {
+    CloudTopTemperature,
+    Precipitation,
+    SeaSurfaceTemperature,
+}
+
+impl Product {
+    pub const ALL: [Product; 3] = [
+        Product::CloudTopTemperature,
+        Product::Precipitation,
+        Product::SeaSurfaceTemperature,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "cloud-top" | "ctt" => Some(Product::CloudTopTemperature),
+            "precipitation" | "rain" => Some(Product::Precipitation),
+            "sst" | "sea-surface" => Some(Product::SeaSurfaceTemperature),
+            _ => None,
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Product::CloudTopTemperature => "cloud_top_temperature.png",
+            Product::Precipitation => "precipitation.png",
+            Product::SeaSurfaceTemperature => "sea_surface_temperature.png",
+        }
+    }
+}
+
+/// Generate the selected products from a synced, unenhanced APT image.
+pub fn generate_products(
+    image_path: &str,
+    sync_column: u32,
+    satellite: Satellite,
+    products: &[Product],
+) -> Result<Vec<String>, String> {
+    let image = image::open(image_path)
+        .map_err(|e| e.to_string())?
+        .to_luma8();
+    if image.width() != apt::LINE_WIDTH {
+        return Err(format!(
+            "Expected an APT image {} pixels wide, got {}",
+            apt::LINE_WIDTH,
+            image.width()
+        ));
+    }
+
+    let calibration = calibration::calibrate(&image, sync_column, satellite);
+    let temperatures = calibration.temperature_table();
+    let (visible, infrared) = apt::split_channels(&image, sync_column);
+
+    let mut paths = Vec::new();
+    for product in products {
+        let output = match product {
+            Product::CloudTopTemperature => cloud_top_temperature(&infrared, &temperatures),
+            Product::Precipitation => precipitation(&infrared, &temperatures),
+            Product::SeaSurfaceTemperature => {
+                sea_surface_temperature(&visible, &infrared, &temperatures)
+            }
+        };
+
+        let output_path = product.file_name().to_string();
+        println!("Saving {:?} to: {}", product, output_path);
+        output.save(&output_path).map_err(|e| e.to_string())?;
+        paths.push(output_path);
+    }
+
+    Ok(paths)
+}
+
+fn with_legend(width: u32, height: u32) -> RgbImage {
+    ImageBuffer::new(width, height + legend::LEGEND_HEIGHT)
+}
+
+fn cloud_top_color(temperature: f32) -> Rgb<u8> {
+    Rgb(rainbow(
+        (CLOUD_TOP_MAX - temperature) / (CLOUD_TOP_MAX - CLOUD_TOP_MIN),
+    ))
+}
+
+/// Cloud-top (brightness) temperature, cold tops in red.
+fn cloud_top_temperature(infrared: &GrayImage, temperatures: &[f32; 256]) -> RgbImage {
+    let (width, height) = infrared.dimensions();
+    let mut output = with_legend(width, height);
+    for (x, y, pixel) in infrared.enumerate_pixels() {
+        output.put_pixel(x, y, cloud_top_color(temperatures[pixel[0] as usize]));
+    }
+
+    legend::draw_color_scale(
+        &mut output,
+        height,
+        CLOUD_TOP_MIN - KELVIN,
+        CLOUD_TOP_MAX - KELVIN,
+        20.0,
+        "C",
+        |celsius| cloud_top_color(celsius + KELVIN),
+    );
+    output
+}
+
+const PRECIPITATION_CLASSES: [(&str, Rgb<u8>); 3] = [
+    ("-40C", Rgb([0, 200, 0])),
+    ("-55C", Rgb([255, 220, 0])),
+    ("-70C", Rgb([230, 0, 0])),
+];
+
+/// IR image with cold cloud tops (likely precipitation) overlaid in color.
+fn precipitation(infrared: &GrayImage, temperatures: &[f32; 256]) -> RgbImage {
+    let (width, height) = infrared.dimensions();
+    let mut output = with_legend(width, height);
+    for (x, y, pixel) in infrared.enumerate_pixels() {
+        let grey = pixel[0];
+        let temperature = temperatures[grey as usize];
+        let class = if temperature < HEAVY_PRECIPITATION {
+            Some(2)
+        } else if temperature < MODERATE_PRECIPITATION {
+            Some(1)
+        } else if temperature < LIGHT_PRECIPITATION {
+            Some(0)
+        } else {
+            None
+        };
+
+        let pixel = match class {
+            Some(class) => {
+                let overlay = PRECIPITATION_CLASSES[class].1;
+                Rgb([
+                    ((overlay[0] as u32 * 3 + grey as u32 * 2) / 5) as u8,
+                    ((overlay[1] as u32 * 3 + grey as u32 * 2) / 5) as u8,
+                    ((overlay[2] as u32 * 3 + grey as u32 * 2) / 5) as u8,
+                ])
+            }
+            None => Rgb([grey, grey, grey]),
+        };
+        output.put_pixel(x, y, pixel);
+    }
+
+    legend::draw_swatches(&mut output, height, &PRECIPITATION_CLASSES);
+    output
+}
+
+fn sst_color(temperature: f32) -> Rgb<u8> {
+    Rgb(rainbow((temperature - SST_MIN) / (SST_MAX - SST_MIN)))
+}
+
+/// Crude sea surface temperature: clear-sky pixels colored, cold or bright (cloudy) pixels masked.
+fn sea_surface_temperature(
+    visible: &GrayImage,
+    infrared: &GrayImage,
+    temperatures: &[f32; 256],
+) -> RgbImage {
+    let (width, height) = infrared.dimensions();
+    let mut output = with_legend(width, height);
+    for (x, y, pixel) in infrared.enumerate_pixels() {
+        let temperature = temperatures[pixel[0] as usize];
+        let cloudy = temperature < SST_MIN || visible.get_pixel(x, y)[0] > SST_MAX_VISIBLE;
+        let pixel = if cloudy {
+            Rgb([40, 40, 40])
+        } else {
+            sst_color(temperature)
+        };
+        output.put_pixel(x, y, pixel);
+    }
+
+    legend::draw_color_scale(
+        &mut output,
+        height,
+        SST_MIN - KELVIN,
+        SST_MAX - KELVIN,
+        5.0,
+        "C",
+        |celsius| sst_color(celsius + KELVIN),
+    );
+    output
+}
@@ -0,0 +1,340 @@
+use crate::legend;
+
+use image::{GrayImage, ImageBuffer, Rgb, RgbImage};
+use std::fs::File;
+use std::io::Write;
+
+// Sync A and space view lengths in samples at 20800 Hz (39 and 47 words of 5 samples)
+const SYNC_SAMPLES: usize = 195;
+const SPACE_SAMPLES: usize = 235;
+// Samples ignored at each end of the space view
+const SPACE_MARGIN: usize = 20;
+
+// A line is usable when both its sync score and SNR pass these thresholds. The sync
+// score threshold is shared by every stage that tells signal lines from noise.
+pub const MIN_SYNC_SCORE: f32 = 0.3;
+const MIN_SNR_DB: f32 = 3.0;
+// Length of the segments searched for the worst stretches of the pass
+const SEGMENT_LINES: usize = 32;
+const WORST_SEGMENTS: usize = 3;
+
+pub const REPORT_PATH: &str = "quality_report.json";
+
+const PLOT_WIDTH: u32 = 800;
+const PLOT_HEIGHT: u32 = 200;
+const MAX_PLOT_SNR_DB: f32 = 40.0;
+
+/// Median of integer absolute values from their histogram, interpolated within its
+/// bin so that it does not move by whole levels. 0 for an empty histogram.
+fn histogram_median(histogram: &[u64]) -> f32 {
+    let half = histogram.iter().sum::<u64>() as f32 / 2.0;
+    let mut below = 0.0;
+    for (value, &count) in histogram.iter().enumerate() {
+        let count = count as f32;
+        if count > 0.0 && below + count >= half {
+            // Value k covers [k - 0.5, k + 0.5], and 0 only [0, 0.5]
+            let (start, width) = if value == 0 {
+                (0.0, 0.5)
+            } else {
+                (value as f32 - 0.5, 1.0)
+            };
+            return start + width * (half - below) / count;
+        }
+        below += count;
+    }
+    0.0
+}
+
+/// Pixel noise of an image from the median absolute difference of horizontal
+/// neighbors, scaled to a Gaussian sigma. Only `rows` are read, and in them the
+/// pairs of consecutive `columns` that are next to each other. None without a pair.
+pub fn neighbor_noise_sigma(image: &GrayImage, rows: &[u32], columns: &[u32]) -> Option<f32> {
+    let mut histogram = [0u64; 256];
+    for &y in rows {
+        for pair in columns.windows(2).filter(|pair| pair[1] == pair[0] + 1) {
+            let left = image.get_pixel(pair[0], y)[0];
+            histogram[image.get_pixel(pair[1], y)[0].abs_diff(left) as usize] += 1;
+        }
+    }
+    if histogram.iter().all(|&count| count == 0) {
+        return None;
+    }
+    // MAD to sigma, and the difference of two pixels has twice the variance of one
+    Some(histogram_median(&histogram) * 1.4826 / std::f32::consts::SQRT_2)
+}
+
+#[derive(Clone, Debug)]
+pub struct LineQuality {
+    // Normalized correlation of the best sync match
+    pub sync_score: f32,
+    // Sync contrast over space view noise
+    pub snr_db: f32,
+    // Standard deviation of the space view
+    pub noise: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize,
+    pub snr_db: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct QualityReport {
+    pub lines: Vec<LineQuality>,
+    // Usable line range, end exclusive
+    pub usable_start: usize,
+    pub usable_end: usize,
+    pub mean_snr_db: f32,
+    pub mean_sync_score: f32,
+    pub worst_segments: Vec<Segment>,
+}
+
+/// Quality of one line of the demodulated signal whose sync A starts at `sync_start`.
+pub fn line_quality(row: &[f32], sync_start: usize, sync_score: f32) -> LineQuality {
+    let at = |i: usize| row[(sync_start + i) % row.len()];
+
+    // Sync contrast: mean of the samples above the median minus the mean of those below
+    let mut sync: Vec<f32> = (0..SYNC_SAMPLES).map(at).collect();
+    sync.sort_by(|a, b| a.total_cmp(b));
+    let half = SYNC_SAMPLES / 2;
+    let low = sync[..half].iter().sum::<f32>() / half as f32;
+    let high = sync[half..].iter().sum::<f32>() / (SYNC_SAMPLES - half) as f32;
+
+    // The space view is flat, whatever varies there is noise
+    let space: Vec<f32> = (SYNC_SAMPLES + SPACE_MARGIN
+        ..SYNC_SAMPLES + SPACE_SAMPLES - SPACE_MARGIN)
+        .map(at)
+        .collect();
+    let mean = space.iter().sum::<f32>() / space.len() as f32;
+    let noise = (space.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / space.len() as f32).sqrt();
+
+    let snr_db = 20.0 * ((high - low).max(f32::EPSILON) / noise.max(f32::EPSILON)).log10();
+
+    LineQuality {
+        // max() also maps a NaN score (silent line) to 0
+        sync_score: sync_score.max(0.0),
+        snr_db,
+        noise,
+    }
+}
+
+impl QualityReport {
+    pub fn new(lines: Vec<LineQuality>) -> Self {
+        let usable =
+            |line: &LineQuality| line.sync_score >= MIN_SYNC_SCORE && line.snr_db >= MIN_SNR_DB;
+        let usable_start = lines.iter().position(usable).unwrap_or(0);
+        let usable_end = lines.iter().rposition(usable).map(|i| i + 1).unwrap_or(0);
+
+        let usable_lines = &lines[usable_start..usable_end.max(usable_start)];
+        let count = usable_lines.len().max(1) as f32;
+        let mean_snr_db = usable_lines.iter().map(|l| l.snr_db).sum::<f32>() / count;
+        let mean_sync_score = usable_lines.iter().map(|l| l.sync_score).sum::<f32>() / count;
+
+        // Non-overlapping segments of the usable range with the lowest mean SNR
+        let mut segments: Vec<Segment> = (usable_start..usable_end)
+            .step_by(SEGMENT_LINES)
+            .map(|start| {
+                let end = (start + SEGMENT_LINES).min(usable_end);
+                let snr_db =
+                    lines[start..end].iter().map(|l| l.snr_db).sum::<f32>() / (end - start) as f32;
+                Segment { start, end, snr_db }
+            })
+            .collect();
+        segments.sort_by(|a, b| a.snr_db.total_cmp(&b.snr_db));
+        segments.truncate(WORST_SEGMENTS);
+
+        Self {
+            lines,
+            usable_start,
+            usable_end,
+            mean_snr_db,
+            mean_sync_score,
+            worst_segments: segments,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let worst = self
+            .worst_segments
+            .iter()
+            .map(|s| format!("{}-{} ({:.1} dB)", s.start, s.end, s.snr_db))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!(
+            "Usable lines {}-{} of {}, mean SNR {:.1} dB, mean sync {:.2}, worst: {}",
+            self.usable_start,
+            self.usable_end,
+            self.lines.len(),
+            self.mean_snr_db,
+            self.mean_sync_score,
+            if worst.is_empty() { "-" } else { &worst }
+        )
+    }
+
+    pub fn write_json(&self, path: &str) -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{{")?;
+        writeln!(file, "  \"total_lines\": {},", self.lines.len())?;
+        writeln!(
+            file,
+            "  \"usable_lines\": {{ \"start\": {}, \"end\": {} }},",
+            self.usable_start, self.usable_end
+        )?;
+        writeln!(file, "  \"mean_snr_db\": {:.3},", self.mean_snr_db)?;
+        writeln!(file, "  \"mean_sync_score\": {:.4},", self.mean_sync_score)?;
+        let segments = self
+            .worst_segments
+            .iter()
+            .map(|s| {
+                format!(
+                    "    {{ \"start\": {}, \"end\": {}, \"snr_db\": {:.3} }}",
+                    s.start, s.end, s.snr_db
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n");
+        writeln!(file, "  \"worst_segments\": [\n{}\n  ],", segments)?;
+        let lines = self
+            .lines
+            .iter()
+            .map(|l| {
+                format!(
+                    "    {{ \"sync_score\": {:.4}, \"snr_db\": {:.3}, \"noise\": {:.3} }}",
+                    l.sync_score, l.snr_db, l.noise
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",\n");
+        writeln!(file, "  \"lines\": [\n{}\n  ]", lines)?;
+        writeln!(file, "}}")?;
+        Ok(())
+    }
+
+    /// Plot SNR (green) and sync score (yellow) against the line number,
+    /// with the usable range shaded.
+    pub fn plot(&self) -> RgbImage {
+        let mut image: RgbImage =
+            ImageBuffer::from_pixel(PLOT_WIDTH, PLOT_HEIGHT, Rgb([20, 20, 20]));
+        let total = self.lines.len().max(1);
+        let column = |line: usize| (line * PLOT_WIDTH as usize / total) as u32;
+        let row =
+            |fraction: f32| ((1.0 - fraction.clamp(0.0, 1.0)) * (PLOT_HEIGHT - 1) as f32) as u32;
+
+        for x in column(self.usable_start)..column(self.usable_end).min(PLOT_WIDTH) {
+            for y in 0..PLOT_HEIGHT {
+                image.put_pixel(x, y, Rgb([40, 40, 60]));
+            }
+        }
+        for segment in &self.worst_segments {
+            for x in column(segment.start)..column(segment.end).min(PLOT_WIDTH) {
+                for y in 0..PLOT_HEIGHT {
+                    image.put_pixel(x, y, Rgb([80, 30, 30]));
+                }
+            }
+        }
+
+        for (i, line) in self.lines.iter().enumerate() {
+            let x = column(i).min(PLOT_WIDTH - 1);
+            image.put_pixel(x, row(line.sync_score), Rgb([230, 200, 0]));
+            image.put_pixel(x, row(line.snr_db / MAX_PLOT_SNR_DB), Rgb([0, 220, 0]));
+        }
+
+        legend::draw_text(&mut image, 4, 4, "SNR 0-40 DB", 2, Rgb([0, 220, 0]));
+        legend::draw_text(&mut image, 4, 18, "SYNC 0-1", 2, Rgb([230, 200, 0]));
+        image
+    }
+
+    /// Save the JSON report and the plot, returning the plot path.
+    pub fn save(&self) -> Result<String, String> {
+        let json_path = REPORT_PATH;
+        println!("Saving quality report to: {}", json_path);
+        self.write_json(json_path).map_err(|e| e.to_string())?;
+
+        let plot_path = String::from("quality_report.png");
+        println!("Saving quality plot to: {}", plot_path);
+        self.plot().save(&plot_path).map_err(|e| e.to_string())?;
+        Ok(plot_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(sync_score: f32, snr_db: f32) -> LineQuality {
+        LineQuality {
+            sync_score,
+            snr_db,
+            noise: 1.0,
+        }
+    }
+
+    #[test]
+    fn line_snr_compares_sync_contrast_to_space_noise() {
+        // Sync A square wave of amplitude 1 from sample 10390, wrapping around the line,
+        // then a space view with +-0.01 of noise
+        let sync_start = 10390;
+        let mut row = vec![0.0; 10400];
+        for i in 0..SYNC_SAMPLES + SPACE_SAMPLES {
+            let value = if i < SYNC_SAMPLES {
+                if i % 20 < 10 {
+                    1.0
+                } else {
+                    0.0
+                }
+            } else if i % 2 == 0 {
+                0.51
+            } else {
+                0.49
+            };
+            row[(sync_start + i) % 10400] = value;
+        }
+
+        let quality = line_quality(&row, sync_start, 0.8);
+        assert!(
+            (quality.noise - 0.01).abs() < 1e-4,
+            "noise {}",
+            quality.noise
+        );
+        assert!(
+            (quality.snr_db - 40.0).abs() < 0.5,
+            "SNR {}",
+            quality.snr_db
+        );
+        assert_eq!(line_quality(&row, sync_start, f32::NAN).sync_score, 0.0);
+    }
+
+    #[test]
+    fn report_finds_usable_range_and_worst_segments() {
+        // Noise before line 20 and after line 180, a fade at lines 96-127
+        let lines: Vec<LineQuality> = (0..200)
+            .map(|i| match i {
+                0..20 | 180.. => line(0.1, 0.0),
+                96..128 => line(0.9, 5.0),
+                64..96 => line(0.9, 12.0),
+                _ => line(0.9, 20.0),
+            })
+            .collect();
+        let report = QualityReport::new(lines);
+        assert_eq!((report.usable_start, report.usable_end), (20, 180));
+        assert_eq!(report.worst_segments.len(), WORST_SEGMENTS);
+        // Segments start at the usable start, so the fade spans two of them
+        let worst = &report.worst_segments[0];
+        assert!(worst.start >= 84 && worst.end <= 148, "{:?}", worst);
+        assert!(report
+            .worst_segments
+            .windows(2)
+            .all(|pair| pair[0].snr_db <= pair[1].snr_db));
+        assert!((report.mean_sync_score - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn report_of_noise_only_is_empty() {
+        let report = QualityReport::new(vec![line(0.0, 0.0); 50]);
+        assert_eq!((report.usable_start, report.usable_end), (0, 0));
+        assert!(report.worst_segments.is_empty());
+        assert_eq!(report.mean_snr_db, 0.0);
+    }
+}
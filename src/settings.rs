@@ -1,3 +1,8 @@
+use crate::augment::{Augmentation, Combine};
+use crate::calibration::Satellite;
+use crate::color::Palette;
+use crate::inference::{Backend, ExecutionProvider};
+use crate::products::Product;
 use crate::settings_logic::connect_settings_logic;
 use crate::ui_elements::UiElements;
 
@@ -5,37 +10,115 @@ use std::sync::Arc;
 use std::sync::Mutex;
 
 pub struct FunctionsSettings {
+    // Decode even when the input diagnostics find nothing decodable
+    pub force_decode: bool,
+    // Audio cleanup settings
+    pub impulse_blanker: bool,
+    pub notch_filter: bool,
+    pub noise_reduction: bool,
     //Low pass filter settings
     pub cutoff_freq: f32,
     // Sync apt settings
     pub additional_offset: usize,
+    // Trim settings, line_range (first, last exclusive) overrides the automatic trim
+    pub auto_trim: bool,
+    pub trim_margin: usize,
+    pub line_range: Option<(usize, usize)>,
     // Envelope detection settings
     pub window_size: usize,
     pub scaling_factor: f32,
+    // Dropout settings
+    pub repair_dropouts: bool,
+    pub inpaint_model_path: String,
     // Enhance image settings
     pub cpu_threads: usize,
+    // Installed enhancement model to use, the active one of the registry when None
+    pub enhance_model: Option<String>,
+    // Model download settings, empty when unset
+    pub model_mirror: String,
+    pub download_proxy: String,
+    pub model_sha256: String,
+    pub execution_provider: ExecutionProvider,
+    pub backend: Backend,
+    // Pixels shared by neighboring model patches, blended to hide seams
+    pub tile_overlap: usize,
+    // Patches per model call
+    pub batch_size: usize,
+    // Test-time augmentation of the model patches
+    pub augmentation: Augmentation,
+    pub augmentation_combine: Combine,
     // SGBNR settings
     pub blur_sigma: f32,
     pub brightness_threshold: f32,
     pub noise_threshold: f32,
     pub sharpen_sigma: f32,
     pub sharpen_threshold: i32,
+    // Radius of the window whose mean and deviation select the pixels to blur
+    pub sgbnr_radius: u32,
+    // Width in pixel levels of the soft switch between kept and blurred pixels
+    pub sgbnr_transition: f32,
+    // Save the blend mask next to the SGBNR output
+    pub sgbnr_export_mask: bool,
+    // Estimate the brightness and noise thresholds from every image instead
+    pub sgbnr_auto_thresholds: bool,
+    // Color settings
+    pub palette: Option<Palette>,
+    // Product settings
+    pub products: Vec<Product>,
+    pub satellite: Satellite,
+    // Cloud mask settings
+    pub cloud_mask: bool,
+    pub cloud_visible_threshold: f32,
+    pub cloud_infrared_threshold: f32,
+    pub cloud_uniformity_threshold: f32,
+    pub cloud_model_path: String,
 }
 
 impl FunctionsSettings {
     pub fn new(ui_elements: &UiElements) -> Arc<Mutex<Self>> {
         // Create instance with default values
         let settings = Arc::new(Mutex::new(Self {
+            force_decode: false,
+            impulse_blanker: false,
+            notch_filter: false,
+            noise_reduction: false,
             cutoff_freq: 5000.0,
             additional_offset: 120,
+            auto_trim: true,
+            trim_margin: 20,
+            line_range: None,
             window_size: 10,
             scaling_factor: 2.5,
+            repair_dropouts: false,
+            inpaint_model_path: String::new(),
             cpu_threads: 1,
+            enhance_model: None,
+            model_mirror: String::new(),
+            download_proxy: String::new(),
+            model_sha256: String::new(),
+            execution_provider: ExecutionProvider::default(),
+            backend: Backend::default(),
+            tile_overlap: 32,
+            batch_size: 4,
+            augmentation: Augmentation::Off,
+            augmentation_combine: Combine::Mean,
             blur_sigma: 8.0,
             brightness_threshold: 5.0,
             noise_threshold: 27.5,
             sharpen_sigma: 1.5,
             sharpen_threshold: 5,
+            sgbnr_radius: 1,
+            sgbnr_transition: 4.0,
+            sgbnr_export_mask: false,
+            sgbnr_auto_thresholds: false,
+            palette: None,
+            products: Vec::new(),
+            satellite: Satellite::Noaa19,
+            cloud_mask: false,
+            cloud_visible_threshold: 30.0,
+            cloud_infrared_threshold: 265.0,
+            cloud_uniformity_threshold: 1.5,
+            cloud_model_path: String::new(),
         }));
         // Connect UI elements to settings
         connect_settings_logic(ui_elements, &settings);
@@ -45,16 +128,47 @@ impl FunctionsSettings {
 
     pub fn new_without_ui() -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(Self {
+            force_decode: false,
+            impulse_blanker: false,
+            notch_filter: false,
+            noise_reduction: false,
             cutoff_freq: 5000.0,
             additional_offset: 120,
+            auto_trim: true,
+            trim_margin: 20,
+            line_range: None,
             window_size: 10,
             scaling_factor: 2.5,
+            repair_dropouts: false,
+            inpaint_model_path: String::new(),
             cpu_threads: 1,
+            enhance_model: None,
+            model_mirror: String::new(),
+            download_proxy: String::new(),
+            model_sha256: String::new(),
+            execution_provider: ExecutionProvider::default(),
+            backend: Backend::default(),
+            tile_overlap: 32,
+            batch_size: 4,
+            augmentation: Augmentation::Off,
+            augmentation_combine: Combine::Mean,
             blur_sigma: 8.0,
             brightness_threshold: 5.0,
             noise_threshold: 27.5,
             sharpen_sigma: 1.5,
             sharpen_threshold: 5,
+            sgbnr_radius: 1,
+            sgbnr_transition: 4.0,
+            sgbnr_export_mask: false,
+            sgbnr_auto_thresholds: false,
+            palette: None,
+            products: Vec::new(),
+            satellite: Satellite::Noaa19,
+            cloud_mask: false,
+            cloud_visible_threshold: 30.0,
+            cloud_infrared_threshold: 265.0,
+            cloud_uniformity_threshold: 1.5,
+            cloud_model_path: String::new(),
         }))
     }
 }
@@ -1,10 +1,63 @@
+use crate::augment::{Augmentation, Combine};
+use crate::calibration::Satellite;
+use crate::color::Palette;
+use crate::inference::{Backend, ExecutionProvider};
+use crate::models::Registry;
+use crate::products::Product;
 use crate::settings::FunctionsSettings;
 use crate::ui_elements::UiElements;
 
 use glib_macros::clone;
+use gtk4::prelude::*;
 use std::sync::{Arc, Mutex};
 
 pub fn connect_settings_logic(ui_elements: &UiElements, settings: &Arc<Mutex<FunctionsSettings>>) {
+    // Input check settings
+    ui_elements.checkbox_force_decode.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.force_decode = checkbox.is_active();
+                println!("Force decode set to: {}", s.force_decode);
+            }
+        }
+    ));
+
+    // Audio cleanup settings
+    ui_elements.checkbox_impulse_blanker.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.impulse_blanker = checkbox.is_active();
+                println!("Impulse blanker set to: {}", s.impulse_blanker);
+            }
+        }
+    ));
+
+    ui_elements.checkbox_notch_filter.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.notch_filter = checkbox.is_active();
+                println!("Notch filter set to: {}", s.notch_filter);
+            }
+        }
+    ));
+
+    ui_elements.checkbox_noise_reduction.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.noise_reduction = checkbox.is_active();
+                println!("Noise reduction set to: {}", s.noise_reduction);
+            }
+        }
+    ));
+
     // Cutoff frequency settings
     ui_elements
         .cutoff_frequency_spinbutton
@@ -33,6 +86,69 @@ pub fn connect_settings_logic(ui_elements: &UiElements, settings: &Arc<Mutex<Fun
             }
         ));
 
+    // Trim settings
+    ui_elements.checkbox_auto_trim.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.auto_trim = checkbox.is_active();
+                println!("Auto trim set to: {}", s.auto_trim);
+            }
+        }
+    ));
+
+    ui_elements
+        .trim_margin_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.trim_margin = spin_button.value() as usize;
+                    println!("Trim margin set to: {}", s.trim_margin);
+                }
+            }
+        ));
+
+    // Manual crop, the handles override the automatic trim while the checkbox is active
+    let update_line_range = clone!(
+        #[strong]
+        settings,
+        #[strong(rename_to = checkbox)]
+        ui_elements.checkbox_manual_crop,
+        #[strong(rename_to = first_scale)]
+        ui_elements.crop_first_scale,
+        #[strong(rename_to = last_scale)]
+        ui_elements.crop_last_scale,
+        move || {
+            let manual = checkbox.is_active();
+            first_scale.set_sensitive(manual);
+            last_scale.set_sensitive(manual);
+            if let Ok(mut s) = settings.lock() {
+                s.line_range = manual.then(|| {
+                    let first = first_scale.value() as usize;
+                    // At least one line is kept
+                    (first, (last_scale.value() as usize).max(first + 1))
+                });
+                println!("Line range set to: {:?}", s.line_range);
+            }
+        }
+    );
+    ui_elements.checkbox_manual_crop.connect_toggled(clone!(
+        #[strong]
+        update_line_range,
+        move |_| update_line_range()
+    ));
+    ui_elements.crop_first_scale.connect_value_changed(clone!(
+        #[strong]
+        update_line_range,
+        move |_| update_line_range()
+    ));
+    ui_elements
+        .crop_last_scale
+        .connect_value_changed(move |_| update_line_range());
+
     // Window size settings
     ui_elements
         .window_size_spinbutton
@@ -61,6 +177,29 @@ pub fn connect_settings_logic(ui_elements: &UiElements, settings: &Arc<Mutex<Fun
             }
         ));
 
+    // Dropout settings
+    ui_elements.checkbox_repair_dropouts.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.repair_dropouts = checkbox.is_active();
+                println!("Repair dropouts set to: {}", s.repair_dropouts);
+            }
+        }
+    ));
+
+    ui_elements.inpaint_model_entry.connect_changed(clone!(
+        #[strong]
+        settings,
+        move |entry| {
+            if let Ok(mut s) = settings.lock() {
+                s.inpaint_model_path = entry.text().to_string();
+                println!("Inpainting model set to: {}", s.inpaint_model_path);
+            }
+        }
+    ));
+
     // CPU threads settings
     ui_elements
         .cpu_threads_spinbutton
@@ -75,6 +214,155 @@ pub fn connect_settings_logic(ui_elements: &UiElements, settings: &Arc<Mutex<Fun
             }
         ));
 
+    // Tile overlap settings
+    ui_elements
+        .tile_overlap_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.tile_overlap = spin_button.value() as usize;
+                    println!("Tile overlap set to: {}", s.tile_overlap);
+                }
+            }
+        ));
+
+    // Batch size settings
+    ui_elements
+        .batch_size_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.batch_size = spin_button.value() as usize;
+                    println!("Batch size set to: {}", s.batch_size);
+                }
+            }
+        ));
+
+    // Test-time augmentation settings
+    ui_elements
+        .augmentation_dropdown
+        .connect_selected_notify(clone!(
+            #[strong]
+            settings,
+            move |dropdown| {
+                if let Ok(mut s) = settings.lock() {
+                    s.augmentation = Augmentation::from_index(dropdown.selected());
+                    println!("Test-time augmentation set to: {}", s.augmentation.name());
+                }
+            }
+        ));
+
+    ui_elements
+        .augmentation_combine_dropdown
+        .connect_selected_notify(clone!(
+            #[strong]
+            settings,
+            move |dropdown| {
+                if let Ok(mut s) = settings.lock() {
+                    s.augmentation_combine = Combine::from_index(dropdown.selected());
+                    println!(
+                        "Augmentation combination set to: {}",
+                        s.augmentation_combine.name()
+                    );
+                }
+            }
+        ));
+
+    // Execution provider settings
+    ui_elements
+        .execution_provider_dropdown
+        .connect_selected_notify(clone!(
+            #[strong]
+            settings,
+            move |dropdown| {
+                if let Ok(mut s) = settings.lock() {
+                    s.execution_provider = ExecutionProvider::from_index(dropdown.selected());
+                    println!("Execution provider set to: {}", s.execution_provider.name());
+                }
+            }
+        ));
+
+    ui_elements.backend_dropdown.connect_selected_notify(clone!(
+        #[strong]
+        settings,
+        move |dropdown| {
+            if let Ok(mut s) = settings.lock() {
+                s.backend = Backend::from_index(dropdown.selected());
+                println!("Inference backend set to: {}", s.backend.name());
+            }
+        }
+    ));
+
+    // Enhancement model settings
+    ui_elements.model_dropdown.connect_selected_notify(clone!(
+        #[strong]
+        settings,
+        #[strong(rename_to = details_label)]
+        ui_elements.model_details_label,
+        move |dropdown| {
+            let name = dropdown
+                .selected_item()
+                .and_downcast::<gtk4::StringObject>()
+                .map(|item| item.string().to_string());
+            let Some(name) = name else {
+                details_label.set_text("No model installed, model.onnx is used");
+                return;
+            };
+            // The choice is kept as the registry's active model for the next start
+            let mut registry = Registry::load().unwrap_or_default();
+            if let Some(entry) = registry.find(&name) {
+                details_label.set_text(&entry.summary());
+            }
+            if registry.active.as_deref() != Some(name.as_str()) {
+                if let Err(e) = registry.set_active(&name).and_then(|_| registry.save()) {
+                    eprintln!("Error saving the active model: {}", e);
+                }
+            }
+            if let Ok(mut s) = settings.lock() {
+                println!("Enhancement model set to: {}", name);
+                s.enhance_model = Some(name);
+            }
+        }
+    ));
+
+    // Model download settings
+    ui_elements.model_mirror_entry.connect_changed(clone!(
+        #[strong]
+        settings,
+        move |entry| {
+            if let Ok(mut s) = settings.lock() {
+                s.model_mirror = entry.text().to_string();
+                println!("Model mirror set to: {}", s.model_mirror);
+            }
+        }
+    ));
+
+    ui_elements.download_proxy_entry.connect_changed(clone!(
+        #[strong]
+        settings,
+        move |entry| {
+            if let Ok(mut s) = settings.lock() {
+                s.download_proxy = entry.text().to_string();
+                println!("Download proxy set to: {}", s.download_proxy);
+            }
+        }
+    ));
+
+    ui_elements.model_sha256_entry.connect_changed(clone!(
+        #[strong]
+        settings,
+        move |entry| {
+            if let Ok(mut s) = settings.lock() {
+                s.model_sha256 = entry.text().to_string();
+                println!("Model SHA-256 set to: {}", s.model_sha256);
+            }
+        }
+    ));
+
     // Blur sigma settings
     ui_elements
         .blur_sigma_spinbutton
@@ -144,4 +432,199 @@ pub fn connect_settings_logic(ui_elements: &UiElements, settings: &Arc<Mutex<Fun
                 }
             }
         ));
+
+    // SGBNR window radius settings
+    ui_elements
+        .sgbnr_radius_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.sgbnr_radius = spin_button.value() as u32;
+                    println!("SGBNR window radius set to: {}", s.sgbnr_radius);
+                }
+            }
+        ));
+
+    // SGBNR transition width settings
+    ui_elements
+        .sgbnr_transition_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.sgbnr_transition = spin_button.value() as f32;
+                    println!("SGBNR transition width set to: {}", s.sgbnr_transition);
+                }
+            }
+        ));
+
+    // The estimated thresholds replace the ones set by hand
+    let brightness_threshold_spinbutton = ui_elements.brightness_threshold_spinbutton.clone();
+    let noise_threshold_spinbutton = ui_elements.noise_threshold_spinbutton.clone();
+    ui_elements
+        .checkbox_sgbnr_auto_thresholds
+        .connect_toggled(clone!(
+            #[strong]
+            settings,
+            move |checkbox| {
+                let auto = checkbox.is_active();
+                brightness_threshold_spinbutton.set_sensitive(!auto);
+                noise_threshold_spinbutton.set_sensitive(!auto);
+                if let Ok(mut s) = settings.lock() {
+                    s.sgbnr_auto_thresholds = auto;
+                    println!("SGBNR auto thresholds set to: {}", s.sgbnr_auto_thresholds);
+                }
+            }
+        ));
+
+    ui_elements
+        .checkbox_sgbnr_export_mask
+        .connect_toggled(clone!(
+            #[strong]
+            settings,
+            move |checkbox| {
+                if let Ok(mut s) = settings.lock() {
+                    s.sgbnr_export_mask = checkbox.is_active();
+                    println!("SGBNR mask export set to: {}", s.sgbnr_export_mask);
+                }
+            }
+        ));
+
+    // Palette settings
+    let custom_lut_entry = ui_elements.custom_lut_entry.clone();
+    ui_elements.palette_dropdown.connect_selected_notify(clone!(
+        #[strong]
+        settings,
+        move |dropdown| {
+            if let Ok(mut s) = settings.lock() {
+                s.palette = Palette::from_index(dropdown.selected(), &custom_lut_entry.text());
+                println!("Palette set to: {:?}", s.palette);
+            }
+        }
+    ));
+
+    // Custom LUT settings
+    let palette_dropdown = ui_elements.palette_dropdown.clone();
+    ui_elements.custom_lut_entry.connect_changed(clone!(
+        #[strong]
+        settings,
+        move |entry| {
+            if let Ok(mut s) = settings.lock() {
+                s.palette = Palette::from_index(palette_dropdown.selected(), &entry.text());
+                println!("Palette set to: {:?}", s.palette);
+            }
+        }
+    ));
+
+    // Products settings
+    for checkbutton in &ui_elements.product_checkbuttons {
+        let product_checkbuttons = ui_elements.product_checkbuttons.clone();
+        checkbutton.connect_toggled(clone!(
+            #[strong]
+            settings,
+            move |_| {
+                if let Ok(mut s) = settings.lock() {
+                    s.products = Product::ALL
+                        .iter()
+                        .zip(&product_checkbuttons)
+                        .filter(|(_, checkbutton)| checkbutton.is_active())
+                        .map(|(product, _)| *product)
+                        .collect();
+                    println!("Products set to: {:?}", s.products);
+                }
+            }
+        ));
+    }
+
+    // Satellite settings
+    ui_elements
+        .satellite_dropdown
+        .connect_selected_notify(clone!(
+            #[strong]
+            settings,
+            move |dropdown| {
+                if let Ok(mut s) = settings.lock() {
+                    s.satellite = Satellite::from_index(dropdown.selected());
+                    println!("Satellite set to: {:?}", s.satellite);
+                }
+            }
+        ));
+
+    // Cloud mask settings
+    ui_elements.checkbox_cloud_mask.connect_toggled(clone!(
+        #[strong]
+        settings,
+        move |checkbox| {
+            if let Ok(mut s) = settings.lock() {
+                s.cloud_mask = checkbox.is_active();
+                println!("Cloud mask set to: {}", s.cloud_mask);
+            }
+        }
+    ));
+
+    // Cloud visible threshold settings
+    ui_elements
+        .cloud_visible_threshold_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.cloud_visible_threshold = spin_button.value() as f32;
+                    println!(
+                        "Cloud visible threshold set to: {}",
+                        s.cloud_visible_threshold
+                    );
+                }
+            }
+        ));
+
+    // Cloud infrared threshold settings
+    ui_elements
+        .cloud_infrared_threshold_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.cloud_infrared_threshold = spin_button.value() as f32;
+                    println!(
+                        "Cloud infrared threshold set to: {}",
+                        s.cloud_infrared_threshold
+                    );
+                }
+            }
+        ));
+
+    // Cloud uniformity threshold settings
+    ui_elements
+        .cloud_uniformity_threshold_spinbutton
+        .connect_value_changed(clone!(
+            #[strong]
+            settings,
+            move |spin_button| {
+                if let Ok(mut s) = settings.lock() {
+                    s.cloud_uniformity_threshold = spin_button.value() as f32;
+                    println!(
+                        "Cloud uniformity threshold set to: {}",
+                        s.cloud_uniformity_threshold
+                    );
+                }
+            }
+        ));
+
+    // Cloud classifier model settings
+    ui_elements.cloud_model_entry.connect_changed(clone!(
+        #[strong]
+        settings,
+        move |entry| {
+            if let Ok(mut s) = settings.lock() {
+                s.cloud_model_path = entry.text().to_string();
+                println!("Cloud model set to: {}", s.cloud_model_path);
+            }
+        }
+    ));
 }
@@ -0,0 +1,130 @@
+use crate::apt;
+
+use image::GrayImage;
+
+// A telemetry frame is 16 wedges of 8 lines each, repeated every 128 lines
+pub const TELEMETRY_WIDTH: u32 = 45;
+pub const WEDGE_HEIGHT: usize = 8;
+pub const WEDGE_COUNT: usize = 16;
+const FRAME_HEIGHT: usize = WEDGE_HEIGHT * WEDGE_COUNT;
+// Columns ignored on each side of the telemetry strip (blurred by resampling)
+const TELEMETRY_MARGIN: u32 = 8;
+
+// Wedge indices (0-based)
+pub const ZERO_MODULATION_WEDGE: usize = 8;
+pub const PRT_WEDGES: [usize; 4] = [9, 10, 11, 12];
+pub const BACK_SCAN_WEDGE: usize = 14;
+
+pub struct Telemetry {
+    // Mean image value of each wedge
+    pub wedges: [f32; WEDGE_COUNT],
+}
+
+/// Nominal value of the grey scale wedges 1-8 (and 9, zero modulation) on a 0-255 scale.
+fn reference_wedge(index: usize) -> f32 {
+    if index < ZERO_MODULATION_WEDGE {
+        (index + 1) as f32 * 255.0 / 8.0
+    } else {
+        0.0
+    }
+}
+
+/// Mean value of the telemetry strip of `channel` (0 = A, 1 = B) for every line.
+fn telemetry_lines(image: &GrayImage, sync_column: u32, channel: u32) -> Vec<f32> {
+    let start = apt::channel_start(sync_column, channel) + apt::CHANNEL_WIDTH + TELEMETRY_MARGIN;
+    let width = TELEMETRY_WIDTH - 2 * TELEMETRY_MARGIN;
+    let strip = apt::extract_columns(image, start, width);
+
+    strip
+        .rows()
+        .map(|row| row.map(|p| p[0] as f32).sum::<f32>() / width as f32)
+        .collect()
+}
+
+/// Average every wedge over all complete frames starting at `phase`.
+/// Only the inner lines of each wedge are used to avoid transitions.
+fn wedge_means(lines: &[f32], phase: usize) -> [f32; WEDGE_COUNT] {
+    let mut sums = [0.0; WEDGE_COUNT];
+    let mut counts = [0usize; WEDGE_COUNT];
+
+    for (i, value) in lines.iter().enumerate().skip(phase) {
+        let position = (i - phase) % FRAME_HEIGHT;
+        let line_in_wedge = position % WEDGE_HEIGHT;
+        if line_in_wedge == 0 || line_in_wedge == WEDGE_HEIGHT - 1 {
+            continue;
+        }
+        let wedge = position / WEDGE_HEIGHT;
+        sums[wedge] += value;
+        counts[wedge] += 1;
+    }
+
+    let mut means = [0.0; WEDGE_COUNT];
+    for wedge in 0..WEDGE_COUNT {
+        means[wedge] = sums[wedge] / counts[wedge].max(1) as f32;
+    }
+    means
+}
+
+/// Correlation between the measured grey scale wedges and their nominal values.
+fn grey_scale_score(means: &[f32; WEDGE_COUNT]) -> f32 {
+    let n = (ZERO_MODULATION_WEDGE + 1) as f32;
+    let mean_measured = means[..=ZERO_MODULATION_WEDGE].iter().sum::<f32>() / n;
+    let mean_reference = (0..=ZERO_MODULATION_WEDGE)
+        .map(reference_wedge)
+        .sum::<f32>()
+        / n;
+
+    let (mut covariance, mut var_measured, mut var_reference) = (0.0, 0.0, 0.0);
+    for (wedge, measured) in means.iter().enumerate().take(ZERO_MODULATION_WEDGE + 1) {
+        let dm = measured - mean_measured;
+        let dr = reference_wedge(wedge) - mean_reference;
+        covariance += dm * dr;
+        var_measured += dm * dm;
+        var_reference += dr * dr;
+    }
+
+    if var_measured <= 0.0 {
+        return f32::MIN;
+    }
+    covariance / (var_measured.sqrt() * var_reference.sqrt())
+}
+
+/// Locate the telemetry frame of `channel` and read its 16 wedges.
+/// Returns None if the image holds less than two frames.
+pub fn read_telemetry(image: &GrayImage, sync_column: u32, channel: u32) -> Option<Telemetry> {
+    let lines = telemetry_lines(image, sync_column, channel);
+    if lines.len() < 2 * FRAME_HEIGHT {
+        return None;
+    }
+
+    let (frame_start, wedges) = (0..FRAME_HEIGHT)
+        .map(|phase| (phase, wedge_means(&lines, phase)))
+        .max_by(|(_, a), (_, b)| grey_scale_score(a).total_cmp(&grey_scale_score(b)))?;
+
+    println!("Telemetry frame starts at line {}", frame_start);
+    println!("Telemetry wedges: {:?}", wedges);
+
+    Some(Telemetry { wedges })
+}
+
+impl Telemetry {
+    /// Linear map from image values to nominal 0-255 values fitted on the grey scale wedges.
+    pub fn grey_scale_fit(&self) -> (f32, f32) {
+        let n = (ZERO_MODULATION_WEDGE + 1) as f32;
+        let xs = &self.wedges[..=ZERO_MODULATION_WEDGE];
+        let mean_x = xs.iter().sum::<f32>() / n;
+        let mean_y = (0..=ZERO_MODULATION_WEDGE)
+            .map(reference_wedge)
+            .sum::<f32>()
+            / n;
+
+        let (mut sxy, mut sxx) = (0.0, 0.0);
+        for (wedge, x) in xs.iter().enumerate() {
+            sxy += (x - mean_x) * (reference_wedge(wedge) - mean_y);
+            sxx += (x - mean_x) * (x - mean_x);
+        }
+
+        let gain = if sxx > 0.0 { sxy / sxx } else { 1.0 };
+        (gain, mean_y - gain * mean_x)
+    }
+}
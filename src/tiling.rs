@@ -0,0 +1,324 @@
+use image::{GrayImage, Luma};
+use rayon::prelude::*;
+use std::error::Error;
+
+/// Mirror an index outside 0..length back into the image (edge pixel not repeated).
+fn reflect(index: i64, length: u32) -> u32 {
+    let length = length as i64;
+    if length == 1 {
+        return 0;
+    }
+    let period = 2 * (length - 1);
+    let i = index.rem_euclid(period);
+    (if i < length { i } else { period - i }) as u32
+}
+
+/// Tile origins along one axis. The image is extended by half the overlap on each side
+/// so that border pixels are not left on the edge of a tile either.
+fn origins(length: u32, patch_size: usize, overlap: usize) -> Vec<i64> {
+    let margin = (overlap / 2) as i64;
+    let step = (patch_size - overlap).max(1) as i64;
+    let end = length as i64 + margin;
+    let mut origins = Vec::new();
+    let mut origin = -margin;
+    loop {
+        origins.push(origin);
+        if origin + patch_size as i64 >= end {
+            break;
+        }
+        // The last tile is moved back to end exactly on the extended border
+        origin = (origin + step).min(end - patch_size as i64);
+    }
+    origins
+}
+
+/// Number of patches run_tiled feeds the model for an image of `dimensions`.
+pub fn tile_count(dimensions: (u32, u32), patch_size: usize, overlap: usize) -> usize {
+    let overlap = overlap.min(patch_size / 2);
+    origins(dimensions.0, patch_size, overlap).len()
+        * origins(dimensions.1, patch_size, overlap).len()
+}
+
+/// Blending weight across one tile: a Hann taper over the `overlap` pixels at each
+/// end and 1 in between. Tapers of neighboring tiles sum to 1 in their overlap.
+fn taper(patch_size: usize, overlap: usize) -> Vec<f32> {
+    (0..patch_size)
+        .map(|i| {
+            let distance = i.min(patch_size - 1 - i);
+            if distance >= overlap {
+                1.0
+            } else {
+                let t = (distance as f32 + 0.5) / overlap as f32;
+                (std::f32::consts::FRAC_PI_2 * t).sin().powi(2)
+            }
+        })
+        .collect()
+}
+
+/// Run `infer` on square patches in [0, 1] covering the image with `overlap` pixels
+/// shared between neighbors, reflecting the image at its borders, and blend the
+/// overlapping outputs so that no seams show at tile boundaries. `infer` gets up to
+/// `batch_size` patches laid out one after the other, with their count, and returns
+/// the outputs in the same layout, each `scale` times the patch side. The outputs are
+/// placed and blended in output coordinates, so the result is `scale` times the image.
+/// Batches run in parallel and every output is kept until the blending, nothing is
+/// locked.
+pub fn run_tiled<F>(
+    image: &GrayImage,
+    patch_size: usize,
+    scale: usize,
+    overlap: usize,
+    batch_size: usize,
+    infer: F,
+) -> Result<GrayImage, Box<dyn Error + Send + Sync>>
+where
+    F: Fn(&[f32], usize) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> + Sync,
+{
+    run_tiled_channels(&[image], patch_size, scale, overlap, batch_size, infer)
+}
+
+/// Same as run_tiled for a model taking several images of the same size as channels:
+/// every patch holds one plane per channel, in the order of `channels`, and the model
+/// still returns a single plane per patch.
+pub fn run_tiled_channels<F>(
+    channels: &[&GrayImage],
+    patch_size: usize,
+    scale: usize,
+    overlap: usize,
+    batch_size: usize,
+    infer: F,
+) -> Result<GrayImage, Box<dyn Error + Send + Sync>>
+where
+    F: Fn(&[f32], usize) -> Result<Vec<f32>, Box<dyn Error + Send + Sync>> + Sync,
+{
+    let (width, height) = channels.first().ok_or("No channel to tile")?.dimensions();
+    if channels.iter().any(|c| c.dimensions() != (width, height)) {
+        return Err("The channels differ in size".into());
+    }
+    let overlap = overlap.min(patch_size / 2);
+    let scale = scale.max(1);
+    let tiles: Vec<(i64, i64)> = origins(height, patch_size, overlap)
+        .into_iter()
+        .flat_map(|y| {
+            origins(width, patch_size, overlap)
+                .into_iter()
+                .map(move |x| (x, y))
+        })
+        .collect();
+
+    let output_size = patch_size * scale;
+    let output_pixels = output_size * output_size;
+    let outputs = tiles
+        .par_chunks(batch_size.max(1))
+        .map(|batch| {
+            let patches: Vec<f32> = batch
+                .iter()
+                .flat_map(|&(x0, y0)| {
+                    channels.iter().flat_map(move |channel| {
+                        (0..patch_size as i64).flat_map(move |y| {
+                            (0..patch_size as i64).map(move |x| {
+                                let pixel = channel
+                                    .get_pixel(reflect(x0 + x, width), reflect(y0 + y, height));
+                                pixel[0] as f32 / 255.0
+                            })
+                        })
+                    })
+                })
+                .collect();
+            let output = infer(&patches, batch.len())?;
+            if output.len() != batch.len() * output_pixels {
+                return Err(format!(
+                    "Expected {} output values per batch, got {}",
+                    batch.len() * output_pixels,
+                    output.len()
+                )
+                .into());
+            }
+            Ok(output)
+        })
+        .collect::<Result<Vec<Vec<f32>>, Box<dyn Error + Send + Sync>>>()?;
+    let outputs: Vec<&[f32]> = outputs
+        .iter()
+        .flat_map(|batch| batch.chunks_exact(output_pixels))
+        .collect();
+
+    // Weighted sum of every tile covering a pixel, in output coordinates
+    let (width, height) = (width * scale as u32, height * scale as u32);
+    let weights = taper(output_size, overlap * scale);
+    let mut sum = vec![0.0f32; (width * height) as usize];
+    let mut total = vec![0.0f32; (width * height) as usize];
+    for (&(x0, y0), output) in tiles.iter().zip(&outputs) {
+        let (x0, y0) = (x0 * scale as i64, y0 * scale as i64);
+        for y in 0..output_size {
+            let image_y = y0 + y as i64;
+            if image_y < 0 || image_y >= height as i64 {
+                continue;
+            }
+            for x in 0..output_size {
+                let image_x = x0 + x as i64;
+                if image_x < 0 || image_x >= width as i64 {
+                    continue;
+                }
+                let weight = weights[x] * weights[y];
+                let index = image_y as usize * width as usize + image_x as usize;
+                sum[index] += weight * output[y * output_size + x];
+                total[index] += weight;
+            }
+        }
+    }
+
+    Ok(GrayImage::from_fn(width, height, |x, y| {
+        let index = (y * width + x) as usize;
+        let value = sum[index] / total[index].max(f32::EPSILON);
+        Luma([(value * 255.0).round().clamp(0.0, 255.0) as u8])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH_SIZE: usize = 64;
+
+    fn gradient(width: u32, height: u32) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            Luma([((x * 255 / width + y * 255 / height) / 2) as u8])
+        })
+    }
+
+    /// Largest step between horizontally adjacent pixels.
+    fn max_step(image: &GrayImage) -> i32 {
+        let (width, height) = image.dimensions();
+        (0..height)
+            .flat_map(|y| (1..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                (image.get_pixel(x, y)[0] as i32 - image.get_pixel(x - 1, y)[0] as i32).abs()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn identity_model_reproduces_image() {
+        let image = gradient(150, 97);
+        for overlap in [0, 8, 16, 32] {
+            let output = run_tiled(&image, PATCH_SIZE, 1, overlap, 1, |patch, _| {
+                Ok(patch.to_vec())
+            })
+            .unwrap();
+            assert_eq!(output, image, "overlap {}", overlap);
+        }
+    }
+
+    #[test]
+    fn edge_artifacts_leave_no_seams() {
+        // A model that darkens the border of every patch, like one seeing zero padding
+        let darken_edges = |patch: &[f32], _| {
+            Ok(patch
+                .iter()
+                .enumerate()
+                .map(|(i, &p)| {
+                    let (x, y) = (i % PATCH_SIZE, i / PATCH_SIZE);
+                    let edge = x.min(y).min(PATCH_SIZE - 1 - x).min(PATCH_SIZE - 1 - y);
+                    if edge < 4 {
+                        p - 0.15
+                    } else {
+                        p
+                    }
+                })
+                .collect())
+        };
+        let image = GrayImage::from_pixel(200, 130, Luma([128]));
+
+        let seams = run_tiled(&image, PATCH_SIZE, 1, 0, 1, darken_edges).unwrap();
+        assert!(max_step(&seams) > 30);
+
+        let blended = run_tiled(&image, PATCH_SIZE, 1, 32, 1, darken_edges).unwrap();
+        assert!(max_step(&blended) <= 2, "step {}", max_step(&blended));
+        assert!(blended.pixels().all(|p| p[0].abs_diff(128) <= 2));
+    }
+
+    #[test]
+    fn batches_match_single_patches() {
+        let image = gradient(300, 170);
+        let invert = |patch: &[f32]| patch.iter().map(|p| 1.0 - p).collect::<Vec<f32>>();
+        let single = run_tiled(&image, PATCH_SIZE, 1, 16, 1, |patch, _| Ok(invert(patch))).unwrap();
+        for batch_size in [2, 5, 64] {
+            let batched = run_tiled(&image, PATCH_SIZE, 1, 16, batch_size, |batch, count| {
+                assert!(count <= batch_size);
+                Ok(invert(batch))
+            })
+            .unwrap();
+            assert_eq!(batched, single, "batch size {}", batch_size);
+        }
+    }
+
+    #[test]
+    fn upscaling_model_is_stitched_in_output_coordinates() {
+        // Nearest neighbor 2x upscaling of every patch
+        let upscale = |batch: &[f32], count| {
+            let pixels = PATCH_SIZE * PATCH_SIZE;
+            Ok((0..count * pixels * 4)
+                .map(|i| {
+                    let (n, i) = (i / (pixels * 4), i % (pixels * 4));
+                    let (x, y) = (i % (PATCH_SIZE * 2) / 2, i / (PATCH_SIZE * 2) / 2);
+                    batch[n * pixels + y * PATCH_SIZE + x]
+                })
+                .collect())
+        };
+        let image = gradient(150, 97);
+        let expected = GrayImage::from_fn(300, 194, |x, y| *image.get_pixel(x / 2, y / 2));
+        for overlap in [0, 16, 32] {
+            let output = run_tiled(&image, PATCH_SIZE, 2, overlap, 3, upscale).unwrap();
+            assert_eq!(output, expected, "overlap {}", overlap);
+        }
+    }
+
+    #[test]
+    fn channels_are_laid_out_per_patch() {
+        let image = gradient(150, 97);
+        let inverted = GrayImage::from_fn(150, 97, |x, y| Luma([255 - image.get_pixel(x, y)[0]]));
+        let pixels = PATCH_SIZE * PATCH_SIZE;
+        // Averaging a channel with its inverse is flat gray wherever the planes line up
+        let output = run_tiled_channels(
+            &[&image, &inverted],
+            PATCH_SIZE,
+            1,
+            16,
+            3,
+            |batch, count| {
+                Ok((0..count * pixels)
+                    .map(|i| {
+                        let (n, i) = (i / pixels, i % pixels);
+                        (batch[2 * n * pixels + i] + batch[(2 * n + 1) * pixels + i]) / 2.0
+                    })
+                    .collect())
+            },
+        )
+        .unwrap();
+        assert!(output.pixels().all(|p| p[0].abs_diff(128) <= 1));
+
+        let first = run_tiled_channels(
+            &[&image, &inverted],
+            PATCH_SIZE,
+            1,
+            16,
+            3,
+            |batch, count| {
+                Ok((0..count * pixels)
+                    .map(|i| batch[2 * (i / pixels) * pixels + i % pixels])
+                    .collect())
+            },
+        )
+        .unwrap();
+        assert_eq!(first, image);
+    }
+
+    #[test]
+    fn reflection_stays_inside() {
+        assert_eq!(reflect(-1, 10), 1);
+        assert_eq!(reflect(10, 10), 8);
+        assert_eq!(reflect(25, 10), 7);
+        assert_eq!(reflect(-3, 1), 0);
+    }
+}
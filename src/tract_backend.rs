@@ -0,0 +1,130 @@
+use crate::inference::{Model, TensorInfo};
+
+use ndarray::{ArrayD, IxDyn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tract_onnx::prelude::*;
+use tract_onnx::tract_hir::infer::Factoid;
+
+type Plan = TypedRunnableModel<TypedModel>;
+
+/// A model run by tract. tract optimizes a model for one input shape, so a plan is
+/// built on the first run with every new shape and kept.
+pub struct TractModel {
+    model: InferenceModel,
+    inputs: Vec<TensorInfo>,
+    outputs: Vec<TensorInfo>,
+    plans: Mutex<HashMap<Vec<usize>, Arc<Plan>>>,
+}
+
+/// Dimensions of a fact, -1 for the unknown ones, None when even the rank is.
+fn dimensions(fact: &InferenceFact) -> Option<Vec<i64>> {
+    if fact.shape.is_open() {
+        return None;
+    }
+    Some(
+        fact.shape
+            .dims()
+            .map(|dim| {
+                dim.concretize()
+                    .and_then(|dim| dim.to_i64().ok())
+                    .unwrap_or(-1)
+            })
+            .collect(),
+    )
+}
+
+impl TractModel {
+    pub fn load(model_path: &str) -> Result<Self, Box<dyn Error>> {
+        let model = tract_onnx::onnx()
+            .model_for_path(model_path)
+            .map_err(|e| format!("Cannot load {} with tract: {}", model_path, e))?;
+
+        let inputs = model
+            .input_outlets()?
+            .iter()
+            .map(|&outlet| {
+                Ok(TensorInfo {
+                    name: model.node(outlet.node).name.clone(),
+                    dimensions: dimensions(model.outlet_fact(outlet)?),
+                })
+            })
+            .collect::<TractResult<Vec<TensorInfo>>>()?;
+        let outputs = model
+            .output_outlets()?
+            .iter()
+            .map(|&outlet| {
+                Ok(TensorInfo {
+                    name: model
+                        .outlet_label(outlet)
+                        .unwrap_or(&model.node(outlet.node).name)
+                        .to_string(),
+                    dimensions: dimensions(model.outlet_fact(outlet)?),
+                })
+            })
+            .collect::<TractResult<Vec<TensorInfo>>>()?;
+
+        Ok(Self {
+            model,
+            inputs,
+            outputs,
+            plans: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn plan(&self, shape: &[usize]) -> TractResult<Arc<Plan>> {
+        // Held while optimizing so that parallel runs do not build the same plan
+        let mut plans = self.plans.lock().unwrap();
+        if let Some(plan) = plans.get(shape) {
+            return Ok(plan.clone());
+        }
+        let mut model = self
+            .model
+            .clone()
+            .with_input_fact(0, f32::fact(shape).into())?;
+        // Declared output shapes use their own symbols, let tract infer them instead
+        for index in 0..self.outputs.len() {
+            model.set_output_fact(index, InferenceFact::default())?;
+        }
+        let plan = Arc::new(model.into_optimized()?.into_runnable()?);
+        plans.insert(shape.to_vec(), plan.clone());
+        Ok(plan)
+    }
+}
+
+impl Model for TractModel {
+    fn inputs(&self) -> &[TensorInfo] {
+        &self.inputs
+    }
+
+    fn outputs(&self) -> &[TensorInfo] {
+        &self.outputs
+    }
+
+    fn run(
+        &self,
+        input_name: &str,
+        input: ArrayD<f32>,
+        output_name: &str,
+    ) -> Result<ArrayD<f32>, Box<dyn Error + Send + Sync>> {
+        if self.inputs.len() != 1 || self.inputs[0].name != input_name {
+            return Err(format!("tract runs models with the single input {}", input_name).into());
+        }
+        let index = self
+            .outputs
+            .iter()
+            .position(|output| output.name == output_name)
+            .ok_or("Missing model output")?;
+
+        let shape = input.shape().to_vec();
+        let data: Vec<f32> = input.iter().copied().collect();
+        let tensor = Tensor::from_shape(&shape, &data)?;
+        let outputs = self.plan(&shape)?.run(tvec!(tensor.into()))?;
+        let output = outputs[index].to_array_view::<f32>()?;
+        Ok(ArrayD::from_shape_vec(
+            IxDyn(output.shape()),
+            output.iter().copied().collect(),
+        )?)
+    }
+}
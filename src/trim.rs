@@ -0,0 +1,143 @@
+use crate::quality::{LineQuality, MIN_SYNC_SCORE};
+
+use std::f32::consts::PI;
+
+// APT subcarrier frequency and the offsets searched around it (Hz)
+pub const SUBCARRIER_FREQ: f32 = 2400.0;
+const SUBCARRIER_SEARCH: [f32; 11] = [
+    -20.0, -16.0, -12.0, -8.0, -4.0, 0.0, 4.0, 8.0, 12.0, 16.0, 20.0,
+];
+
+// Share of a line's energy the subcarrier must carry for the line to hold any signal,
+// noise stays around 0.001
+pub const MIN_SUBCARRIER_FRACTION: f32 = 0.01;
+// A line carries signal when its sync score passes quality::MIN_SYNC_SCORE and its
+// subcarrier energy this fraction of the 90th percentile of the subcarrier energy
+const MIN_RELATIVE_ENERGY: f32 = 0.25;
+// Number of lines in the majority filter that removes isolated decisions
+const SMOOTHING_LINES: usize = 15;
+
+/// Power of `frequency` in `samples` relative to their total power (0 to 1 for a pure tone).
+pub fn goertzel_fraction(samples: &[f32], frequency: f32, sample_rate: f32) -> f32 {
+    let coefficient = 2.0 * (2.0 * PI * frequency / sample_rate).cos();
+    let (mut s1, mut s2) = (0.0f32, 0.0f32);
+    let mut energy = 0.0;
+    for &sample in samples {
+        let s0 = sample + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+        energy += sample * sample;
+    }
+
+    let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+    if energy <= 0.0 {
+        return 0.0;
+    }
+    2.0 * power / (samples.len() as f32 * energy)
+}
+
+/// Share of every line's energy carried by the 2400 Hz subcarrier.
+pub fn subcarrier_energy(samples: &[f32], frame_width: usize, sample_rate: f32) -> Vec<f32> {
+    samples
+        .chunks_exact(frame_width)
+        .map(|line| {
+            SUBCARRIER_SEARCH
+                .iter()
+                .map(|offset| goertzel_fraction(line, SUBCARRIER_FREQ + offset, sample_rate))
+                .fold(0.0, f32::max)
+        })
+        .collect()
+}
+
+/// First and last (exclusive) line carrying signal, widened by `margin` lines.
+/// Returns None when no line looks like signal.
+pub fn detect_signal_span(
+    quality: &[LineQuality],
+    energy: &[f32],
+    margin: usize,
+) -> Option<(usize, usize)> {
+    let lines = quality.len().min(energy.len());
+    if lines == 0 {
+        return None;
+    }
+
+    let mut sorted = energy[..lines].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let reference = sorted[(lines - 1) * 9 / 10];
+    let min_energy = reference * MIN_RELATIVE_ENERGY;
+
+    let signal: Vec<bool> = (0..lines)
+        .map(|i| quality[i].sync_score >= MIN_SYNC_SCORE && energy[i] >= min_energy)
+        .collect();
+
+    // Majority filter so single bad lines inside the pass (or lucky ones in the static) do not count
+    let half = SMOOTHING_LINES / 2;
+    let smoothed: Vec<bool> = (0..lines)
+        .map(|i| {
+            let window = &signal[i.saturating_sub(half)..(i + half + 1).min(lines)];
+            window.iter().filter(|&&s| s).count() * 2 > window.len()
+        })
+        .collect();
+
+    let start = smoothed.iter().position(|&s| s)?;
+    let end = smoothed.iter().rposition(|&s| s)? + 1;
+    Some((start.saturating_sub(margin), (end + margin).min(lines)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(sync_score: f32) -> LineQuality {
+        LineQuality {
+            sync_score,
+            snr_db: 20.0,
+            noise: 1.0,
+        }
+    }
+
+    #[test]
+    fn span_ignores_isolated_lines() {
+        // Signal at lines 50-149 with a dropout at 100, a lucky noise line at 10
+        let quality: Vec<LineQuality> = (0..200)
+            .map(|i| match i {
+                100 => line(0.0),
+                10 | 50..150 => line(0.8),
+                _ => line(0.1),
+            })
+            .collect();
+        let energy: Vec<f32> = (0..200)
+            .map(|i| {
+                if i == 10 || (50..150).contains(&i) {
+                    0.5
+                } else {
+                    0.01
+                }
+            })
+            .collect();
+        assert_eq!(detect_signal_span(&quality, &energy, 0), Some((50, 150)));
+        assert_eq!(detect_signal_span(&quality, &energy, 20), Some((30, 170)));
+        assert_eq!(detect_signal_span(&quality, &energy, 80), Some((0, 200)));
+    }
+
+    #[test]
+    fn no_span_in_noise() {
+        let quality = vec![line(0.1); 100];
+        assert_eq!(detect_signal_span(&quality, &[0.5; 100], 10), None);
+        assert_eq!(detect_signal_span(&[], &[], 10), None);
+    }
+
+    #[test]
+    fn subcarrier_energy_finds_an_offset_tone() {
+        let sample_rate = 20800.0;
+        let tone: Vec<f32> = (0..10400 * 2)
+            .map(|i| (2.0 * PI * 2412.0 * i as f32 / sample_rate).sin())
+            .collect();
+        let energy = subcarrier_energy(&tone, 10400, sample_rate);
+        assert_eq!(energy.len(), 2);
+        assert!(energy.iter().all(|&e| e > 0.9), "{:?}", energy);
+
+        let other = goertzel_fraction(&tone[..10400], 1200.0, sample_rate);
+        assert!(other < 0.01, "{}", other);
+    }
+}
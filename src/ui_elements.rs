@@ -1,6 +1,12 @@
+use crate::augment::{AUGMENTATION_NAMES, COMBINE_NAMES};
+use crate::calibration::SATELLITE_NAMES;
+use crate::color::PALETTE_NAMES;
+use crate::inference::{self, Backend, ExecutionProvider, BACKEND_NAMES, PROVIDER_NAMES};
+use crate::products::PRODUCT_NAMES;
+
 use gtk4::{
-    prelude::*, ApplicationWindow, Box, Button, CheckButton, Entry, HeaderBar, Label, Picture,
-    ProgressBar, SpinButton, Stack, StackSwitcher, Window,
+    prelude::*, ApplicationWindow, Box, Button, CheckButton, DropDown, Entry, HeaderBar, Label,
+    Picture, ProgressBar, Scale, SpinButton, Stack, StackSwitcher, StringList, Window,
 };
 use sysinfo::System;
 
@@ -9,25 +15,72 @@ pub struct UiElements {
     pub text_box: Entry,
     pub button_proceed: Button,
     pub button_open_file: Button,
+    pub combine_entry: Entry,
+    pub button_add_recording: Button,
     pub button_settings: Button,
+    pub button_auto_tune: Button,
     pub checkbox_sync: CheckButton,
     pub checkbox_use_model: CheckButton,
     pub checkbox_use_sgbnr: CheckButton,
+    pub checkbox_split_passes: CheckButton,
     pub picture_widget: Picture,
+    pub output_dropdown: DropDown,
+    pub quality_label: Label,
+    pub checkbox_manual_crop: CheckButton,
+    pub crop_first_scale: Scale,
+    pub crop_last_scale: Scale,
     pub progress_bar: ProgressBar,
 
     // Settings ui
     settings_window: Window,
+    pub checkbox_force_decode: CheckButton,
+    pub checkbox_impulse_blanker: CheckButton,
+    pub checkbox_notch_filter: CheckButton,
+    pub checkbox_noise_reduction: CheckButton,
     pub cutoff_frequency_spinbutton: SpinButton,
     pub additional_offset_spinbutton: SpinButton,
     pub window_size_spinbutton: SpinButton,
     pub scaling_factor_spinbutton: SpinButton,
+    pub checkbox_repair_dropouts: CheckButton,
+    pub inpaint_model_entry: Entry,
     pub cpu_threads_spinbutton: SpinButton,
+    pub execution_provider_dropdown: DropDown,
+    pub backend_dropdown: DropDown,
+    pub tile_overlap_spinbutton: SpinButton,
+    pub batch_size_spinbutton: SpinButton,
+    pub augmentation_dropdown: DropDown,
+    pub augmentation_combine_dropdown: DropDown,
+    pub model_dropdown: DropDown,
+    pub model_details_label: Label,
+    pub button_import_model: Button,
+    pub model_mirror_entry: Entry,
+    pub download_proxy_entry: Entry,
+    pub model_sha256_entry: Entry,
     pub blur_sigma_spinbutton: SpinButton,
     pub brightness_threshold_spinbutton: SpinButton,
     pub noise_threshold_spinbutton: SpinButton,
     pub sharpen_sigma_spinbutton: SpinButton,
     pub sharpen_threshold_spinbutton: SpinButton,
+    pub sgbnr_radius_spinbutton: SpinButton,
+    pub sgbnr_transition_spinbutton: SpinButton,
+    pub checkbox_sgbnr_export_mask: CheckButton,
+    pub checkbox_sgbnr_auto_thresholds: CheckButton,
+    pub sgbnr_estimate_label: Label,
+    pub button_sgbnr_preview: Button,
+    pub sgbnr_preview_picture: Picture,
+    pub sgbnr_mask_picture: Picture,
+    pub checkbox_auto_trim: CheckButton,
+    pub trim_margin_spinbutton: SpinButton,
+    pub palette_dropdown: DropDown,
+    pub custom_lut_entry: Entry,
+    pub button_browse_lut: Button,
+    pub product_checkbuttons: Vec<CheckButton>,
+    pub satellite_dropdown: DropDown,
+    pub checkbox_cloud_mask: CheckButton,
+    pub cloud_visible_threshold_spinbutton: SpinButton,
+    pub cloud_infrared_threshold_spinbutton: SpinButton,
+    pub cloud_uniformity_threshold_spinbutton: SpinButton,
+    pub cloud_model_entry: Entry,
 }
 
 impl UiElements {
@@ -50,8 +103,20 @@ impl UiElements {
 
         let button_open_file = Button::with_label("Open File");
 
+        // Other recordings of the same pass, separated by ';'
+        let combine_entry = Entry::new();
+        combine_entry.set_placeholder_text(Some(
+            "Other recordings of the same pass to combine with (optional)...",
+        ));
+        combine_entry.set_hexpand(true);
+
+        let button_add_recording = Button::with_label("Add Recording");
+
         let button_settings = Button::with_label("Settings");
 
+        let button_auto_tune = Button::with_label("Auto-tune");
+        button_auto_tune.set_sensitive(false);
+
         let checkbox_sync = gtk4::CheckButton::with_label("Sync");
         checkbox_sync.set_active(false);
 
@@ -61,6 +126,9 @@ impl UiElements {
         let checkbox_use_sgbnr = gtk4::CheckButton::with_label("Enhance image (SGBNR)");
         checkbox_use_sgbnr.set_active(false);
 
+        let checkbox_split_passes = gtk4::CheckButton::with_label("Split passes");
+        checkbox_split_passes.set_active(false);
+
         let main_vbox = gtk4::Box::new(gtk4::Orientation::Vertical, 12);
         main_vbox.set_hexpand(true);
         main_vbox.set_vexpand(true);
@@ -78,14 +146,22 @@ impl UiElements {
         top_grid.attach(&button_open_file, 3, 0, 1, 1);
         button_open_file.set_hexpand(false);
 
+        top_grid.attach(&combine_entry, 0, 1, 3, 1);
+        top_grid.attach(&button_add_recording, 3, 1, 1, 1);
+
         let checkbox_box = gtk4::Box::new(gtk4::Orientation::Horizontal, 12);
         checkbox_box.append(&checkbox_sync);
         checkbox_box.append(&checkbox_use_model);
         checkbox_box.append(&checkbox_use_sgbnr);
+        checkbox_box.append(&checkbox_split_passes);
+
+        let settings_box = gtk4::Box::new(gtk4::Orientation::Horizontal, 12);
+        settings_box.append(&button_settings);
+        settings_box.append(&button_auto_tune);
 
-        top_grid.attach(&button_settings, 0, 1, 1, 1);
-        top_grid.attach(&checkbox_box, 1, 1, 2, 1);
-        top_grid.attach(&button_proceed, 3, 1, 1, 1);
+        top_grid.attach(&settings_box, 0, 2, 1, 1);
+        top_grid.attach(&checkbox_box, 1, 2, 2, 1);
+        top_grid.attach(&button_proceed, 3, 2, 1, 1);
 
         main_vbox.append(&top_grid);
 
@@ -95,6 +171,51 @@ impl UiElements {
 
         main_vbox.append(&picture_widget);
 
+        let output_box = gtk4::Box::new(gtk4::Orientation::Horizontal, 12);
+        output_box.set_margin_start(12);
+        output_box.set_margin_end(12);
+        let output_label = Label::new(Some("Output:"));
+        let output_dropdown = DropDown::from_strings(&[]);
+        output_dropdown.set_hexpand(true);
+        output_dropdown.set_sensitive(false);
+        output_box.append(&output_label);
+        output_box.append(&output_dropdown);
+
+        main_vbox.append(&output_box);
+
+        let quality_label = Label::new(None);
+        quality_label.set_margin_start(12);
+        quality_label.set_margin_end(12);
+        quality_label.set_xalign(0.0);
+        quality_label.set_wrap(true);
+        quality_label.set_selectable(true);
+
+        main_vbox.append(&quality_label);
+
+        // Crop handles over the decoded lines, ranges are set after each run
+        let crop_box = gtk4::Box::new(gtk4::Orientation::Horizontal, 12);
+        crop_box.set_margin_start(12);
+        crop_box.set_margin_end(12);
+        let checkbox_manual_crop = CheckButton::with_label("Manual crop");
+        checkbox_manual_crop.set_sensitive(false);
+        let crop_first_scale = Scale::with_range(gtk4::Orientation::Horizontal, 0.0, 1.0, 1.0);
+        crop_first_scale.set_hexpand(true);
+        crop_first_scale.set_digits(0);
+        crop_first_scale.set_draw_value(true);
+        crop_first_scale.set_sensitive(false);
+        let crop_last_scale = Scale::with_range(gtk4::Orientation::Horizontal, 0.0, 1.0, 1.0);
+        crop_last_scale.set_hexpand(true);
+        crop_last_scale.set_digits(0);
+        crop_last_scale.set_draw_value(true);
+        crop_last_scale.set_sensitive(false);
+        crop_box.append(&checkbox_manual_crop);
+        crop_box.append(&Label::new(Some("First line")));
+        crop_box.append(&crop_first_scale);
+        crop_box.append(&Label::new(Some("Last line")));
+        crop_box.append(&crop_last_scale);
+
+        main_vbox.append(&crop_box);
+
         let progress_bar = gtk4::ProgressBar::new();
         progress_bar.set_margin_bottom(12);
         progress_bar.set_margin_start(12);
@@ -125,6 +246,33 @@ impl UiElements {
         header.set_show_title_buttons(true);
         settings_window.set_titlebar(Some(&header));
 
+        // Widget - Audio cleanup settings
+        let audio_cleanup_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
+        audio_cleanup_settings_box.set_margin_top(12);
+        audio_cleanup_settings_box.set_margin_bottom(12);
+        audio_cleanup_settings_box.set_margin_start(12);
+        audio_cleanup_settings_box.set_margin_end(12);
+        let audio_cleanup_label = Label::new(Some("Applied before demodulation"));
+        audio_cleanup_label.set_xalign(0.5);
+        audio_cleanup_label.set_justify(gtk4::Justification::Center);
+        let checkbox_impulse_blanker = CheckButton::with_label("Impulse blanker");
+        checkbox_impulse_blanker.set_halign(gtk4::Align::Center);
+        let checkbox_notch_filter = CheckButton::with_label("Adaptive notch filter");
+        checkbox_notch_filter.set_halign(gtk4::Align::Center);
+        let checkbox_noise_reduction = CheckButton::with_label("Noise reduction");
+        checkbox_noise_reduction.set_halign(gtk4::Align::Center);
+        audio_cleanup_settings_box.append(&audio_cleanup_label);
+        audio_cleanup_settings_box.append(&checkbox_impulse_blanker);
+        audio_cleanup_settings_box.append(&checkbox_notch_filter);
+        audio_cleanup_settings_box.append(&checkbox_noise_reduction);
+        let input_checks_label = Label::new(Some("Input checks"));
+        input_checks_label.set_xalign(0.5);
+        input_checks_label.set_justify(gtk4::Justification::Center);
+        let checkbox_force_decode = CheckButton::with_label("Decode without subcarrier");
+        checkbox_force_decode.set_halign(gtk4::Align::Center);
+        audio_cleanup_settings_box.append(&input_checks_label);
+        audio_cleanup_settings_box.append(&checkbox_force_decode);
+
         // Widget - Low pass filter settings
         let low_pass_filter_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
         low_pass_filter_settings_box.set_margin_top(12);
@@ -193,6 +341,42 @@ impl UiElements {
         additional_offset_spinbutton.set_width_request(200);
         sync_apt_settings_box.append(&additional_offset_label);
         sync_apt_settings_box.append(&additional_offset_spinbutton);
+        let checkbox_auto_trim = CheckButton::with_label("Trim noise-only lines");
+        checkbox_auto_trim.set_active(true);
+        checkbox_auto_trim.set_halign(gtk4::Align::Center);
+        let trim_margin_label = Label::new(Some("Trim Margin (lines)\n(0-500)"));
+        trim_margin_label.set_xalign(0.5);
+        trim_margin_label.set_justify(gtk4::Justification::Center);
+        let trim_margin_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(20.0, 0.0, 500.0, 1.0, 10.0, 0.0))
+            .build();
+        trim_margin_spinbutton.set_hexpand(false);
+        trim_margin_spinbutton.set_halign(gtk4::Align::Center);
+        trim_margin_spinbutton.set_width_request(200);
+        sync_apt_settings_box.append(&checkbox_auto_trim);
+        sync_apt_settings_box.append(&trim_margin_label);
+        sync_apt_settings_box.append(&trim_margin_spinbutton);
+
+        // Widget - Dropout settings
+        let dropout_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
+        dropout_settings_box.set_margin_top(12);
+        dropout_settings_box.set_margin_bottom(12);
+        dropout_settings_box.set_margin_start(12);
+        dropout_settings_box.set_margin_end(12);
+        let checkbox_repair_dropouts = CheckButton::with_label("Repair dropout lines");
+        checkbox_repair_dropouts.set_halign(gtk4::Align::Center);
+        let inpaint_model_label = Label::new(Some(
+            "Inpainting Model (optional)\n(ONNX, interpolation if empty)",
+        ));
+        inpaint_model_label.set_xalign(0.5);
+        inpaint_model_label.set_justify(gtk4::Justification::Center);
+        let inpaint_model_entry = Entry::new();
+        inpaint_model_entry.set_placeholder_text(Some("Path to an inpainting .onnx..."));
+        inpaint_model_entry.set_halign(gtk4::Align::Center);
+        inpaint_model_entry.set_width_request(200);
+        dropout_settings_box.append(&checkbox_repair_dropouts);
+        dropout_settings_box.append(&inpaint_model_label);
+        dropout_settings_box.append(&inpaint_model_entry);
 
         // Widget - Enhance image settings
         let sys = System::new_all();
@@ -221,13 +405,140 @@ impl UiElements {
         cpu_threads_spinbutton.set_width_request(200);
         enhance_image_settings_box.append(&cpu_threads_label);
         enhance_image_settings_box.append(&cpu_threads_spinbutton);
+        let execution_provider_label = Label::new(Some(
+            &(String::from("Execution Provider\n(built in: ")
+                + &inference::compiled_providers().join(", ")
+                + ")"),
+        ));
+        execution_provider_label.set_xalign(0.5);
+        execution_provider_label.set_justify(gtk4::Justification::Center);
+        let execution_provider_dropdown = DropDown::from_strings(&PROVIDER_NAMES);
+        execution_provider_dropdown.set_selected(ExecutionProvider::default().index());
+        execution_provider_dropdown.set_hexpand(false);
+        execution_provider_dropdown.set_halign(gtk4::Align::Center);
+        execution_provider_dropdown.set_width_request(200);
+        enhance_image_settings_box.append(&execution_provider_label);
+        enhance_image_settings_box.append(&execution_provider_dropdown);
+        let backend_label = Label::new(Some(
+            &(String::from("Inference Backend\n(built in: ")
+                + &inference::compiled_backends().join(", ")
+                + ")"),
+        ));
+        backend_label.set_xalign(0.5);
+        backend_label.set_justify(gtk4::Justification::Center);
+        let backend_dropdown = DropDown::from_strings(&BACKEND_NAMES);
+        backend_dropdown.set_selected(Backend::default().index());
+        backend_dropdown.set_hexpand(false);
+        backend_dropdown.set_halign(gtk4::Align::Center);
+        backend_dropdown.set_width_request(200);
+        enhance_image_settings_box.append(&backend_label);
+        enhance_image_settings_box.append(&backend_dropdown);
+        let tile_overlap_label = Label::new(Some("Tile Overlap\n(0-128)"));
+        tile_overlap_label.set_xalign(0.5);
+        tile_overlap_label.set_justify(gtk4::Justification::Center);
+        let tile_overlap_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(32.0, 0.0, 128.0, 1.0, 8.0, 0.0))
+            .build();
+        tile_overlap_spinbutton.set_hexpand(false);
+        tile_overlap_spinbutton.set_halign(gtk4::Align::Center);
+        tile_overlap_spinbutton.set_width_request(200);
+        enhance_image_settings_box.append(&tile_overlap_label);
+        enhance_image_settings_box.append(&tile_overlap_spinbutton);
+        let batch_size_label = Label::new(Some("Batch Size\n(1-64)"));
+        batch_size_label.set_xalign(0.5);
+        batch_size_label.set_justify(gtk4::Justification::Center);
+        let batch_size_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(4.0, 1.0, 64.0, 1.0, 4.0, 0.0))
+            .build();
+        batch_size_spinbutton.set_hexpand(false);
+        batch_size_spinbutton.set_halign(gtk4::Align::Center);
+        batch_size_spinbutton.set_width_request(200);
+        enhance_image_settings_box.append(&batch_size_label);
+        enhance_image_settings_box.append(&batch_size_spinbutton);
+        let augmentation_label = Label::new(Some("Test-Time Augmentation\n(model runs per patch)"));
+        augmentation_label.set_xalign(0.5);
+        augmentation_label.set_justify(gtk4::Justification::Center);
+        let augmentation_dropdown = DropDown::from_strings(&AUGMENTATION_NAMES);
+        augmentation_dropdown.set_selected(0);
+        augmentation_dropdown.set_hexpand(false);
+        augmentation_dropdown.set_halign(gtk4::Align::Center);
+        augmentation_dropdown.set_width_request(200);
+        enhance_image_settings_box.append(&augmentation_label);
+        enhance_image_settings_box.append(&augmentation_dropdown);
+        let augmentation_combine_label = Label::new(Some("Combine Augmented Outputs\n(per pixel)"));
+        augmentation_combine_label.set_xalign(0.5);
+        augmentation_combine_label.set_justify(gtk4::Justification::Center);
+        let augmentation_combine_dropdown = DropDown::from_strings(&COMBINE_NAMES);
+        augmentation_combine_dropdown.set_selected(0);
+        augmentation_combine_dropdown.set_hexpand(false);
+        augmentation_combine_dropdown.set_halign(gtk4::Align::Center);
+        augmentation_combine_dropdown.set_width_request(200);
+        enhance_image_settings_box.append(&augmentation_combine_label);
+        enhance_image_settings_box.append(&augmentation_combine_dropdown);
+
+        // Widget - Models settings
+        let models_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
+        models_settings_box.set_margin_top(12);
+        models_settings_box.set_margin_bottom(12);
+        models_settings_box.set_margin_start(12);
+        models_settings_box.set_margin_end(12);
+        let model_label = Label::new(Some("Enhancement Model\n(installed)"));
+        model_label.set_xalign(0.5);
+        model_label.set_justify(gtk4::Justification::Center);
+        // Filled from the model registry by the UI logic
+        let model_dropdown = DropDown::new(Some(StringList::new(&[])), None::<gtk4::Expression>);
+        model_dropdown.set_hexpand(false);
+        model_dropdown.set_halign(gtk4::Align::Center);
+        model_dropdown.set_width_request(200);
+        let model_details_label = Label::new(Some("No model installed, model.onnx is used"));
+        model_details_label.set_justify(gtk4::Justification::Center);
+        model_details_label.set_wrap(true);
+        model_details_label.set_max_width_chars(60);
+        model_details_label.set_selectable(true);
+        let button_import_model = Button::with_label("Import Model...");
+        button_import_model.set_halign(gtk4::Align::Center);
+        button_import_model.set_width_request(200);
+        models_settings_box.append(&model_label);
+        models_settings_box.append(&model_dropdown);
+        models_settings_box.append(&model_details_label);
+        models_settings_box.append(&button_import_model);
+        let model_mirror_label = Label::new(Some("Download Mirror (optional)\n(tried first)"));
+        model_mirror_label.set_xalign(0.5);
+        model_mirror_label.set_justify(gtk4::Justification::Center);
+        let model_mirror_entry = Entry::new();
+        model_mirror_entry.set_placeholder_text(Some("URL of the U-Net model..."));
+        model_mirror_entry.set_halign(gtk4::Align::Center);
+        model_mirror_entry.set_width_request(200);
+        let download_proxy_label = Label::new(Some("Proxy (optional)\n(http, https or socks5)"));
+        download_proxy_label.set_xalign(0.5);
+        download_proxy_label.set_justify(gtk4::Justification::Center);
+        let download_proxy_entry = Entry::new();
+        download_proxy_entry.set_placeholder_text(Some("http://host:port"));
+        download_proxy_entry.set_halign(gtk4::Align::Center);
+        download_proxy_entry.set_width_request(200);
+        let model_sha256_label = Label::new(Some(
+            "Expected SHA-256 (optional)\n(else the server's is used)",
+        ));
+        model_sha256_label.set_xalign(0.5);
+        model_sha256_label.set_justify(gtk4::Justification::Center);
+        let model_sha256_entry = Entry::new();
+        model_sha256_entry.set_placeholder_text(Some("64 hex digits..."));
+        model_sha256_entry.set_halign(gtk4::Align::Center);
+        model_sha256_entry.set_width_request(200);
+        models_settings_box.append(&model_mirror_label);
+        models_settings_box.append(&model_mirror_entry);
+        models_settings_box.append(&download_proxy_label);
+        models_settings_box.append(&download_proxy_entry);
+        models_settings_box.append(&model_sha256_label);
+        models_settings_box.append(&model_sha256_entry);
 
         // Widget - SGBNR settings
+        let sgbnr_settings_page_box = Box::new(gtk4::Orientation::Vertical, 12);
+        sgbnr_settings_page_box.set_margin_top(12);
+        sgbnr_settings_page_box.set_margin_bottom(12);
+        sgbnr_settings_page_box.set_margin_start(12);
+        sgbnr_settings_page_box.set_margin_end(12);
         let sgbnr_settings_main_box = Box::new(gtk4::Orientation::Horizontal, 12);
-        sgbnr_settings_main_box.set_margin_top(12);
-        sgbnr_settings_main_box.set_margin_bottom(12);
-        sgbnr_settings_main_box.set_margin_start(12);
-        sgbnr_settings_main_box.set_margin_end(12);
         sgbnr_settings_main_box.set_halign(gtk4::Align::Center);
         let sgbnr_settings_1box = Box::new(gtk4::Orientation::Vertical, 12);
         let sgbnr_settings_2box = Box::new(gtk4::Orientation::Vertical, 12);
@@ -280,6 +591,36 @@ impl UiElements {
         sharpen_threshold_spinbutton.set_hexpand(false);
         sharpen_threshold_spinbutton.set_halign(gtk4::Align::Center);
         sharpen_threshold_spinbutton.set_width_request(200);
+        let sgbnr_radius_label = Label::new(Some("Window Radius\n(1-16)"));
+        sgbnr_radius_label.set_xalign(0.5);
+        sgbnr_radius_label.set_justify(gtk4::Justification::Center);
+        let sgbnr_radius_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(1.0, 1.0, 16.0, 1.0, 4.0, 0.0))
+            .build();
+        sgbnr_radius_spinbutton.set_hexpand(false);
+        sgbnr_radius_spinbutton.set_halign(gtk4::Align::Center);
+        sgbnr_radius_spinbutton.set_width_request(200);
+        let sgbnr_transition_label = Label::new(Some("Transition Width\n(0-50)"));
+        sgbnr_transition_label.set_xalign(0.5);
+        sgbnr_transition_label.set_justify(gtk4::Justification::Center);
+        let sgbnr_transition_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(4.0, 0.0, 50.0, 0.5, 5.0, 0.0))
+            .digits(1)
+            .build();
+        sgbnr_transition_spinbutton.set_hexpand(false);
+        sgbnr_transition_spinbutton.set_halign(gtk4::Align::Center);
+        sgbnr_transition_spinbutton.set_width_request(200);
+        let checkbox_sgbnr_export_mask = CheckButton::with_label("Export blend mask");
+        checkbox_sgbnr_export_mask.set_halign(gtk4::Align::Center);
+        let checkbox_sgbnr_auto_thresholds =
+            CheckButton::with_label("Auto thresholds (estimated per image)");
+        checkbox_sgbnr_auto_thresholds.set_halign(gtk4::Align::Center);
+        let sgbnr_estimate_label = Label::new(Some(
+            "Preview to estimate the thresholds of the displayed image",
+        ));
+        sgbnr_estimate_label.set_xalign(0.5);
+        sgbnr_estimate_label.set_justify(gtk4::Justification::Center);
+        sgbnr_estimate_label.set_wrap(true);
 
         sgbnr_settings_1box.append(&blur_sigma_label);
         sgbnr_settings_1box.append(&blur_sigma_spinbutton);
@@ -291,12 +632,158 @@ impl UiElements {
         sgbnr_settings_2box.append(&sharpen_sigma_spinbutton);
         sgbnr_settings_2box.append(&sharpen_threshold_label);
         sgbnr_settings_2box.append(&sharpen_threshold_spinbutton);
+        sgbnr_settings_2box.append(&sgbnr_radius_label);
+        sgbnr_settings_2box.append(&sgbnr_radius_spinbutton);
+        sgbnr_settings_1box.append(&sgbnr_transition_label);
+        sgbnr_settings_1box.append(&sgbnr_transition_spinbutton);
+        sgbnr_settings_2box.append(&checkbox_sgbnr_export_mask);
 
         sgbnr_settings_main_box.append(&sgbnr_settings_1box);
         sgbnr_settings_main_box.append(&sgbnr_settings_2box);
 
+        // Output and blend mask of the displayed image with the current settings
+        let button_sgbnr_preview = Button::with_label("Preview on Displayed Image");
+        button_sgbnr_preview.set_halign(gtk4::Align::Center);
+        let sgbnr_preview_box = Box::new(gtk4::Orientation::Horizontal, 12);
+        let sgbnr_preview_picture = Picture::new();
+        let sgbnr_mask_picture = Picture::new();
+        for picture in [&sgbnr_preview_picture, &sgbnr_mask_picture] {
+            picture.set_hexpand(true);
+            picture.set_vexpand(true);
+            picture.set_size_request(240, 240);
+            sgbnr_preview_box.append(picture);
+        }
+        sgbnr_settings_page_box.append(&sgbnr_settings_main_box);
+        sgbnr_settings_page_box.append(&checkbox_sgbnr_auto_thresholds);
+        sgbnr_settings_page_box.append(&sgbnr_estimate_label);
+        sgbnr_settings_page_box.append(&button_sgbnr_preview);
+        sgbnr_settings_page_box.append(&sgbnr_preview_box);
+
+        // Widget - Color settings
+        let color_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
+        color_settings_box.set_margin_top(12);
+        color_settings_box.set_margin_bottom(12);
+        color_settings_box.set_margin_start(12);
+        color_settings_box.set_margin_end(12);
+        let palette_label = Label::new(Some("Palette\n(requires Sync)"));
+        palette_label.set_xalign(0.5);
+        palette_label.set_justify(gtk4::Justification::Center);
+        let palette_dropdown = DropDown::from_strings(&PALETTE_NAMES);
+        palette_dropdown.set_selected(0);
+        palette_dropdown.set_hexpand(false);
+        palette_dropdown.set_halign(gtk4::Align::Center);
+        palette_dropdown.set_width_request(200);
+        let custom_lut_label = Label::new(Some("Custom LUT\n(256x256 PNG)"));
+        custom_lut_label.set_xalign(0.5);
+        custom_lut_label.set_justify(gtk4::Justification::Center);
+        let custom_lut_box = Box::new(gtk4::Orientation::Horizontal, 12);
+        custom_lut_box.set_halign(gtk4::Align::Center);
+        let custom_lut_entry = Entry::new();
+        custom_lut_entry.set_placeholder_text(Some("Select a palette PNG..."));
+        custom_lut_entry.set_width_request(200);
+        let button_browse_lut = Button::with_label("Browse");
+        custom_lut_box.append(&custom_lut_entry);
+        custom_lut_box.append(&button_browse_lut);
+        color_settings_box.append(&palette_label);
+        color_settings_box.append(&palette_dropdown);
+        color_settings_box.append(&custom_lut_label);
+        color_settings_box.append(&custom_lut_box);
+
+        // Widget - Products settings
+        let products_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
+        products_settings_box.set_margin_top(12);
+        products_settings_box.set_margin_bottom(12);
+        products_settings_box.set_margin_start(12);
+        products_settings_box.set_margin_end(12);
+        let products_label = Label::new(Some("Products\n(requires Sync)"));
+        products_label.set_xalign(0.5);
+        products_label.set_justify(gtk4::Justification::Center);
+        products_settings_box.append(&products_label);
+        let product_checkbuttons: Vec<CheckButton> = PRODUCT_NAMES
+            .iter()
+            .map(|name| {
+                let checkbutton = CheckButton::with_label(name);
+                checkbutton.set_halign(gtk4::Align::Center);
+                products_settings_box.append(&checkbutton);
+                checkbutton
+            })
+            .collect();
+        let satellite_label = Label::new(Some("Satellite\n(IR calibration)"));
+        satellite_label.set_xalign(0.5);
+        satellite_label.set_justify(gtk4::Justification::Center);
+        let satellite_dropdown = DropDown::from_strings(&SATELLITE_NAMES);
+        satellite_dropdown.set_selected(2);
+        satellite_dropdown.set_hexpand(false);
+        satellite_dropdown.set_halign(gtk4::Align::Center);
+        satellite_dropdown.set_width_request(200);
+        products_settings_box.append(&satellite_label);
+        products_settings_box.append(&satellite_dropdown);
+
+        // Widget - Cloud mask settings
+        let cloud_mask_settings_box = Box::new(gtk4::Orientation::Vertical, 12);
+        cloud_mask_settings_box.set_margin_top(12);
+        cloud_mask_settings_box.set_margin_bottom(12);
+        cloud_mask_settings_box.set_margin_start(12);
+        cloud_mask_settings_box.set_margin_end(12);
+        let checkbox_cloud_mask = CheckButton::with_label("Cloud mask (requires Sync)");
+        checkbox_cloud_mask.set_halign(gtk4::Align::Center);
+        let cloud_visible_threshold_label =
+            Label::new(Some("Visible Albedo Threshold (%)\n(1-100)"));
+        cloud_visible_threshold_label.set_xalign(0.5);
+        cloud_visible_threshold_label.set_justify(gtk4::Justification::Center);
+        let cloud_visible_threshold_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(30.0, 1.0, 100.0, 1.0, 10.0, 0.0))
+            .digits(1)
+            .build();
+        cloud_visible_threshold_spinbutton.set_hexpand(false);
+        cloud_visible_threshold_spinbutton.set_halign(gtk4::Align::Center);
+        cloud_visible_threshold_spinbutton.set_width_request(200);
+        let cloud_infrared_threshold_label =
+            Label::new(Some("IR Temperature Threshold (K)\n(180-320)"));
+        cloud_infrared_threshold_label.set_xalign(0.5);
+        cloud_infrared_threshold_label.set_justify(gtk4::Justification::Center);
+        let cloud_infrared_threshold_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(265.0, 180.0, 320.0, 1.0, 10.0, 0.0))
+            .digits(1)
+            .build();
+        cloud_infrared_threshold_spinbutton.set_hexpand(false);
+        cloud_infrared_threshold_spinbutton.set_halign(gtk4::Align::Center);
+        cloud_infrared_threshold_spinbutton.set_width_request(200);
+        let cloud_uniformity_threshold_label =
+            Label::new(Some("IR Uniformity Threshold (K)\n(0.1-20)"));
+        cloud_uniformity_threshold_label.set_xalign(0.5);
+        cloud_uniformity_threshold_label.set_justify(gtk4::Justification::Center);
+        let cloud_uniformity_threshold_spinbutton = SpinButton::builder()
+            .adjustment(&gtk4::Adjustment::new(1.5, 0.1, 20.0, 0.1, 1.0, 0.0))
+            .digits(1)
+            .build();
+        cloud_uniformity_threshold_spinbutton.set_hexpand(false);
+        cloud_uniformity_threshold_spinbutton.set_halign(gtk4::Align::Center);
+        cloud_uniformity_threshold_spinbutton.set_width_request(200);
+        let cloud_model_label = Label::new(Some("Classifier Model (optional)\n(ONNX)"));
+        cloud_model_label.set_xalign(0.5);
+        cloud_model_label.set_justify(gtk4::Justification::Center);
+        let cloud_model_entry = Entry::new();
+        cloud_model_entry.set_placeholder_text(Some("Path to a classifier .onnx..."));
+        cloud_model_entry.set_halign(gtk4::Align::Center);
+        cloud_model_entry.set_width_request(200);
+        cloud_mask_settings_box.append(&checkbox_cloud_mask);
+        cloud_mask_settings_box.append(&cloud_visible_threshold_label);
+        cloud_mask_settings_box.append(&cloud_visible_threshold_spinbutton);
+        cloud_mask_settings_box.append(&cloud_infrared_threshold_label);
+        cloud_mask_settings_box.append(&cloud_infrared_threshold_spinbutton);
+        cloud_mask_settings_box.append(&cloud_uniformity_threshold_label);
+        cloud_mask_settings_box.append(&cloud_uniformity_threshold_spinbutton);
+        cloud_mask_settings_box.append(&cloud_model_label);
+        cloud_mask_settings_box.append(&cloud_model_entry);
+
         // Create a stack and add a couple of pages
         let stack = Stack::new();
+        stack.add_titled(
+            &audio_cleanup_settings_box,
+            Some("audio_cleanup"),
+            "Audio Cleanup",
+        );
         stack.add_titled(
             &low_pass_filter_settings_box,
             Some("low_pass_filter"),
@@ -308,12 +795,17 @@ impl UiElements {
             "Envelope Detection",
         );
         stack.add_titled(&sync_apt_settings_box, Some("sync_apt"), "Sync APT");
+        stack.add_titled(&dropout_settings_box, Some("dropouts"), "Dropouts");
         stack.add_titled(
             &enhance_image_settings_box,
             Some("enhance_image"),
             "Enhance Image",
         );
-        stack.add_titled(&sgbnr_settings_main_box, Some("sgbnr"), "SGBNR");
+        stack.add_titled(&models_settings_box, Some("models"), "Models");
+        stack.add_titled(&sgbnr_settings_page_box, Some("sgbnr"), "SGBNR");
+        stack.add_titled(&color_settings_box, Some("color"), "Color");
+        stack.add_titled(&products_settings_box, Some("products"), "Products");
+        stack.add_titled(&cloud_mask_settings_box, Some("cloud_mask"), "Cloud Mask");
 
         // Create a stack switcher and attach it to the stack
         let stack_switcher = StackSwitcher::new();
@@ -328,24 +820,71 @@ impl UiElements {
             text_box,
             button_proceed,
             button_open_file,
+            combine_entry,
+            button_add_recording,
             button_settings,
+            button_auto_tune,
             checkbox_sync,
             checkbox_use_model,
             checkbox_use_sgbnr,
+            checkbox_split_passes,
             picture_widget,
+            output_dropdown,
+            quality_label,
+            checkbox_manual_crop,
+            crop_first_scale,
+            crop_last_scale,
             progress_bar,
             // Settings ui
             settings_window,
+            checkbox_force_decode,
+            checkbox_impulse_blanker,
+            checkbox_notch_filter,
+            checkbox_noise_reduction,
             cutoff_frequency_spinbutton,
             additional_offset_spinbutton,
             window_size_spinbutton,
             scaling_factor_spinbutton,
+            checkbox_repair_dropouts,
+            inpaint_model_entry,
             cpu_threads_spinbutton,
+            execution_provider_dropdown,
+            backend_dropdown,
+            tile_overlap_spinbutton,
+            batch_size_spinbutton,
+            augmentation_dropdown,
+            augmentation_combine_dropdown,
+            model_dropdown,
+            model_details_label,
+            button_import_model,
+            model_mirror_entry,
+            download_proxy_entry,
+            model_sha256_entry,
             blur_sigma_spinbutton,
             brightness_threshold_spinbutton,
             noise_threshold_spinbutton,
             sharpen_sigma_spinbutton,
             sharpen_threshold_spinbutton,
+            sgbnr_radius_spinbutton,
+            sgbnr_transition_spinbutton,
+            checkbox_sgbnr_export_mask,
+            checkbox_sgbnr_auto_thresholds,
+            sgbnr_estimate_label,
+            button_sgbnr_preview,
+            sgbnr_preview_picture,
+            sgbnr_mask_picture,
+            checkbox_auto_trim,
+            trim_margin_spinbutton,
+            palette_dropdown,
+            custom_lut_entry,
+            button_browse_lut,
+            product_checkbuttons,
+            satellite_dropdown,
+            checkbox_cloud_mask,
+            cloud_visible_threshold_spinbutton,
+            cloud_infrared_threshold_spinbutton,
+            cloud_uniformity_threshold_spinbutton,
+            cloud_model_entry,
         }
     }
 
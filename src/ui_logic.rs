@@ -1,24 +1,24 @@
 use crate::app_state::AppState;
+use crate::autotune;
+use crate::combine::combine_recordings;
+use crate::gaussian_blur;
+use crate::inference;
+use crate::models::{self, Registry};
+use crate::passes::split_passes;
 use crate::settings::FunctionsSettings;
 use crate::ui_elements::UiElements;
 use crate::wav::compute_signal;
 
 use glib_macros::clone;
 use gtk4::{gdk, gio, glib, prelude::*};
-use reqwest::blocking::get;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{
     env,
-    fs::File,
-    io::{Read, Write},
     path::Path,
     rc::Rc,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
-const UNET_MODEL_URL: &str =
-    "https://huggingface.co/TempUser123/NOAA_U-Net/resolve/main/model.onnx?download=true";
-
 fn load_css() {
     let settings = gio::Settings::new("org.gnome.desktop.interface");
     let color_scheme = settings.string("color-scheme");
@@ -44,6 +44,146 @@ fn load_css() {
     );
 }
 
+/// Fill the model dropdown from the registry, selecting the active model.
+fn refresh_models(ui_elements: &UiElements) {
+    let registry = Registry::load().unwrap_or_else(|e| {
+        eprintln!("Error loading the model registry: {}", e);
+        Registry::default()
+    });
+    let names: Vec<&str> = registry
+        .models
+        .iter()
+        .map(|model| model.name.as_str())
+        .collect();
+    ui_elements
+        .model_dropdown
+        .set_model(Some(&gtk4::StringList::new(&names)));
+    if let Some(index) = registry
+        .active
+        .as_deref()
+        .and_then(|active| names.iter().position(|&name| name == active))
+    {
+        ui_elements.model_dropdown.set_selected(index as u32);
+    }
+}
+
+/// Download and install the U-Net model, showing its progress with a cancel button.
+/// Returns whether the model was installed.
+async fn download_unet_model(
+    ui_elements: &UiElements,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> bool {
+    let source = {
+        let s = settings.lock().unwrap();
+        models::unet_source(&s.model_mirror, &s.download_proxy, &s.model_sha256)
+    };
+
+    let progress_window = gtk4::Window::builder()
+        .title("Downloading Model")
+        .default_width(300)
+        .default_height(100)
+        .modal(true)
+        .transient_for(&ui_elements.window)
+        .build();
+    progress_window.set_resizable(false);
+
+    let progress_bar = gtk4::ProgressBar::new();
+    progress_bar.set_show_text(true);
+    progress_bar.set_text(Some("Starting download..."));
+    progress_bar.set_margin_top(12);
+    progress_bar.set_margin_bottom(12);
+    progress_bar.set_margin_start(12);
+    progress_bar.set_margin_end(12);
+    let button_cancel = gtk4::Button::with_label("Cancel");
+    button_cancel.set_halign(gtk4::Align::Center);
+    button_cancel.set_margin_bottom(12);
+    let progress_box = gtk4::Box::new(gtk4::Orientation::Vertical, 0);
+    progress_box.append(&progress_bar);
+    progress_box.append(&button_cancel);
+
+    progress_window.set_child(Some(&progress_box));
+    progress_window.present();
+
+    // Cancelling keeps the partial download for the next attempt to resume
+    let cancel = Arc::new(AtomicBool::new(false));
+    button_cancel.connect_clicked(clone!(
+        #[strong]
+        cancel,
+        move |button| {
+            button.set_sensitive(false);
+            cancel.store(true, Ordering::SeqCst);
+        }
+    ));
+    progress_window.connect_close_request(clone!(
+        #[strong]
+        cancel,
+        move |_| {
+            cancel.store(true, Ordering::SeqCst);
+            glib::Propagation::Proceed
+        }
+    ));
+
+    // offload the blocking download to the thread pool
+    let (sender, receiver) = async_channel::bounded(1);
+    let task = gio::spawn_blocking(clone!(
+        #[strong]
+        cancel,
+        move || {
+            models::install_unet_model(&source, &cancel, |progress| {
+                let _ = sender.try_send(progress);
+            })
+        }
+    ));
+
+    // Update the progress bar with the download progress
+    glib::MainContext::default().spawn_local(clone!(
+        #[strong]
+        progress_bar,
+        async move {
+            while let Ok(progress) = receiver.recv().await {
+                match progress.fraction() {
+                    Some(fraction) => progress_bar.set_fraction(fraction),
+                    // No length from the server, show activity only
+                    None => progress_bar.pulse(),
+                }
+                progress_bar.set_text(Some(&progress.text()));
+            }
+        }
+    ));
+
+    let result = task.await;
+    let cancelled = cancel.load(Ordering::SeqCst);
+    progress_window.close();
+    match result {
+        Ok(Ok(entry)) => {
+            println!("Installed {}", entry.summary());
+            refresh_models(ui_elements);
+            true
+        }
+        Ok(Err(_)) if cancelled => {
+            println!("Download cancelled, it resumes on the next attempt");
+            false
+        }
+        Ok(Err(e)) => {
+            eprintln!("Error downloading model: {}", e);
+            gtk4::AlertDialog::builder()
+                .message("The model download failed")
+                .detail(format!(
+                    "{}\n\nCheck the connection, or set a mirror or proxy on the Models page of the settings.",
+                    e
+                ))
+                .modal(true)
+                .build()
+                .show(Some(&ui_elements.window));
+            false
+        }
+        Err(_) => {
+            eprintln!("Model download panicked");
+            false
+        }
+    }
+}
+
 pub fn build_ui(app: &gtk4::Application) {
     load_css();
 
@@ -58,8 +198,8 @@ pub fn build_ui(app: &gtk4::Application) {
     let ui_elements = Rc::new(UiElements::new(app));
     // Initialize object to hold settings
     let settings = FunctionsSettings::new(&ui_elements);
+    refresh_models(&ui_elements);
 
-    let (sender, receiver) = async_channel::bounded(1);
     // Logic for filepicker
     ui_elements.button_open_file.connect_clicked(clone!(
         #[strong]
@@ -90,6 +230,44 @@ pub fn build_ui(app: &gtk4::Application) {
                             if let Some(path) = file.path() {
                                 ui_elements.text_box.set_text(&path.to_string_lossy());
                                 ui_elements.button_proceed.set_sensitive(true);
+                                ui_elements.button_auto_tune.set_sensitive(true);
+                            }
+                        }
+                    }
+                ),
+            );
+        }
+    ));
+
+    // Logic for adding recordings to combine
+    ui_elements.button_add_recording.connect_clicked(clone!(
+        #[strong]
+        ui_elements,
+        move |_| {
+            let file_dialog = gtk4::FileDialog::new();
+            let filter = gtk4::FileFilter::new();
+            filter.set_name(Some("WAV files"));
+            filter.add_mime_type("audio/x-wav");
+            let filter_store = gio::ListStore::with_type(gtk4::FileFilter::static_type());
+            filter_store.append(&filter);
+            file_dialog.set_filters(Some(&filter_store));
+            file_dialog.set_modal(true);
+
+            file_dialog.open(
+                Some(&ui_elements.window),
+                None::<&gio::Cancellable>,
+                clone!(
+                    #[strong]
+                    ui_elements,
+                    move |result| {
+                        if let Ok(file) = result {
+                            if let Some(path) = file.path() {
+                                let mut recordings = ui_elements.combine_entry.text().to_string();
+                                if !recordings.is_empty() {
+                                    recordings.push(';');
+                                }
+                                recordings.push_str(&path.to_string_lossy());
+                                ui_elements.combine_entry.set_text(&recordings);
                             }
                         }
                     }
@@ -107,6 +285,209 @@ pub fn build_ui(app: &gtk4::Application) {
         }
     ));
 
+    // Logic for auto-tune button
+    ui_elements.button_auto_tune.connect_clicked(clone!(
+        #[strong]
+        ui_elements,
+        #[strong]
+        settings,
+        move |button| {
+            let filename = ui_elements.text_box.text().to_string();
+            if filename.is_empty() {
+                return;
+            }
+            button.set_sensitive(false);
+            ui_elements.button_proceed.set_sensitive(false);
+            ui_elements.progress_bar.set_text(Some("Auto-tuning..."));
+
+            let task = gio::spawn_blocking(clone!(
+                #[strong]
+                settings,
+                move || autotune::tune(&filename, &settings)
+            ));
+            glib::MainContext::default().spawn_local(clone!(
+                #[strong]
+                ui_elements,
+                async move {
+                    match task.await {
+                        Ok(Ok(result)) => {
+                            // The spin buttons store the values in the settings
+                            let best = &result.best;
+                            ui_elements
+                                .cutoff_frequency_spinbutton
+                                .set_value(best.cutoff_freq as f64);
+                            ui_elements
+                                .window_size_spinbutton
+                                .set_value(best.window_size as f64);
+                            ui_elements
+                                .scaling_factor_spinbutton
+                                .set_value(best.scaling_factor as f64);
+                            ui_elements
+                                .additional_offset_spinbutton
+                                .set_value(best.additional_offset as f64);
+                            ui_elements
+                                .quality_label
+                                .set_text(&format!("Auto-tune: {}", result.summary()));
+                            ui_elements
+                                .progress_bar
+                                .set_text(Some("Auto-tune complete"));
+                        }
+                        Ok(Err(e)) => {
+                            eprintln!("Error auto-tuning: {}", e);
+                            ui_elements
+                                .progress_bar
+                                .set_text(Some(&format!("Auto-tune failed: {}", e)));
+                        }
+                        Err(_) => eprintln!("Auto-tune panicked"),
+                    }
+                    ui_elements.button_auto_tune.set_sensitive(true);
+                    ui_elements.button_proceed.set_sensitive(true);
+                }
+            ));
+        }
+    ));
+
+    // Logic for custom palette filepicker
+    ui_elements.button_browse_lut.connect_clicked(clone!(
+        #[strong]
+        ui_elements,
+        move |_| {
+            let file_dialog = gtk4::FileDialog::new();
+            let filter = gtk4::FileFilter::new();
+            filter.set_name(Some("PNG palettes"));
+            filter.add_mime_type("image/png");
+            let filter_store = gio::ListStore::with_type(gtk4::FileFilter::static_type());
+            filter_store.append(&filter);
+            file_dialog.set_filters(Some(&filter_store));
+            file_dialog.set_modal(true);
+
+            file_dialog.open(
+                Some(&ui_elements.window),
+                None::<&gio::Cancellable>,
+                clone!(
+                    #[strong]
+                    ui_elements,
+                    move |result| {
+                        if let Ok(file) = result {
+                            if let Some(path) = file.path() {
+                                ui_elements
+                                    .custom_lut_entry
+                                    .set_text(&path.to_string_lossy());
+                            }
+                        }
+                    }
+                ),
+            );
+        }
+    ));
+
+    // Logic for model import filepicker
+    ui_elements.button_import_model.connect_clicked(clone!(
+        #[strong]
+        ui_elements,
+        move |_| {
+            let file_dialog = gtk4::FileDialog::new();
+            let filter = gtk4::FileFilter::new();
+            filter.set_name(Some("ONNX models"));
+            filter.add_pattern("*.onnx");
+            let filter_store = gio::ListStore::with_type(gtk4::FileFilter::static_type());
+            filter_store.append(&filter);
+            file_dialog.set_filters(Some(&filter_store));
+            file_dialog.set_modal(true);
+
+            file_dialog.open(
+                Some(&ui_elements.window),
+                None::<&gio::Cancellable>,
+                clone!(
+                    #[strong]
+                    ui_elements,
+                    move |result| {
+                        let Some(path) = result.ok().and_then(|file| file.path()) else {
+                            return;
+                        };
+                        ui_elements.button_import_model.set_sensitive(false);
+                        ui_elements
+                            .model_details_label
+                            .set_text("Importing model...");
+                        // Loading the model to inspect it can take a while
+                        let task = gio::spawn_blocking(move || {
+                            Registry::load()
+                                .and_then(|mut registry| registry.import(&path, None, "1", None))
+                        });
+                        glib::MainContext::default().spawn_local(clone!(
+                            #[strong]
+                            ui_elements,
+                            async move {
+                                match task.await {
+                                    Ok(Ok(entry)) => {
+                                        refresh_models(&ui_elements);
+                                        ui_elements
+                                            .model_details_label
+                                            .set_text(&format!("Imported {}", entry.summary()));
+                                    }
+                                    Ok(Err(e)) => {
+                                        eprintln!("Error importing model: {}", e);
+                                        ui_elements
+                                            .model_details_label
+                                            .set_text(&format!("Import failed: {}", e));
+                                    }
+                                    Err(_) => eprintln!("Model import panicked"),
+                                }
+                                ui_elements.button_import_model.set_sensitive(true);
+                            }
+                        ));
+                    }
+                ),
+            );
+        }
+    ));
+
+    // Logic for the SGBNR preview of the displayed image
+    ui_elements.button_sgbnr_preview.connect_clicked(clone!(
+        #[strong]
+        ui_elements,
+        #[strong]
+        settings,
+        move |button| {
+            let Some(path) = ui_elements
+                .picture_widget
+                .file()
+                .and_then(|file| file.path())
+            else {
+                eprintln!("No image displayed to preview SGBNR on");
+                return;
+            };
+            button.set_sensitive(false);
+            let task = gio::spawn_blocking(clone!(
+                #[strong]
+                settings,
+                move || gaussian_blur::preview(&path, &settings)
+            ));
+            glib::MainContext::default().spawn_local(clone!(
+                #[strong]
+                ui_elements,
+                async move {
+                    match task.await {
+                        Ok(Ok((output_path, mask_path, estimate))) => {
+                            ui_elements
+                                .sgbnr_preview_picture
+                                .set_filename(Some(&output_path));
+                            ui_elements
+                                .sgbnr_mask_picture
+                                .set_filename(Some(&mask_path));
+                            ui_elements
+                                .sgbnr_estimate_label
+                                .set_text(&format!("Estimated: {}", estimate.summary()));
+                        }
+                        Ok(Err(e)) => eprintln!("Error previewing SGBNR: {}", e),
+                        Err(_) => eprintln!("SGBNR preview panicked"),
+                    }
+                    ui_elements.button_sgbnr_preview.set_sensitive(true);
+                }
+            ));
+        }
+    ));
+
     // Logic for proceed button
     ui_elements.checkbox_sync.connect_toggled(clone!(
         #[strong]
@@ -125,8 +506,9 @@ pub fn build_ui(app: &gtk4::Application) {
         app_state,
         #[strong]
         ui_elements,
+        #[strong]
+        settings,
         move |checkbox| {
-            let sender = sender.clone();
             let is_active = checkbox.is_active();
             println!("Enhance image: {}", checkbox.is_active());
             app_state
@@ -138,122 +520,32 @@ pub fn build_ui(app: &gtk4::Application) {
             }
 
             if checkbox.is_active() {
-                let model_path = Path::new("model.onnx");
-                if !model_path.exists() {
+                let enhance_model = settings.lock().unwrap().enhance_model.clone();
+                let model_path = models::model_path(enhance_model.as_deref());
+                if !model_path.is_ok_and(|path| Path::new(&path).exists()) {
                     let dialog = gtk4::AlertDialog::builder()
                         .message("The U-Net model file is missing. Would you like to download it?")
                         .buttons(["Yes", "No"])
                         .modal(true)
                         .build();
 
-                    let progress_window = gtk4::Window::builder()
-                        .title("Downloading Model")
-                        .default_width(300)
-                        .default_height(100)
-                        .modal(true)
-                        .transient_for(&ui_elements.window)
-                        .build();
-                    progress_window.set_resizable(false);
-
-                    let progress_bar = gtk4::ProgressBar::new();
-                    progress_bar.set_show_text(true);
-                    progress_bar.set_text(Some("Starting download..."));
-                    progress_bar.set_margin_top(12);
-                    progress_bar.set_margin_bottom(12);
-                    progress_bar.set_margin_start(12);
-                    progress_bar.set_margin_end(12);
-
-                    progress_window.set_child(Some(&progress_bar));
-                    progress_window.present();
-
-                    // clone needed values for async/blocked tasks
-                    let dialog_clone = dialog.clone();
-                    let sender_clone = sender.clone();
-                    let ui_elements_clone = ui_elements.clone();
-
                     // await the user's response before proceeding
                     glib::MainContext::default().spawn_local(clone!(
                         #[strong]
-                        progress_window,
+                        ui_elements,
+                        #[strong]
+                        settings,
                         async move {
-                            if let Ok(answer) = dialog_clone
-                                .choose_future(Some(&ui_elements_clone.window))
-                                .await
-                            {
-                                if answer == 0 {
-                                    // offload the blocking download to the thread pool
-                                    gio::spawn_blocking(move || {
-                                        let mut downloaded: u64 = 0;
-                                        if let Ok(mut resp) = get(UNET_MODEL_URL) {
-                                            if resp.status().is_success() {
-                                                let total = resp.content_length().unwrap_or(0);
-                                                if let Ok(mut file) = File::create("model.onnx") {
-                                                    let mut buf = [0u8; 8192];
-                                                    // read in chunks
-                                                    loop {
-                                                        match resp.read(&mut buf) {
-                                                            Ok(0) => break,
-                                                            Ok(n) => {
-                                                                downloaded += n as u64;
-                                                                if file.write_all(&buf[..n]).is_ok()
-                                                                {
-                                                                    let fraction = downloaded
-                                                                        as f64
-                                                                        / total as f64;
-                                                                    let text = format!(
-                                                                        "Downloading... {:.0}%",
-                                                                        fraction * 100.0
-                                                                    );
-                                                                    let _ = sender_clone
-                                                                        .try_send((fraction, text));
-                                                                }
-                                                            }
-                                                            Err(e) => {
-                                                                eprintln!(
-                                                                    "Error reading chunk: {}",
-                                                                    e
-                                                                );
-                                                                break;
-                                                            }
-                                                        }
-                                                    }
-                                                    // final update
-                                                    while sender_clone
-                                                        .try_send((1.0, "Download complete".into()))
-                                                        .is_err()
-                                                    {
-                                                        std::thread::sleep(
-                                                            std::time::Duration::from_millis(10),
-                                                        );
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    });
-                                } else {
-                                    // User chose not to download the model
-                                    progress_window.close();
-                                    ui_elements_clone.checkbox_use_model.set_active(false);
-                                }
+                            let answer = dialog.choose_future(Some(&ui_elements.window)).await;
+                            let installed = match answer {
+                                Ok(0) => download_unet_model(&ui_elements, &settings).await,
+                                _ => false,
+                            };
+                            if !installed {
+                                ui_elements.checkbox_use_model.set_active(false);
                             }
                         }
                     ));
-
-                    // Update the progress bar with the download progress
-                    let ui_elements_clone = ui_elements.clone();
-                    let progress_window_clone = progress_window.clone();
-                    let progress_rx = receiver.clone();
-                    glib::MainContext::default().spawn_local(async move {
-                        while let Ok((fraction, text)) = progress_rx.recv().await {
-                            progress_bar.set_fraction(fraction);
-                            progress_bar.set_text(Some(&text));
-                            if fraction >= 1.0 {
-                                progress_window_clone.close();
-                                ui_elements_clone.checkbox_use_model.set_active(true);
-                                break;
-                            }
-                        }
-                    });
                 }
             }
         }
@@ -278,6 +570,18 @@ pub fn build_ui(app: &gtk4::Application) {
         }
     ));
 
+    // Logic for split passes checkbox
+    ui_elements.checkbox_split_passes.connect_toggled(clone!(
+        #[strong]
+        app_state,
+        move |checkbox| {
+            println!("Split passes: {}", checkbox.is_active());
+            app_state
+                .split_passes
+                .store(checkbox.is_active(), Ordering::SeqCst);
+        }
+    ));
+
     let (sender, receiver) = async_channel::bounded(1);
 
     // Logic for the proceed button
@@ -291,6 +595,16 @@ pub fn build_ui(app: &gtk4::Application) {
         move |_| {
             let sender = sender.clone();
             let filename = ui_elements.text_box.text().to_string();
+            let mut recordings = vec![filename.clone()];
+            recordings.extend(
+                ui_elements
+                    .combine_entry
+                    .text()
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(String::from),
+            );
             if !filename.is_empty() {
                 gio::spawn_blocking(clone!(
                     #[strong]
@@ -301,22 +615,53 @@ pub fn build_ui(app: &gtk4::Application) {
                         let app_state = app_state.clone();
                         let settings = settings.clone();
                         let filename = filename.to_string();
-                        // Call the function to enhance the image with the model
-                        compute_signal(&filename, &app_state, &settings, &sender);
+                        if recordings.len() > 1 {
+                            app_state.passes.lock().unwrap().clear();
+                            if let Err(e) =
+                                combine_recordings(&recordings, &app_state, &settings, &sender)
+                            {
+                                eprintln!("Error combining recordings: {}", e);
+                                while sender.try_send((1.0, String::new())).is_err() {
+                                    std::thread::sleep(std::time::Duration::from_millis(10));
+                                }
+                            }
+                        } else if app_state.split_passes.load(Ordering::SeqCst) {
+                            if let Err(e) = split_passes(&filename, &app_state, &settings, &sender)
+                            {
+                                eprintln!("Error splitting passes: {}", e);
+                                while sender.try_send((1.0, String::new())).is_err() {
+                                    std::thread::sleep(std::time::Duration::from_millis(10));
+                                }
+                            }
+                        } else {
+                            app_state.passes.lock().unwrap().clear();
+                            // Call the function to enhance the image with the model
+                            compute_signal(&filename, &app_state, &settings, &sender);
+                        }
                     }
                 ));
             }
 
             // update progress bar with processing progress
             let ui_elements_clone = ui_elements.clone();
+            let app_state_clone = app_state.clone();
             let progress_rx = receiver.clone();
             glib::MainContext::default().spawn_local(async move {
                 ui_elements_clone.button_proceed.set_sensitive(false);
                 while let Ok((fraction, text)) = progress_rx.recv().await {
                     if fraction >= 1.0 {
-                        ui_elements_clone
-                            .progress_bar
-                            .set_text(Some("Processing complete"));
+                        let input = app_state_clone.input.lock().unwrap().clone();
+                        let status = match *app_state_clone.cloud_fraction.lock().unwrap() {
+                            _ if text.is_empty() => String::from("Nothing to decode"),
+                            Some(fraction) => {
+                                format!(
+                                    "Processing complete (cloud cover {:.0}%)",
+                                    fraction * 100.0
+                                )
+                            }
+                            None => String::from("Processing complete"),
+                        };
+                        ui_elements_clone.progress_bar.set_text(Some(&status));
                         ui_elements_clone.progress_bar.set_fraction(1.0);
                         ui_elements_clone.button_proceed.set_sensitive(true);
 
@@ -325,6 +670,70 @@ pub fn build_ui(app: &gtk4::Application) {
                             let file = gio::File::for_path(&path);
                             ui_elements_clone.picture_widget.set_file(Some(&file));
                         }
+
+                        // Input warnings, and an explanation when the input was refused
+                        let mut quality_text = String::new();
+                        if let Some(input) = &input {
+                            quality_text.push_str(&format!("Input: {}\n", input.summary()));
+                            for warning in &input.warnings {
+                                quality_text.push_str(&format!("Warning: {}\n", warning));
+                            }
+                            if path.is_empty() && input.hopeless {
+                                gtk4::AlertDialog::builder()
+                                    .message("Nothing to decode in this recording")
+                                    .detail(format!(
+                                        "{}\n\nEnable \"Decode without subcarrier\" in the settings to decode it anyway.",
+                                        input.warnings.join("\n")
+                                    ))
+                                    .modal(true)
+                                    .build()
+                                    .show(Some(&ui_elements_clone.window));
+                            }
+                        }
+                        quality_text += &app_state_clone
+                            .passes
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .enumerate()
+                            .map(|(n, pass)| format!("Pass {}: {}\n", n + 1, pass.summary()))
+                            .collect::<String>();
+                        if let Some(report) = app_state_clone.quality.lock().unwrap().as_ref() {
+                            quality_text.push_str(&report.summary());
+                        }
+                        if let Some(provider) = inference::active_provider() {
+                            quality_text
+                                .push_str(&format!("\nExecution provider: {}", provider.name()));
+                        }
+                        ui_elements_clone.quality_label.set_text(&quality_text);
+
+                        // Move the crop handles onto the lines kept by this run
+                        if let Some((first, last, total)) =
+                            *app_state_clone.line_span.lock().unwrap()
+                        {
+                            let total = total as f64;
+                            ui_elements_clone.crop_first_scale.set_range(0.0, total);
+                            ui_elements_clone.crop_last_scale.set_range(0.0, total);
+                            if !ui_elements_clone.checkbox_manual_crop.is_active() {
+                                ui_elements_clone.crop_first_scale.set_value(first as f64);
+                                ui_elements_clone.crop_last_scale.set_value(last as f64);
+                            }
+                            ui_elements_clone.checkbox_manual_crop.set_sensitive(true);
+                        }
+
+                        // List every generated image in the output selector
+                        let outputs = app_state_clone.outputs.lock().unwrap().clone();
+                        let output_refs: Vec<&str> = outputs.iter().map(|o| o.as_str()).collect();
+                        let model = gtk4::StringList::new(&output_refs);
+                        ui_elements_clone.output_dropdown.set_model(Some(&model));
+                        if let Some(position) = outputs.iter().position(|o| *o == path) {
+                            ui_elements_clone
+                                .output_dropdown
+                                .set_selected(position as u32);
+                        }
+                        ui_elements_clone
+                            .output_dropdown
+                            .set_sensitive(outputs.len() > 1);
                         break;
                     }
                     ui_elements_clone.progress_bar.set_fraction(fraction);
@@ -334,5 +743,22 @@ pub fn build_ui(app: &gtk4::Application) {
         }
     ));
 
+    // Logic for output selector
+    ui_elements
+        .output_dropdown
+        .connect_selected_item_notify(clone!(
+            #[strong]
+            ui_elements,
+            move |dropdown| {
+                if let Some(item) = dropdown
+                    .selected_item()
+                    .and_downcast::<gtk4::StringObject>()
+                {
+                    let file = gio::File::for_path(item.string());
+                    ui_elements.picture_widget.set_file(Some(&file));
+                }
+            }
+        ));
+
     ui_elements.window.present();
 }
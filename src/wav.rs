@@ -1,32 +1,51 @@
 use crate::app_state::AppState;
+use crate::apt;
+use crate::audio_cleanup::{self, CleanupSettings};
+use crate::cloud_mask::{self, CloudMaskSettings};
+use crate::color;
+use crate::diagnostics::InputDiagnostics;
+use crate::dropout;
+use crate::enhancer::{self, EnhanceOptions, Enhancer};
 use crate::gaussian_blur;
+use crate::inference;
+use crate::models;
+use crate::products;
+use crate::quality::{self, LineQuality, QualityReport};
 use crate::settings::FunctionsSettings;
+use crate::trim;
 
 use async_channel::Sender;
-use hound::WavReader;
-use image::{GenericImageView, GrayImage, ImageBuffer, Luma};
-use ort::{
-    execution_providers::CUDAExecutionProvider,
-    session::{builder::GraphOptimizationLevel, Session},
-    value::Tensor,
-};
+use hound::{WavReader, WavSpec};
+use image::{GrayImage, ImageBuffer, Luma};
 use rayon::prelude::*;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 use std::{error::Error, sync::Arc, sync::Mutex};
 use sysinfo::{get_current_pid, Pid, System};
 
+// Sync pattern for APT signal
+// [..WW..WW..WW..WW..WW..WW..WW........]
+pub const SYNC_PATTERN: [f32; 36] = [
+    -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0,
+    -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0, -1.0, -1.0,
+    -1.0, -1.0,
+];
+
+/// Decode a recording through every enabled stage. Returns the path of the image
+/// to display, empty when there was nothing to decode, and every file written to
+/// the working directory, none when decoding failed.
 pub fn compute_signal(
     filepath: &str,
     app_state: &AppState,
     settings: &Arc<Mutex<FunctionsSettings>>,
     sender: &Sender<(f64, String)>,
-) -> String {
+) -> (String, Vec<String>) {
     let mut ram_usage: Vec<f32> = Vec::new();
     let mut cpu_usage: Vec<f32> = Vec::new();
 
     // Start timer
     let start = Instant::now();
+    inference::reset_active_provider();
 
     // System information
     let mut sys = System::new_all();
@@ -55,62 +74,312 @@ pub fn compute_signal(
         app_state.use_model.load(Ordering::Relaxed)
     );
 
+    let demodulated = match demodulate(filepath, app_state, settings, sender, &mut || {
+        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
+        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+    }) {
+        Ok(Some(demodulated)) => demodulated,
+        Ok(None) => {
+            app_state.outputs.lock().unwrap().clear();
+            *app_state.quality.lock().unwrap() = None;
+            *app_state.cloud_fraction.lock().unwrap() = None;
+            while sender.try_send((1.0, String::new())).is_err() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            return (String::new(), Vec::new());
+        }
+        Err(e) => {
+            eprintln!("Error reading samples: {}", e);
+            return (String::from("Error reading samples"), Vec::new());
+        }
+    };
+
+    let path = match generate_image(
+        &demodulated.signal,
+        demodulated.sample_rate,
+        apt::REDUCTION_FACTOR,
+    ) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Error generating image: {}", e);
+            return (String::from("Error generating image"), Vec::new());
+        }
+    };
+
     // Update progress bar
-    let _ = sender.try_send((0.1, String::from("Loading WAV file...")));
+    let _ = sender.try_send((0.9, String::from("Generating image...")));
 
-    /*
-        Loading wav files with hound
-    */
-    let mut reader = WavReader::open(filepath).unwrap();
-    let spec = reader.spec();
-    if app_state.debug {
-        println!("Wav file: {}", filepath);
-        println!("Sample rate: {}", spec.sample_rate);
-        println!("Channels: {}", spec.channels);
-        println!("Sample format: {:?}", spec.sample_format);
+    let mut outputs = vec![path.clone()];
+    // Files written besides the outputs shown in the GUI
+    let mut data_files = Vec::new();
+
+    // Signal quality report
+    let report = QualityReport::new(demodulated.lines);
+    println!("Quality: {}", report.summary());
+    match report.save() {
+        Ok(plot_path) => {
+            outputs.push(plot_path);
+            data_files.push(String::from(quality::REPORT_PATH));
+        }
+        Err(e) => eprintln!("Error saving quality report: {}", e),
     }
+    let sync_scores: Vec<f32> = report.lines.iter().map(|line| line.sync_score).collect();
+    *app_state.quality.lock().unwrap() = Some(report);
+
+    // Dropout repair, every later stage works on the repaired image
+    let (repair_dropouts, inpaint_model_path, cpu_threads, provider, backend) = {
+        let s = settings.lock().unwrap();
+        (
+            s.repair_dropouts,
+            s.inpaint_model_path.clone(),
+            s.cpu_threads,
+            s.execution_provider,
+            s.backend,
+        )
+    };
+    let path = if repair_dropouts {
+        println!("Repairing dropouts...");
+        match dropout::repair_dropouts(
+            &path,
+            &sync_scores,
+            &inpaint_model_path,
+            cpu_threads,
+            provider,
+            backend,
+        ) {
+            Ok((repaired_path, mask_path)) => {
+                outputs.push(repaired_path.clone());
+                outputs.push(mask_path);
+                repaired_path
+            }
+            Err(e) => {
+                eprintln!("Error repairing dropouts: {}", e);
+                path
+            }
+        }
+    } else {
+        path
+    };
+    let image_path = path.clone();
 
-    push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-    push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+    let path = if app_state.use_model.load(Ordering::Relaxed) {
+        println!("Enhancing image...");
+        let (enhance_model, cpu_threads, provider, backend, options) = {
+            let s = settings.lock().unwrap();
+            (
+                s.enhance_model.clone(),
+                s.cpu_threads,
+                s.execution_provider,
+                s.backend,
+                EnhanceOptions::from_settings(&s),
+            )
+        };
+        let enhanced = models::model_path(enhance_model.as_deref())
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|model_path| {
+                println!("Model: {}", model_path);
+                enhancer::with_cached(
+                    &app_state.enhancer,
+                    &model_path,
+                    cpu_threads,
+                    provider,
+                    backend,
+                    |enhancer| enhance_image_with_model(&path, enhancer, options),
+                )
+            })
+            .and_then(|result| result);
 
-    let target_sample_rate = 20800;
+        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
+        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
 
-    let mut samples: Vec<f32> = Vec::new();
-    if spec.sample_format == hound::SampleFormat::Float {
-        let samples_float = match reader
-            .samples::<f32>()
-            .collect::<Result<Vec<f32>, hound::Error>>()
-        {
-            Ok(samples) => samples,
+        match enhanced {
+            Ok(enhanced_image_path) => {
+                outputs.push(enhanced_image_path.clone());
+                enhanced_image_path
+            }
             Err(e) => {
-                eprintln!("Error reading samples: {}", e);
-                return String::from("Error reading samples");
+                eprintln!("Error enhancing image, keeping it as is: {}", e);
+                path
             }
-        };
-        let channels = spec.channels as usize;
-        for i in (0..samples_float.len()).step_by(channels) {
-            samples.push(samples_float[i]);
         }
-    } else if spec.sample_format == hound::SampleFormat::Int {
-        let samples_int = match reader
-            .samples::<i32>()
-            .collect::<Result<Vec<i32>, hound::Error>>()
-        {
-            Ok(samples) => samples,
+    } else if app_state.use_sgbnr.load(Ordering::Relaxed) {
+        println!("Enhancing image with SGBNR...");
+        let enhanced = gaussian_blur::selective_gaussian_blur(&path, settings);
+
+        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
+        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+
+        match enhanced {
+            Ok((enhanced_image_path, mask_path)) => {
+                outputs.push(enhanced_image_path.clone());
+                outputs.extend(mask_path);
+                enhanced_image_path
+            }
             Err(e) => {
-                eprintln!("Error reading samples: {}", e);
-                return String::from("Error reading samples");
+                eprintln!("Error enhancing image with SGBNR, keeping it as is: {}", e);
+                path
             }
-        };
-        let channels = spec.channels as usize;
-        for i in (0..samples_int.len()).step_by(channels) {
-            samples.push(samples_int[i] as f32);
+        }
+    } else {
+        path
+    };
+
+    // False-color composite of the visible and IR channels
+    let palette = settings.lock().unwrap().palette.clone();
+    let path = match palette {
+        Some(palette) if app_state.sync.load(Ordering::Relaxed) => {
+            println!("Applying palette {:?}...", palette);
+            let additional_offset = settings.lock().unwrap().additional_offset;
+            match color::generate_color_image(&path, apt::sync_column(additional_offset), &palette)
+            {
+                Ok(color_image_path) => {
+                    outputs.push(color_image_path.clone());
+                    color_image_path
+                }
+                Err(e) => {
+                    eprintln!("Error generating color image: {}", e);
+                    path
+                }
+            }
+        }
+        Some(_) => {
+            eprintln!("Palettes need a synced image, skipping color composite");
+            path
+        }
+        None => path,
+    };
+
+    // Derived products from the calibrated IR channel
+    let (products, satellite) = {
+        let s = settings.lock().unwrap();
+        (s.products.clone(), s.satellite)
+    };
+    if !products.is_empty() {
+        if app_state.sync.load(Ordering::Relaxed) {
+            println!("Generating products {:?}...", products);
+            let additional_offset = settings.lock().unwrap().additional_offset;
+            match products::generate_products(
+                &image_path,
+                apt::sync_column(additional_offset),
+                satellite,
+                &products,
+            ) {
+                Ok(product_paths) => outputs.extend(product_paths),
+                Err(e) => eprintln!("Error generating products: {}", e),
+            }
+        } else {
+            eprintln!("Products need a synced image, skipping products");
+        }
+    }
+
+    // Cloud mask and cloud fraction
+    let mut cloud_fraction = None;
+    if settings.lock().unwrap().cloud_mask {
+        if app_state.sync.load(Ordering::Relaxed) {
+            println!("Generating cloud mask...");
+            let (sync_column, cloud_mask_settings) = {
+                let s = settings.lock().unwrap();
+                (
+                    apt::sync_column(s.additional_offset),
+                    CloudMaskSettings {
+                        visible_threshold: s.cloud_visible_threshold,
+                        infrared_threshold: s.cloud_infrared_threshold,
+                        uniformity_threshold: s.cloud_uniformity_threshold,
+                        model_path: s.cloud_model_path.clone(),
+                        cpu_threads: s.cpu_threads,
+                        execution_provider: s.execution_provider,
+                        backend: s.backend,
+                        satellite: s.satellite,
+                    },
+                )
+            };
+            match cloud_mask::generate_cloud_mask(&image_path, sync_column, &cloud_mask_settings) {
+                Ok((mask_path, fraction)) => {
+                    outputs.push(mask_path);
+                    data_files.push(String::from(cloud_mask::FRACTIONS_PATH));
+                    cloud_fraction = Some(fraction);
+                }
+                Err(e) => eprintln!("Error generating cloud mask: {}", e),
+            }
+        } else {
+            eprintln!("The cloud mask needs a synced image, skipping cloud mask");
         }
     }
+    *app_state.cloud_fraction.lock().unwrap() = cloud_fraction;
+
+    println!("Outputs: {:?}", outputs);
+    let files = outputs.iter().cloned().chain(data_files).collect();
+    *app_state.outputs.lock().unwrap() = outputs;
 
     push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
     push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
 
+    // Stop timer
+    let duration = start.elapsed();
+
+    // Print benchmark results
+    println!("Time elapsed: {:?}", duration);
+    if app_state.benchmark_ram {
+        println!(
+            "Avg RAM usage: {:.2} MB",
+            ram_usage.iter().sum::<f32>() / ram_usage.len() as f32
+        );
+    }
+    if app_state.benchmark_cpu {
+        println!(
+            "Avg CPU usage: {:.2} %",
+            cpu_usage.iter().sum::<f32>() / cpu_usage.len() as f32
+        );
+    }
+
+    while sender.try_send((1.0, path.clone())).is_err() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    (path, files)
+}
+
+/// A recording demodulated, synced when sync is on, and trimmed to its pass.
+pub struct Demodulated {
+    // One line of half a second per image row
+    pub signal: Vec<f32>,
+    pub sample_rate: f32,
+    pub lines: Vec<LineQuality>,
+}
+
+/// The stages of compute_signal before any image is written: input checks, audio
+/// cleanup, demodulation, sync, line quality and trimming. Stores the input
+/// diagnostics and the line span in `app_state`. None when there is nothing to
+/// decode, `sample_usage` is called between stages for the benchmarks.
+pub fn demodulate(
+    filepath: &str,
+    app_state: &AppState,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+    sender: &Sender<(f64, String)>,
+    sample_usage: &mut dyn FnMut(),
+) -> Result<Option<Demodulated>, String> {
+    // Update progress bar
+    let _ = sender.try_send((0.1, String::from("Loading WAV file...")));
+
+    let (samples, spec) = load_samples(filepath, app_state.debug)?;
+
+    // Input level and subcarrier checks, refuse recordings with nothing to decode
+    let diagnostics = InputDiagnostics::new(&samples, &spec);
+    println!("Input: {}", diagnostics.summary());
+    for warning in &diagnostics.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    let hopeless = diagnostics.hopeless;
+    *app_state.input.lock().unwrap() = Some(diagnostics);
+    if hopeless && !settings.lock().unwrap().force_decode {
+        eprintln!("Nothing to decode in {}, not generating an image", filepath);
+        return Ok(None);
+    }
+
+    let target_sample_rate = 20800;
+
+    sample_usage();
+
     // Update progress bar
     let _ = sender.try_send((0.3, String::from("Processing samples...")));
 
@@ -122,10 +391,24 @@ pub fn compute_signal(
 
     // Resampling
     let ratio = target_sample_rate as f64 / spec.sample_rate as f64;
-    let resampled_samples = resample_signal(samples, ratio);
+    let resampled_samples = resample_signal(&samples, ratio);
+
+    // Optional cleanup of the audio before demodulation
+    let cleanup_settings = {
+        let s = settings.lock().unwrap();
+        CleanupSettings {
+            impulse_blanker: s.impulse_blanker,
+            notch_filter: s.notch_filter,
+            noise_reduction: s.noise_reduction,
+        }
+    };
+    let resampled_samples = audio_cleanup::clean(
+        resampled_samples,
+        target_sample_rate as f32,
+        &cleanup_settings,
+    );
 
-    push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-    push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+    sample_usage();
 
     // Update progress bar
     let _ = sender.try_send((0.5, String::from("Resampling...")));
@@ -141,8 +424,7 @@ pub fn compute_signal(
     let cutoff_freq = settings.lock().unwrap().cutoff_freq;
     let filtered_signal = low_pass_filter(&resampled_samples, cutoff_freq, frequency);
 
-    push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-    push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+    sample_usage();
 
     // Update progress bar
     let _ = sender.try_send((0.7, String::from("Filtering signal...")));
@@ -152,143 +434,113 @@ pub fn compute_signal(
     let scaling_factor = settings.lock().unwrap().scaling_factor;
     let am_signal = envelope_detection(&filtered_signal, window_size, scaling_factor);
 
-    push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-    push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+    sample_usage();
 
     // Update progress bar
     let _ = sender.try_send((0.8, String::from("Demodulating...")));
 
     // APT Signal sync
-    let path: String = if app_state.sync.load(Ordering::Relaxed) {
+    let frame_width = (frequency * 0.5) as usize;
+    let (signal, quality_lines) = if app_state.sync.load(Ordering::Relaxed) {
         println!("Syncing...");
-        let frame_width = (frequency * 0.5) as usize;
-
-        // Sync pattern for APT signal
-        // [..WW..WW..WW..WW..WW..WW..WW........]
-        let sync_pattern = vec![
-            -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0,
-            -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, -1.0,
-            -1.0, -1.0, -1.0, -1.0, -1.0,
-        ];
         let additional_offset = settings.lock().unwrap().additional_offset;
-        let synced_signal = sync_apt(&am_signal, frame_width, &sync_pattern, additional_offset);
-
-        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
-
-        match generate_image(&synced_signal, frequency, 5) {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Error generating image: {}", e);
-                return String::from("Error generating image");
-            }
-        }
+        sync_apt(&am_signal, frame_width, &SYNC_PATTERN, additional_offset)
     } else {
-        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
-
-        match generate_image(&am_signal, frequency, 5) {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!("Error generating image: {}", e);
-                return String::from("Error generating image");
-            }
-        }
+        let quality_lines = measure_quality(&am_signal, frame_width, &SYNC_PATTERN);
+        (am_signal, quality_lines)
     };
 
-    // Update progress bar
-    let _ = sender.try_send((0.9, String::from("Generating image...")));
+    sample_usage();
 
-    if app_state.use_model.load(Ordering::Relaxed) {
-        println!("Enhancing image...");
-        let model_path = "model.onnx";
-        let cpu_threads = settings.lock().unwrap().cpu_threads;
-        let enhanced_image_path = enhance_image_with_model(&path, model_path, cpu_threads).unwrap();
-
-        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
-
-        // Stop timer
-        let duration = start.elapsed();
-
-        // Print benchmark results
-        println!("Time elapsed: {:?}", duration);
-        if app_state.benchmark_ram {
-            println!(
-                "Avg RAM usage: {:.2} MB",
-                ram_usage.iter().sum::<f32>() / ram_usage.len() as f32
-            );
-        }
-        if app_state.benchmark_cpu {
-            println!(
-                "Avg CPU usage: {:.2} %",
-                cpu_usage.iter().sum::<f32>() / cpu_usage.len() as f32
-            );
-        }
-
-        while sender.try_send((1.0, enhanced_image_path.clone())).is_err() {
-            std::thread::sleep(Duration::from_millis(10));
-        }
-
-        enhanced_image_path
-    } else if app_state.use_sgbnr.load(Ordering::Relaxed) {
-        println!("Enhancing image with SGBNR...");
-        let enhanced_image_path = gaussian_blur::selective_gaussian_blur(&path, settings).unwrap();
-
-        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
-
-        // Stop timer
-        let duration = start.elapsed();
-
-        // Print benchmark results
-        println!("Time elapsed: {:?}", duration);
-        if app_state.benchmark_ram {
-            println!(
-                "Avg RAM usage: {:.2} MB",
-                ram_usage.iter().sum::<f32>() / ram_usage.len() as f32
-            );
-        }
-        if app_state.benchmark_cpu {
-            println!(
-                "Avg CPU usage: {:.2} %",
-                cpu_usage.iter().sum::<f32>() / cpu_usage.len() as f32
+    // Drop the noise-only lines before and after the pass so they do not skew normalization
+    let total_lines = quality_lines.len();
+    let (line_range, auto_trim, trim_margin) = {
+        let s = settings.lock().unwrap();
+        (s.line_range, s.auto_trim, s.trim_margin)
+    };
+    let (first_line, last_line) = match line_range {
+        Some((first, last)) if first < last.min(total_lines) => (first, last.min(total_lines)),
+        Some((first, last)) => {
+            eprintln!(
+                "Line range {}-{} is outside the {} lines of the recording, keeping every line",
+                first, last, total_lines
             );
+            (0, total_lines)
         }
-
-        while sender.try_send((1.0, enhanced_image_path.clone())).is_err() {
-            std::thread::sleep(Duration::from_millis(10));
+        None if auto_trim => {
+            let energy = trim::subcarrier_energy(&resampled_samples, frame_width, frequency);
+            trim::detect_signal_span(&quality_lines, &energy, trim_margin).unwrap_or_else(|| {
+                eprintln!("No signal detected, keeping every line");
+                (0, total_lines)
+            })
         }
+        None => (0, total_lines),
+    };
+    println!(
+        "Keeping lines {}-{} of {}",
+        first_line, last_line, total_lines
+    );
+    *app_state.line_span.lock().unwrap() = Some((first_line, last_line, total_lines));
 
-        enhanced_image_path
-    } else {
-        push_ram_usage(&app_state.benchmark_ram, &mut sys, &mut ram_usage, pid);
-        push_cpu_usage(&app_state.benchmark_cpu, &mut sys, &mut cpu_usage, pid);
+    Ok(Some(Demodulated {
+        signal: signal[first_line * frame_width..last_line * frame_width].to_vec(),
+        sample_rate: frequency,
+        lines: quality_lines[first_line..last_line].to_vec(),
+    }))
+}
 
-        // Stop timer
-        let duration = start.elapsed();
+/// Load the first channel of a WAV file.
+pub fn load_samples(filepath: &str, debug: bool) -> Result<(Vec<f32>, WavSpec), String> {
+    /*
+        Loading wav files with hound
+    */
+    let mut reader = WavReader::open(filepath).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+    if debug {
+        println!("Wav file: {}", filepath);
+        println!("Sample rate: {}", spec.sample_rate);
+        println!("Channels: {}", spec.channels);
+        println!("Sample format: {:?}", spec.sample_format);
+    }
 
-        // Print benchmark results
-        println!("Time elapsed: {:?}", duration);
-        if app_state.benchmark_ram {
-            println!(
-                "Avg RAM usage: {:.2} MB",
-                ram_usage.iter().sum::<f32>() / ram_usage.len() as f32
-            );
-        }
-        if app_state.benchmark_cpu {
-            println!(
-                "Avg CPU usage: {:.2} %",
-                cpu_usage.iter().sum::<f32>() / cpu_usage.len() as f32
-            );
-        }
+    let channels = spec.channels as usize;
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .step_by(channels)
+            .collect::<Result<Vec<f32>, hound::Error>>(),
+        hound::SampleFormat::Int => reader
+            .samples::<i32>()
+            .step_by(channels)
+            .map(|sample| sample.map(|s| s as f32))
+            .collect::<Result<Vec<f32>, hound::Error>>(),
+    }
+    .map_err(|e| e.to_string())?;
 
-        while sender.try_send((1.0, path.clone())).is_err() {
-            std::thread::sleep(Duration::from_millis(10));
-        }
+    Ok((samples, spec))
+}
 
-        path
-    }
+/// Subcarrier energy and sync quality of every line of a recording, used to find passes.
+pub fn scan_lines(
+    samples: &[f32],
+    sample_rate: u32,
+    settings: &Arc<Mutex<FunctionsSettings>>,
+) -> (Vec<f32>, Vec<LineQuality>) {
+    let frequency = 20800.0;
+    let frame_width = (frequency * 0.5) as usize;
+    let resampled_samples = resample_signal(samples, frequency as f64 / sample_rate as f64);
+    let energy = trim::subcarrier_energy(&resampled_samples, frame_width, frequency);
+
+    let (cutoff_freq, window_size, scaling_factor) = {
+        let s = settings.lock().unwrap();
+        (s.cutoff_freq, s.window_size, s.scaling_factor)
+    };
+    let filtered_signal = low_pass_filter(&resampled_samples, cutoff_freq, frequency);
+    let am_signal = envelope_detection(&filtered_signal, window_size, scaling_factor);
+    (
+        energy,
+        measure_quality(&am_signal, frame_width, &SYNC_PATTERN),
+    )
 }
 
 fn push_ram_usage(benchmark_ram: &bool, sys: &mut System, ram_usage: &mut Vec<f32>, pid: Pid) {
@@ -319,7 +571,7 @@ fn push_cpu_usage(benchmark_cpu: &bool, sys: &mut System, cpu_usage: &mut Vec<f3
     }
 }
 
-fn resample_signal(samples: Vec<f32>, ratio: f64) -> Vec<f32> {
+pub fn resample_signal(samples: &[f32], ratio: f64) -> Vec<f32> {
     let target_len = (samples.len() as f64 * ratio) as usize;
     (0..target_len)
         .filter_map(|i| {
@@ -335,7 +587,7 @@ fn resample_signal(samples: Vec<f32>, ratio: f64) -> Vec<f32> {
         .collect()
 }
 
-fn low_pass_filter(samples: &[f32], cutoff_freq: f32, sample_rate: f32) -> Vec<f32> {
+pub fn low_pass_filter(samples: &[f32], cutoff_freq: f32, sample_rate: f32) -> Vec<f32> {
     assert!(
         cutoff_freq > 0.0 && cutoff_freq < sample_rate / 2.0,
         "Invalid cutoff frequency"
@@ -358,22 +610,24 @@ fn low_pass_filter(samples: &[f32], cutoff_freq: f32, sample_rate: f32) -> Vec<f
     filtered_samples
 }
 
-fn normalize_image(image: &mut GrayImage) {
-    let max_value = *image.iter().max().unwrap();
-    let min_value = *image.iter().min().unwrap();
+fn normalize_image(image: &mut GrayImage) -> Result<(), Box<dyn Error>> {
+    let (Some(&max_value), Some(&min_value)) = (image.iter().max(), image.iter().min()) else {
+        return Err("Cannot normalize an empty image".into());
+    };
 
     for pixel in image.iter_mut() {
         *pixel = (((*pixel as f32 - min_value as f32) / (max_value as f32 - min_value as f32))
             * 255.0) as u8;
     }
+    Ok(())
 }
 
-fn find_sync_position(signal: &[f32], sync_pattern: &[f32]) -> usize {
+pub fn find_sync_position(signal: &[f32], sync_pattern: &[f32]) -> (usize, f32) {
     let sync_len = sync_pattern.len();
     let signal_len = signal.len();
 
     if sync_len == 0 || signal_len == 0 || sync_len > signal_len {
-        return 0; // Return 0 if input is invalid
+        return (0, 0.0); // Return 0 if input is invalid
     }
 
     let mut best_offset = 0;
@@ -398,17 +652,29 @@ fn find_sync_position(signal: &[f32], sync_pattern: &[f32]) -> usize {
         }
     }
 
-    best_offset
+    (best_offset, best_score)
+}
+
+// Per-line quality of an unsynced signal, from the same sync search sync_apt uses
+fn measure_quality(signal: &[f32], frame_width: usize, sync_pattern: &[f32]) -> Vec<LineQuality> {
+    signal
+        .par_chunks_exact(frame_width)
+        .map(|row| {
+            let (sync_start, sync_score) = find_sync_position(row, sync_pattern);
+            quality::line_quality(row, sync_start, sync_score)
+        })
+        .collect()
 }
 
-fn sync_apt(
+pub fn sync_apt(
     signal: &[f32],
     frame_width: usize,
     sync_pattern: &[f32],
     additional_offset: usize,
-) -> Vec<f32> {
+) -> (Vec<f32>, Vec<LineQuality>) {
     let mut synced = Vec::with_capacity(signal.len());
     let rows = signal.len() / frame_width;
+    let mut line_quality = Vec::with_capacity(rows);
 
     for r in 0..rows {
         let row_start = r * frame_width;
@@ -416,7 +682,8 @@ fn sync_apt(
         let row_slice = &signal[row_start..row_end];
 
         // Find best correlation offset using find_sync_position
-        let best_offset = find_sync_position(row_slice, sync_pattern);
+        let (best_offset, best_score) = find_sync_position(row_slice, sync_pattern);
+        line_quality.push(quality::line_quality(row_slice, best_offset, best_score));
 
         // Fine-tune the alignment by checking a small range around the best offset
         let mut fine_tuned_offset = best_offset;
@@ -466,10 +733,10 @@ fn sync_apt(
         synced.extend_from_slice(&row_slice[..fine_tuned_offset]);
     }
 
-    synced
+    (synced, line_quality)
 }
 
-fn envelope_detection(signal: &[f32], window_size: usize, scaling_factor: f32) -> Vec<f32> {
+pub fn envelope_detection(signal: &[f32], window_size: usize, scaling_factor: f32) -> Vec<f32> {
     let mut envelope: Vec<f32> = Vec::with_capacity(signal.len());
     for i in 0..signal.len() {
         let mut max: f32 = 0.0; // specify the type of max explicitly
@@ -482,19 +749,29 @@ fn envelope_detection(signal: &[f32], window_size: usize, scaling_factor: f32) -
     envelope
 }
 
-fn generate_image(
-    signal: &[f32],
-    frequency: f32,
-    reduction_factor: u32,
-) -> Result<String, Box<dyn Error>> {
+/// Pixel value of a demodulated sample, before normalization.
+pub fn luminance(sample: f32) -> u8 {
     const SCALE_FACTOR: f32 = 32.0;
     const MAX_LUMINANCE: f32 = 255.0;
 
+    let lum = sample / SCALE_FACTOR - SCALE_FACTOR;
+    lum.clamp(0.0, MAX_LUMINANCE) as u8
+}
+
+/// Image of a demodulated signal, one row per line, reduced in width and normalized.
+pub fn build_image(
+    signal: &[f32],
+    frequency: f32,
+    reduction_factor: u32,
+) -> Result<GrayImage, Box<dyn Error>> {
     let frame_width = (frequency * 0.5) as u32;
     println!("Frame width: {}", frame_width);
     let w = frame_width;
     let h = (signal.len() / frame_width as usize) as u32;
     println!("Width: {}, Height: {}", w, h);
+    if h == 0 {
+        return Err("Not a single full line to generate an image from".into());
+    }
 
     let mut img: GrayImage = ImageBuffer::new(w, h);
 
@@ -502,9 +779,7 @@ fn generate_image(
     let mut py = 0;
 
     for &sample in signal.iter() {
-        let mut lum = sample / SCALE_FACTOR - SCALE_FACTOR;
-        lum = lum.clamp(0.0, MAX_LUMINANCE);
-        img.put_pixel(px, py, Luma([lum as u8]));
+        img.put_pixel(px, py, Luma([luminance(sample)]));
         px += 1;
         if px >= w {
             px = 0;
@@ -528,114 +803,27 @@ fn generate_image(
     }
     img = img_resized;
 
-    normalize_image(&mut img);
-
-    img.save("image.png")?;
+    normalize_image(&mut img)?;
+    Ok(img)
+}
 
+fn generate_image(
+    signal: &[f32],
+    frequency: f32,
+    reduction_factor: u32,
+) -> Result<String, Box<dyn Error>> {
+    build_image(signal, frequency, reduction_factor)?.save("image.png")?;
     Ok(String::from("image.png"))
 }
 
 pub fn enhance_image_with_model(
     image_path: &str,
-    model_path: &str,
-    cpu_threads: usize,
+    enhancer: &Enhancer,
+    options: EnhanceOptions,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    // Load the ONNX model
-    let model = Session::builder()?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .with_intra_threads(cpu_threads)?
-        .with_execution_providers([CUDAExecutionProvider::default().build()])?
-        .commit_from_file(model_path)?;
-
-    println!("Inputs:");
-    for (i, input) in model.inputs.iter().enumerate() {
-        println!("    {i} {}: {}", input.name, input.input_type);
-    }
-    println!("Outputs:");
-    for (i, output) in model.outputs.iter().enumerate() {
-        println!("    {i} {}: {}", output.name, output.output_type);
-    }
-
-    // Load and preprocess the image
     let image = image::open(image_path)?.to_luma8();
-    let (width, height) = image.dimensions();
-    let patch_size = 256;
-    let step_size = patch_size;
-
-    // Create a blank output image
-    let output_image = Mutex::new(GrayImage::new(width, height));
-
-    // Process patches in parallel
-    (0..height)
-        .into_par_iter()
-        .step_by(step_size)
-        .for_each(|i| {
-            (0..width).into_par_iter().step_by(step_size).for_each(|j| {
-                println!("Processing patch at ({}, {})", j, i);
-                // Extract the patch from the image
-                let patch = image
-                    .view(
-                        j,
-                        i,
-                        (patch_size as u32).min(width - j),
-                        (patch_size as u32).min(height - i),
-                    )
-                    .to_image();
-
-                // Pad the patch to the full patch size if necessary
-                let mut padded_patch = GrayImage::new(patch_size as u32, patch_size as u32);
-                for y in 0..patch.height() {
-                    for x in 0..patch.width() {
-                        padded_patch.put_pixel(x, y, *patch.get_pixel(x, y));
-                    }
-                }
-
-                // Convert the patch to a tensor
-                let tensor: Tensor<f32> = Tensor::from_array(
-                    ndarray::Array4::from_shape_vec(
-                        (1, 1, patch_size, patch_size),
-                        padded_patch
-                            .iter()
-                            .map(|&p| p as f32 / 255.0)
-                            .collect::<Vec<f32>>(),
-                    )
-                    .unwrap(),
-                )
-                .unwrap();
-
-                // Run the model
-                let result = match model.run(vec![("input.1", tensor)]) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        eprintln!("Error running model: {}", e);
-                        return;
-                    }
-                };
-
-                // Get the output patch
-                let output_patch = result
-                    .get("95")
-                    .unwrap()
-                    .try_extract_tensor::<f32>()
-                    .unwrap();
-
-                // Copy the output patch to the output image
-                let mut output_image = output_image.lock().unwrap();
-                for y in 0..patch.height() {
-                    for x in 0..patch.width() {
-                        let value =
-                            (output_patch[[0, 0, y as usize, x as usize]] * 255.0).round() as u8;
-                        output_image.put_pixel(j + x, i + y, Luma([value]));
-                    }
-                }
-            });
-        });
-
-    output_image
-        .lock()
-        .unwrap()
-        .save("enhanced_image.png")
-        .unwrap();
+    let output_image = enhancer.enhance(&image, options)?;
+    output_image.save("enhanced_image.png")?;
 
     Ok(String::from("enhanced_image.png"))
 }